@@ -0,0 +1,147 @@
+use sha2::{Digest, Sha256};
+
+/// Version byte of the addresses of this chain, the first byte of every address
+pub const ADDRESS_VERSION: u8 = 0x3c;
+/// Size of the hash of the public key an address carries
+const HASH_SIZE: usize = 20;
+/// Size of the checksum ending every address
+const CHECKSUM_SIZE: usize = 4;
+/// The base58 alphabet: letters and digits, without `0`, `O`, `I` and `l` which are easily mistaken for one another
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Returns the address of a public key, where the coins sent to its owner go
+/// An address is the base58 encoding of the version byte, the first 20 bytes of the SHA-256 of the public key,
+/// and a checksum: the first 4 bytes of the double SHA-256 of what precedes it
+/// *`public_key` - the public key as hex, as written in transactions
+pub fn from_public_key(public_key: &str) -> String {
+    let key = hex::decode(public_key).unwrap_or_default();
+    let mut bytes = vec![ADDRESS_VERSION];
+    bytes.extend_from_slice(&Sha256::digest(&key)[..HASH_SIZE]);
+    let checksum = checksum(&bytes);
+    bytes.extend_from_slice(&checksum);
+    encode_base58(&bytes)
+}
+
+/// Checks that an address was written by `from_public_key`: base58, of the right size and version, with a valid checksum
+/// A mistyped character breaks the checksum, so the coins are not sent to an address nobody owns
+pub fn check(address: &str) -> Result<(), String> {
+    let bytes = decode_base58(address).ok_or_else(|| format!("invalid address {}, not base58", address))?;
+    if bytes.len() != 1 + HASH_SIZE + CHECKSUM_SIZE {
+        return Err(format!("invalid address {}, wrong length", address));
+    }
+    if bytes[0] != ADDRESS_VERSION {
+        return Err(format!("invalid address {}, unknown version {}", address, bytes[0]));
+    }
+    let (payload, check) = bytes.split_at(1 + HASH_SIZE);
+    if checksum(payload) != check {
+        return Err(format!("invalid address {}, wrong checksum, it is probably mistyped", address));
+    }
+    Ok(())
+}
+
+/// Returns the checksum of the beginning of an address
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = Sha256::digest(&Sha256::digest(payload));
+    let mut checksum = [0_u8; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&hash[..CHECKSUM_SIZE]);
+    checksum
+}
+
+/// Writes bytes in base58, each leading zero byte as a `1`
+fn encode_base58(bytes: &[u8]) -> String {
+    // Base 58 digits, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in bytes {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = bytes.iter().take_while(|byte| **byte == 0).count();
+    std::iter::repeat_n(ALPHABET[0] as char, zeros)
+        .chain(digits.iter().rev().map(|digit| ALPHABET[*digit as usize] as char))
+        .collect()
+}
+
+/// Reads base58, returns None if a character is not in the alphabet
+fn decode_base58(text: &str) -> Option<Vec<u8>> {
+    // Bytes, least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|c| *c == ALPHABET[0]).count();
+    Some(std::iter::repeat_n(0, zeros).chain(bytes.into_iter().rev()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the address carrying `payload`, with its checksum
+    fn with_checksum(payload: &[u8]) -> String {
+        let mut bytes = payload.to_vec();
+        bytes.extend_from_slice(&checksum(payload));
+        encode_base58(&bytes)
+    }
+
+    fn public_key() -> String {
+        "11".repeat(32)
+    }
+
+    #[test]
+    fn base58_round_trips() {
+        assert_eq!(encode_base58(b"Hello World!"), "2NEpo7TZRRrLZSi2U");
+        assert_eq!(encode_base58(&[0, 0, 0x28, 0x7f, 0xb4, 0xcd]), "11233QC4");
+        assert_eq!(decode_base58("11233QC4"), Some(vec![0, 0, 0x28, 0x7f, 0xb4, 0xcd]));
+        assert_eq!(decode_base58(""), Some(Vec::new()));
+        assert_eq!(decode_base58("0OIl"), None);
+    }
+
+    #[test]
+    fn addresses_of_public_keys_are_valid() {
+        let address = from_public_key(&public_key());
+        assert_eq!(check(&address), Ok(()));
+        assert_eq!(decode_base58(&address).unwrap()[0], ADDRESS_VERSION);
+        assert_ne!(from_public_key(&"22".repeat(32)), address);
+    }
+
+    #[test]
+    fn mistyped_addresses_are_refused() {
+        let address = from_public_key(&public_key());
+        let last = address.chars().last().unwrap();
+        let typo = format!("{}{}", &address[..address.len() - 1], if last == 'z' { 'y' } else { 'z' });
+        assert_eq!(check(&typo), Err(format!("invalid address {}, wrong checksum, it is probably mistyped", typo)));
+        let zero = address.replacen(&address[2..3], "0", 1);
+        assert_eq!(check(&zero), Err(format!("invalid address {}, not base58", zero)));
+    }
+
+    #[test]
+    fn addresses_of_the_wrong_length_or_version_are_refused() {
+        let short = with_checksum(&[ADDRESS_VERSION; 1 + HASH_SIZE - 1]);
+        assert_eq!(check(&short), Err(format!("invalid address {}, wrong length", short)));
+        let long = with_checksum(&[ADDRESS_VERSION; 1 + HASH_SIZE + 1]);
+        assert_eq!(check(&long), Err(format!("invalid address {}, wrong length", long)));
+        let truncated = &from_public_key(&public_key())[1..];
+        assert!(check(truncated).is_err());
+        let mut other = vec![0x00];
+        other.extend_from_slice(&[7; HASH_SIZE]);
+        let other = with_checksum(&other);
+        assert_eq!(check(&other), Err(format!("invalid address {}, unknown version 0", other)));
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::storage;
+
+/// Misbehaviour score at which a peer gets banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a ban lasts, in seconds
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+/// Penalty for a frame we could not decrypt or decode
+pub const MALFORMED_FRAME_PENALTY: u32 = 20;
+/// Penalty for a message sent while the peer was over its rate limit
+pub const RATE_LIMIT_PENALTY: u32 = 5;
+/// Penalty for a block breaking the consensus rules
+pub const INVALID_BLOCK_PENALTY: u32 = 50;
+/// Penalty for a transaction breaking the mempool rules
+pub const INVALID_TRANSACTION_PENALTY: u32 = 10;
+/// Time in seconds after which one point of a misbehaviour score is forgiven
+const SCORE_DECAY_TIME: u64 = 60;
+
+/// The peers banned by a Miner, by address and by public key, saved to disk so the bans survive a restart
+/// *`path` - the file the bans are saved in
+/// *`addresses` - the banned addresses, with the UNIX time (s) at which each ban ends
+/// *`keys` - the banned public keys (hex), proven during the handshake, with the UNIX time (s) at which each ban ends
+/// *`scores` - the misbehaviour score of the addresses which misbehaved lately, with the UNIX time (s) it was last updated.
+/// Scores go down by one point every `SCORE_DECAY_TIME`, they are dropped once back to zero and reset once banned
+pub struct BanList {
+    path: PathBuf,
+    addresses: HashMap<IpAddr, u64>,
+    keys: HashMap<String, u64>,
+    scores: HashMap<IpAddr, (u32, u64)>,
+}
+
+/// Returns the current UNIX time in seconds
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Returns what remains of a misbehaviour score last updated at `updated`, at `now`
+fn decayed(score: u32, updated: u64, now: u64) -> u32 {
+    let forgiven = now.saturating_sub(updated) / SCORE_DECAY_TIME;
+    score.saturating_sub(forgiven.min(u64::from(u32::MAX)) as u32)
+}
+
+impl BanList {
+
+    /// Loads the bans of the node listening on `sockip`, dropping those which already ended
+    /// Each line of the file is `ip <address> <end>` or `key <public key> <end>`
+    pub fn load(sockip: &str) -> Self {
+        let mut bans = BanList {
+            path: storage::node_file(sockip, "bans"),
+            addresses: HashMap::new(),
+            keys: HashMap::new(),
+            scores: HashMap::new(),
+        };
+        let lines = match storage::read_lines(&bans.path) {
+            Ok(lines) => lines,
+            Err(e) => {
+                println!("Err: {}, while loading bans", e);
+                Vec::new()
+            }
+        };
+        let now = now();
+        for line in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 {
+                continue;
+            }
+            let end = match parts[2].parse::<u64>() {
+                Ok(end) if end > now => end,
+                _ => continue,
+            };
+            match parts[0] {
+                "ip" => if let Ok(ip) = parts[1].parse::<IpAddr>() { bans.addresses.insert(ip, end); },
+                "key" if hex::decode(parts[1]).is_ok() => { bans.keys.insert(parts[1].to_lowercase(), end); },
+                _ => {}
+            }
+        }
+        println!("{} addresses and {} keys banned", bans.addresses.len(), bans.keys.len());
+        bans
+    }
+
+    /// Returns true if the address is currently banned
+    pub fn is_address_banned(&self, ip: &IpAddr) -> bool {
+        matches!(self.addresses.get(ip), Some(end) if *end > now())
+    }
+
+    /// Returns true if the public key is currently banned
+    pub fn is_key_banned(&self, key: &[u8]) -> bool {
+        matches!(self.keys.get(&hex::encode(key)), Some(end) if *end > now())
+    }
+
+    /// Raises the misbehaviour score of a peer, and bans it once the score reaches `BAN_THRESHOLD`
+    /// *`ip` - the address the faulty message came from
+    /// *`key` - the public key the peer proved it owns, if any
+    /// *`penalty` - how much the score is raised
+    /// Returns true if the peer was banned
+    pub fn misbehaved(&mut self, ip: IpAddr, key: Option<&[u8]>, penalty: u32) -> bool {
+        let now = now();
+        self.scores.retain(|_, (score, updated)| decayed(*score, *updated, now) > 0);
+        let score = self.scores.get(&ip).map_or(0, |(score, updated)| decayed(*score, *updated, now)).saturating_add(penalty);
+        self.scores.insert(ip, (score, now));
+        println!("Misbehaviour of {}: score {}", &ip, score);
+        if score < BAN_THRESHOLD {
+            return false;
+        }
+        self.scores.remove(&ip);
+        self.ban(ip, key);
+        true
+    }
+
+    /// Bans an address, and the public key it used if any, for `BAN_DURATION` seconds
+    pub fn ban(&mut self, ip: IpAddr, key: Option<&[u8]>) {
+        let end = now() + BAN_DURATION;
+        println!("Banning {} until {}", &ip, end);
+        self.addresses.insert(ip, end);
+        if let Some(key) = key {
+            self.keys.insert(hex::encode(key), end);
+        }
+        self.save();
+    }
+
+    /// Saves the bans which did not end yet
+    pub fn save(&mut self) {
+        let now = now();
+        self.addresses.retain(|_, end| *end > now);
+        self.keys.retain(|_, end| *end > now);
+        let mut lines: Vec<String> = self.addresses.iter().map(|(ip, end)| format!("ip {} {}", ip, end)).collect();
+        lines.extend(self.keys.iter().map(|(key, end)| format!("key {} {}", key, end)));
+        if let Err(e) = storage::write_lines(&self.path, &lines) {
+            println!("Err: {}, while saving bans", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty ban list saved in the temporary directory
+    fn bans(name: &str) -> BanList {
+        let path = std::env::temp_dir().join(format!("ban-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        BanList { path, addresses: HashMap::new(), keys: HashMap::new(), scores: HashMap::new() }
+    }
+
+    #[test]
+    fn scores_decay_with_time() {
+        assert_eq!(decayed(50, 1000, 1000), 50);
+        assert_eq!(decayed(50, 1000, 1000 + 10 * SCORE_DECAY_TIME + 1), 40);
+        assert_eq!(decayed(50, 1000, u64::MAX), 0);
+        assert_eq!(decayed(50, 2000, 1000), 50);
+    }
+
+    #[test]
+    fn misbehaving_peers_are_banned_with_their_key() {
+        let mut bans = bans("key");
+        let (ip, other): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let key = [7_u8; 32];
+        for _ in 1..BAN_THRESHOLD / MALFORMED_FRAME_PENALTY {
+            assert!(!bans.misbehaved(ip, Some(&key), MALFORMED_FRAME_PENALTY));
+        }
+        assert!(!bans.is_key_banned(&key));
+        assert!(bans.misbehaved(ip, Some(&key), MALFORMED_FRAME_PENALTY));
+        assert!(bans.is_address_banned(&ip));
+        assert!(bans.is_key_banned(&key));
+        assert!(!bans.is_address_banned(&other));
+        assert!(!bans.is_key_banned(&[8_u8; 32]));
+        assert!(bans.scores.is_empty());
+
+        let saved = std::fs::read_to_string(&bans.path).unwrap();
+        assert!(saved.contains(&format!("ip {} ", ip)));
+        assert!(saved.contains(&format!("key {} ", hex::encode(key))));
+        std::fs::remove_file(&bans.path).unwrap();
+    }
+
+    #[test]
+    fn scores_are_kept_per_address() {
+        let mut bans = bans("scores");
+        let (ip, other): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(!bans.misbehaved(ip, None, BAN_THRESHOLD - 1));
+        assert!(!bans.misbehaved(other, None, BAN_THRESHOLD - 1));
+        assert!(bans.misbehaved(ip, None, 1));
+        assert!(!bans.is_address_banned(&other));
+        assert_eq!(bans.scores.len(), 1);
+        std::fs::remove_file(&bans.path).unwrap();
+    }
+}
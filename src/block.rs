@@ -1,9 +1,13 @@
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
 use std::str::FromStr;
-use std::num::ParseIntError;
 use std::fmt::UpperHex;
 use bincode;
+use sha2::{Digest, Sha256};
+use crate::spv;
+
+/// Separator between the transactions of a block payload
+pub const TRANSACTION_SEPARATOR: char = '$';
 
 /** Bloc: composants de la BlockChain
  * Composants d'un bloc
@@ -12,9 +16,10 @@ use bincode;
  * Timestamp: pour avoir une notion de temps
  * Nonce: nombre utilisé pour calculer le Proof of Work
  * Previous block hash: L'empreinte cryptographique du bloc précédent
- * Hash: l'empreinte cryptographique de toutes les données ci-dessus, concatanées ensemble
+ * Hash: l'empreinte cryptographique de l'en-tête : les données ci-dessus concaténées, la racine de Merkle des transactions remplaçant le payload
  */
 
+ #[derive(Clone, PartialEq)]
  pub struct Block {
     pub index: u32,
     pub payload: String,
@@ -38,6 +43,114 @@ impl Block {
             hash: vec![0; 16],
         }
     }
+
+    /// Returns true if the stored hash is the hash of the content and starts with `difficulty` zeros
+    pub fn has_valid_proof(&self, difficulty: usize) -> bool {
+        self.header().has_valid_proof(difficulty)
+    }
+
+    /// Returns the transactions of the payload
+    pub fn transactions(&self) -> Vec<String> {
+        self.payload.split(TRANSACTION_SEPARATOR).filter(|t| !t.is_empty()).map(|t| t.to_string()).collect()
+    }
+
+    /// Returns the IDs of the transactions of the payload, in order
+    pub fn transaction_ids(&self) -> Vec<String> {
+        self.transactions().iter().map(|t| transaction_id(t)).collect()
+    }
+
+    /// Returns the block without its payload, the Merkle root of its transactions standing for them
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            merkle_root: spv::merkle_root(&self.transaction_ids()),
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            prev_hash: self.prev_hash.clone(),
+            hash: self.hash.clone(),
+        }
+    }
+}
+
+/// What the hash of a block covers: the block without its payload, whose transactions are committed to by their Merkle root
+/// Wallets check that a transaction is in a block with the header and a Merkle branch, without the other transactions
+/// *`merkle_root` - the root of the Merkle tree of the transaction IDs, as lowercase hex, see `spv::merkle_root`
+#[derive(Clone, PartialEq)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub merkle_root: String,
+    pub timestamp: u128,
+    pub nonce: u64,
+    pub prev_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+impl BlockHeader {
+
+    /// Computes the hash of the block, as lowercase hex
+    /// The hash covers the index, the Merkle root of the transactions, the timestamp, the nonce and the hash of the previous block
+    pub fn compute_hash(&self) -> String {
+        let mut sha256 = Sha256::new();
+        sha256.update(self.index.to_string() + &self.merkle_root + &self.timestamp.to_string() + &self.nonce.to_string() + &String::from_utf8_lossy(&self.prev_hash));
+        format!("{:x}", sha256.finalize())
+    }
+
+    /// Returns true if the stored hash is the hash of the header and starts with `difficulty` zeros
+    pub fn has_valid_proof(&self, difficulty: usize) -> bool {
+        let hash = self.compute_hash();
+        hash.as_bytes() == self.hash.as_slice() && hash.starts_with(&"0".repeat(difficulty))
+    }
+}
+
+impl fmt::Display for BlockHeader {
+    /// Writes the header like a block, the `merkle_root` field replacing the payload
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "id:{};prev_hash:{};timestamp:{};merkle_root:{};nonce:{};hash:{}",
+            &self.index,
+            String::from_utf8_lossy(&self.prev_hash),
+            &self.timestamp,
+            &self.merkle_root,
+            &self.nonce,
+            String::from_utf8_lossy(&self.hash),
+        )
+    }
+}
+
+impl FromStr for BlockHeader {
+    type Err = String;
+
+    /// Reads a header written by `Display`, returns an error naming the first missing or invalid field
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let header: HashMap<&str, &str> = s.split(';')
+        .filter_map(|kv| {
+            let mut parts = kv.splitn(2, ':');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect();
+        let field = |name: &str| header.get(name).copied().ok_or(format!("missing field {}", name));
+        Ok(BlockHeader {
+            index: field("id")?.parse::<u32>().map_err(|e| format!("invalid id: {}", e))?,
+            merkle_root: field("merkle_root")?.to_string(),
+            timestamp: field("timestamp")?.parse::<u128>().map_err(|e| format!("invalid timestamp: {}", e))?,
+            nonce: field("nonce")?.parse::<u64>().map_err(|e| format!("invalid nonce: {}", e))?,
+            prev_hash: field("prev_hash")?.as_bytes().to_vec(),
+            hash: field("hash")?.as_bytes().to_vec(),
+        })
+    }
+}
+
+/// Hash given as previous hash by the first block of the chain
+pub fn genesis_prev_hash() -> Vec<u8> {
+    let mut sha256 = Sha256::new();
+    sha256.update("first_block");
+    format!("{:x}", sha256.finalize()).into_bytes()
+}
+
+/// Returns the ID of a transaction, the SHA-256 of its content as lowercase hex
+pub fn transaction_id(transaction: &str) -> String {
+    let mut sha256 = Sha256::new();
+    sha256.update(transaction);
+    format!("{:x}", sha256.finalize())
 }
 
 impl fmt::Display for Block {
@@ -69,23 +182,25 @@ impl Debug for Block {
 }
 
 impl FromStr for Block {
-    type Err = ParseIntError;
+    type Err = String;
 
+    /// Allows you to create a `Block` from a `String`
+    /// Returns an error naming the first missing or invalid field, blocks come from the network and must not crash us
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        /// Allows you to create a `Block` from a `String`
-        let block: HashMap<String, String> = s.split(';')
-        .map(|kv| kv.split(':').collect::<Vec<&str>>())
-        .map(|vec| {
-            (vec[0].to_string(), vec[1].to_string())
+        let block: HashMap<&str, &str> = s.split(';')
+        .filter_map(|kv| {
+            let mut parts = kv.splitn(2, ':');
+            Some((parts.next()?, parts.next()?))
         })
         .collect();
+        let field = |name: &str| block.get(name).copied().ok_or(format!("missing field {}", name));
         Ok(Block { 
-            index: block.get("id").unwrap().parse::<u32>().unwrap(), 
-            payload: block.get("payload").unwrap().to_string(),
-            timestamp: block.get("timestamp").unwrap().parse::<u128>().unwrap(), 
-            nonce: block.get("nonce").unwrap().parse::<u64>().unwrap(), 
-            prev_hash: block.get("prev_hash").unwrap().as_bytes().to_vec(),
-            hash:  block.get("hash").unwrap().as_bytes().to_vec(),
+            index: field("id")?.parse::<u32>().map_err(|e| format!("invalid id: {}", e))?, 
+            payload: field("payload")?.to_string(),
+            timestamp: field("timestamp")?.parse::<u128>().map_err(|e| format!("invalid timestamp: {}", e))?, 
+            nonce: field("nonce")?.parse::<u64>().map_err(|e| format!("invalid nonce: {}", e))?, 
+            prev_hash: field("prev_hash")?.as_bytes().to_vec(),
+            hash:  field("hash")?.as_bytes().to_vec(),
      })
     }
 }
@@ -0,0 +1,170 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::block::{self, Block};
+use crate::storage;
+
+/// The blocks of the main chain a wallet downloaded from its Miner, kept so a sync resumes where the last one stopped
+/// *`path` - the file of the blocks, `data/<socket>.blocks`, `difficulty <difficulty>` then a block per line
+/// *`difficulty` - the proof of work difficulty of the chain, told by the Miner of the first sync then kept,
+/// so no Miner can later make us accept blocks of a lower difficulty
+/// *`blocks` - the blocks, `blocks[i].index == i`
+pub struct BlockStore {
+    path: PathBuf,
+    difficulty: Option<usize>,
+    blocks: Vec<Block>,
+}
+
+impl BlockStore {
+
+    /// Reads the blocks downloaded by the wallet listening on `sockip`
+    /// The blocks following one which can not be read are dropped, they are downloaded again
+    pub fn load(sockip: &str) -> Self {
+        let path = storage::node_file(sockip, "blocks");
+        let lines = storage::read_lines(&path).unwrap_or_else(|e| {
+            println!("Err: {}, the downloaded blocks are lost", e);
+            Vec::new()
+        });
+        let difficulty = lines.first().and_then(|line| line.strip_prefix("difficulty ")).and_then(|difficulty| difficulty.parse::<usize>().ok());
+        let blocks = lines.iter().skip(difficulty.is_some() as usize).map_while(|line| Block::from_str(line).ok()).collect();
+        BlockStore { path, difficulty, blocks }
+    }
+
+    /// Returns the difficulty kept, None before the first sync
+    pub fn difficulty(&self) -> Option<usize> {
+        self.difficulty
+    }
+
+    /// Returns the difficulty the blocks must have: `reported`, told by a Miner, the first time, then the one kept
+    /// Fails if `reported` is lower than the difficulty kept
+    pub fn trust_difficulty(&mut self, reported: usize) -> Result<usize, String> {
+        match self.difficulty {
+            Some(difficulty) if reported < difficulty => Err(format!("the Miner lowered the difficulty from {} to {}", difficulty, reported)),
+            Some(difficulty) => Ok(difficulty),
+            None => {
+                self.difficulty = Some(reported);
+                Ok(reported)
+            }
+        }
+    }
+
+    /// Returns the number of blocks downloaded, the height of the next one
+    pub fn height(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the last block downloaded
+    pub fn tip(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    /// Returns the block downloaded at `height`
+    pub fn get(&self, height: u32) -> Option<&Block> {
+        self.blocks.get(height as usize)
+    }
+
+    /// Adds blocks following our last one
+    /// Each block must have the next height, follow the previous block and carry a valid proof of work
+    /// Returns why a block was refused, the blocks before it being kept
+    pub fn append(&mut self, blocks: Vec<Block>, difficulty: usize) -> Result<(), String> {
+        for block in blocks {
+            let prev_hash = self.tip().map(|tip| tip.hash.clone()).unwrap_or_else(block::genesis_prev_hash);
+            if block.index as usize != self.height() {
+                return Err(format!("block {} received instead of block {}", block.index, self.height()));
+            }
+            if block.prev_hash != prev_hash {
+                return Err(format!("block {} does not follow block {}", block.index, self.height().saturating_sub(1)));
+            }
+            if !block.has_valid_proof(difficulty) {
+                return Err(format!("block {} has an invalid proof of work", block.index));
+            }
+            self.blocks.push(block);
+        }
+        Ok(())
+    }
+
+    /// Forgets the last `count` blocks, which left the main chain of the Miner
+    pub fn rewind(&mut self, count: usize) {
+        self.blocks.truncate(self.height().saturating_sub(count));
+    }
+
+    /// Writes the difficulty and the blocks to the disk
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut lines: Vec<String> = self.difficulty.iter().map(|difficulty| format!("difficulty {}", difficulty)).collect();
+        lines.extend(self.blocks.iter().map(|block| block.to_string()));
+        storage::write_lines(&self.path, &lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    /// Returns `count` blocks following each other from the first one, with a proof of work of `difficulty`
+    fn mine(count: u32, difficulty: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for index in 0..count {
+            let prev_hash = blocks.last().map_or_else(block::genesis_prev_hash, |block| block.hash.clone());
+            let mut block = Block::new(index, String::new(), 0, 0, prev_hash);
+            let mut header = block.header();
+            testutil::prove(&mut header, difficulty);
+            block.nonce = header.nonce;
+            block.hash = header.hash;
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn empty() -> BlockStore {
+        BlockStore { path: PathBuf::new(), difficulty: None, blocks: Vec::new() }
+    }
+
+    #[test]
+    fn the_first_difficulty_is_kept() {
+        let mut store = empty();
+        assert_eq!(store.difficulty(), None);
+        assert_eq!(store.trust_difficulty(2), Ok(2));
+        assert_eq!(store.trust_difficulty(3), Ok(2));
+        assert_eq!(store.trust_difficulty(1), Err("the Miner lowered the difficulty from 2 to 1".to_string()));
+        assert_eq!(store.difficulty(), Some(2));
+    }
+
+    #[test]
+    fn only_following_blocks_are_appended() {
+        let blocks = mine(4, 1);
+        let mut store = empty();
+        assert_eq!(store.append(blocks[1..].to_vec(), 1), Err("block 1 received instead of block 0".to_string()));
+        assert_eq!(store.append(blocks.clone(), 64), Err("block 0 has an invalid proof of work".to_string()));
+        assert_eq!(store.height(), 0);
+        assert_eq!(store.append(blocks.clone(), 1), Ok(()));
+        assert!(store.get(3) == Some(&blocks[3]));
+        store.rewind(2);
+        assert_eq!(store.height(), 2);
+
+        let mut forked = blocks[2].clone();
+        forked.prev_hash = blocks[0].hash.clone();
+        forked.hash = forked.header().compute_hash().into_bytes();
+        assert_eq!(store.append(vec![forked], 0), Err("block 2 does not follow block 1".to_string()));
+        let mut unproven = blocks[2].clone();
+        unproven.nonce += 1;
+        assert_eq!(store.append(vec![unproven], 0), Err("block 2 has an invalid proof of work".to_string()));
+        assert_eq!(store.height(), 2);
+    }
+
+    #[test]
+    fn blocks_and_difficulty_survive_a_restart() {
+        storage::set_data_dir(std::env::temp_dir().join(format!("blockstore-test-{}", std::process::id())));
+        let blocks = mine(3, 1);
+        let mut store = BlockStore::load("127.0.0.1:9999");
+        assert_eq!((store.difficulty(), store.height()), (None, 0));
+        store.trust_difficulty(1).unwrap();
+        store.append(blocks.clone(), 1).unwrap();
+        store.save().unwrap();
+
+        let loaded = BlockStore::load("127.0.0.1:9999");
+        assert_eq!(loaded.difficulty(), Some(1));
+        assert_eq!(loaded.height(), 3);
+        assert!(loaded.tip() == blocks.last());
+        std::fs::remove_file(&loaded.path).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use crate::block::{self, Block};
+use crate::config::ChainConfig;
+use crate::index::{ChainIndex, TransactionLocation};
+use crate::ledger::Ledger;
+use crate::transaction::{OutPoint, Transaction};
+use crate::utxo::Utxo;
+
+/// Most blocks kept while waiting for their parent
+const MAX_ORPHANS: usize = 100;
+/// Biggest payload a block may have, in bytes
+pub const MAX_BLOCK_SIZE: usize = 16 * 1024;
+
+/// A change of the main chain, reported so that the state built on top of it (mempool...) can follow
+pub enum ChainEvent {
+    /// The block became part of the main chain
+    Connected(Block),
+    /// The block left the main chain because of a reorganisation
+    Disconnected(Block),
+}
+
+/// Why a block was not added to the chain
+#[derive(Debug)]
+pub enum ChainError {
+    /// We already have this block
+    Known,
+    /// We do not know the parent of the block yet, it is kept until the parent arrives
+    Orphan,
+    /// The block breaks the rules, the sender should not have sent it
+    Invalid(String),
+}
+
+/// The blockchain of a Miner, with the blocks of the competing branches
+/// The main chain is the longest one, a branch becoming longer than it makes the chain reorganise
+/// *`blocks` - the main chain, `blocks[i].index == i`
+/// *`side` - the valid blocks which are not on the main chain, by hash
+/// *`orphans` - the blocks whose parent we do not know yet, by hash of the parent
+/// *`index` - the lookups of the main chain: transactions by ID, transactions by address and heights by block hash
+/// *`config` - the consensus parameters of the network
+/// *`ledger` - the balances and nonces of every address at the tip of the main chain
+pub struct Chain {
+    pub blocks: Vec<Block>,
+    side: HashMap<Vec<u8>, Block>,
+    orphans: HashMap<Vec<u8>, Vec<Block>>,
+    pub index: ChainIndex,
+    pub config: ChainConfig,
+    pub ledger: Ledger,
+}
+
+impl Chain {
+
+    /// CONSTRUCTOR
+    /// Returns an empty chain
+    pub fn new(config: ChainConfig) -> Self {
+        Chain {
+            blocks: Vec::new(),
+            side: HashMap::new(),
+            orphans: HashMap::new(),
+            index: ChainIndex::new(),
+            ledger: Ledger::new(config.ledger),
+            config,
+        }
+    }
+
+    /// Returns the last block of the main chain
+    pub fn tip(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    /// Returns the number of blocks of the main chain
+    pub fn height(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the index of the block of the main chain which includes the transaction
+    pub fn transaction_height(&self, transaction_id: &str) -> Option<u32> {
+        self.index.transaction(transaction_id).map(|location| location.height)
+    }
+
+    /// Returns where a transaction of the main chain is, and how many blocks confirm it (its own included)
+    pub fn locate(&self, transaction_id: &str) -> Option<(TransactionLocation, u32)> {
+        let location = self.index.transaction(transaction_id)?;
+        Some((location, self.blocks.len() as u32 - location.height))
+    }
+
+    /// Returns an unspent output of the main chain, always None on an account chain
+    pub fn unspent(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        match &self.ledger {
+            Ledger::Utxo(outputs) => outputs.get(outpoint),
+            Ledger::Accounts(_) => None,
+        }
+    }
+
+    /// Returns the unspent outputs owned by `address`, mature or not, always empty on an account chain
+    pub fn unspent_of(&self, address: &str) -> Vec<(OutPoint, Utxo)> {
+        match &self.ledger {
+            Ledger::Utxo(outputs) => outputs.owned_by(address),
+            Ledger::Accounts(_) => Vec::new(),
+        }
+    }
+
+    /// Returns what `address` can spend in the next block: its balance minus its block rewards which are not mature yet
+    pub fn spendable(&self, address: &str) -> u64 {
+        let height = self.blocks.len() as u32;
+        let locked = self.immature_rewards(|i| self.blocks.get(i as usize), height);
+        self.ledger.account(address).balance.saturating_sub(locked.get(address).copied().unwrap_or(0))
+    }
+
+    /// Sums, for each address, the block rewards which can not be spent yet in the block of index `height`
+    /// *`block_at` - the block of each index up to `height` included, on the branch being looked at
+    fn immature_rewards<'a>(&self, block_at: impl Fn(u32) -> Option<&'a Block>, height: u32) -> HashMap<String, u64> {
+        let mut locked = HashMap::new();
+        for index in height.saturating_sub(self.config.coinbase_maturity)..=height {
+            if self.config.is_mature(index, height) {
+                continue;
+            }
+            let coinbase = block_at(index).and_then(|block| block.transactions().first().and_then(|t| Transaction::from_str(t).ok()));
+            if let Some(Transaction::Coinbase { to, amount, .. }) = coinbase {
+                let total: &mut u64 = locked.entry(to).or_default();
+                *total = total.saturating_add(amount);
+            }
+        }
+        locked
+    }
+
+    /// Applies a block to the ledger, the blocks of lower index being `blocks[..fork]` then `branch`
+    fn apply_to_ledger(&mut self, block: &Block, fork: usize, branch: &[Block]) -> Result<(), ChainError> {
+        let locked = self.immature_rewards(|i| match i as usize {
+            i if i == block.index as usize => Some(block),
+            i if i < fork => self.blocks.get(i),
+            i => branch.get(i - fork),
+        }, block.index);
+        self.ledger.apply_block(block, &locked, &self.config).map_err(|e| ChainError::Invalid(format!("block {}: {}", block.index, e)))
+    }
+
+    /// Adds a block to the chain, then the orphans waiting for it
+    /// Returns the changes of the main chain, in the order they happened. A reorganisation disconnects blocks from the tip down,
+    /// then connects the new branch from the lowest block up. The list is empty when the block only extends a shorter branch
+    pub fn add_block(&mut self, block: Block) -> Result<Vec<ChainEvent>, ChainError> {
+        let hash = block.hash.clone();
+        let mut events = match self.connect(block.clone()) {
+            Ok(events) => events,
+            Err(ChainError::Orphan) => {
+                if self.orphans.values().map(|orphans| orphans.len()).sum::<usize>() < MAX_ORPHANS {
+                    self.orphans.entry(block.prev_hash.clone()).or_default().push(block);
+                }
+                return Err(ChainError::Orphan);
+            }
+            Err(e) => return Err(e),
+        };
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for orphan in self.orphans.remove(&parent).unwrap_or_default() {
+                let orphan_hash = orphan.hash.clone();
+                if let Ok(more) = self.connect(orphan) {
+                    events.extend(more);
+                    parents.push(orphan_hash);
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Adds one block whose parent we know, to the main chain or to a side branch
+    fn connect(&mut self, block: Block) -> Result<Vec<ChainEvent>, ChainError> {
+        let known = self.side.contains_key(&block.hash)
+            || self.index.height(&block.hash).is_some()
+            || matches!(self.orphans.get(&block.prev_hash), Some(orphans) if orphans.iter().any(|o| o.hash == block.hash));
+        if known {
+            return Err(ChainError::Known);
+        }
+        if !block.has_valid_proof(self.config.difficulty) {
+            return Err(ChainError::Invalid("wrong hash or proof of work".to_string()));
+        }
+        check_transactions(&block, &self.config)?;
+
+        // Walk back through the side branches until we reach the main chain
+        let mut branch = vec![block];
+        let fork = loop {
+            let last = branch.last().unwrap();
+            if last.index == 0 {
+                if last.prev_hash != block::genesis_prev_hash() {
+                    return Err(ChainError::Invalid("first block with a wrong previous hash".to_string()));
+                }
+                break 0;
+            }
+            let parent_index = (last.index - 1) as usize;
+            if self.index.height(&last.prev_hash) == Some(parent_index as u32) {
+                break parent_index + 1;
+            }
+            match self.side.get(&last.prev_hash) {
+                Some(parent) if parent.index + 1 == last.index => branch.push(parent.clone()),
+                Some(_) => return Err(ChainError::Invalid("index does not follow the parent".to_string())),
+                None => return Err(ChainError::Orphan),
+            }
+        };
+
+        let block = branch.remove(0);
+        if (block.index as usize) < self.blocks.len() {
+            // A shorter branch, kept in case it becomes the longest
+            self.side.insert(block.hash.clone(), block);
+            return Ok(Vec::new());
+        }
+
+        // The ledger follows the new branch first, if one of its blocks breaks it the main chain stays as it was
+        branch.reverse();
+        branch.push(block);
+        for _ in fork..self.blocks.len() {
+            self.ledger.undo_block();
+        }
+        for (applied, new) in branch.iter().enumerate() {
+            if let Err(e) = self.apply_to_ledger(new, fork, &branch[..applied]) {
+                for _ in 0..applied {
+                    self.ledger.undo_block();
+                }
+                for index in fork..self.blocks.len() {
+                    let old = self.blocks[index].clone();
+                    self.apply_to_ledger(&old, index, &[]).expect("the main chain was valid");
+                }
+                self.side.remove(&new.hash);
+                return Err(e);
+            }
+        }
+
+        let mut events = Vec::new();
+        while self.blocks.len() > fork {
+            let old = self.blocks.pop().unwrap();
+            self.index.disconnect(&old);
+            self.side.insert(old.hash.clone(), old.clone());
+            events.push(ChainEvent::Disconnected(old));
+        }
+        for new in branch {
+            self.side.remove(&new.hash);
+            self.index.connect(&new);
+            self.blocks.push(new.clone());
+            events.push(ChainEvent::Connected(new));
+        }
+        Ok(events)
+    }
+}
+
+/// Checks the transactions of a block, whatever the branch it belongs to
+/// The payload must fit in `MAX_BLOCK_SIZE`, start with a coinbase for this height paying exactly the block subsidy plus
+/// the fees of the other transactions, and every transaction must be valid, of the ledger mode of the chain, in canonical form, signed by
+/// its sender and present only once
+fn check_transactions(block: &Block, config: &ChainConfig) -> Result<(), ChainError> {
+    let invalid = |reason: String| Err(ChainError::Invalid(reason));
+    if block.payload.len() > MAX_BLOCK_SIZE {
+        return invalid(format!("payload of {} bytes, more than {}", block.payload.len(), MAX_BLOCK_SIZE));
+    }
+    let mut seen = HashSet::new();
+    let mut parsed = Vec::new();
+    for transaction in block.transactions() {
+        let t = Transaction::from_str(&transaction).map_err(ChainError::Invalid)?;
+        if t.to_string() != transaction {
+            return invalid(format!("transaction {} not in canonical form", transaction));
+        }
+        if !config.ledger.accepts(&t) {
+            return invalid(format!("transaction {} does not belong to a {} chain", transaction, config.ledger));
+        }
+        if !t.has_valid_signature() {
+            return invalid(format!("transaction {} with a wrong signature", transaction));
+        }
+        let txid = block::transaction_id(&transaction);
+        if !seen.insert(txid.clone()) {
+            return invalid(format!("transaction {} included twice", txid));
+        }
+        parsed.push(t);
+    }
+    let (coinbase, transfers) = match parsed.split_first() {
+        Some((coinbase, transfers)) => (coinbase, transfers),
+        None => return invalid("no coinbase".to_string()),
+    };
+    if transfers.iter().any(|t| t.is_coinbase()) {
+        return invalid("coinbase after the first transaction".to_string());
+    }
+    let reward = transfers.iter().try_fold(config.block_subsidy(block.index), |total, t| total.checked_add(t.fee()));
+    match (coinbase, reward) {
+        (Transaction::Coinbase { height, amount, .. }, Some(reward)) if *height == block.index && *amount == reward => Ok(()),
+        (Transaction::Coinbase { height, .. }, _) if *height != block.index => invalid(format!("coinbase for height {}", height)),
+        (Transaction::Coinbase { amount, .. }, reward) => invalid(format!("coinbase pays {}, subsidy and fees are {:?}", amount, reward)),
+        _ => invalid("first transaction is not a coinbase".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LedgerMode;
+    use crate::keys::WalletKey;
+    use crate::transaction::Output;
+
+    /// Returns a UTXO chain without proof of work nor maturity, each block creating 100
+    fn utxo_chain() -> Chain {
+        Chain::new(ChainConfig { difficulty: 0, subsidy: 100, coinbase_maturity: 0, ledger: LedgerMode::Utxo, ..ChainConfig::default() })
+    }
+
+    /// Returns the block following `parent`, paying its coinbase to `miner`
+    fn mine(parent: Option<&Block>, miner: &WalletKey, spends: &[Transaction]) -> Block {
+        let index = parent.map_or(0, |parent| parent.index + 1);
+        let coinbase = Transaction::Coinbase { height: index, to: miner.address(), amount: 100 };
+        let payload = std::iter::once(&coinbase).chain(spends).map(|t| t.to_string()).collect::<Vec<String>>()
+            .join(&block::TRANSACTION_SEPARATOR.to_string());
+        let prev_hash = parent.map_or_else(block::genesis_prev_hash, |parent| parent.hash.clone());
+        let mut block = Block::new(index, payload, 0, 0, prev_hash);
+        block.hash = block.header().compute_hash().into_bytes();
+        block
+    }
+
+    /// Returns the changes as the indexes of the blocks, negative when disconnected
+    fn changes(events: Vec<ChainEvent>) -> Vec<i64> {
+        events.into_iter().map(|event| match event {
+            ChainEvent::Connected(block) => i64::from(block.index),
+            ChainEvent::Disconnected(block) => -i64::from(block.index),
+        }).collect()
+    }
+
+    #[test]
+    fn reorganisations_undo_and_apply_the_ledger_again() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut chain = utxo_chain();
+        let first = mine(None, &alice, &[]);
+        let reward = OutPoint { txid: block::transaction_id(&first.transactions()[0]), index: 0 };
+        let mut pay = Transaction::spend(&alice.public_key(), vec![reward.clone()], vec![Output { address: bob.address(), amount: 100 }], 0, "");
+        pay.sign(&alice);
+        let paid = OutPoint { txid: pay.id(), index: 0 };
+        let a1 = mine(Some(&first), &bob, &[pay]);
+        assert_eq!(changes(chain.add_block(first.clone()).unwrap()), vec![0]);
+        assert_eq!(changes(chain.add_block(a1.clone()).unwrap()), vec![1]);
+        assert_eq!((chain.ledger.account(&alice.address()).balance, chain.ledger.account(&bob.address()).balance), (0, 200));
+
+        // A longer branch where alice keeps her reward
+        let b1 = mine(Some(&first), &alice, &[]);
+        let b2 = mine(Some(&b1), &alice, &[]);
+        assert!(changes(chain.add_block(b1).unwrap()).is_empty());
+        assert_eq!(changes(chain.add_block(b2).unwrap()), vec![-1, 1, 2]);
+        assert_eq!((chain.ledger.account(&alice.address()).balance, chain.ledger.account(&bob.address()).balance), (300, 0));
+        assert!(chain.unspent(&reward).is_some());
+        assert!(chain.unspent(&paid).is_none());
+        assert_eq!(chain.transaction_height(&paid.txid), None);
+
+        // The first branch grows longer again, its spend comes back
+        let a2 = mine(Some(&a1), &bob, &[]);
+        let a3 = mine(Some(&a2), &bob, &[]);
+        assert!(changes(chain.add_block(a2).unwrap()).is_empty());
+        assert_eq!(changes(chain.add_block(a3).unwrap()), vec![-2, -1, 1, 2, 3]);
+        assert_eq!((chain.ledger.account(&alice.address()).balance, chain.ledger.account(&bob.address()).balance), (0, 400));
+        assert!(chain.unspent(&reward).is_none());
+        assert_eq!(chain.unspent(&paid).map(|utxo| utxo.output.amount), Some(100));
+        assert_eq!(chain.transaction_height(&paid.txid), Some(1));
+        assert!(chain.blocks[1] == a1);
+    }
+
+    #[test]
+    fn a_branch_breaking_the_ledger_leaves_the_main_chain_as_it_was() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut chain = utxo_chain();
+        let first = mine(None, &alice, &[]);
+        let a1 = mine(Some(&first), &alice, &[]);
+        chain.add_block(first.clone()).unwrap();
+        chain.add_block(a1).unwrap();
+
+        // The spend of the longer branch uses an output which does not exist on it
+        let missing = OutPoint { txid: block::transaction_id(&chain.blocks[1].transactions()[0]), index: 0 };
+        let mut theft = Transaction::spend(&alice.public_key(), vec![missing], vec![Output { address: bob.address(), amount: 100 }], 0, "");
+        theft.sign(&alice);
+        let b1 = mine(Some(&first), &bob, &[]);
+        let b2 = mine(Some(&b1), &bob, &[theft]);
+        chain.add_block(b1).unwrap();
+        assert!(matches!(chain.add_block(b2), Err(ChainError::Invalid(_))));
+        assert_eq!(chain.height(), 2);
+        assert_eq!((chain.ledger.account(&alice.address()).balance, chain.ledger.account(&bob.address()).balance), (200, 0));
+    }
+
+    #[test]
+    fn a_transaction_included_twice_is_refused() {
+        let alice = WalletKey::generate().unwrap();
+        let mut chain = utxo_chain();
+        let first = mine(None, &alice, &[]);
+        let reward = OutPoint { txid: block::transaction_id(&first.transactions()[0]), index: 0 };
+        let mut pay = Transaction::spend(&alice.public_key(), vec![reward], vec![Output { address: alice.address(), amount: 100 }], 0, "");
+        pay.sign(&alice);
+        chain.add_block(first.clone()).unwrap();
+
+        match chain.add_block(mine(Some(&first), &alice, &[pay.clone(), pay.clone()])) {
+            Err(ChainError::Invalid(reason)) => assert_eq!(reason, format!("transaction {} included twice", pay.id())),
+            _ => panic!("block including a transaction twice accepted"),
+        }
+        assert_eq!(chain.height(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use std::io;
+use std::path::Path;
+use crate::block;
+use crate::index::TransactionStatus;
+use crate::offline::OfflineTransaction;
+use crate::transaction::Transaction;
+use crate::wallet::{self, CommandError, Wallet};
+
+/// Exit status of a command which succeeded
+pub const EXIT_OK: i32 = 0;
+/// Exit status of a command which failed: invalid argument, not enough coins, transaction rejected by the Miner or not verified...
+pub const EXIT_FAILED: i32 = 1;
+/// Exit status when the Miner could not be reached or did not answer
+pub const EXIT_MINER: i32 = 2;
+/// Exit status when the wallet could not be opened: no keystore, wrong passphrase...
+pub const EXIT_WALLET: i32 = 3;
+
+/// A wallet command run once from the command line, for scripts
+pub enum WalletCommand {
+    /// Sends `amount` to `to`, see `Wallet::submit_transaction`
+    Send { to: String, amount: u64, fee: Option<u64>, message: String },
+    /// Shows what the addresses of the wallet own
+    Balance,
+    /// Checks with a proof that the transaction is in the main chain
+    Verify(String),
+    /// Shows a page of the history of the addresses of the wallet, from 1
+    History(usize),
+    /// Downloads the blocks of the main chain the wallet does not have yet, see `Wallet::sync`
+    Sync,
+    /// Writes to `path` the transaction `Send` would send, unsigned, see `Wallet::export_transaction`
+    Export { path: String, to: String, amount: u64, fee: Option<u64>, message: String },
+    /// Signs the transaction of `path` and writes it to `out`, once the user confirmed it unless `confirmed`
+    Sign { path: String, out: String, confirmed: bool },
+    /// Sends the transaction signed offline of a file, see `Wallet::import_transaction`
+    Submit(String),
+}
+
+/// Runs a command with the wallet listening on `socket`, then prints its result or why it failed
+/// *`miners` - the Miners to talk to first, see `wallet::open_wallet`
+/// *`json` - prints a JSON object instead of text, errors included, for scripts
+/// Returns the exit status of the process
+pub fn run(socket: &str, miners: &[String], command: WalletCommand, json: bool) -> i32 {
+    let wallet = match wallet::open_wallet(socket, miners) {
+        Ok(wallet) => wallet,
+        Err(e) => return fail(&e, "wallet", EXIT_WALLET, json),
+    };
+    let result = match command {
+        WalletCommand::Send { to, amount, fee, message } => send(&wallet, &to, amount, fee, &message, json),
+        WalletCommand::Balance => balance(&wallet, json),
+        WalletCommand::Verify(txid) => verify(&wallet, &txid, json),
+        WalletCommand::History(page) => history(&wallet, page, json),
+        WalletCommand::Sync => sync(&wallet, json),
+        WalletCommand::Export { path, to, amount, fee, message } => export(&wallet, &path, &to, amount, fee, &message, json),
+        WalletCommand::Sign { path, out, confirmed } => sign(&wallet, &path, &out, confirmed, json),
+        WalletCommand::Submit(path) => submit(&wallet, &path, json),
+    };
+    match result {
+        Ok(output) => {
+            println!("{}", output);
+            EXIT_OK
+        }
+        Err(CommandError::Miner(e)) => fail(&e, "miner", EXIT_MINER, json),
+        Err(CommandError::Failed(e)) => fail(&e, "failed", EXIT_FAILED, json),
+    }
+}
+
+/// Prints why a command failed, on the standard output as JSON or on the error output as text
+/// Returns `status`
+fn fail(reason: &str, kind: &str, status: i32, json: bool) -> i32 {
+    match json {
+        true => println!("{}", object(&[("error", string(reason)), ("kind", string(kind))])),
+        false => eprintln!("Error: {}", reason),
+    }
+    status
+}
+
+fn send(wallet: &Wallet, to: &str, amount: u64, fee: Option<u64>, message: &str, json: bool) -> Result<String, CommandError> {
+    let transaction = wallet.submit_transaction(to, amount, fee, message)?;
+    Ok(match json {
+        true => object(&[
+            ("txid", string(&transaction.id())),
+            ("to", string(to)),
+            ("amount", amount.to_string()),
+            ("fee", transaction.fee().to_string()),
+        ]),
+        false => format!("Transaction {} sent with a fee of {}", transaction.id(), transaction.fee()),
+    })
+}
+
+fn balance(wallet: &Wallet, json: bool) -> Result<String, CommandError> {
+    let balance = wallet.balance()?;
+    Ok(match json {
+        true => object(&[
+            ("confirmed", balance.confirmed.to_string()),
+            ("incoming", balance.incoming.to_string()),
+            ("outgoing", balance.outgoing.to_string()),
+            ("spendable", balance.spendable.to_string()),
+            ("addresses", array(wallet.addresses().iter().map(|address| string(address)))),
+        ]),
+        false => balance.to_string(),
+    })
+}
+
+fn verify(wallet: &Wallet, txid: &str, json: bool) -> Result<String, CommandError> {
+    let confirmations = wallet.verify_transaction(txid)?;
+    Ok(match json {
+        true => object(&[("txid", string(txid)), ("verified", "true".to_string()), ("confirmations", confirmations.to_string())]),
+        false => format!("Transaction is verified ! {} confirmations", confirmations),
+    })
+}
+
+fn history(wallet: &Wallet, page: usize, json: bool) -> Result<String, CommandError> {
+    if page == 0 {
+        return Err(CommandError::Failed("pages start at 1".to_string()));
+    }
+    let addresses = wallet.addresses();
+    let history = wallet.ask_history_page(&addresses, (page - 1) * wallet::HISTORY_PAGE_SIZE, wallet::HISTORY_PAGE_SIZE)?;
+    if !json {
+        return Ok(wallet.describe_history(&history, page));
+    }
+    let transactions = history.entries.iter().filter_map(|entry| match entry {
+        TransactionStatus::Confirmed { location, confirmations, transaction } => {
+            let (received, sent) = transaction.parse::<Transaction>().map(|parsed| parsed.value_for(&addresses)).unwrap_or((0, 0));
+            Some(object(&[
+                ("txid", string(&block::transaction_id(transaction))),
+                ("height", location.height.to_string()),
+                ("position", location.position.to_string()),
+                ("confirmations", confirmations.to_string()),
+                ("received", received.to_string()),
+                ("sent", sent.to_string()),
+            ]))
+        }
+        _ => None,
+    });
+    Ok(object(&[
+        ("page", page.to_string()),
+        ("pages", (history.total as usize).div_ceil(wallet::HISTORY_PAGE_SIZE).max(1).to_string()),
+        ("total", history.total.to_string()),
+        ("transactions", array(transactions)),
+    ]))
+}
+
+fn sync(wallet: &Wallet, json: bool) -> Result<String, CommandError> {
+    let report = wallet.sync()?;
+    Ok(match json {
+        true => object(&[
+            ("downloaded", report.downloaded.to_string()),
+            ("rewound", report.rewound.to_string()),
+            ("height", report.height.to_string()),
+        ]),
+        false => report.to_string(),
+    })
+}
+
+fn export(wallet: &Wallet, path: &str, to: &str, amount: u64, fee: Option<u64>, message: &str, json: bool) -> Result<String, CommandError> {
+    let offline = wallet.export_transaction(Path::new(path), to, amount, fee, message)?;
+    Ok(match json {
+        true => offline_object(&offline, path),
+        false => format!("{}\nwritten to {}", offline.describe(), path),
+    })
+}
+
+/// Shows the transaction, on the error output in JSON mode, and asks the user to type yes before signing it
+/// The wallet never reaches a Miner, it may run on a machine without network
+fn sign(wallet: &Wallet, path: &str, out: &str, confirmed: bool, json: bool) -> Result<String, CommandError> {
+    let mut offline = OfflineTransaction::read(Path::new(path))?;
+    if offline.is_signed() {
+        return Err(CommandError::Failed(format!("{} is already signed", path)));
+    }
+    if !confirmed {
+        let question = format!("{}\nSign this transaction ? Type yes to sign it", offline.describe());
+        match json {
+            true => eprintln!("{}", question),
+            false => println!("{}", question),
+        }
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).map_err(|e| format!("{}, could not read the answer", e))?;
+        if answer.trim() != "yes" {
+            return Err(CommandError::Failed("signing refused".to_string()));
+        }
+    }
+    wallet.sign_offline(&mut offline)?;
+    offline.write(Path::new(out)).map_err(|e| format!("{}, could not write {}", e, out))?;
+    Ok(match json {
+        true => offline_object(&offline, out),
+        false => format!("Transaction {} signed, written to {}", offline.transaction.id(), out),
+    })
+}
+
+fn submit(wallet: &Wallet, path: &str, json: bool) -> Result<String, CommandError> {
+    let transaction = wallet.import_transaction(Path::new(path))?;
+    Ok(match json {
+        true => object(&[("txid", string(&transaction.id())), ("fee", transaction.fee().to_string())]),
+        false => format!("Transaction {} sent with a fee of {}", transaction.id(), transaction.fee()),
+    })
+}
+
+/// Writes an offline transaction as a JSON object: where it was written, whether it is signed, its fee and its inputs,
+/// and its ID once signed
+fn offline_object(offline: &OfflineTransaction, path: &str) -> String {
+    let inputs = offline.inputs.iter().map(|(outpoint, amount)| object(&[("outpoint", string(&outpoint.to_string())), ("amount", amount.to_string())]));
+    object(&[
+        ("file", string(path)),
+        ("signed", offline.is_signed().to_string()),
+        ("ledger", string(&offline.ledger.to_string())),
+        ("fee", offline.transaction.fee().to_string()),
+        ("inputs", array(inputs)),
+        ("txid", if offline.is_signed() { string(&offline.transaction.id()) } else { "null".to_string() }),
+    ])
+}
+
+/// Writes a JSON object, the values being already written as JSON
+fn object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{}:{}", string(name), value)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Writes a JSON array, the values being already written as JSON
+fn array(values: impl Iterator<Item = String>) -> String {
+    format!("[{}]", values.collect::<Vec<String>>().join(","))
+}
+
+/// Writes a JSON string
+fn string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
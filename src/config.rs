@@ -0,0 +1,178 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::storage;
+use crate::transaction::Transaction;
+
+/// How the chain keeps track of who owns what
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LedgerMode {
+    /// Every address has a balance and a nonce, transactions are `transfer`s
+    Account,
+    /// Coins are unspent outputs of previous transactions, transactions are `spend`s consuming and creating outputs
+    Utxo,
+}
+
+impl LedgerMode {
+    /// Returns true if the transaction belongs to this mode, coinbases belong to both
+    pub fn accepts(&self, transaction: &Transaction) -> bool {
+        match transaction {
+            Transaction::Coinbase { .. } => true,
+            Transaction::Transfer { .. } => *self == LedgerMode::Account,
+            Transaction::Spend { .. } => *self == LedgerMode::Utxo,
+        }
+    }
+}
+
+impl fmt::Display for LedgerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerMode::Account => write!(f, "account"),
+            LedgerMode::Utxo => write!(f, "utxo"),
+        }
+    }
+}
+
+impl FromStr for LedgerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "account" => Ok(LedgerMode::Account),
+            "utxo" => Ok(LedgerMode::Utxo),
+            _ => Err(format!("unknown ledger mode {}", s)),
+        }
+    }
+}
+
+/// Consensus parameters shared by every node of a network
+/// Every Miner of a network must use the same values, blocks made with other values are refused
+/// *`difficulty` - the number of leading zeros the hash of every block must have
+/// *`subsidy` - the amount created by the coinbase of the first blocks
+/// *`halving_interval` - the number of blocks after which the subsidy is halved
+/// *`coinbase_maturity` - the number of blocks to wait before spending what a coinbase paid
+/// *`ledger` - whether coins are tracked as account balances or as unspent outputs
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainConfig {
+    pub difficulty: usize,
+    pub subsidy: u64,
+    pub halving_interval: u32,
+    pub coinbase_maturity: u32,
+    pub ledger: LedgerMode,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            difficulty: 1,
+            subsidy: 50_0000_0000,
+            halving_interval: 210_000,
+            coinbase_maturity: 10,
+            ledger: LedgerMode::Account,
+        }
+    }
+}
+
+impl ChainConfig {
+
+    /// Loads the parameters from a file of `key = value` lines, `#` starting a comment
+    /// Missing keys keep their default value
+    pub fn load(path: &PathBuf) -> io::Result<Self> {
+        let lines: Vec<String> = storage::read_lines(path)?.iter().map(|line| line.split('#').next().unwrap_or("").to_string()).collect();
+        lines.join(";").parse::<ChainConfig>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns the amount a coinbase may create at this height, on top of the fees
+    /// The subsidy is halved every `halving_interval` blocks, until it reaches 0
+    pub fn block_subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            0
+        } else {
+            self.subsidy >> halvings
+        }
+    }
+
+    /// Returns true if what the coinbase of block `coinbase_height` paid can be spent in block `height`
+    pub fn is_mature(&self, coinbase_height: u32, height: u32) -> bool {
+        height >= coinbase_height.saturating_add(self.coinbase_maturity)
+    }
+}
+
+impl fmt::Display for ChainConfig {
+    /// Writes the parameters as `key = value` separated by `;`, to be sent to the wallets
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "difficulty = {}; subsidy = {}; halving_interval = {}; coinbase_maturity = {}; ledger = {}",
+            self.difficulty, self.subsidy, self.halving_interval, self.coinbase_maturity, self.ledger)
+    }
+}
+
+impl FromStr for ChainConfig {
+    type Err = String;
+
+    /// Reads `key = value` settings separated by `;`, missing keys keep their default value
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ChainConfig::default();
+        let invalid = |setting: &str, reason: &str| format!("{}: {}", setting, reason);
+        for setting in s.split(';').map(|setting| setting.trim()).filter(|setting| !setting.is_empty()) {
+            let (key, value) = match setting.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(invalid(setting, "expected key = value")),
+            };
+            match key {
+                "difficulty" => config.difficulty = value.parse().map_err(|_| invalid(setting, "not a number"))?,
+                "subsidy" => config.subsidy = value.parse().map_err(|_| invalid(setting, "not a number"))?,
+                "halving_interval" => config.halving_interval = value.parse().map_err(|_| invalid(setting, "not a number"))?,
+                "coinbase_maturity" => config.coinbase_maturity = value.parse().map_err(|_| invalid(setting, "not a number"))?,
+                "ledger" => config.ledger = value.parse().map_err(|e: String| invalid(setting, &e))?,
+                _ => return Err(invalid(setting, "unknown key")),
+            }
+        }
+        if config.halving_interval == 0 {
+            return Err(invalid("halving_interval", "must not be 0"));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_subsidy_is_halved_until_nothing_is_left() {
+        let config = ChainConfig { subsidy: 100, halving_interval: 10, ..ChainConfig::default() };
+        assert_eq!(config.block_subsidy(0), 100);
+        assert_eq!(config.block_subsidy(9), 100);
+        assert_eq!(config.block_subsidy(10), 50);
+        assert_eq!(config.block_subsidy(25), 25);
+        assert_eq!(config.block_subsidy(70), 0);
+        assert_eq!(config.block_subsidy(u32::MAX), 0);
+        let default = ChainConfig::default();
+        assert_eq!(default.block_subsidy(63 * default.halving_interval), 0);
+        assert_eq!(ChainConfig { halving_interval: 1, ..ChainConfig::default() }.block_subsidy(64), 0);
+    }
+
+    #[test]
+    fn coinbases_mature_after_their_maturity() {
+        let config = ChainConfig { coinbase_maturity: 10, ..ChainConfig::default() };
+        assert!(!config.is_mature(5, 14));
+        assert!(config.is_mature(5, 15));
+        assert!(ChainConfig { coinbase_maturity: 0, ..ChainConfig::default() }.is_mature(5, 5));
+    }
+
+    #[test]
+    fn settings_are_read_back_and_checked() {
+        let config = ChainConfig { difficulty: 3, subsidy: 7, halving_interval: 20, coinbase_maturity: 0, ledger: LedgerMode::Utxo };
+        assert_eq!(config.to_string().parse::<ChainConfig>(), Ok(config));
+        assert_eq!("".parse::<ChainConfig>(), Ok(ChainConfig::default()));
+        assert_eq!(" difficulty=2 ;; ".parse::<ChainConfig>(), Ok(ChainConfig { difficulty: 2, ..ChainConfig::default() }));
+
+        assert_eq!("difficulty = two".parse::<ChainConfig>(), Err("difficulty = two: not a number".to_string()));
+        assert_eq!("difficulty".parse::<ChainConfig>(), Err("difficulty: expected key = value".to_string()));
+        assert_eq!("speed = 2".parse::<ChainConfig>(), Err("speed = 2: unknown key".to_string()));
+        assert_eq!("ledger = bank".parse::<ChainConfig>(), Err("ledger = bank: unknown ledger mode bank".to_string()));
+        assert_eq!("halving_interval = 0".parse::<ChainConfig>(), Err("halving_interval: must not be 0".to_string()));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use crate::keys::WalletKey;
+
+/// Size of the seed every key of a wallet is derived from, 128 bits
+pub const SEED_SIZE: usize = 16;
+/// Key of the HMAC giving the master key from the seed (SLIP-0010, Ed25519)
+const MASTER_KEY: &[u8] = b"ed25519 seed";
+/// Index of the first hardened child, Ed25519 keys can only be derived hardened
+const HARDENED: u32 = 0x8000_0000;
+/// The account whose keys the wallets use, keys are derived at `m/ACCOUNT'/index'`
+const ACCOUNT: u32 = 0;
+
+/// The words of a mnemonic, one for each value of a byte
+/// A mnemonic writes each byte of the seed as a word, then a checksum word: the first byte of the SHA-256 of the seed
+const WORDS: [&str; 256] = [
+    "able", "acid", "acorn", "actor", "adapt", "admit", "adult", "agent", "alarm", "album", "alert", "alley",
+    "amber", "angle", "ankle", "apple", "april", "arena", "armor", "arrow", "atlas", "audio", "autumn",
+    "bacon", "badge", "bakery", "balance", "bamboo", "banana", "banner", "barrel", "basket", "beach",
+    "beauty", "bench", "berry", "bison", "blanket", "board", "bonus", "border", "bottle", "bounce", "bracket",
+    "brain", "bread", "breeze", "brick", "bridge", "broom", "bubble", "bucket", "butter", "cabin", "cactus",
+    "camel", "candle", "canoe", "canvas", "carbon", "carpet", "castle", "cattle", "ceiling", "cement",
+    "cherry", "chess", "circle", "citizen", "clay", "cliff", "clock", "cloud", "clover", "coffee", "comet",
+    "copper", "coral", "cotton", "cousin", "coyote", "cradle", "crater", "cricket", "curtain", "cushion",
+    "dancer", "dawn", "debate", "decade", "deer", "desert", "diamond", "dinner", "dolphin", "donkey",
+    "dragon", "drama", "dream", "drum", "eagle", "earth", "echo", "elbow", "ember", "empire", "engine",
+    "falcon", "fancy", "feather", "fence", "fiber", "fiddle", "filter", "finger", "flame", "flute", "forest",
+    "fossil", "fox", "galaxy", "garden", "garlic", "gentle", "giant", "ginger", "giraffe", "glacier", "globe",
+    "gold", "grape", "gravel", "guitar", "hammer", "harbor", "harvest", "hawk", "helmet", "hero", "honey",
+    "horizon", "hotel", "humble", "igloo", "image", "island", "ivory", "jacket", "jaguar", "jelly", "jewel",
+    "jungle", "kayak", "kettle", "kingdom", "kitten", "ladder", "lagoon", "lamp", "lantern", "laptop",
+    "lemon", "leopard", "letter", "lily", "linen", "lizard", "lobster", "lumber", "magnet", "mango", "maple",
+    "marble", "meadow", "melody", "mirror", "monkey", "moose", "mosaic", "muffin", "museum", "napkin",
+    "nectar", "needle", "nest", "noodle", "ocean", "olive", "onion", "orange", "orbit", "orchid", "otter",
+    "owl", "oyster", "paddle", "palace", "panda", "parade", "parrot", "peanut", "pebble", "pencil", "pepper",
+    "piano", "pillow", "pilot", "planet", "plaza", "pocket", "pony", "potato", "pumpkin", "puzzle", "quartz",
+    "rabbit", "radar", "raven", "ribbon", "river", "robot", "rocket", "saddle", "salmon", "sandal", "saturn",
+    "scarf", "shadow", "shelf", "silver", "sketch", "sleeve", "spider", "spoon", "statue", "summer", "sunset",
+    "tablet", "teapot", "tiger", "timber", "tomato", "tunnel", "turtle", "valley", "velvet", "violin",
+    "volcano", "walnut", "whale", "window", "winter", "wizard", "yogurt", "zebra", "zipper",
+];
+
+/// Generates a new random seed
+pub fn generate_seed() -> io::Result<Vec<u8>> {
+    let mut seed = vec![0_u8; SEED_SIZE];
+    SystemRandom::new().fill(&mut seed).map_err(|_| io::Error::other("could not generate a seed"))?;
+    Ok(seed)
+}
+
+/// Returns the checksum word of a seed
+fn checksum(seed: &[u8]) -> u8 {
+    Sha256::digest(seed)[0]
+}
+
+/// Writes a seed as a mnemonic, words separated by spaces
+pub fn to_mnemonic(seed: &[u8]) -> String {
+    seed.iter().chain(std::iter::once(&checksum(seed)))
+        .map(|byte| WORDS[*byte as usize])
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Reads a mnemonic written by `to_mnemonic`, whatever the case and the spaces between the words
+/// Returns an error naming the first unknown word, or if the number of words or the checksum are wrong
+pub fn from_mnemonic(mnemonic: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = mnemonic.split_whitespace()
+        .map(|word| {
+            let word = word.to_lowercase();
+            WORDS.iter().position(|known| *known == word).map(|byte| byte as u8).ok_or(format!("unknown word {}", word))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    if bytes.len() != SEED_SIZE + 1 {
+        return Err(format!("{} words expected, {} given", SEED_SIZE + 1, bytes.len()));
+    }
+    let check = bytes.pop().unwrap_or_default();
+    if check != checksum(&bytes) {
+        return Err("wrong checksum, a word is mistyped".to_string());
+    }
+    Ok(bytes)
+}
+
+/// Derives the key of index `index` of a wallet from its seed, at `m/ACCOUNT'/index'` (SLIP-0010, Ed25519)
+pub fn derive_key(seed: &[u8], index: u32) -> io::Result<WalletKey> {
+    let master = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, MASTER_KEY), seed);
+    let (mut key, mut chain_code) = (master.as_ref()[..32].to_vec(), master.as_ref()[32..].to_vec());
+    for step in [ACCOUNT, index].iter() {
+        if *step >= HARDENED {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "key index too large"));
+        }
+        let mut data = vec![0_u8];
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&(step | HARDENED).to_be_bytes());
+        let child = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, &chain_code), &data);
+        key = child.as_ref()[..32].to_vec();
+        chain_code = child.as_ref()[32..].to_vec();
+    }
+    WalletKey::from_seed(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics_give_back_their_seed() {
+        let seed = generate_seed().unwrap();
+        let mnemonic = to_mnemonic(&seed);
+        assert_eq!(mnemonic.split(' ').count(), SEED_SIZE + 1);
+        assert_eq!(from_mnemonic(&mnemonic), Ok(seed.clone()));
+        assert_eq!(from_mnemonic(&format!("  {}\n", mnemonic.to_uppercase().replace(' ', "\t "))), Ok(seed));
+    }
+
+    #[test]
+    fn every_word_is_a_different_byte() {
+        let mut words = WORDS.to_vec();
+        words.sort_unstable();
+        words.dedup();
+        assert_eq!(words.len(), 256);
+    }
+
+    #[test]
+    fn mistyped_mnemonics_are_refused() {
+        let seed: Vec<u8> = (0..SEED_SIZE as u8).collect();
+        let mnemonic = to_mnemonic(&seed);
+        let mut words: Vec<&str> = mnemonic.split(' ').collect();
+        let checksum_word = words[SEED_SIZE];
+        words[3] = WORDS[(seed[3] as usize + 1) % 256];
+        assert_eq!(from_mnemonic(&words.join(" ")), Err("wrong checksum, a word is mistyped".to_string()));
+        words[3] = "blockchain";
+        assert_eq!(from_mnemonic(&words.join(" ")), Err("unknown word blockchain".to_string()));
+        words[3] = WORDS[seed[3] as usize];
+        assert_eq!(from_mnemonic(&words[..SEED_SIZE].join(" ")), Err(format!("{} words expected, {} given", SEED_SIZE + 1, SEED_SIZE)));
+        assert_eq!(words[SEED_SIZE], checksum_word);
+        assert_eq!(from_mnemonic(&words.join(" ")), Ok(seed));
+    }
+
+    #[test]
+    fn keys_follow_slip_0010() {
+        // Test vector 1 of SLIP-0010 for Ed25519, key m/0'/1'
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!(derive_key(&seed, 1).unwrap().public_key(), "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187");
+        assert_eq!(derive_key(&seed, 1).unwrap().address(), derive_key(&seed, 1).unwrap().address());
+        assert_ne!(derive_key(&seed, 0).unwrap().address(), derive_key(&seed, 1).unwrap().address());
+        assert!(derive_key(&seed, HARDENED).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use crate::block::{self, Block};
+use crate::transaction::Transaction;
+
+/// Where a transaction of the main chain is
+/// *`height` - the index of the block including it
+/// *`position` - its position in the transactions of the block, the coinbase being 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransactionLocation {
+    pub height: u32,
+    pub position: u32,
+}
+
+/// The lookups of the main chain, updated block by block as the main chain changes
+/// *`transactions` - the location of each transaction, by transaction ID
+/// *`addresses` - the IDs of the transactions sending to or from each address, in the order of the chain
+/// *`heights` - the height of each block, by hash
+pub struct ChainIndex {
+    transactions: HashMap<String, TransactionLocation>,
+    addresses: HashMap<String, Vec<String>>,
+    heights: HashMap<Vec<u8>, u32>,
+}
+
+impl ChainIndex {
+
+    /// CONSTRUCTOR
+    /// Returns the index of an empty chain
+    pub fn new() -> Self {
+        ChainIndex {
+            transactions: HashMap::new(),
+            addresses: HashMap::new(),
+            heights: HashMap::new(),
+        }
+    }
+
+    /// Returns where a transaction of the main chain is
+    pub fn transaction(&self, transaction_id: &str) -> Option<TransactionLocation> {
+        self.transactions.get(transaction_id).copied()
+    }
+
+    /// Returns the IDs of the transactions of the main chain sending to or from `address`, oldest first
+    pub fn history(&self, address: &str) -> &[String] {
+        self.addresses.get(address).map(|ids| ids.as_slice()).unwrap_or(&[])
+    }
+
+    /// Returns the height of a block of the main chain
+    pub fn height(&self, hash: &[u8]) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Indexes a block which joined the main chain, on top of the blocks already indexed
+    pub fn connect(&mut self, block: &Block) {
+        self.heights.insert(block.hash.clone(), block.index);
+        for (position, transaction) in block.transactions().iter().enumerate() {
+            let id = block::transaction_id(transaction);
+            self.transactions.insert(id.clone(), TransactionLocation { height: block.index, position: position as u32 });
+            if let Ok(parsed) = Transaction::from_str(transaction) {
+                for address in parsed.addresses() {
+                    self.addresses.entry(address).or_default().push(id.clone());
+                }
+            }
+        }
+    }
+
+    /// Forgets a block which left the main chain, which must be the last block indexed
+    pub fn disconnect(&mut self, block: &Block) {
+        self.heights.remove(&block.hash);
+        for transaction in block.transactions().iter().rev() {
+            let id = block::transaction_id(transaction);
+            self.transactions.remove(&id);
+            if let Ok(parsed) = Transaction::from_str(transaction) {
+                for address in parsed.addresses() {
+                    if let Some(ids) = self.addresses.get_mut(&address) {
+                        if ids.last() == Some(&id) {
+                            ids.pop();
+                        }
+                        if ids.is_empty() {
+                            self.addresses.remove(&address);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for ChainIndex {
+    fn default() -> Self {
+        ChainIndex::new()
+    }
+}
+
+/// What a Miner knows about a transaction, sent to the wallets looking it up
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionStatus {
+    /// In the main chain, with the number of blocks on top of it and its own
+    Confirmed { location: TransactionLocation, confirmations: u32, transaction: String },
+    /// Waiting in the mempool
+    Pending(String),
+    /// Neither in the main chain nor in the mempool
+    Unknown,
+}
+
+impl fmt::Display for TransactionStatus {
+    /// Writes `confirmed <height> <position> <confirmations> <transaction>`, `pending <transaction>` or `unknown`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionStatus::Confirmed { location, confirmations, transaction } =>
+                write!(f, "confirmed {} {} {} {}", location.height, location.position, confirmations, transaction),
+            TransactionStatus::Pending(transaction) => write!(f, "pending {}", transaction),
+            TransactionStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A page of the transactions of the main chain sending to or from some addresses, sent to the wallets asking for their history
+/// *`total` - the number of transactions of the addresses, in every page
+/// *`entries` - the transactions of the page, newest first, all `TransactionStatus::Confirmed`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryPage {
+    pub total: u32,
+    pub entries: Vec<TransactionStatus>,
+}
+
+impl fmt::Display for HistoryPage {
+    /// Writes the total then the entries, separated by `$` like the transactions of a block
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.total)?;
+        for entry in &self.entries {
+            write!(f, "{}{}", block::TRANSACTION_SEPARATOR, entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HistoryPage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(block::TRANSACTION_SEPARATOR);
+        let total = parts.next().unwrap_or_default().parse::<u32>().map_err(|e| format!("invalid history page: {}", e))?;
+        let entries = parts.map(|entry| entry.parse::<TransactionStatus>()).collect::<Result<Vec<TransactionStatus>, String>>()?;
+        Ok(HistoryPage { total, entries })
+    }
+}
+
+impl FromStr for TransactionStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let number = |i: usize| parts[i].parse::<u32>().map_err(|e| format!("invalid transaction status {}: {}", s, e));
+        match (parts.first().copied(), parts.len()) {
+            (Some("confirmed"), 5) => Ok(TransactionStatus::Confirmed {
+                location: TransactionLocation { height: number(1)?, position: number(2)? },
+                confirmations: number(3)?,
+                transaction: parts[4].to_string(),
+            }),
+            (Some("pending"), 2) => Ok(TransactionStatus::Pending(parts[1].to_string())),
+            (Some("unknown"), 1) => Ok(TransactionStatus::Unknown),
+            _ => Err(format!("invalid transaction status {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::WalletKey;
+
+    /// Returns the block following `parent` made of `transactions`, the hash standing for the proof of work
+    fn next_block(parent: Option<&Block>, transactions: &[&Transaction]) -> Block {
+        let payload = transactions.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(&block::TRANSACTION_SEPARATOR.to_string());
+        let prev_hash = parent.map_or_else(block::genesis_prev_hash, |parent| parent.hash.clone());
+        let mut block = Block::new(parent.map_or(0, |parent| parent.index + 1), payload, 0, 0, prev_hash);
+        block.hash = block.header().compute_hash().into_bytes();
+        block
+    }
+
+    fn id(transaction: &Transaction) -> String {
+        block::transaction_id(&transaction.to_string())
+    }
+
+    #[test]
+    fn blocks_are_indexed_and_forgotten_from_the_top() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let coinbase = Transaction::Coinbase { height: 0, to: alice.address(), amount: 50 };
+        let transfer = Transaction::signed_transfer(&alice, &bob.address(), 10, 1, 1, "");
+        let reward = Transaction::Coinbase { height: 1, to: bob.address(), amount: 51 };
+        let first = next_block(None, &[&coinbase]);
+        let second = next_block(Some(&first), &[&reward, &transfer]);
+
+        let mut index = ChainIndex::new();
+        index.connect(&first);
+        index.connect(&second);
+        assert_eq!(index.transaction(&id(&transfer)), Some(TransactionLocation { height: 1, position: 1 }));
+        assert_eq!(index.transaction(&id(&coinbase)), Some(TransactionLocation { height: 0, position: 0 }));
+        assert_eq!(index.history(&alice.address()), [id(&coinbase), id(&transfer)]);
+        assert_eq!(index.history(&bob.address()), [id(&reward), id(&transfer)]);
+        assert_eq!((index.height(&first.hash), index.height(&second.hash)), (Some(0), Some(1)));
+
+        index.disconnect(&second);
+        assert_eq!(index.transaction(&id(&transfer)), None);
+        assert_eq!(index.history(&alice.address()), [id(&coinbase)]);
+        assert!(index.history(&bob.address()).is_empty());
+        assert!(!index.addresses.contains_key(&bob.address()));
+        assert_eq!(index.height(&second.hash), None);
+        index.disconnect(&first);
+        assert!(index.transactions.is_empty() && index.addresses.is_empty() && index.heights.is_empty());
+    }
+
+    #[test]
+    fn reorganisations_replace_the_history_of_the_branch() {
+        let (alice, bob, carol) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let first = next_block(None, &[&Transaction::Coinbase { height: 0, to: alice.address(), amount: 50 }]);
+        let to_bob = Transaction::signed_transfer(&alice, &bob.address(), 10, 1, 1, "");
+        let to_carol = Transaction::signed_transfer(&alice, &carol.address(), 10, 1, 1, "");
+        let reward = Transaction::Coinbase { height: 1, to: alice.address(), amount: 51 };
+        let (stale, branch) = (next_block(Some(&first), &[&reward, &to_bob]), next_block(Some(&first), &[&reward, &to_carol]));
+        let top = next_block(Some(&branch), &[&Transaction::Coinbase { height: 2, to: carol.address(), amount: 50 }]);
+
+        let mut index = ChainIndex::new();
+        index.connect(&first);
+        index.connect(&stale);
+        // The branch wins: the stale block is disconnected, then the blocks of the branch connected
+        index.disconnect(&stale);
+        index.connect(&branch);
+        index.connect(&top);
+        assert_eq!(index.transaction(&id(&to_bob)), None);
+        assert_eq!(index.transaction(&id(&to_carol)), Some(TransactionLocation { height: 1, position: 1 }));
+        // The coinbase is the same in both blocks, it is found in the branch
+        assert_eq!(index.transaction(&id(&reward)), Some(TransactionLocation { height: 1, position: 0 }));
+        assert!(index.history(&bob.address()).is_empty());
+        assert_eq!(index.history(&carol.address()).len(), 2);
+        assert_eq!(index.history(&alice.address()).len(), 3);
+        assert_eq!((index.height(&stale.hash), index.height(&branch.hash), index.height(&top.hash)), (None, Some(1), Some(2)));
+    }
+}
@@ -0,0 +1,70 @@
+use std::io;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, ED25519};
+use crate::address;
+
+/// Size of an Ed25519 public key
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Size of an Ed25519 signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// The key pair a wallet signs its transactions with, the address of the wallet being derived from its public key
+/// Kept apart from the `NodeIdentity` used to authenticate connections, so the funds do not depend on the transport key
+/// *`key_pair` - the Ed25519 key pair, saved encrypted in the keystore of the wallet so it survives restarts
+pub struct WalletKey {
+    key_pair: Ed25519KeyPair,
+}
+
+impl WalletKey {
+
+    /// Generates a new key pair, returns its PKCS#8 document, the form in which keystores save it
+    pub fn generate_pkcs8() -> io::Result<Vec<u8>> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| invalid_key("key generation failed"))?;
+        Ok(pkcs8.as_ref().to_vec())
+    }
+
+    /// Reads a key pair saved by a keystore
+    pub fn from_pkcs8(pkcs8: &[u8]) -> io::Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| invalid_key("invalid key"))?;
+        Ok(WalletKey { key_pair })
+    }
+
+    /// Builds the key pair whose private key is `seed`, 32 bytes derived from the seed of a wallet, see `hd::derive_key`
+    pub fn from_seed(seed: &[u8]) -> io::Result<Self> {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed).map_err(|_| invalid_key("invalid key seed"))?;
+        Ok(WalletKey { key_pair })
+    }
+
+    /// Creates a key which is never saved, for simulations
+    pub fn generate() -> io::Result<Self> {
+        WalletKey::from_pkcs8(&WalletKey::generate_pkcs8()?)
+    }
+
+    /// Returns our public key as hex, written in our transactions so their signature can be checked
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Returns our address, where the coins sent to us go, see `address::from_public_key`
+    pub fn address(&self) -> String {
+        address::from_public_key(&self.public_key())
+    }
+
+    /// Signs `message`, returns the signature as hex
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.key_pair.sign(message).as_ref())
+    }
+}
+
+/// Returns true if `signature` (hex) is the signature of `message` by the owner of `public_key` (hex)
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> bool {
+    match (hex::decode(public_key), hex::decode(signature)) {
+        (Ok(key), Ok(signature)) => signature::UnparsedPublicKey::new(&ED25519, key).verify(message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// Builds the error returned when a key can not be read or created
+fn invalid_key(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
@@ -0,0 +1,405 @@
+use std::io::{self, IsTerminal, Write};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use crate::hd;
+use crate::keys::WalletKey;
+use crate::storage;
+
+/// Version written at the top of every keystore, a keystore of a later version is refused
+/// Version 1 only held random keys, they are kept as imported keys and a seed is added.
+/// Before version 3, the addresses were the public keys of the keys
+const KEYSTORE_VERSION: u32 = 3;
+/// Environment variable read for the passphrase before asking for it on the standard input
+pub const PASSPHRASE_VARIABLE: &str = "WALLET_PASSPHRASE";
+/// Environment variable read for the mnemonic of a restored wallet before asking for it on the standard input
+pub const MNEMONIC_VARIABLE: &str = "WALLET_MNEMONIC";
+/// PBKDF2 iterations used by new keystores, the count is saved so it can be raised later
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// Fewest PBKDF2 iterations a keystore may ask for, so a modified file can not make the passphrase easy to guess
+const MIN_ITERATIONS: u32 = 10_000;
+/// Most PBKDF2 iterations a keystore may ask for, so a modified file can not make the wallet hang while opening it
+const MAX_ITERATIONS: u32 = 10_000_000;
+/// Size of the random salt of the passphrase
+const SALT_SIZE: usize = 16;
+/// Additional data authenticated with the encrypted keys, so they can not be moved to another kind of file
+/// The Miner, the wallet ID and, from version 2, the number of used keys follow it, see `associated_data`
+const KEYS_AAD: &[u8] = b"blockchain-keystore-keys";
+
+/// The file keeping the private keys of a wallet, encrypted with its passphrase, and what the wallet must remember between runs
+/// The seed and the imported keys are encrypted with AES-256-GCM, the key being derived from the passphrase with PBKDF2-HMAC-SHA256.
+/// The rest is written in clear, one `name value` per line
+/// *`path` - where the keystore is saved
+/// *`salt`, `iterations` - how the encryption key was derived from the passphrase
+/// *`secret` - the encryption key, kept to save the keystore again without asking for the passphrase
+/// *`miner` - the IP address of the Miner the wallet talks to
+/// *`wallet_id` - the ID this Miner gave the wallet
+/// *`seed` - the seed the keys of the wallet are derived from, see `hd::derive_key`
+/// *`used` - how many keys derived from the seed the wallet uses, from index 0
+/// *`imported` - the PKCS#8 documents of the keys which do not come from the seed, created by older versions
+pub struct Keystore {
+    path: PathBuf,
+    salt: Vec<u8>,
+    iterations: u32,
+    secret: [u8; 32],
+    pub miner: String,
+    pub wallet_id: u32,
+    seed: Vec<u8>,
+    used: u32,
+    imported: Vec<Vec<u8>>,
+}
+
+impl Keystore {
+
+    /// Opens the keystore of the wallet listening on `sockip`, or creates it with a new seed the first time
+    /// A key saved in clear by an older version of the wallet is moved into the new keystore, and stays its main key
+    /// *`miner` - the Miner the wallet talks to, saved in a new keystore
+    /// *`passphrase` - the passphrase protecting the keys
+    pub fn open_or_create(sockip: &str, miner: &str, passphrase: &str) -> io::Result<Self> {
+        let path = storage::node_file(sockip, "keystore");
+        if !storage::read_lines(&path)?.is_empty() {
+            return Keystore::open(path, passphrase);
+        }
+        let legacy_path = storage::node_file(sockip, "wallet");
+        let keystore = match storage::read_lines(&legacy_path)?.first() {
+            Some(line) => {
+                println!("Moving the key of {} into the keystore", legacy_path.display());
+                let pkcs8 = hex::decode(line).map_err(|e| invalid_keystore(&e.to_string()))?;
+                Keystore::create(path, miner, passphrase, hd::generate_seed()?, 0, vec![pkcs8])?
+            }
+            None => Keystore::create(path, miner, passphrase, hd::generate_seed()?, 1, Vec::new())?,
+        };
+        if legacy_path.exists() {
+            std::fs::remove_file(&legacy_path)?;
+        }
+        Ok(keystore)
+    }
+
+    /// Opens the keystore of the wallet listening on `sockip`, fails if it has none yet
+    pub fn open_existing(sockip: &str, passphrase: &str) -> io::Result<Self> {
+        let path = storage::node_file(sockip, "keystore");
+        if storage::read_lines(&path)?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no keystore {}, start the wallet with --wallet first", path.display())));
+        }
+        Keystore::open(path, passphrase)
+    }
+
+    /// Creates the keystore of the wallet listening on `sockip` from the seed of a mnemonic, fails if it already has one
+    /// The keystore starts with the first key of the seed, the other used keys are found by scanning the chain
+    pub fn restore(sockip: &str, miner: &str, passphrase: &str, seed: Vec<u8>) -> io::Result<Self> {
+        let path = storage::node_file(sockip, "keystore");
+        if !storage::read_lines(&path)?.is_empty() {
+            return Err(invalid_keystore(&format!("{} already exists, it would be lost", path.display())));
+        }
+        Keystore::create(path, miner, passphrase, seed, 1, Vec::new())
+    }
+
+    /// Creates and saves a new keystore, with a new salt
+    fn create(path: PathBuf, miner: &str, passphrase: &str, seed: Vec<u8>, used: u32, imported: Vec<Vec<u8>>) -> io::Result<Self> {
+        if passphrase.is_empty() {
+            return Err(invalid_keystore("the passphrase must not be empty"));
+        }
+        let mut salt = vec![0_u8; SALT_SIZE];
+        SystemRandom::new().fill(&mut salt).map_err(|_| invalid_keystore("could not generate a salt"))?;
+        let keystore = Keystore {
+            secret: derive_secret(passphrase, &salt, PBKDF2_ITERATIONS),
+            path,
+            salt,
+            iterations: PBKDF2_ITERATIONS,
+            miner: miner.to_string(),
+            wallet_id: 0,
+            seed,
+            used,
+            imported,
+        };
+        keystore.save()?;
+        Ok(keystore)
+    }
+
+    /// Opens an existing keystore, fails if the passphrase is wrong or the file was modified
+    pub fn open(path: PathBuf, passphrase: &str) -> io::Result<Self> {
+        let lines = storage::read_lines(&path)?;
+        let field = |name: &str| lines.iter()
+            .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')))
+            .ok_or_else(|| invalid_keystore(&format!("missing field {}", name)));
+        let number = |name: &str| field(name)?.parse::<u32>().map_err(|e| invalid_keystore(&format!("invalid {}: {}", name, e)));
+        let version = number("version")?;
+        if version == 0 || version > KEYSTORE_VERSION {
+            return Err(invalid_keystore("unsupported keystore version"));
+        }
+        let salt = hex::decode(field("salt")?).map_err(|e| invalid_keystore(&e.to_string()))?;
+        let iterations = number("iterations")?;
+        if !(MIN_ITERATIONS..=MAX_ITERATIONS).contains(&iterations) {
+            return Err(invalid_keystore(&format!("invalid iterations: {}, not between {} and {}", iterations, MIN_ITERATIONS, MAX_ITERATIONS)));
+        }
+        let secret = derive_secret(passphrase, &salt, iterations);
+        let mut sealed = hex::decode(field("keys")?).map_err(|e| invalid_keystore(&e.to_string()))?;
+        if sealed.len() < aead::NONCE_LEN {
+            return Err(invalid_keystore("encrypted keys too short"));
+        }
+        let mut encrypted = sealed.split_off(aead::NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).map_err(|_| invalid_keystore("invalid nonce"))?;
+        let (miner, wallet_id) = (field("miner")?.to_string(), number("id")?);
+        // Version 1 had no seed, so no count of the keys used
+        let used = if version == 1 { None } else { Some(number("used")?) };
+        let aad = associated_data(&miner, wallet_id, used);
+        let plain = cipher(&secret).open_in_place(nonce, Aad::from(aad), &mut encrypted)
+            .map_err(|_| invalid_keystore("wrong passphrase or damaged keystore"))?;
+        let plain = String::from_utf8_lossy(plain).to_string();
+        // Version 1 only holds the keys, version 2 starts with the seed
+        let (seed, imported) = match (version, plain.split_once('|')) {
+            (1, _) => (hd::generate_seed()?, plain.as_str()),
+            (_, Some((seed, imported))) => (hex::decode(seed).map_err(|e| invalid_keystore(&e.to_string()))?, imported),
+            _ => return Err(invalid_keystore("missing seed")),
+        };
+        let imported = imported.split(',')
+            .filter(|key| !key.is_empty())
+            .map(hex::decode)
+            .collect::<Result<Vec<Vec<u8>>, _>>()
+            .map_err(|e| invalid_keystore(&e.to_string()))?;
+        let keystore = Keystore {
+            path,
+            salt,
+            iterations,
+            secret,
+            miner,
+            wallet_id,
+            seed,
+            used: used.unwrap_or(0),
+            imported,
+        };
+        let addresses: Vec<&str> = lines.iter().filter_map(|line| line.strip_prefix("address ")).collect();
+        let expected = match version {
+            1 | 2 => keystore.keys()?.iter().map(|key| key.public_key()).collect(),
+            _ => keystore.addresses()?,
+        };
+        if addresses != expected.iter().map(|a| a.as_str()).collect::<Vec<&str>>() {
+            return Err(invalid_keystore("the addresses do not match the keys"));
+        }
+        Ok(keystore)
+    }
+
+    /// Returns the key pairs of the wallet: the imported ones, then the used ones of the seed. The first one is the main key
+    pub fn keys(&self) -> io::Result<Vec<WalletKey>> {
+        let imported = self.imported.iter().map(|pkcs8| WalletKey::from_pkcs8(pkcs8));
+        imported.chain((0..self.used).map(|index| hd::derive_key(&self.seed, index))).collect()
+    }
+
+    /// Returns the mnemonic of the seed, which restores every key of the wallet but the imported ones
+    pub fn mnemonic(&self) -> String {
+        hd::to_mnemonic(&self.seed)
+    }
+
+    /// Returns the number of keys which do not come from the seed, the mnemonic does not back them up
+    pub fn imported_count(&self) -> usize {
+        self.imported.len()
+    }
+
+    /// Returns the address of the key of index `index` of the seed, used or not
+    pub fn derived_address(&self, index: u32) -> io::Result<String> {
+        Ok(hd::derive_key(&self.seed, index)?.address())
+    }
+
+    /// Sets how many keys of the seed the wallet uses, at least one, then saves the keystore
+    pub fn set_used(&mut self, used: u32) -> io::Result<()> {
+        self.used = used.max(1);
+        self.save()
+    }
+
+    /// Starts using the next key of the seed and saves the keystore
+    /// Returns the address of the new key
+    pub fn new_address(&mut self) -> io::Result<String> {
+        let address = self.derived_address(self.used)?;
+        self.set_used(self.used + 1)?;
+        Ok(address)
+    }
+
+    /// Returns the addresses of the wallet, the public keys of its key pairs
+    pub fn addresses(&self) -> io::Result<Vec<String>> {
+        Ok(self.keys()?.iter().map(|key| key.address()).collect())
+    }
+
+    /// Writes the keystore, the keys being encrypted again with a new nonce
+    pub fn save(&self) -> io::Result<()> {
+        let mut nonce = [0_u8; aead::NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| invalid_keystore("could not generate a nonce"))?;
+        let imported = self.imported.iter().map(hex::encode).collect::<Vec<String>>().join(",");
+        let mut encrypted = format!("{}|{}", hex::encode(&self.seed), imported).into_bytes();
+        let aad = associated_data(&self.miner, self.wallet_id, Some(self.used));
+        cipher(&self.secret).seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut encrypted)
+            .map_err(|_| invalid_keystore("could not encrypt the keys"))?;
+        let mut lines = vec![
+            format!("version {}", KEYSTORE_VERSION),
+            format!("miner {}", self.miner),
+            format!("id {}", self.wallet_id),
+        ];
+        lines.extend(self.addresses()?.iter().map(|address| format!("address {}", address)));
+        lines.push(format!("used {}", self.used));
+        lines.push(format!("salt {}", hex::encode(&self.salt)));
+        lines.push(format!("iterations {}", self.iterations));
+        lines.push(format!("keys {}{}", hex::encode(nonce), hex::encode(&encrypted)));
+        storage::write_lines(&self.path, &lines)
+    }
+}
+
+/// Returns the passphrase of the wallet: the `WALLET_PASSPHRASE` environment variable if set, else the first line typed
+pub fn read_passphrase() -> io::Result<String> {
+    read_secret(PASSPHRASE_VARIABLE, "Passphrase")
+}
+
+/// Returns the mnemonic a wallet is restored from: the `WALLET_MNEMONIC` environment variable if set, else the next line typed
+pub fn read_mnemonic() -> io::Result<String> {
+    read_secret(MNEMONIC_VARIABLE, "Mnemonic")
+}
+
+/// Reads the environment variable `variable`, or a line of the standard input after showing `prompt`
+/// A line typed in a terminal is not shown, `stty` turning its echo off while it is read
+fn read_secret(variable: &str, prompt: &str) -> io::Result<String> {
+    if let Ok(secret) = std::env::var(variable) {
+        return Ok(secret);
+    }
+    print!("{}: ", prompt);
+    io::stdout().flush()?;
+    let hidden = io::stdin().is_terminal() && set_echo(false);
+    let mut secret = String::new();
+    let read = io::stdin().read_line(&mut secret);
+    if hidden {
+        set_echo(true);
+        println!();
+    }
+    read?;
+    Ok(secret.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Turns on or off the echo of the terminal of the standard input
+/// Returns false if `stty` could not change it, the line typed is then shown
+fn set_echo(echo: bool) -> bool {
+    Command::new("stty")
+        .arg(if echo { "echo" } else { "-echo" })
+        .stdin(Stdio::inherit())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Returns the additional data authenticated with the encrypted keys: the lines written in clear that the wallet trusts
+/// `used` is missing from the keystores of version 1, which had no seed
+fn associated_data(miner: &str, wallet_id: u32, used: Option<u32>) -> Vec<u8> {
+    let mut aad = KEYS_AAD.to_vec();
+    aad.extend(format!("|{}|{}", miner, wallet_id).into_bytes());
+    if let Some(used) = used {
+        aad.extend(format!("|{}", used).into_bytes());
+    }
+    aad
+}
+
+/// Derives the encryption key of the keystore from the passphrase
+fn derive_secret(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut secret = [0_u8; 32];
+    let iterations = NonZeroU32::new(iterations).unwrap_or(NonZeroU32::new(PBKDF2_ITERATIONS).unwrap());
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut secret);
+    secret
+}
+
+/// Returns the AES-256-GCM cipher of the keys
+fn cipher(secret: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, secret).expect("AES-256 keys are 32 bytes"))
+}
+
+/// Builds the error returned when a keystore can not be read or written
+fn invalid_keystore(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_path;
+
+    #[test]
+    fn saved_keystores_open_with_their_passphrase() {
+        let path = temp_path("keystore-round-trip");
+        let mut keystore = Keystore::create(path.clone(), "127.0.0.1:8000", "secret", hd::generate_seed().unwrap(), 1, Vec::new()).unwrap();
+        keystore.wallet_id = 7;
+        keystore.new_address().unwrap();
+
+        let opened = Keystore::open(path.clone(), "secret").unwrap();
+        assert_eq!(opened.miner, "127.0.0.1:8000");
+        assert_eq!(opened.wallet_id, 7);
+        assert_eq!(opened.mnemonic(), keystore.mnemonic());
+        assert_eq!(opened.addresses().unwrap(), keystore.addresses().unwrap());
+        assert_eq!(opened.addresses().unwrap().len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn imported_keys_come_first() {
+        let path = temp_path("keystore-imported");
+        let pkcs8 = WalletKey::generate_pkcs8().unwrap();
+        let imported = WalletKey::from_pkcs8(&pkcs8).unwrap().address();
+        let keystore = Keystore::create(path.clone(), "127.0.0.1:8000", "secret", hd::generate_seed().unwrap(), 1, vec![pkcs8]).unwrap();
+
+        let opened = Keystore::open(path.clone(), "secret").unwrap();
+        assert_eq!(opened.imported_count(), 1);
+        assert_eq!(opened.addresses().unwrap(), vec![imported, keystore.derived_address(0).unwrap()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrases_and_modified_files_are_refused() {
+        assert!(Keystore::create(temp_path("keystore-empty"), "127.0.0.1:8000", "", hd::generate_seed().unwrap(), 1, Vec::new()).is_err());
+        let path = temp_path("keystore-refused");
+        Keystore::create(path.clone(), "127.0.0.1:8000", "secret", hd::generate_seed().unwrap(), 1, Vec::new()).unwrap();
+        assert!(Keystore::open(path.clone(), "Secret").is_err());
+
+        // Another address could make the wallet give out an address whose key it does not have
+        let lines = storage::read_lines(&path).unwrap();
+        let other = WalletKey::generate().unwrap().address();
+        let swapped: Vec<String> = lines.iter()
+            .map(|line| if line.starts_with("address ") { format!("address {}", other) } else { line.clone() })
+            .collect();
+        storage::write_lines(&path, &swapped).unwrap();
+        assert!(Keystore::open(path.clone(), "secret").is_err());
+
+        let flipped: Vec<String> = lines.iter()
+            .map(|line| match line.strip_prefix("keys ") {
+                Some(keys) => format!("keys {}{}", &keys[..keys.len() - 1], if keys.ends_with('0') { '1' } else { '0' }),
+                None => line.clone(),
+            })
+            .collect();
+        storage::write_lines(&path, &flipped).unwrap();
+        assert!(Keystore::open(path.clone(), "secret").is_err());
+        storage::write_lines(&path, &lines).unwrap();
+        assert!(Keystore::open(path.clone(), "secret").is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn lines_in_clear_are_authenticated() {
+        let path = temp_path("keystore-clear");
+        Keystore::create(path.clone(), "127.0.0.1:8000", "secret", hd::generate_seed().unwrap(), 1, Vec::new()).unwrap();
+        let lines = storage::read_lines(&path).unwrap();
+        let replaced = |name: &str, value: &str| -> Vec<String> {
+            lines.iter()
+                .map(|line| if line.starts_with(&format!("{} ", name)) { format!("{} {}", name, value) } else { line.clone() })
+                .collect()
+        };
+
+        // Another Miner would be trusted with our registration, another ID would be sent to it
+        for (name, value) in [("miner", "127.0.0.1:9000"), ("id", "3"), ("used", "2")] {
+            storage::write_lines(&path, &replaced(name, value)).unwrap();
+            assert!(Keystore::open(path.clone(), "secret").is_err(), "{} changed", name);
+        }
+        for iterations in [MIN_ITERATIONS - 1, MAX_ITERATIONS + 1] {
+            storage::write_lines(&path, &replaced("iterations", &iterations.to_string())).unwrap();
+            let refused = Keystore::open(path.clone(), "secret").err().map(|e| e.to_string());
+            assert!(refused.is_some_and(|e| e.starts_with("invalid iterations")));
+        }
+        storage::write_lines(&path, &lines).unwrap();
+        assert!(Keystore::open(path.clone(), "secret").is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
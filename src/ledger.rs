@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use crate::address;
+use crate::block::Block;
+use crate::config::{ChainConfig, LedgerMode};
+use crate::transaction::Transaction;
+use crate::utxo::UtxoSet;
+
+/// The state of an address
+/// *`balance` - what the address owns, including the block rewards which are not mature yet
+/// *`nonce` - the nonce of the last transaction sent from the address, 0 if it never sent one
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64,
+}
+
+/// What a Miner tells a wallet about an address
+/// *`balance` - the balance in the main chain
+/// *`spendable` - what can still be spent: the mature balance, minus what the waiting transactions already spend
+/// *`next_nonce` - the nonce the next transaction of the address must use, after the waiting ones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountSummary {
+    pub balance: u64,
+    pub spendable: u64,
+    pub next_nonce: u64,
+}
+
+impl fmt::Display for AccountSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.balance, self.spendable, self.next_nonce)
+    }
+}
+
+impl FromStr for AccountSummary {
+    type Err = String;
+
+    /// Reads the `balance spendable next_nonce` sent by a Miner
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<u64> = s.split_whitespace().map(|v| v.parse::<u64>()).collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+        match values.as_slice() {
+            [balance, spendable, next_nonce] => Ok(AccountSummary { balance: *balance, spendable: *spendable, next_nonce: *next_nonce }),
+            _ => Err(format!("invalid account summary {}", s)),
+        }
+    }
+}
+
+/// Why a transaction can not be applied to the ledger
+#[derive(Debug, PartialEq)]
+pub enum LedgerError {
+    /// The nonce is not the one following the last transaction of the sender
+    Nonce { expected: u64, found: u64 },
+    /// The sender does not own enough mature coins for the amount and the fee
+    Overspend { available: u64, needed: u64 },
+    /// A balance would not fit in 64 bits
+    Overflow,
+    /// The output spent does not exist or was already spent
+    Missing(String),
+    /// The output spent belongs to another address than the sender
+    NotOwner(String),
+    /// The output spent was created by a coinbase which is not mature yet
+    Immature(String),
+    /// The outputs spent do not hold exactly the outputs created plus the fee
+    Unbalanced { inputs: u64, needed: u64 },
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::Nonce { expected, found } => write!(f, "nonce {} used, {} expected", found, expected),
+            LedgerError::Overspend { available, needed } => write!(f, "spends {}, only {} available", needed, available),
+            LedgerError::Overflow => write!(f, "balance overflow"),
+            LedgerError::Missing(outpoint) => write!(f, "output {} unknown or already spent", outpoint),
+            LedgerError::NotOwner(outpoint) => write!(f, "output {} belongs to someone else", outpoint),
+            LedgerError::Immature(outpoint) => write!(f, "output {} is not mature", outpoint),
+            LedgerError::Unbalanced { inputs, needed } => write!(f, "inputs hold {}, outputs and fee need {}", inputs, needed),
+        }
+    }
+}
+
+/// Who owns what as of the tip of the main chain, kept the way the chain configuration chose
+pub enum Ledger {
+    Accounts(Accounts),
+    Utxo(UtxoSet),
+}
+
+impl Ledger {
+
+    /// CONSTRUCTOR
+    /// Returns the empty ledger of a chain using `mode`
+    pub fn new(mode: LedgerMode) -> Self {
+        match mode {
+            LedgerMode::Account => Ledger::Accounts(Accounts::new()),
+            LedgerMode::Utxo => Ledger::Utxo(UtxoSet::new()),
+        }
+    }
+
+    /// Returns the state of an address. On a UTXO chain the balance is the total of its unspent outputs and the nonce is 0
+    pub fn account(&self, address: &str) -> Account {
+        match self {
+            Ledger::Accounts(accounts) => accounts.account(address),
+            Ledger::Utxo(outputs) => Account { balance: outputs.balance(address), nonce: 0 },
+        }
+    }
+
+    /// Applies the transactions of the next block of the main chain, all of them or none
+    /// *`locked` - the block rewards of each address which can not be spent yet, see `Accounts::apply_block`
+    pub fn apply_block(&mut self, block: &Block, locked: &HashMap<String, u64>, config: &ChainConfig) -> Result<(), LedgerError> {
+        match self {
+            Ledger::Accounts(accounts) => accounts.apply_block(block, locked),
+            Ledger::Utxo(outputs) => outputs.apply_block(block, config),
+        }
+    }
+
+    /// Rolls back the last block applied
+    pub fn undo_block(&mut self) {
+        match self {
+            Ledger::Accounts(accounts) => accounts.undo_block(),
+            Ledger::Utxo(outputs) => outputs.undo_block(),
+        }
+    }
+}
+
+/// The balances and nonces of every address
+/// *`accounts` - the addresses which ever received or sent something
+/// *`undo` - for each block of the main chain, the accounts it changed with their previous value, to roll it back
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+    undo: Vec<Vec<(String, Option<Account>)>>,
+}
+
+impl Accounts {
+
+    /// CONSTRUCTOR
+    /// Returns the accounts of an empty chain
+    pub fn new() -> Self {
+        Accounts {
+            accounts: HashMap::new(),
+            undo: Vec::new(),
+        }
+    }
+
+    /// Returns the state of an address, empty if it never appeared in the chain
+    pub fn account(&self, address: &str) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    /// Applies the transactions of the next block of the main chain, all of them or none
+    /// *`locked` - the block rewards of each address which can not be spent yet, including the coinbase of this block
+    pub fn apply_block(&mut self, block: &Block, locked: &HashMap<String, u64>) -> Result<(), LedgerError> {
+        let mut changes: HashMap<String, Account> = HashMap::new();
+        for transaction in block.transactions() {
+            // Blocks reaching the ledger were checked by the chain, only the state is verified here
+            let transaction = match Transaction::from_str(&transaction) {
+                Ok(transaction) => transaction,
+                Err(_) => continue,
+            };
+            match transaction {
+                // Not accepted by an account chain
+                Transaction::Spend { .. } => continue,
+                Transaction::Coinbase { to, amount, .. } => {
+                    let mut receiver = changes.get(&to).copied().unwrap_or_else(|| self.account(&to));
+                    receiver.balance = receiver.balance.checked_add(amount).ok_or(LedgerError::Overflow)?;
+                    changes.insert(to, receiver);
+                }
+                Transaction::Transfer { from, to, amount, fee, nonce, .. } => {
+                    let from = address::from_public_key(&from);
+                    let mut sender = changes.get(&from).copied().unwrap_or_else(|| self.account(&from));
+                    let expected = sender.nonce.checked_add(1).ok_or(LedgerError::Overflow)?;
+                    if nonce != expected {
+                        return Err(LedgerError::Nonce { expected, found: nonce });
+                    }
+                    let needed = amount.checked_add(fee).ok_or(LedgerError::Overflow)?;
+                    let available = sender.balance.saturating_sub(locked.get(&from).copied().unwrap_or(0));
+                    if needed > available {
+                        return Err(LedgerError::Overspend { available, needed });
+                    }
+                    sender.balance -= needed;
+                    sender.nonce = nonce;
+                    changes.insert(from, sender);
+                    let mut receiver = changes.get(&to).copied().unwrap_or_else(|| self.account(&to));
+                    receiver.balance = receiver.balance.checked_add(amount).ok_or(LedgerError::Overflow)?;
+                    changes.insert(to, receiver);
+                }
+            }
+        }
+
+        let mut undo = Vec::new();
+        for (address, account) in changes {
+            undo.push((address.clone(), self.accounts.insert(address, account)));
+        }
+        self.undo.push(undo);
+        Ok(())
+    }
+
+    /// Rolls back the last block applied
+    pub fn undo_block(&mut self) {
+        for (address, previous) in self.undo.pop().unwrap_or_default() {
+            match previous {
+                Some(account) => self.accounts.insert(address, account),
+                None => self.accounts.remove(&address),
+            };
+        }
+    }
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Accounts::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::TRANSACTION_SEPARATOR;
+    use crate::keys::WalletKey;
+
+    /// Returns a block of `transactions`, the ledger does not look at the hashes
+    fn block(index: u32, transactions: &[Transaction]) -> Block {
+        let payload = transactions.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(&TRANSACTION_SEPARATOR.to_string());
+        Block::new(index, payload, 0, 0, Vec::new())
+    }
+
+    fn coinbase(height: u32, to: &WalletKey, amount: u64) -> Transaction {
+        Transaction::Coinbase { height, to: to.address(), amount }
+    }
+
+    #[test]
+    fn transfers_move_balances_and_nonces() {
+        let (alice, bob, miner) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut accounts = Accounts::new();
+        accounts.apply_block(&block(0, &[coinbase(0, &alice, 100)]), &HashMap::new()).unwrap();
+        let transfers = [
+            coinbase(1, &miner, 50 + 3),
+            Transaction::signed_transfer(&alice, &bob.address(), 30, 1, 1, ""),
+            Transaction::signed_transfer(&alice, &bob.address(), 20, 2, 2, ""),
+        ];
+        accounts.apply_block(&block(1, &transfers), &HashMap::new()).unwrap();
+
+        assert_eq!(accounts.account(&alice.address()), Account { balance: 47, nonce: 2 });
+        assert_eq!(accounts.account(&bob.address()), Account { balance: 50, nonce: 0 });
+        assert_eq!(accounts.account(&miner.address()), Account { balance: 53, nonce: 0 });
+    }
+
+    #[test]
+    fn wrong_nonces_and_overspends_change_nothing() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut accounts = Accounts::new();
+        accounts.apply_block(&block(0, &[coinbase(0, &alice, 100)]), &HashMap::new()).unwrap();
+
+        let transfer = Transaction::signed_transfer(&alice, &bob.address(), 10, 0, 1, "");
+        let replayed = [transfer.clone(), transfer];
+        assert_eq!(accounts.apply_block(&block(1, &replayed), &HashMap::new()), Err(LedgerError::Nonce { expected: 2, found: 1 }));
+        let overspend = [Transaction::signed_transfer(&alice, &bob.address(), 100, 1, 1, "")];
+        assert_eq!(accounts.apply_block(&block(1, &overspend), &HashMap::new()), Err(LedgerError::Overspend { available: 100, needed: 101 }));
+        let locked: HashMap<String, u64> = vec![(alice.address(), 60)].into_iter().collect();
+        let immature = [Transaction::signed_transfer(&alice, &bob.address(), 50, 0, 1, "")];
+        assert_eq!(accounts.apply_block(&block(1, &immature), &locked), Err(LedgerError::Overspend { available: 40, needed: 50 }));
+
+        assert_eq!(accounts.account(&alice.address()), Account { balance: 100, nonce: 0 });
+        assert_eq!(accounts.account(&bob.address()), Account::default());
+    }
+
+    #[test]
+    fn undo_then_apply_again_gives_the_same_accounts() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut accounts = Accounts::new();
+        accounts.apply_block(&block(0, &[coinbase(0, &alice, 100)]), &HashMap::new()).unwrap();
+        let first = block(1, &[coinbase(1, &bob, 50), Transaction::signed_transfer(&alice, &bob.address(), 30, 0, 1, "")]);
+        accounts.apply_block(&first, &HashMap::new()).unwrap();
+        let applied = accounts.accounts.clone();
+
+        // A reorganisation replaces the block by another one spending the same nonce, then the first branch wins again
+        accounts.undo_block();
+        assert_eq!(accounts.account(&alice.address()), Account { balance: 100, nonce: 0 });
+        assert!(!accounts.accounts.contains_key(&bob.address()));
+        let other = block(1, &[coinbase(1, &alice, 50), Transaction::signed_transfer(&alice, &bob.address(), 70, 0, 1, "")]);
+        accounts.apply_block(&other, &HashMap::new()).unwrap();
+        assert_eq!(accounts.account(&alice.address()), Account { balance: 80, nonce: 1 });
+        accounts.undo_block();
+        accounts.apply_block(&first, &HashMap::new()).unwrap();
+        assert_eq!(accounts.accounts, applied);
+
+        accounts.undo_block();
+        accounts.undo_block();
+        assert!(accounts.accounts.is_empty());
+        accounts.undo_block();
+        assert!(accounts.accounts.is_empty());
+    }
+}
@@ -2,12 +2,43 @@
 
 use std::str::FromStr;
 use std::env;
-use clap::{Arg, App, SubCommand};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Arg, App, AppSettings, SubCommand};
 
 mod miner;
 mod wallet;
+mod peer;
+mod storage;
+mod protocol;
+mod secure;
+mod ban;
+mod ratelimit;
+mod transport;
+mod sim;
+mod chain;
+mod mempool;
+mod transaction;
+mod config;
+mod keys;
+mod keystore;
+mod ledger;
+mod utxo;
+mod index;
+mod notify;
+mod spv;
+mod hd;
+mod address;
+mod repl;
+mod cli;
+mod pool;
+mod blockstore;
+mod offline;
+mod watch;
 
 mod block;
+#[cfg(test)]
+mod testutil;
 fn main() {
 
     // let block = block::Block::from_str(&block::Block::new(0, "Premier bloc".to_owned(), 0, 0, vec![0; 32]).to_string());
@@ -22,19 +53,68 @@ fn main() {
                  .long("create")
                  .value_name("HOST:PORT")
                  .takes_value(true)
+                 .validator(protocol::validate_address)
                  .help("Create a new miner on the specified host:port"))
         .arg(Arg::with_name("join")
                  .short("j")
                  .long("join")
                  .value_names(&["HOST:PORT" ,"HOST:PORT"])
                  .takes_value(true)
+                 .validator(protocol::validate_address)
                  .help("Join miner on the specified host:port"))
         .arg(Arg::with_name("wallet")
                 .short("w")
                 .long("wallet")
                 .value_names(&["HOST:PORT", "HOST:PORT"])
                 .takes_value(true)
+                .validator(protocol::validate_address)
                 .help("Join wallet to a specified miner on host:port"))
+        .arg(Arg::with_name("miners")
+                .long("miners")
+                .value_name("HOST:PORT,...")
+                .takes_value(true)
+                .use_delimiter(true)
+                .validator(protocol::validate_address)
+                .help("With --wallet, other miners taking over when the first one fails, more are learned from its peers"))
+        .arg(Arg::with_name("shutdown")
+                .short("s")
+                .long("shutdown")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .validator(protocol::validate_address)
+                .help("Ask the miner running on this machine at host:port to leave the network and stop"))
+        .arg(Arg::with_name("mine")
+                .short("m")
+                .long("mine")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .validator(protocol::validate_address)
+                .help("Ask the miner running on this machine at host:port to mine a block now, even without waiting transactions"))
+        .arg(Arg::with_name("payout")
+                .long("payout")
+                .value_name("ADDRESS")
+                .takes_value(true)
+                .validator(validate_payout)
+                .help("Address paid by the coinbases of the blocks mined, the public key of the miner by default"))
+        .arg(Arg::with_name("trust-loopback")
+                .long("trust-loopback")
+                .help("With --create or --join, does not rate limit the connections from this machine, for a network of local nodes"))
+        .arg(Arg::with_name("restore")
+                .long("restore")
+                .help("With --wallet, creates the keystore from a mnemonic (WALLET_MNEMONIC or typed) and finds the addresses it used"))
+        .arg(Arg::with_name("watch")
+                .long("watch")
+                .value_name("KEY_OR_ADDRESS,...")
+                .takes_value(true)
+                .use_delimiter(true)
+                .conflicts_with("restore")
+                .validator(validate_watch)
+                .help("With --wallet, makes a watch-only wallet tracking these public keys or addresses, without keys nor passphrase"))
+        .arg(Arg::with_name("chain")
+                .long("chain")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("File of consensus parameters (difficulty, subsidy, halving_interval, coinbase_maturity), the same for every miner of the network"))
         .subcommand(SubCommand::with_name("test")
                 .about("controls testing features")
                 .version("1.3")
@@ -42,8 +122,195 @@ fn main() {
                 .arg(Arg::with_name("debug")
                     .short("d")
                     .help("print debug information verbosely")))
+        .subcommand(SubCommand::with_name("wallet")
+                .about("Runs one command with an existing wallet then exits, for scripts. The passphrase is read from WALLET_PASSPHRASE")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .after_help("EXIT STATUS:\n    0 success, 1 the command failed, 2 the Miner could not be reached, 3 the wallet could not be opened")
+                .arg(Arg::with_name("socket")
+                    .long("socket")
+                    .value_name("HOST:PORT")
+                    .required(true)
+                    .validator(protocol::validate_address)
+                    .help("Address of the wallet, which names its keystore"))
+                .arg(Arg::with_name("miner")
+                    .long("miner")
+                    .value_name("HOST:PORT")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .use_delimiter(true)
+                    .validator(protocol::validate_address)
+                    .help("Miners to talk to first, in order, before those known by the wallet and the one saved in its keystore"))
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Prints the result, or the error, as a JSON object"))
+                .subcommand(SubCommand::with_name("send")
+                    .about("Sends coins to an address")
+                    .arg(Arg::with_name("address").required(true).validator(validate_payout).help("Address receiving the coins"))
+                    .arg(Arg::with_name("amount").required(true).validator(validate_number).help("Amount sent"))
+                    .arg(Arg::with_name("fee")
+                        .long("fee")
+                        .value_name("FEE")
+                        .validator(validate_number)
+                        .help("Fee paid to the miner of the block, the normal estimate of the Miner by default"))
+                    .arg(Arg::with_name("message")
+                        .long("message")
+                        .value_name("TEXT")
+                        .help("Message carried by the transaction")))
+                .subcommand(SubCommand::with_name("balance")
+                    .about("Shows the confirmed, unconfirmed and spendable amounts of the addresses of the wallet"))
+                .subcommand(SubCommand::with_name("verify")
+                    .about("Checks with a proof from the Miner that a transaction is in the main chain")
+                    .arg(Arg::with_name("txid").required(true).help("ID of the transaction")))
+                .subcommand(SubCommand::with_name("history")
+                    .about("Shows a page of the transactions of the addresses of the wallet, newest first")
+                    .arg(Arg::with_name("page")
+                        .long("page")
+                        .value_name("PAGE")
+                        .default_value("1")
+                        .validator(validate_number)
+                        .help("Page shown, from 1")))
+                .subcommand(SubCommand::with_name("sync")
+                    .about("Downloads the blocks of the main chain the wallet does not have yet, resuming the last sync"))
+                .subcommand(SubCommand::with_name("export")
+                    .about("Writes to a file the transaction send would send, unsigned, to be signed by an offline wallet")
+                    .arg(Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("File the unsigned transaction is written to"))
+                    .arg(Arg::with_name("address").required(true).validator(validate_payout).help("Address receiving the coins"))
+                    .arg(Arg::with_name("amount").required(true).validator(validate_number).help("Amount sent"))
+                    .arg(Arg::with_name("fee")
+                        .long("fee")
+                        .value_name("FEE")
+                        .validator(validate_number)
+                        .help("Fee paid to the miner of the block, the normal estimate of the Miner by default"))
+                    .arg(Arg::with_name("message")
+                        .long("message")
+                        .value_name("TEXT")
+                        .help("Message carried by the transaction")))
+                .subcommand(SubCommand::with_name("sign")
+                    .about("Shows an exported transaction then signs it with a key of the keystore, without reaching any Miner")
+                    .arg(Arg::with_name("file").required(true).help("File of the unsigned transaction"))
+                    .arg(Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("File the signed transaction is written to, <FILE>.signed by default"))
+                    .arg(Arg::with_name("yes")
+                        .long("yes")
+                        .help("Signs without asking for a confirmation")))
+                .subcommand(SubCommand::with_name("submit")
+                    .about("Sends to the Miner a transaction signed offline")
+                    .arg(Arg::with_name("file").required(true).help("File of the signed transaction"))))
+        .subcommand(SubCommand::with_name("simulate")
+                .about("Runs a network of miners inside this process, over a simulated network")
+                .arg(Arg::with_name("nodes")
+                    .long("nodes")
+                    .value_name("COUNT")
+                    .default_value("4")
+                    .validator(validate_number)
+                    .help("Number of miners"))
+                .arg(Arg::with_name("seed")
+                    .long("seed")
+                    .value_name("SEED")
+                    .default_value("0")
+                    .validator(validate_number)
+                    .help("Seed of the random losses and delays, the same seed replays the same network"))
+                .arg(Arg::with_name("min-latency")
+                    .long("min-latency")
+                    .value_name("MS")
+                    .default_value("5")
+                    .validator(validate_number)
+                    .help("Shortest delay of a frame, in ms"))
+                .arg(Arg::with_name("max-latency")
+                    .long("max-latency")
+                    .value_name("MS")
+                    .default_value("50")
+                    .validator(validate_number)
+                    .help("Longest delay of a frame, in ms"))
+                .arg(Arg::with_name("loss")
+                    .long("loss")
+                    .value_name("PROBABILITY")
+                    .default_value("0")
+                    .validator(validate_probability)
+                    .help("Probability that a connection attempt is lost"))
+                .arg(Arg::with_name("reorder")
+                    .long("reorder")
+                    .value_name("PROBABILITY")
+                    .default_value("0")
+                    .validator(validate_probability)
+                    .help("Probability that a connection is overtaken by later ones"))
+                .arg(Arg::with_name("transactions")
+                    .long("transactions")
+                    .value_name("COUNT")
+                    .default_value("10")
+                    .validator(validate_number)
+                    .help("Number of transactions sent to the miners"))
+                .arg(Arg::with_name("duration")
+                    .long("duration")
+                    .value_name("SECONDS")
+                    .default_value("10")
+                    .validator(validate_number)
+                    .help("How long the network runs before being stopped"))
+                .arg(Arg::with_name("partition")
+                    .long("partition")
+                    .value_names(&["FROM_S", "TO_S"])
+                    .validator(validate_number)
+                    .help("Splits the miners in two halves which can not reach each other, between these two times")))
         .get_matches();
 
+    if let Some(simulate) = matches.subcommand_matches("simulate") {
+        let number = |name: &str| simulate.value_of(name).unwrap().trim().parse::<u64>().unwrap();
+        let config = sim::SimConfig {
+            seed: number("seed"),
+            min_latency: Duration::from_millis(number("min-latency")),
+            max_latency: Duration::from_millis(number("max-latency")),
+            loss: simulate.value_of("loss").unwrap().parse::<f64>().unwrap(),
+            reorder: simulate.value_of("reorder").unwrap().parse::<f64>().unwrap(),
+        };
+        let partition = simulate.values_of("partition").map(|values| {
+            let times: Vec<u64> = values.map(|v| v.parse::<u64>().unwrap()).collect();
+            (Duration::from_secs(times[0]), Duration::from_secs(times[1]))
+        });
+        sim::run(config, number("nodes") as usize, number("transactions") as usize, Duration::from_secs(number("duration")), partition);
+        return;
+    }
+
+
+    if let Some(wallet) = matches.subcommand_matches("wallet") {
+        let command = match wallet.subcommand() {
+            ("send", Some(send)) => cli::WalletCommand::Send {
+                to: send.value_of("address").unwrap().to_string(),
+                amount: send.value_of("amount").unwrap().parse::<u64>().unwrap(),
+                fee: send.value_of("fee").map(|fee| fee.parse::<u64>().unwrap()),
+                message: send.value_of("message").unwrap_or("").to_string(),
+            },
+            ("balance", _) => cli::WalletCommand::Balance,
+            ("sync", _) => cli::WalletCommand::Sync,
+            ("export", Some(export)) => cli::WalletCommand::Export {
+                path: export.value_of("out").unwrap().to_string(),
+                to: export.value_of("address").unwrap().to_string(),
+                amount: export.value_of("amount").unwrap().parse::<u64>().unwrap(),
+                fee: export.value_of("fee").map(|fee| fee.parse::<u64>().unwrap()),
+                message: export.value_of("message").unwrap_or("").to_string(),
+            },
+            ("sign", Some(sign)) => {
+                let path = sign.value_of("file").unwrap().to_string();
+                cli::WalletCommand::Sign {
+                    out: sign.value_of("out").map(|out| out.to_string()).unwrap_or_else(|| format!("{}.signed", path)),
+                    path,
+                    confirmed: sign.is_present("yes"),
+                }
+            }
+            ("submit", Some(submit)) => cli::WalletCommand::Submit(submit.value_of("file").unwrap().to_string()),
+            ("verify", Some(verify)) => cli::WalletCommand::Verify(verify.value_of("txid").unwrap().to_string()),
+            ("history", Some(history)) => cli::WalletCommand::History(history.value_of("page").unwrap().parse::<usize>().unwrap()),
+            _ => unreachable!(),
+        };
+        let miners: Vec<String> = wallet.values_of("miner").map(|miners| miners.map(|miner| miner.to_string()).collect()).unwrap_or_default();
+        let status = cli::run(wallet.value_of("socket").unwrap(), &miners, command, wallet.is_present("json"));
+        std::process::exit(status);
+    }
 
     let create = matches.value_of("create").unwrap_or("");
     println!("{}",create);
@@ -54,7 +321,32 @@ fn main() {
     let wallet = matches.value_of("wallet").unwrap_or("");
     println!("{}",wallet);
 
-    let args: Vec<String> = env::args().collect();
+    let restore = matches.is_present("restore");
+    let trust_loopback = matches.is_present("trust-loopback");
+    let payout = matches.value_of("payout").map(|payout| payout.to_string());
+    let miners: Vec<String> = matches.values_of("miners").map(|miners| miners.map(|miner| miner.to_string()).collect()).unwrap_or_default();
+    let watch: Vec<String> = matches.values_of("watch").map(|watch| watch.map(|entry| entry.to_string()).collect()).unwrap_or_default();
+    let chain_config = match matches.value_of("chain") {
+        Some(path) => match config::ChainConfig::load(&PathBuf::from(path)) {
+            Ok(chain_config) => chain_config,
+            Err(e) => {
+                println!("miner: invalid chain parameters in {}: {}", path, e);
+                return;
+            }
+        },
+        None => config::ChainConfig::default(),
+    };
+
+    // Les options --payout, --chain, --miners, --watch, --restore et --trust-loopback sont retirées avant de vérifier les autres arguments
+    let mut args: Vec<String> = Vec::new();
+    let mut raw_args = env::args();
+    while let Some(arg) = raw_args.next() {
+        if arg == "--payout" || arg == "--chain" || arg == "--miners" || arg == "--watch" {
+            raw_args.next();
+        } else if arg != "--restore" && arg != "--trust-loopback" && !["--payout=", "--chain=", "--miners=", "--watch="].iter().any(|option| arg.starts_with(option)) {
+            args.push(arg);
+        }
+    }
     
     // On vérifie les arguments
     if args.len() == 1 {
@@ -78,6 +370,10 @@ fn main() {
         role = "joiner";
     } else if &args[1] == "-w" || &args[1] == "--wallet" {
         role = "wallet";
+    } else if &args[1] == "-s" || &args[1] == "--shutdown" {
+        role = "shutdown";
+    } else if &args[1] == "-m" || &args[1] == "--mine" {
+        role = "mine";
     } else {
         println!("miner: operation not recognized");
         return ();
@@ -85,20 +381,55 @@ fn main() {
 
     if role == "joiner" {
         address = &args[3];
-        miner::create_miner('j',socket.to_string(), address.to_string());
     }
 
     if role == "creator" {
-        miner::create_miner('c',socket.to_string(), String::new());
+        miner::create_miner('c',socket.to_string(), String::new(), payout, chain_config, trust_loopback);
     } else if role == "joiner" {
-        miner::create_miner('j',socket.to_string(), address.to_string());
+        miner::create_miner('j',socket.to_string(), address.to_string(), payout, chain_config, trust_loopback);
+    }
+
+    if role == "shutdown" {
+        miner::request_shutdown(socket.to_string());
+    }
+
+    if role == "mine" {
+        miner::request_mining(socket.to_string());
     }
 
     if role == "wallet" {
         address = &args[3];
         println!("wallet : {} ; {}",socket.to_string(), address.to_string());
-        wallet::create_wallet(socket.to_string(), address.to_string());
+        let mut wallet_miners = vec![address.to_string()];
+        wallet_miners.extend(miners);
+        wallet::create_wallet(socket.to_string(), wallet_miners, restore, watch);
     }
 
     return ();
 }
+
+/// Checks that a command line value is a positive integer
+fn validate_number(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{} is not a positive integer", value)),
+    }
+}
+
+/// Checks that an address is valid, see `address::check`
+fn validate_payout(value: String) -> Result<(), String> {
+    address::check(&value)
+}
+
+/// Checks that a watched entry is a public key or an address, see `watch::check_entry`
+fn validate_watch(value: String) -> Result<(), String> {
+    watch::check_entry(&value)
+}
+
+/// Checks that a command line value is a probability, between 0 and 1
+fn validate_probability(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(()),
+        _ => Err(format!("{} is not a probability between 0 and 1", value)),
+    }
+}
@@ -0,0 +1,718 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::address;
+use crate::block::{self, Block};
+use crate::chain::{Chain, ChainEvent};
+use crate::ledger::AccountSummary;
+use crate::transaction::{OutPoint, Transaction};
+use crate::utxo::UtxoSet;
+
+/// Most transactions kept waiting at once
+pub const MAX_TRANSACTIONS: usize = 5000;
+/// Most bytes of transactions kept waiting at once
+pub const MAX_BYTES: usize = 1 << 20;
+/// Biggest transaction accepted
+pub const MAX_TRANSACTION_SIZE: usize = 4096;
+/// Time in sec after which a transaction which was never mined is dropped
+pub const EXPIRY: u64 = 24 * 60 * 60;
+/// Number of recent blocks looked at to estimate fees
+const FEE_ESTIMATE_BLOCKS: usize = 10;
+
+/// Returns the fee rate of a transaction, in fee units per 1000 bytes
+pub fn fee_rate(fee: u64, size: usize) -> u64 {
+    fee.saturating_mul(1000) / size.max(1) as u64
+}
+
+/// Returns the fee a transaction of `size` bytes must pay to reach `rate`
+pub fn fee_for_rate(rate: u64, size: usize) -> u64 {
+    rate.saturating_mul(size as u64).div_ceil(1000)
+}
+
+/// Fee rates (per 1000 bytes) advised to wallets
+/// *`fast` - enough to beat the transactions waiting in the mempool and get into the next block
+/// *`normal` - the median of the lowest fee rates accepted by the recent blocks
+/// *`slow` - the lowest fee rate accepted by the recent blocks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeEstimate {
+    pub fast: u64,
+    pub normal: u64,
+    pub slow: u64,
+}
+
+impl fmt::Display for FeeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.fast, self.normal, self.slow)
+    }
+}
+
+impl FromStr for FeeEstimate {
+    type Err = String;
+
+    /// Reads the `fast normal slow` sent by a Miner
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rates: Vec<u64> = s.split_whitespace().map(|r| r.parse::<u64>()).collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+        match rates.as_slice() {
+            [fast, normal, slow] => Ok(FeeEstimate { fast: *fast, normal: *normal, slow: *slow }),
+            _ => Err(format!("invalid fee estimate {}", s)),
+        }
+    }
+}
+
+/// Why a transaction was not added to the mempool
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    /// The transaction is already waiting in the mempool
+    Duplicate,
+    /// The transaction is already in a block of the main chain
+    Confirmed,
+    /// The transaction breaks the rules, the sender should not have sent it
+    Invalid(String),
+    /// The transaction can not be mined with the current state of the chain (nonce already used, balance too low...)
+    /// Not a fault of the sender, which may have seen another chain
+    Rejected(String),
+    /// The mempool is full of transactions with a higher priority
+    Full,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Duplicate => write!(f, "transaction already in the mempool"),
+            MempoolError::Confirmed => write!(f, "transaction already in the chain"),
+            MempoolError::Invalid(reason) => write!(f, "invalid transaction: {}", reason),
+            MempoolError::Rejected(reason) => write!(f, "transaction rejected: {}", reason),
+            MempoolError::Full => write!(f, "mempool full"),
+        }
+    }
+}
+
+/// A transaction waiting to be mined
+/// *`transaction` - the transaction itself, encoded
+/// *`fee` - the fee it pays to the miner
+/// *`sender` - the address spending coins
+/// *`nonce` - the nonce of the transaction in the transactions of `sender`, None for the spends of a UTXO chain
+/// *`inputs` - the outputs the transaction spends, empty for the transfers of an account chain
+/// *`cost` - what the transaction takes from `sender`: amount and fee, or the total of its inputs
+/// *`added` - the UNIX time (s) at which it entered the mempool
+/// *`sequence` - the order of arrival, older transactions have a lower sequence
+pub struct MempoolEntry {
+    pub transaction: String,
+    pub fee: u64,
+    pub sender: String,
+    pub nonce: Option<u64>,
+    pub inputs: Vec<OutPoint>,
+    pub cost: u64,
+    pub added: u64,
+    pub sequence: u64,
+}
+
+impl MempoolEntry {
+    /// Returns the fee rate of the transaction, see `fee_rate`
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.transaction.len())
+    }
+
+    /// Returns the priority of the transaction, the lowest priority is evicted first and mined last
+    /// Transactions paying more per byte go first, then those which waited longer
+    fn priority(&self) -> (u64, Reverse<u64>) {
+        (self.fee_rate(), Reverse(self.sequence))
+    }
+}
+
+/// The transactions waiting to be mined, indexed by transaction ID
+/// *`entries` - the transactions, by ID
+/// *`bytes` - the total size of the transactions
+/// *`next_sequence` - the sequence given to the next transaction
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    bytes: usize,
+    next_sequence: u64,
+}
+
+/// Returns the current UNIX time in seconds
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Mempool {
+
+    /// CONSTRUCTOR
+    /// Returns an empty mempool
+    pub fn new() -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            bytes: 0,
+            next_sequence: 0,
+        }
+    }
+
+    /// Returns the number of waiting transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no transaction is waiting
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Checks a transaction (encoding, signature...) against the chain and adds it
+    /// On an account chain, its nonce must follow the nonce of its sender in the chain and those of its waiting transactions, without
+    /// gap, and its sender must be able to pay it on top of its other waiting transactions. On a UTXO chain, its inputs must be unspent mature outputs of the main
+    /// chain that no waiting transaction spends already
+    /// When the mempool is over its limits, the transactions with the lowest priority are evicted, possibly this one
+    /// Returns the ID of the transaction
+    pub fn insert(&mut self, transaction: String, chain: &Chain) -> Result<String, MempoolError> {
+        if transaction.is_empty() {
+            return Err(MempoolError::Invalid("empty".to_string()));
+        }
+        if transaction.len() > MAX_TRANSACTION_SIZE {
+            return Err(MempoolError::Invalid(format!("{} bytes, more than {}", transaction.len(), MAX_TRANSACTION_SIZE)));
+        }
+        let parsed = Transaction::from_str(&transaction).map_err(MempoolError::Invalid)?;
+        if parsed.to_string() != transaction {
+            return Err(MempoolError::Invalid("not in canonical form".to_string()));
+        }
+        if parsed.is_coinbase() {
+            return Err(MempoolError::Invalid("coinbase outside of a block".to_string()));
+        }
+        if !parsed.has_valid_signature() {
+            return Err(MempoolError::Invalid("wrong signature".to_string()));
+        }
+        let fee = parsed.fee();
+        let id = block::transaction_id(&transaction);
+        if self.entries.contains_key(&id) {
+            return Err(MempoolError::Duplicate);
+        }
+        if chain.transaction_height(&id).is_some() {
+            return Err(MempoolError::Confirmed);
+        }
+        if !chain.config.ledger.accepts(&parsed) {
+            return Err(MempoolError::Invalid(format!("not a transaction of a {} chain", chain.config.ledger)));
+        }
+        let (sender, nonce, inputs, cost) = match &parsed {
+            Transaction::Transfer { from, amount, fee, nonce, .. } => {
+                let sender = address::from_public_key(from);
+                let cost = amount.checked_add(*fee).ok_or_else(|| MempoolError::Invalid("amount and fee overflow".to_string()))?;
+                if *nonce <= chain.ledger.account(&sender).nonce {
+                    return Err(MempoolError::Rejected(format!("nonce {} already used", nonce)));
+                }
+                if self.entries.values().any(|entry| entry.sender == sender && entry.nonce == Some(*nonce)) {
+                    return Err(MempoolError::Rejected(format!("nonce {} used by a waiting transaction", nonce)));
+                }
+                let expected = self.next_nonce(chain, &sender);
+                if *nonce != expected {
+                    return Err(MempoolError::Rejected(format!("nonce {} leaves a gap, {} expected", nonce, expected)));
+                }
+                let available = chain.spendable(&sender).saturating_sub(self.pending_cost(&sender));
+                if cost > available {
+                    return Err(MempoolError::Rejected(format!("spends {}, only {} available", cost, available)));
+                }
+                (sender, Some(*nonce), Vec::new(), cost)
+            }
+            Transaction::Spend { from, inputs, .. } => {
+                let cost = UtxoSet::check_spend(&parsed, chain.height() as u32, &chain.config, |outpoint| chain.unspent(outpoint))
+                    .map_err(|e| MempoolError::Rejected(e.to_string()))?;
+                if let Some(input) = inputs.iter().find(|input| self.is_spent(input)) {
+                    return Err(MempoolError::Rejected(format!("output {} already spent by a waiting transaction", input)));
+                }
+                (address::from_public_key(from), None, inputs.clone(), cost)
+            }
+            Transaction::Coinbase { .. } => unreachable!(),
+        };
+
+        self.bytes += transaction.len();
+        let entry = MempoolEntry { transaction, fee, sender, nonce, inputs, cost, added: now(), sequence: self.next_sequence };
+        self.entries.insert(id.clone(), entry);
+        self.next_sequence += 1;
+        self.evict();
+        match self.entries.contains_key(&id) {
+            true => Ok(id),
+            false => Err(MempoolError::Full),
+        }
+    }
+
+    /// Returns what the waiting transactions of `address` spend
+    pub fn pending_cost(&self, address: &str) -> u64 {
+        self.entries.values().filter(|entry| entry.sender == address).fold(0, |total, entry| total.saturating_add(entry.cost))
+    }
+
+    /// Returns true if a waiting transaction spends `outpoint`
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.entries.values().any(|entry| entry.inputs.contains(outpoint))
+    }
+
+    /// Returns the unspent outputs of the main chain which `address` can spend in the next block: the mature ones that no waiting
+    /// transaction spends already. Always empty on an account chain
+    pub fn unspent(&self, chain: &Chain, address: &str) -> Vec<(OutPoint, u64)> {
+        let height = chain.height() as u32;
+        chain.unspent_of(address).into_iter()
+            .filter(|(outpoint, utxo)| (!utxo.coinbase || chain.config.is_mature(utxo.height, height)) && !self.is_spent(outpoint))
+            .map(|(outpoint, utxo)| (outpoint, utxo.output.amount))
+            .collect()
+    }
+
+    /// Returns the nonce the next transaction of `address` must use, after its waiting transactions
+    pub fn next_nonce(&self, chain: &Chain, address: &str) -> u64 {
+        let waiting = self.entries.values().filter(|entry| entry.sender == address).filter_map(|entry| entry.nonce).max();
+        waiting.unwrap_or(0).max(chain.ledger.account(address).nonce).saturating_add(1)
+    }
+
+    /// Returns what a wallet needs to know about `address`: its balance, what it can spend and its next nonce
+    pub fn summary(&self, chain: &Chain, address: &str) -> AccountSummary {
+        AccountSummary {
+            balance: chain.ledger.account(address).balance,
+            spendable: chain.spendable(address).saturating_sub(self.pending_cost(address)),
+            next_nonce: self.next_nonce(chain, address),
+        }
+    }
+
+    /// Returns a waiting transaction
+    pub fn get(&self, transaction_id: &str) -> Option<&MempoolEntry> {
+        self.entries.get(transaction_id)
+    }
+
+    /// Removes a transaction, returns it if it was waiting
+    pub fn remove(&mut self, transaction_id: &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(transaction_id)?;
+        self.bytes -= entry.transaction.len();
+        Some(entry)
+    }
+
+    /// Removes a transaction with the waiting transactions of its sender using a higher nonce, which can not be mined without it
+    /// Returns the IDs of the removed transactions
+    pub fn remove_with_descendants(&mut self, transaction_id: &str) -> Vec<String> {
+        let (sender, nonce) = match self.entries.get(transaction_id) {
+            Some(entry) => (entry.sender.clone(), entry.nonce),
+            None => return Vec::new(),
+        };
+        let mut removed: Vec<String> = match nonce {
+            Some(nonce) => self.entries.iter()
+                .filter(|(_, entry)| entry.sender == sender && entry.nonce.is_some_and(|n| n > nonce))
+                .map(|(id, _)| id.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+        removed.push(transaction_id.to_string());
+        for id in &removed {
+            self.remove(id);
+        }
+        removed
+    }
+
+    /// Evicts the transactions with the lowest priority, with their descendants, until the mempool is within its limits
+    fn evict(&mut self) {
+        while self.entries.len() > MAX_TRANSACTIONS || self.bytes > MAX_BYTES {
+            let lowest = match self.entries.iter().min_by_key(|(_, entry)| entry.priority()) {
+                Some((id, _)) => id.clone(),
+                None => return,
+            };
+            for id in self.remove_with_descendants(&lowest) {
+                println!("Mempool full, evicting {}", &id);
+            }
+        }
+    }
+
+    /// Drops the transactions which waited more than `EXPIRY` seconds, with their descendants
+    pub fn expire(&mut self) {
+        let limit = now().saturating_sub(EXPIRY);
+        let expired: Vec<String> = self.entries.iter()
+            .filter(|(_, entry)| entry.added < limit)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            for id in self.remove_with_descendants(&id) {
+                println!("Transaction {} expired", &id);
+            }
+        }
+    }
+
+    /// Returns the waiting transactions, highest priority first
+    fn by_priority(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| Reverse(entry.priority()));
+        entries
+    }
+
+    /// Picks the transactions of the next block: the highest fee rates first, as long as they fit in `max_bytes`
+    /// A transaction too big for the space left is skipped, smaller ones after it may still fit.
+    /// The transfers of a sender go in the order of their nonces, without gap, as long as the sender can pay them.
+    /// Spends were checked against the main chain when they arrived, and no two waiting spends share an output
+    /// Returns the transactions and the total of their fees
+    pub fn block_template(&self, chain: &Chain, max_bytes: usize) -> (Vec<String>, u64) {
+        let mut transactions = Vec::new();
+        let mut fees: u64 = 0;
+        let mut space = max_bytes;
+        let mut senders: HashMap<&str, (u64, u64)> = HashMap::new();
+        let mut waiting = self.by_priority();
+        // A transaction skipped for its nonce may become minable once the previous one of its sender is picked
+        loop {
+            let mut left = Vec::new();
+            for entry in waiting.iter().copied() {
+                let nonce = match entry.nonce {
+                    Some(nonce) => nonce,
+                    None => {
+                        if entry.transaction.len() < space {
+                            space -= entry.transaction.len() + 1;
+                            fees = fees.saturating_add(entry.fee);
+                            transactions.push(entry.transaction.clone());
+                        }
+                        continue;
+                    }
+                };
+                let (next_nonce, available) = *senders.entry(entry.sender.as_str())
+                    .or_insert_with(|| (chain.ledger.account(&entry.sender).nonce.saturating_add(1), chain.spendable(&entry.sender)));
+                // Each transaction takes one more byte for the separator
+                if entry.transaction.len() >= space || entry.cost > available {
+                    continue;
+                }
+                if nonce != next_nonce {
+                    left.push(entry);
+                    continue;
+                }
+                senders.insert(entry.sender.as_str(), (next_nonce.saturating_add(1), available - entry.cost));
+                space -= entry.transaction.len() + 1;
+                fees = fees.saturating_add(entry.fee);
+                transactions.push(entry.transaction.clone());
+            }
+            if left.len() == waiting.len() {
+                break;
+            }
+            waiting = left;
+        }
+        (transactions, fees)
+    }
+
+    /// Estimates the fee rates a transaction should pay, from the recent blocks of `chain` and the mempool pressure
+    /// *`block_size` - the space available for transactions in a block
+    pub fn estimate_fees(&self, chain: &Chain, block_size: usize) -> FeeEstimate {
+        let mut minimums: Vec<u64> = chain.blocks.iter().rev().take(FEE_ESTIMATE_BLOCKS)
+            .filter_map(|block| block.transactions().iter()
+                .filter_map(|t| Transaction::from_str(t).ok().filter(|t| !t.is_coinbase()).map(|p| fee_rate(p.fee(), t.len())))
+                .min())
+            .collect();
+        minimums.sort_unstable();
+        let slow = minimums.first().copied().unwrap_or(0);
+        let normal = minimums.get(minimums.len() / 2).copied().unwrap_or(slow);
+
+        // When the mempool holds more than a block, a transaction must pay more than the first one left out
+        let mut used = 0;
+        let mut cutoff = None;
+        for entry in self.by_priority() {
+            used += entry.transaction.len() + 1;
+            if used > block_size {
+                cutoff = Some(entry.fee_rate() + 1);
+                break;
+            }
+        }
+        FeeEstimate { fast: cutoff.unwrap_or(normal).max(normal), normal, slow }
+    }
+
+    /// Returns the waiting transactions in order of arrival
+    pub fn transactions(&self) -> Vec<String> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries.into_iter().map(|entry| entry.transaction.clone()).collect()
+    }
+
+    /// Returns the waiting transactions sending to or from one of `addresses`, in order of arrival
+    pub fn transactions_of(&self, addresses: &[&str]) -> Vec<String> {
+        self.transactions().into_iter()
+            .filter(|transaction| match Transaction::from_str(transaction) {
+                Ok(parsed) => parsed.addresses().iter().any(|address| addresses.contains(&address.as_str())),
+                Err(_) => false,
+            })
+            .collect()
+    }
+
+    /// Follows the changes of the main chain, in order: the transactions of disconnected blocks come back,
+    /// those of connected blocks leave. `chain` must already include the changes
+    /// The transactions whose nonce or inputs the new main chain used are dropped
+    pub fn apply(&mut self, events: &[ChainEvent], chain: &Chain) {
+        for event in events {
+            match event {
+                ChainEvent::Connected(block) => self.block_connected(block),
+                ChainEvent::Disconnected(block) => self.block_disconnected(block, chain),
+            }
+        }
+        let replayed: Vec<String> = self.entries.iter()
+            .filter(|(_, entry)| match entry.nonce {
+                Some(nonce) => nonce <= chain.ledger.account(&entry.sender).nonce,
+                None => entry.inputs.iter().any(|input| chain.unspent(input).is_none()),
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in replayed {
+            println!("Transaction {} dropped, its nonce or inputs are used", &id);
+            self.remove(&id);
+        }
+    }
+
+    /// Removes the transactions included in a block which joined the main chain
+    pub fn block_connected(&mut self, block: &Block) {
+        for transaction in block.transactions() {
+            self.remove(&block::transaction_id(&transaction));
+        }
+    }
+
+    /// Puts back the transactions of a block which left the main chain, unless the new main chain includes them
+    /// The coinbase, always first, is dropped: it only existed for that block. The waiting transfers of the same senders, which
+    /// come from the blocks disconnected before or arrived since, are put back after them so their nonces stay without gap
+    pub fn block_disconnected(&mut self, block: &Block, chain: &Chain) {
+        let transactions: Vec<String> = block.transactions().into_iter().skip(1).collect();
+        let senders: Vec<String> = transactions.iter().filter_map(|transaction| match Transaction::from_str(transaction) {
+            Ok(Transaction::Transfer { from, .. }) => Some(address::from_public_key(&from)),
+            _ => None,
+        }).collect();
+        let mut followers: Vec<(u64, String)> = self.entries.iter()
+            .filter(|(_, entry)| senders.contains(&entry.sender))
+            .filter_map(|(id, entry)| entry.nonce.map(|nonce| (nonce, id.clone())))
+            .collect();
+        followers.sort();
+        let followers: Vec<String> = followers.into_iter().filter_map(|(_, id)| self.remove(&id)).map(|entry| entry.transaction).collect();
+        for transaction in transactions.into_iter().chain(followers) {
+            match self.insert(transaction, chain) {
+                Ok(id) => println!("Transaction {} back in the mempool", &id),
+                Err(MempoolError::Confirmed) | Err(MempoolError::Duplicate) => {}
+                Err(e) => println!("Transaction of block {} dropped: {}", block.index, e),
+            }
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChainConfig;
+    use crate::keys::WalletKey;
+
+    /// Returns an account chain without proof of work nor maturity, whose first blocks pay the subsidy to each of `keys`
+    fn funded_chain(keys: &[&WalletKey]) -> Chain {
+        let config = ChainConfig { difficulty: 0, coinbase_maturity: 0, ..ChainConfig::default() };
+        let mut chain = Chain::new(config);
+        for key in keys {
+            let block = next_block(&chain, chain.tip(), key, &[]);
+            chain.add_block(block).unwrap();
+        }
+        chain
+    }
+
+    /// Returns the block following `parent` in `chain`, paying the subsidy and the fees to `miner`, then including `transfers`
+    fn next_block(chain: &Chain, parent: Option<&Block>, miner: &WalletKey, transfers: &[&str]) -> Block {
+        let index = parent.map_or(0, |parent| parent.index + 1);
+        let fees: u64 = transfers.iter().map(|t| Transaction::from_str(t).unwrap().fee()).sum();
+        let coinbase = Transaction::Coinbase { height: index, to: miner.address(), amount: chain.config.block_subsidy(index) + fees };
+        let payload = std::iter::once(coinbase.to_string()).chain(transfers.iter().map(|t| t.to_string())).collect::<Vec<String>>()
+            .join(&block::TRANSACTION_SEPARATOR.to_string());
+        let prev_hash = parent.map_or_else(block::genesis_prev_hash, |parent| parent.hash.clone());
+        let mut block = Block::new(index, payload, 0, 0, prev_hash);
+        block.hash = block.header().compute_hash().into_bytes();
+        block
+    }
+
+    /// Adds a waiting transaction without checking it, `size` bytes long
+    fn filler(mempool: &mut Mempool, id: usize, fee: u64, size: usize) {
+        let transaction = format!("{}{}", "0".repeat(size - id.to_string().len()), id);
+        mempool.bytes += transaction.len();
+        let entry = MempoolEntry {
+            transaction,
+            fee,
+            sender: format!("filler {}", id),
+            nonce: None,
+            inputs: Vec::new(),
+            cost: 0,
+            added: now(),
+            sequence: mempool.next_sequence,
+        };
+        mempool.entries.insert(format!("filler {}", id), entry);
+        mempool.next_sequence += 1;
+    }
+
+    #[test]
+    fn template_follows_fee_rates_and_nonces() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let chain = funded_chain(&[&alice, &bob]);
+        let mut mempool = Mempool::new();
+        let alice_first = Transaction::signed_transfer(&alice, &bob.address(), 1000, 100, 1, "").to_string();
+        let alice_second = Transaction::signed_transfer(&alice, &bob.address(), 1000, 300, 2, "").to_string();
+        let bob_first = Transaction::signed_transfer(&bob, &alice.address(), 1000, 200, 1, "").to_string();
+        for transaction in [&alice_first, &bob_first, &alice_second] {
+            mempool.insert(transaction.clone(), &chain).unwrap();
+        }
+
+        assert_eq!(mempool.transactions(), vec![alice_first.clone(), bob_first.clone(), alice_second.clone()]);
+        let (transactions, fees) = mempool.block_template(&chain, MAX_BYTES);
+        assert_eq!(transactions, vec![bob_first, alice_first, alice_second]);
+        assert_eq!(fees, 600);
+    }
+
+    #[test]
+    fn template_skips_what_does_not_fit() {
+        let alice = WalletKey::generate().unwrap();
+        let chain = funded_chain(&[&alice]);
+        let mut mempool = Mempool::new();
+        filler(&mut mempool, 0, 1000, 500);
+        filler(&mut mempool, 1, 1, 100);
+
+        let (transactions, fees) = mempool.block_template(&chain, 200);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].len(), 100);
+        assert_eq!(fees, 1);
+    }
+
+    #[test]
+    fn fee_estimates_follow_recent_blocks_then_the_mempool() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut chain = funded_chain(&[&alice, &bob]);
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.estimate_fees(&chain, 1000), FeeEstimate { fast: 0, normal: 0, slow: 0 });
+
+        // Each block accepts a lowest fee rate, the transfers of a block paying more do not count
+        let mut rates = Vec::new();
+        for (nonce, fees) in [(1, [300, 900]), (3, [100, 900]), (5, [200, 900])] {
+            let transfers: Vec<String> = fees.iter().enumerate()
+                .map(|(i, fee)| Transaction::signed_transfer(&alice, &bob.address(), 10, *fee, nonce + i as u64, "").to_string())
+                .collect();
+            rates.push(fee_rate(fees[0], transfers[0].len()));
+            let block = next_block(&chain, chain.tip(), &bob, &transfers.iter().map(|t| t.as_str()).collect::<Vec<&str>>());
+            chain.add_block(block).unwrap();
+        }
+        let normal = FeeEstimate { fast: rates[2], normal: rates[2], slow: rates[1] };
+        assert_eq!(mempool.estimate_fees(&chain, 1000), normal);
+
+        // A mempool fitting in the next block does not raise the rates
+        filler(&mut mempool, 0, 1000, 400);
+        filler(&mut mempool, 1, 1000, 400);
+        assert_eq!(mempool.estimate_fees(&chain, 1000), normal);
+
+        // Once it holds more, the fast rate beats the first transaction left out, never going below the normal one
+        filler(&mut mempool, 2, 10000, 400);
+        assert_eq!(mempool.estimate_fees(&chain, 1000).fast, fee_rate(1000, 400) + 1);
+        assert_eq!(mempool.estimate_fees(&chain, 1000).normal, normal.normal);
+        filler(&mut mempool, 3, 1, 400);
+        assert_eq!(mempool.estimate_fees(&chain, 1000).fast, fee_rate(1000, 400) + 1);
+        assert_eq!(mempool.estimate_fees(&chain, 500), FeeEstimate { fast: fee_rate(1000, 400) + 1, ..normal });
+        assert_eq!(mempool.estimate_fees(&chain, 1), FeeEstimate { fast: fee_rate(10000, 400) + 1, ..normal });
+    }
+
+    #[test]
+    fn nonces_and_balances_are_checked() {
+        let alice = WalletKey::generate().unwrap();
+        let chain = funded_chain(&[&alice]);
+        let mut mempool = Mempool::new();
+        let balance = chain.spendable(&alice.address());
+        let bad_address = Transaction::signed_transfer(&alice, "x", 1, 1, 1, "").to_string();
+        assert!(matches!(mempool.insert(bad_address, &chain), Err(MempoolError::Invalid(_))));
+        let first = Transaction::signed_transfer(&alice, &alice.address(), balance - 10, 5, 1, "").to_string();
+        mempool.insert(first.clone(), &chain).unwrap();
+        assert_eq!(mempool.insert(first, &chain), Err(MempoolError::Duplicate));
+        let same_nonce = Transaction::signed_transfer(&alice, &alice.address(), 1, 1, 1, "").to_string();
+        assert!(matches!(mempool.insert(same_nonce, &chain), Err(MempoolError::Rejected(_))));
+        let too_much = Transaction::signed_transfer(&alice, &alice.address(), 5, 1, 2, "").to_string();
+        assert!(matches!(mempool.insert(too_much, &chain), Err(MempoolError::Rejected(_))));
+        assert_eq!(mempool.next_nonce(&chain, &alice.address()), 2);
+    }
+
+    #[test]
+    fn nonces_follow_each_other_without_gap() {
+        let alice = WalletKey::generate().unwrap();
+        let chain = funded_chain(&[&alice]);
+        let mut mempool = Mempool::new();
+        let transfer = |nonce| Transaction::signed_transfer(&alice, &alice.address(), 1, 1, nonce, "").to_string();
+        assert_eq!(mempool.insert(transfer(2), &chain), Err(MempoolError::Rejected("nonce 2 leaves a gap, 1 expected".to_string())));
+        mempool.insert(transfer(1), &chain).unwrap();
+        assert!(matches!(mempool.insert(transfer(3), &chain), Err(MempoolError::Rejected(_))));
+        mempool.insert(transfer(2), &chain).unwrap();
+        mempool.insert(transfer(3), &chain).unwrap();
+        assert_eq!(mempool.next_nonce(&chain, &alice.address()), 4);
+    }
+
+    #[test]
+    fn disconnected_transfers_go_back_before_the_waiting_ones() {
+        let alice = WalletKey::generate().unwrap();
+        let mut chain = funded_chain(&[&alice]);
+        // Without fees, so the coinbases pay the subsidy alone
+        let transfer = |nonce| Transaction::signed_transfer(&alice, &alice.address(), 1, 0, nonce, "").to_string();
+        let first = chain.tip().cloned().unwrap();
+        let mined = next_block(&chain, Some(&first), &alice, &[&transfer(1), &transfer(2)]);
+        let mut mempool = Mempool::new();
+        let events = chain.add_block(mined).unwrap();
+        mempool.apply(&events, &chain);
+        mempool.insert(transfer(3), &chain).unwrap();
+
+        // A longer branch without the transfers of alice
+        let other = next_block(&chain, Some(&first), &alice, &[]);
+        chain.add_block(other.clone()).unwrap();
+        let events = chain.add_block(next_block(&chain, Some(&other), &alice, &[])).unwrap();
+        mempool.apply(&events, &chain);
+        assert_eq!(mempool.len(), 3);
+        let (transactions, _) = mempool.block_template(&chain, MAX_BYTES);
+        assert_eq!(transactions, vec![transfer(1), transfer(2), transfer(3)]);
+    }
+
+    #[test]
+    fn evicted_transfers_take_their_descendants_along() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let chain = funded_chain(&[&alice, &bob]);
+        let mut mempool = Mempool::new();
+        for id in 0..MAX_TRANSACTIONS - 3 {
+            filler(&mut mempool, id, 100, 100);
+        }
+        let cheap = mempool.insert(Transaction::signed_transfer(&alice, &bob.address(), 1, 0, 1, "").to_string(), &chain).unwrap();
+        let generous = mempool.insert(Transaction::signed_transfer(&alice, &bob.address(), 1, 10_000, 2, "").to_string(), &chain).unwrap();
+        let other = mempool.insert(Transaction::signed_transfer(&bob, &alice.address(), 1, 10_000, 1, "").to_string(), &chain).unwrap();
+
+        // The transfer of nonce 2 pays well but can not be mined once the one of nonce 1 is gone
+        filler(&mut mempool, MAX_TRANSACTIONS, 100, 100);
+        mempool.evict();
+        assert!(mempool.get(&cheap).is_none());
+        assert!(mempool.get(&generous).is_none());
+        assert!(mempool.get(&other).is_some());
+        assert_eq!(mempool.len(), MAX_TRANSACTIONS - 1);
+        assert_eq!(mempool.next_nonce(&chain, &alice.address()), 1);
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_lowest_priority() {
+        let alice = WalletKey::generate().unwrap();
+        let chain = funded_chain(&[&alice]);
+        let mut mempool = Mempool::new();
+        for id in 0..MAX_TRANSACTIONS {
+            filler(&mut mempool, id, 100, 100);
+        }
+
+        // Paying less per byte than everything waiting, the transaction is evicted as soon as it arrives
+        let cheap = Transaction::signed_transfer(&alice, &alice.address(), 1, 0, 1, "").to_string();
+        assert_eq!(mempool.insert(cheap, &chain), Err(MempoolError::Full));
+        assert_eq!(mempool.len(), MAX_TRANSACTIONS);
+
+        // Among equal fee rates, the most recent filler goes first
+        let generous = Transaction::signed_transfer(&alice, &alice.address(), 1, 10_000, 1, "").to_string();
+        let id = mempool.insert(generous, &chain).unwrap();
+        assert_eq!(mempool.len(), MAX_TRANSACTIONS);
+        assert!(mempool.get(&id).is_some());
+        assert!(mempool.get("filler 0").is_some());
+        assert!(mempool.get(&format!("filler {}", MAX_TRANSACTIONS - 1)).is_none());
+    }
+
+    #[test]
+    fn bytes_are_limited_too() {
+        let mut mempool = Mempool::new();
+        filler(&mut mempool, 0, 1000, MAX_BYTES / 2);
+        filler(&mut mempool, 1, 100_000, MAX_BYTES / 2);
+        filler(&mut mempool, 2, 100, 10);
+        mempool.evict();
+        assert!(mempool.get("filler 0").is_none());
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.bytes, MAX_BYTES / 2 + 10);
+    }
+}
@@ -1,72 +1,36 @@
-use std::net::{TcpStream, TcpListener, Shutdown};
 use std::fmt::{self, Debug, Formatter};
-use std::io::{Read, Write};
+use std::cmp::Reverse;
 use crossbeam_utils::thread;
 // use std::thread;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use std::time::{SystemTime, UNIX_EPOCH};
-use sha2::{Sha256, Sha512, Digest};
 //use log::{info, warn};
 use std::convert::TryFrom;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io;
 use std::str::FromStr;
+use std::thread as std_thread;
+use crate::peer::{self, PeerStatus};
+use crate::storage;
+use crate::protocol::{self, Flag, encode_message, decode_message};
+use crate::secure::{self, NodeIdentity, SecureStream};
+use crate::ban::{self, BanList};
+use crate::ratelimit::{ConnectionSlots, RateLimiter};
+use crate::transport::{Listener, TcpTransport, Transport};
+use crate::address;
+use crate::block;
+use crate::chain::{Chain, ChainError, ChainEvent, MAX_BLOCK_SIZE};
+use crate::config::ChainConfig;
+use crate::index::{HistoryPage, TransactionLocation, TransactionStatus};
+use crate::mempool::{Mempool, MempoolError};
+use crate::notify::{Notifier, Subscriptions, TransactionEvent};
+use crate::spv::{self, InclusionProof};
+use crate::transaction::Transaction;
+use crate::utxo;
+use std::net::IpAddr;
 
-#[path="./block.rs"] mod block;
-
-#[path="./wallet.rs"]
-mod wallet;
-
-/// Used for signaling what kind of requests we are sending when networking
-/// 
-#[derive(Copy, Clone)]
-pub enum Flag {
-    /// Ok -> Network
-    Ok,
-    Connect, // flag to signal that a Miner joined the newtwork
-    Disconnect, // flag to signal that a Miner disconnected from the network
-    RequireID,
-    GiveID,
-    BroadcastConnect,
-    BroadcastDisconnect,
-    Check,
-    Ack,
-    Block,
-    Transaction,
-    MineTransaction,
-    OkMineTransaction,
-    RequireWalletID,
-    RequireBlockchain,
-    SendBlockchain,
-}
-
-
-impl Flag {
-    /// Simple function to convert a integer to a Flag
-    /// Used when receiving/sending a message as primary types are easier to pass than objects
-    /// 
-    fn from_u8(value: u8) -> Flag {
-        match value {
-            0 => Flag::Ok,
-            1 => Flag::Connect,
-            2 => Flag::Disconnect,
-            3 => Flag::RequireID,
-            4 => Flag::GiveID,
-            5 => Flag::BroadcastConnect,
-            6 => Flag::BroadcastDisconnect,
-            7 => Flag::Check,
-            8 => Flag::Ack,
-            9 => Flag::Block,
-            10 => Flag::Transaction,
-            11 => Flag::MineTransaction,
-            12 => Flag::OkMineTransaction,
-            13 => Flag::RequireWalletID,
-            14 => Flag::RequireBlockchain,
-            15 => Flag::SendBlockchain,
-            _ => panic!("Unknown value: {}", value),
-        }
-    }
-}
 
 /// Pimped serialized hashset 
 /// 
@@ -106,185 +70,292 @@ pub fn hashset_to_string(set: &HashSet<(u32, String)>) -> String {
 /// hashset.insert((1,"localhost".to_string()));
 /// hashset.insert((2,"127.0.0.1".to_string()));
 /// 
-/// assert_eq!(hashset_string,hashset);
+/// assert_eq!(hashset_from_string(hashset_string), Ok(hashset));
 /// ```
-pub fn hashset_from_string(hashset :String) -> HashSet<(u32, String)> {
+pub fn hashset_from_string(hashset :String) -> Result<HashSet<(u32, String)>, String> {
     let mut res = HashSet::<(u32,String)>::new();
+    if hashset.trim_matches(char::from(0)).is_empty() {
+        return Ok(res);
+    }
     let splitted: Vec<&str> = hashset.split(";").collect();
     for element in splitted {
-        let couple: Vec<&str> = element.split(",").collect();
-        let id: u32 =  String::from(couple[0].to_string().trim_matches(char::from(0))).parse::<u32>().unwrap();
-        let address: String = String::from(couple[1].to_string().trim_matches(char::from(0)));
+        let (id, address) = element.split_once(",").ok_or_else(|| format!("invalid network entry {}", element))?;
+        let id: u32 = id.trim_matches(char::from(0)).parse::<u32>().map_err(|e| format!("{}, invalid network id", e))?;
+        let address: String = String::from(address.trim_matches(char::from(0)));
         res.insert((id,address));
     }
-    return res.to_owned();
+    return Ok(res);
 }
-/// Size of a message
-const TRAM_SIZE: usize = 500; 
 /// Time in sec for nodes healthcheck
 const REFRESH_TIME: u64 = 15;
-/// Size of the block payload
+/// Number of waiting transactions from which a block is mined
 const BLOCK_PAYLOAD_SIZE: usize = 5;
-/// Difficulty of mining
-const MINING_DIFFICULTY: usize = 1;
-
-/// Util
-/// Concat u8 array
-/// * `first`
-/// * `second`
-/// # Example 
-/// ```rust 
-/// let first: &[u8] = String::from("Hello, ").as_bytes();
-/// let second: &[u8] = String::from("World!").as_bytes();
-/// asserteq!("Hello, World!",concat_u8(&first,&second));
-/// ```
-pub fn concat_u8(first: &[u8], second: &[u8]) -> Vec<u8> {
-    [first, second].concat()
-}
-
-
-/// Add padding to the socket ip to allow fixed size data structure when sending data
-pub fn encode_sockip(sockip: String) -> String {
-    return format!("{:X<21}", sockip);
-}
-
-/// Remove the padding from the socket ip
-pub fn decode_sockip(sockip: String) -> String {
-    return str::replace(&sockip, "X", "");
-}
-
-/// Add padding to the ID to allow fixed sized data structure when sending an ID
-pub fn encode_id(id: String) -> String {
-    return format!("{:Y<10}", id);
-}
-
-/// Remove the padding from the ID field
-pub fn decode_id(message: String) -> String {
-    let id = str::replace(&message, "Y", "");
-    return id;
-}
-
-/// Decode the message received
-pub fn decode_message(msg : &[u8]) -> (Flag, String, String, String){
-    println!("SSSDL111 .{:?}.", msg);
-    let flag = Flag::from_u8(msg[0]); // get the flag
-    let sockip_encoded = std::str::from_utf8(&msg[1..21]).unwrap();
-    let id_encoded = std::str::from_utf8(&msg[22..31]).unwrap();
-    let msg = std::str::from_utf8(&msg[32..]).unwrap();
-    let sockip = decode_sockip(sockip_encoded.to_string());
-    (flag, decode_sockip(sockip.to_string()), decode_id(id_encoded.to_string()), msg.to_string())
-}
-
-/// Encode the sended message
-pub fn encode_message(flag : Flag, sockip : String, id : String, msg : String) -> Vec<u8>{
-    println!("\nEncoding message");
-    let flag_convert: &[u8] = &[flag as u8];
-    let sockip_convert : String = encode_sockip(sockip);
-    let id_convert : String = encode_id(id);
-    println!("\tmessage to encode: {}",&msg);
-    let msg_convert : &[u8] = msg.as_bytes();
-    println!("\tmessage encoded: {:?}",&msg_convert);
-    concat_u8(flag_convert, &concat_u8(sockip_convert.as_bytes(), &concat_u8(id_convert.as_bytes(), msg_convert)))
-}
+/// Space of the block payload kept for the coinbase
+const COINBASE_RESERVED_SIZE: usize = 128;
+/// Time in ms the listener waits between two checks for incoming connections or a shutdown request
+const ACCEPT_POLL_TIME: u64 = 100;
+/// Time a node waits for the answer to a request sent with `ask_and_wait_answer`
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most wallets a Miner gives an ID to
+const MAX_WALLETS: usize = 1000;
+/// Time a connection may stay silent before it is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most transactions sent in one page of history
+const MAX_HISTORY_PAGE: usize = 50;
+/// Most transaction IDs sent in one part of the answer to `RequireHistory`
+const MAX_HISTORY_PART: usize = 1000;
+/// Most transactions sent in one part of the answer to `RequirePending`, so the part fits in a frame
+const MAX_PENDING_PART: usize = 100;
+/// Most blocks sent in answer to one `RequireBlockchain`
+pub const MAX_BLOCKS_PER_REQUEST: u32 = 64;
 
 /// Used to create a new miner, then make it listen for incoming transactions
 /// *`miner_type` - a char representing what kind of miner we need to create ('c' if we are creating a network, 'j' if we are joining an existing network)
 /// *`socket` - the IP address on which the miner will listen for transactions
 /// *`destination` - the IP address of a miner in the network we are joining, if `miner_type` == 'j'
-pub fn create_miner(miner_type: char, socket: String, destination: String) {
+/// *`payout` - the address the coinbases of our blocks pay, our public key if None
+/// *`config` - the consensus parameters of the network
+/// *`trust_loopback` - true to let the connections from this machine through without limit, see `RateLimiter::new`
+pub fn create_miner(miner_type: char, socket: String, destination: String, payout: Option<String>, config: ChainConfig, trust_loopback: bool) {
+    let mut miner = match start_miner(Arc::new(TcpTransport), miner_type, socket, destination, payout, config) {
+        Some(miner) => miner,
+        None => return,
+    };
+    miner.limits = RateLimiter::new(trust_loopback);
+    let running = Arc::clone(&miner.running);
+    if let Err(e) = ctrlc::set_handler(move || {
+        println!("Shutdown signal received");
+        running.store(false, Ordering::SeqCst);
+    }) {
+        println!("Err: {}, shutdown signals will not be handled", e);
+    }
+    miner.listen();
+}
+
+/// Creates a Miner, binds its listener and joins the network, without starting to listen yet
+/// The listener is bound before joining, so the answers of the network wait in its queue until `listen` is called
+/// *`transport` - how the Miner reaches the other nodes
+/// *`miner_type`, `socket`, `destination`, `payout`, `config` - see `create_miner`
+pub fn start_miner(transport: Arc<dyn Transport>, miner_type: char, socket: String, destination: String, payout: Option<String>, config: ChainConfig) -> Option<Miner> {
     println!("Miner creation...");
     let mut miner;
     match miner_type {
-        'c' => { miner = Miner::new(0, socket.to_string()); }
-        'j' => { miner = Miner::new(ask_for_id(&socket, &destination), socket.to_string()); }
-        _ => { println!("Unrecognized miner type"); return (); }
+        'c' => { miner = Miner::new(0, socket.to_string(), transport, config); }
+        'j' => { miner = Miner::new(ask_for_id(transport.as_ref(), &socket, &destination), socket.to_string(), transport, config); }
+        _ => { println!("Unrecognized miner type"); return None; }
     }
+    if let Some(payout) = payout {
+        miner.payout = payout;
+    }
+    println!("Block rewards paid to {}", &miner.payout);
     miner.add_to_network(miner.get_id(),socket.to_string());
+    miner.load_state();
     println!("{:?}", &miner);
     for (i,e) in &miner.network {
         println!("{}, {}",i,e);
     }
+    match miner.transport.bind(&socket) {
+        Ok(listener) => miner.listener = Some(listener),
+        Err(e) => {
+            println!("Err: {}, could not listen on {}", e, &socket);
+            return None;
+        }
+    }
     if !!! destination.is_empty() {
         println!("Now connecting to network...");
         miner.join(destination);
         println!("Connected!\n");
     }
-    println!("Starting to listen...");
-    miner.listen();
+    Some(miner)
+}
+
+/// Asks a Miner running on this machine to shut down gracefully
+/// *`destination` - the IP address the Miner listens on
+pub fn request_shutdown(destination: String) {
+    println!("Asking {} to shut down...", &destination);
+    send_admin_command(&destination, Flag::Shutdown);
+}
+
+/// Asks a Miner running on this machine to mine a block now, even if no transaction is waiting
+/// Needed to start a chain on which nobody owns coins yet, the block rewards having to mature before being spent
+/// *`destination` - the IP address the Miner listens on
+pub fn request_mining(destination: String) {
+    println!("Asking {} to mine a block...", &destination);
+    send_admin_command(&destination, Flag::MineTransaction);
+}
+
+/// Sends an admin command, which the Miner only accepts from the machine it runs on
+fn send_admin_command(destination: &str, flag: Flag) {
+    let identity = match NodeIdentity::ephemeral() {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("Err: {}", e);
+            return;
+        }
+    };
+    match secure::connect(&TcpTransport, destination, &identity) {
+        Ok(mut stream) => {
+            let m: &[u8] = &encode_message(flag, String::new(), String::new(), String::new());
+            match stream.send(m) {
+                Ok(_) => println!("{:?} requested", flag),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Err(e) => println!("Err: {}, during connection", e),
+    }
 }
 
 /// This function is used when a new miner is joining an existing network, and needs a unique ID to be assigned to it
+/// *`transport` - how to reach the miner
 /// *`socket` - the miner whom we are joining
 /// *`destination` - the joining miner IP address
-pub fn ask_for_id(socket: &String, destination: &String) -> u32 {
+pub fn ask_for_id(transport: &dyn Transport, socket: &str, destination: &str) -> u32 {
     println!("Asking {} for id...", &destination);
-    let listener = TcpListener::bind(&socket).unwrap();
-    let mut id: u32 = 0;
+    let id = ask_and_wait_answer(transport, socket, destination, Flag::RequireID).map(|answer| parse_id(&answer)).unwrap_or(0);
+    println!("My ID is {}.", &id);
+    id
+}
 
-    if let Ok(mut stream) = TcpStream::connect(&destination) {
-        let m: &[u8] = &encode_message(Flag::RequireID, socket.to_string(), "".to_string(), "".to_string());
-        match stream.write(m) {
-            Ok(_) => { println!("Asked for id"); }
-            Err(e) => { println!("Error: {}", e); }
+/// Sends a request to another node, then waits for the answer which the node sends back on a new connection to `socket`
+/// *`transport` - how to reach the node
+/// *`socket` - our IP address, on which we listen until the answer arrives
+/// *`destination` - the IP address of the node we are asking
+/// *`flag` - the request
+/// Returns the message of the answer, None if the request could not be sent, the answer was invalid or did not come in time
+pub fn ask_and_wait_answer(transport: &dyn Transport, socket: &str, destination: &str, flag: Flag) -> Option<String> {
+    let identity = match NodeIdentity::load_or_create(socket) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("Err: {}, could not load our identity", e);
+            return None;
+        }
+    };
+    let listener = match transport.bind(socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Err: {}, could not listen on {}", e, socket);
+            return None;
+        }
+    };
+    match secure::connect(transport, destination, &identity) {
+        Ok(mut stream) => {
+            let m: &[u8] = &encode_message(flag, socket.to_string(), "".to_string(), "".to_string());
+            if let Err(e) = stream.send(m) {
+                println!("Error: {}", e);
+                return None;
+            }
+            println!("Message sended");
+        }
+        Err(e) => {
+            println!("Err: {}, during connection", e);
+            return None;
         }
-        println!("Message sended");
     }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("Getting ID from Genesis");
-                id = handle_id(stream);
-                println!("My ID is {}.", &id);
-                return id;
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    while Instant::now() < deadline {
+        let connection = match listener.accept() {
+            Ok(Some((connection, _))) => connection,
+            Ok(None) => {
+                std_thread::sleep(Duration::from_millis(ACCEPT_POLL_TIME));
+                continue;
             }
             Err(e) => {
                 println!("Error: {}", e);
-                return 0;
+                continue;
             }
+        };
+        match secure::accept(connection, &identity) {
+            Ok(mut stream) => {
+                if !identity.accepts(destination, &stream.peer_key) {
+                    println!("Answer refused: {} did not present its pinned key", destination);
+                    continue;
+                }
+                return match stream.recv() {
+                    Ok(Some(data)) => match decode_message(&data) {
+                        Ok((_, _, _, answer)) => Some(answer),
+                        Err(e) => {
+                            println!("Invalid message received: {}", e);
+                            None
+                        }
+                    },
+                    // The node connecting back to prove our address, see `NodeIdentity::bind`, sends nothing
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("Error occured, closing connection: {}", e);
+                        stream.shutdown();
+                        None
+                    }
+                };
+            }
+            Err(e) => println!("Error: {}", e),
         }
     }
-    return id;
-} 
+    println!("No answer from {}", destination);
+    None
+}
 
 /// Used by a joining miner to decode the unique ID he was given
-/// *`stream` - a TCPStream instance of bytes which contains the ID
-pub fn handle_id(mut stream: TcpStream) -> u32 {
-    let mut data = [0 as u8; TRAM_SIZE];
-    match stream.read(&mut data) {
-        Ok(size) if size > 0 => {
-            let tuple : (Flag, String, String, String) = decode_message(&data);
-            let id_as_str : String = tuple.3.trim_matches(|c| c == char::from(0) || c == '\n').to_string();
-            let id_as_str_decoded = decode_id(std::str::from_utf8(id_as_str.as_bytes()).unwrap().to_owned());
-            let id = id_as_str_decoded.parse::<u32>().unwrap();
-            return id;
-        },
-        Ok(_) => { println!("No message received");},
+/// *`answer` - the message which contains the ID
+pub fn parse_id(answer: &str) -> u32 {
+    match answer.trim_matches(|c| c == char::from(0) || c == '\n').parse::<u32>() {
+        Ok(id) => id,
         Err(e) => {
-            println!("Error occured, closing connection: {}", e);
-            stream.shutdown(Shutdown::Both).unwrap();
+            println!("Invalid ID received: {}", e);
+            0
         }
     }
-    {}
-    0
+}
+
+/// Returns true for the messages which act for the address they come from: they join or leave the network with it,
+/// or get an answer sent to it
+fn acts_for_sender(flag: Flag) -> bool {
+    matches!(flag, Flag::Connect | Flag::Disconnect | Flag::Ok | Flag::RequireID | Flag::RequireWalletID | Flag::Subscribe)
 }
 
 /// This struct is used to represent a Miner in our Blockchain Network
 /// *`id` - the ID of our Miner, should be unique within the network
 /// *`network` - a HashSet containing the IDs and IP addresses of every Miner inside the network
-/// *`blocks` - a Vector containing the Blockchain
+/// *`chain` - the Blockchain, with the competing branches
 /// *`sockip` - the IP address on which our Miner will listen for incoming transactions
 /// *`wallets` - a HashSet containing the IDs and IP addresses of every wallets binded to it
-/// *`payload` - TBD
+/// *`mempool` - the transactions waiting to be mined
 /// *`current_block_id` - TBD
+/// *`peers` - the liveness and score of every other Miner of the network, indexed by ID
+/// *`running` - set to false to make the Miner stop listening, abort mining and shut down
+/// *`identity` - the key pair proving who we are when connecting to other nodes
+/// *`bans` - the addresses and keys we refuse to talk to, behind its own lock so connections can be screened while the Miner is busy
+/// *`limits` - how many messages of each kind every peer address may still send
+/// *`transport` - how the Miner reaches the other nodes, TCP or the simulated network
+/// *`listener` - the listener bound by `start_miner`, taken by `listen`
+/// *`payout` - the address the coinbases of our blocks pay
+/// *`subscriptions` - the wallets to notify when the transactions of their addresses move
+/// *`notifier` - the thread sending the notifications to the wallets
+/// *`departures` - the Miners other nodes told us left the network, as `(id, address, sender)`, checked by `confirm_departures`
+/// once we released our lock
+/// *`mine_request` - set when a block must be mined, to the node which does not need to receive it, see `mine_block`
+/// *`mining` - true while a thread mines a block
 pub struct Miner {
     pub id: u32, // Our ID
     pub network: HashSet<(u32, String)>, // The IDs and adresses of every member of the network, always unique
-    pub blocks: Vec<block::Block>, // The blocks calculated by us
+    pub chain: Chain,
     pub sockip: String,
     pub wallets: HashSet<(u32, String)>,
-    pub payload: Vec<String>,
+    pub mempool: Mempool,
     pub current_block_id : u32,
+    pub peers: HashMap<u32, PeerStatus>,
+    pub running: Arc<AtomicBool>,
+    pub identity: Arc<NodeIdentity>,
+    pub bans: Arc<Mutex<BanList>>,
+    pub limits: RateLimiter,
+    pub transport: Arc<dyn Transport>,
+    pub listener: Option<Box<dyn Listener>>,
+    pub payout: String,
+    pub subscriptions: Subscriptions,
+    pub notifier: Notifier,
+    pub departures: Vec<(u32, String, String)>,
+    pub mine_request: Option<String>,
+    pub mining: Arc<AtomicBool>,
 }
 
 impl Miner {
@@ -292,15 +363,32 @@ impl Miner {
     /// CONSTRUCTOR
     /// `socket` - an ip:port string representing where is the Miner listening
     /// Returns a new Miner with a TcpListener that listens to the given ip:port
-    pub fn new (id: u32, socket: String) -> Self {
+    /// `transport` - how the Miner reaches the other nodes
+    /// `config` - the consensus parameters of the network
+    /// The block rewards are paid to the address of the public key of the Miner until another `payout` address is set
+    pub fn new (id: u32, socket: String, transport: Arc<dyn Transport>, config: ChainConfig) -> Self {
+        let identity = Arc::new(NodeIdentity::load_or_create(&socket).expect("could not load the node identity"));
         return Miner {
             id: id,
             network: HashSet::new(),
-            blocks: Vec::new(),
+            chain: Chain::new(config),
             sockip: socket.to_string(),
             wallets: HashSet::new(),
-            payload: Vec::new(),
+            mempool: Mempool::new(),
             current_block_id: 0,
+            peers: HashMap::new(),
+            running: Arc::new(AtomicBool::new(true)),
+            payout: address::from_public_key(&identity.public_key()),
+            notifier: Notifier::start(Arc::clone(&transport), Arc::clone(&identity), socket.to_string(), id),
+            identity,
+            bans: Arc::new(Mutex::new(BanList::load(&socket))),
+            limits: RateLimiter::default(),
+            transport,
+            listener: None,
+            subscriptions: Subscriptions::new(),
+            departures: Vec::new(),
+            mine_request: None,
+            mining: Arc::new(AtomicBool::new(false)),
         }        
     }
 
@@ -313,7 +401,10 @@ impl Miner {
     pub fn display_network(&self) {
         println!("Current network:");
         for miner in &self.network {
-            println!("\tid: {}, sockip: {}", miner.0, miner.1);
+            match self.peers.get(&miner.0) {
+                Some(status) => println!("\tid: {}, sockip: {}, score: {}, latency: {:?}", miner.0, miner.1, status.score, status.latency),
+                None => println!("\tid: {}, sockip: {}", miner.0, miner.1),
+            }
         }
     }
 
@@ -334,12 +425,12 @@ impl Miner {
     pub fn send_message(&self, destination: &String, message: &String, flag: Flag) -> Result<u8,&'static str> {
         let f = flag as u8;
         println!("Sending message: {} \nTo: {} .. {} \nWith Flag: {}",&message, &destination, &destination.chars().count(),&f);
-        match TcpStream::connect(&destination) {
+        match secure::connect(self.transport.as_ref(), destination, &self.identity) {
             Ok(mut stream) => {
                 println!("Connection established.");
                 let m: &[u8] = &encode_message(flag, self.sockip.to_string(), self.id.to_string(), message.to_string());
                 println!("Byte message: {:?}",&m);
-                match stream.write(m) {
+                match stream.send(m) {
                     Ok(_) => println!("Message writen in buffer"),
                     Err(e) => println!("Error during writing: {}", e.to_string()),
                 }
@@ -360,7 +451,7 @@ impl Miner {
     pub fn broadcast_to_network(&self, message: &String, flag: Flag, sender: String) {
         println!("Broadcasting network changes");
         for(_, peer_addr) in &self.network {
-            if peer_addr.to_string() != sender {
+            if peer_addr.to_string() != sender && *peer_addr != self.sockip {
                 match self.send_message(&peer_addr.to_string(), message, flag) {
                     Ok(_) => println!(""),
                     Err(e) => println!("Err: {}",e),
@@ -415,203 +506,497 @@ impl Miner {
         return (max_id+1).to_owned();
     }
 
-    /// This function handles the incoming requests/messages sended by other entities
-    /// *`stream` - a TCPStream instance which contains the data that was received
-    /// The function decodes the incoming message, then act accordingly
-    pub fn handle_client(&mut self, mut stream: TcpStream) {
-        let mut data = [0 as u8; TRAM_SIZE];
-        while match stream.read(&mut data) { 
-            Ok(size) if size > 0 => { // If a message is received
-                println!("Message received of size: {}", &size);
-                let tuple : (Flag, String, String, String) = decode_message(&data);
-                //let flag = Flag::from_u8(data[0]); // get the flag
-                let flag = tuple.0;
-                println!("\tFlag: {}", &data[0]);
-                //println!("\tFlag: {:?}", &flag);
-                //let message = std::str::from_utf8(&data[0..size]).unwrap();
-                let message = tuple.3;
-                println!("\tMessage: {}", &message);
-
-                let text = &message[1..]; // get the remainder of the message
-                let sender_sockip = tuple.1;
-                println!("\tSockIp: {}", &sender_sockip);
-
-                let sender_id_as_str = tuple.2;
-                
-
-                // select appropriate response based on the flag, convert the u8 number to flag
-                match flag {
-                    Flag::Connect => {
-                        // When a miner connects to the network, add him to our HashSet then broadcast the connection message to the network
-                        println!("Connect Flag received");
-                        //let destination = format!("{}:{}",&stream.local_addr().unwrap().ip().to_string(),&stream.local_addr().unwrap().port().to_string());
-                        let destination = &sender_sockip;
-                        match self.send_message(&destination , &hashset_to_string(&self.network), Flag::Ok) {
-                            Ok(_) => println!(""),
-                            Err(e) => println!("Err: {}", e),
+    /// This function handles the incoming requests/messages sended by other entities on one connection
+    /// *`miner` - the shared Miner, only locked while a message is handled so a slow peer can not block the others
+    /// *`stream` - an authenticated connection on which the messages are received
+    pub fn handle_client(miner: &Arc<Mutex<Miner>>, mut stream: SecureStream) {
+        // A connection which stays silent is closed, so it does not hold its handler thread forever
+        if let Err(e) = stream.set_timeout(Some(IDLE_TIMEOUT)) {
+            println!("Err: {}, closing connection", e);
+            return;
+        }
+        let (identity, transport) = {
+            let miner = miner.lock().unwrap();
+            (Arc::clone(&miner.identity), Arc::clone(&miner.transport))
+        };
+        loop {
+            let data = match stream.recv() {
+                Ok(Some(data)) => data,
+                Ok(None) => { println!("No message received"); break; }
+                Err(e) => {
+                    println!("Error occurs, closing connection: {}", e);
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        if let Ok(addr) = stream.peer_addr() {
+                            miner.lock().unwrap().misbehaving(addr.ip(), Some(&stream.peer_key), ban::MALFORMED_FRAME_PENALTY);
                         }
-                        println!("Sender id: {}", sender_id_as_str);
-                        let sender_id = sender_id_as_str.parse::<u32>().unwrap();
-                        let broadcast_message = format!("{};{}", sender_sockip, sender_id);
-                        println!("Broadcasting message: {}", broadcast_message);
-                        self.broadcast_to_network(&broadcast_message, Flag::BroadcastConnect, self.sockip.to_string());
-                        self.add_to_network(sender_id, sender_sockip);
                     }
-                    Flag::Disconnect => {
-                        // If a miner disconnects from the network, remove him from our HashSet and broadcast the disconnection to the Network
-                        println!("Disconnect Flag received");
-                        let sender_id = sender_id_as_str.parse::<u32>().unwrap();
-                        self.remove_from_network(sender_id, sender_sockip.to_owned());
-                        let broadcast_message = format!("{};{}", sender_id, sender_id);
-                        self.broadcast_to_network(&broadcast_message, Flag::BroadcastDisconnect, self.sockip.to_string());
+                    stream.shutdown();
+                    break;
+                }
+            };
+            // A message acting for the address it comes from is only handled once that address is bound to the key of
+            // the peer, so no other node can connect, disconnect or register it. Binding connects back to the address,
+            // which is done without the lock
+            if let Ok((flag, sender_sockip, _, _)) = decode_message(&data) {
+                if acts_for_sender(flag) && !identity.bind(transport.as_ref(), &sender_sockip, &stream.peer_key) {
+                    println!("Message refused: {} could not prove it listens on {}", hex::encode(&stream.peer_key), &sender_sockip);
+                    stream.shutdown();
+                    break;
+                }
+            }
+            let (keep_open, departures, mine_request) = {
+                let mut miner = miner.lock().unwrap();
+                let keep_open = miner.handle_message(&mut stream, &data);
+                miner.display_network();
+                (keep_open, std::mem::take(&mut miner.departures), miner.mine_request.take())
+            };
+            Miner::confirm_departures(miner, departures);
+            if let Some(sender) = mine_request {
+                Miner::mine_block(miner, sender);
+            }
+            if !keep_open {
+                break;
+            }
+        }
+    }
+
+    /// Checks the Miners other nodes told us left the network, without holding the lock during the healthchecks
+    /// Only those which do not answer ours are removed, then their departure is forwarded to the rest of the network
+    /// *`miner` - the shared Miner
+    /// *`departures` - the Miners reported gone, as `(id, address, sender)`, the sender not being told again
+    pub fn confirm_departures(miner: &Arc<Mutex<Miner>>, departures: Vec<(u32, String, String)>) {
+        if departures.is_empty() {
+            return;
+        }
+        let (sockip, id, identity, transport) = {
+            let miner = miner.lock().unwrap();
+            (miner.sockip.to_string(), miner.id, Arc::clone(&miner.identity), Arc::clone(&miner.transport))
+        };
+        for (gone_id, gone_sockip, sender) in departures {
+            if let Ok(rtt) = Miner::health_check(transport.as_ref(), &sockip, id, &gone_sockip, &identity) {
+                println!("Departure of {} ignored: it answered our healthcheck in {:?}", &gone_sockip, rtt);
+                continue;
+            }
+            let mut miner = miner.lock().unwrap();
+            if miner.remove_from_network(gone_id, gone_sockip.to_string()) {
+                miner.broadcast_to_network(&format!("{};{}", gone_sockip, gone_id), Flag::BroadcastDisconnect, sender);
+            }
+        }
+    }
+
+    /// The function decodes one incoming message, then act accordingly
+    /// *`stream` - the connection the message came from, used to answer directly
+    /// *`data` - the decrypted message
+    /// Returns false if the connection must be closed
+    pub fn handle_message(&mut self, stream: &mut SecureStream, data: &[u8]) -> bool {
+        println!("Message received of size: {}", data.len());
+        let peer_ip = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(e) => {
+                println!("Error: {}", e);
+                return false;
+            }
+        };
+        let tuple : (Flag, String, String, String) = match decode_message(data) {
+            Ok(tuple) => tuple,
+            Err(e) => {
+                println!("Invalid message received, closing connection: {}", e);
+                self.misbehaving(peer_ip, Some(&stream.peer_key), ban::MALFORMED_FRAME_PENALTY);
+                return false;
+            }
+        };
+        //let flag = Flag::from_u8(data[0]); // get the flag
+        let flag = tuple.0;
+        println!("\tFlag: {:?}", &flag);
+        //println!("\tFlag: {:?}", &flag);
+        //let message = std::str::from_utf8(&data[0..size]).unwrap();
+        let message = tuple.3;
+        println!("\tMessage: {}", &message);
+
+        let sender_sockip = tuple.1;
+        println!("\tSockIp: {}", &sender_sockip);
+
+        let sender_id_as_str = tuple.2;
+        // An address is bound to the key proven for it, see `handle_client`, a message claiming it with another key is refused
+        if !self.identity.may_claim(&sender_sockip, &stream.peer_key) {
+            println!("Message refused: {} did not present the key pinned or proven for it", &sender_sockip);
+            stream.shutdown();
+            return false;
+        }
+        // Bans follow the key the peer proved it owns, not the node ID it writes in its messages
+        let peer_key = stream.peer_key.clone();
+        if self.bans.lock().unwrap().is_key_banned(&peer_key) {
+            println!("Message refused: the key of {} is banned", &sender_sockip);
+            return false;
+        }
+        if !self.limits.allow(peer_ip, flag) {
+            println!("Message dropped: {} is over its rate limit for {:?}", &peer_ip, &flag);
+            self.misbehaving(peer_ip, Some(&peer_key), ban::RATE_LIMIT_PENALTY);
+            return false;
+        }
+
+        // select appropriate response based on the flag, convert the u8 number to flag
+        match flag {
+            Flag::Connect => {
+                // When a miner connects to the network, add him to our HashSet then broadcast the connection message to the network
+                println!("Connect Flag received");
+                let sender_id = match sender_id_as_str.parse::<u32>() {
+                    Ok(sender_id) => sender_id,
+                    Err(e) => {
+                        println!("Err: {}, invalid Connect id", e);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::MALFORMED_FRAME_PENALTY);
                     }
-                    Flag::Ok => {
-                        println!("Ok Flag received");
-                        let received_network: &String = &message;
-                        println!("Reply is ok!\nNetwork: {} \n Count: {}", received_network, received_network.chars().count());
-                        let network: HashSet<(u32,String)> = hashset_from_string(received_network.to_string());
-
-                        self.network = self.network.union(&network).into_iter().cloned().collect::<HashSet<_>>().clone();
-                        println!("New network: ");
-                        for (i,e) in &self.network {
-                            println!("{}, {}",i,e);
-                        }
-                        // self.broadcast(&message, flag);
-                        self.refresh_nodes_status();
+                };
+                //let destination = format!("{}:{}",&stream.local_addr().unwrap().ip().to_string(),&stream.local_addr().unwrap().port().to_string());
+                let destination = &sender_sockip;
+                match self.send_message(&destination , &hashset_to_string(&self.network), Flag::Ok) {
+                    Ok(_) => println!(""),
+                    Err(e) => println!("Err: {}", e),
+                }
+                println!("Sender id: {}", sender_id);
+                let broadcast_message = format!("{};{}", sender_sockip, sender_id);
+                println!("Broadcasting message: {}", broadcast_message);
+                self.broadcast_to_network(&broadcast_message, Flag::BroadcastConnect, self.sockip.to_string());
+                self.add_to_network(sender_id, sender_sockip);
+            }
+            Flag::Disconnect => {
+                // If a miner disconnects from the network, remove him from our HashSet and broadcast the disconnection to the Network
+                println!("Disconnect Flag received");
+                let sender_id = match sender_id_as_str.parse::<u32>() {
+                    Ok(sender_id) => sender_id,
+                    Err(e) => {
+                        println!("Err: {}, invalid Disconnect id", e);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::MALFORMED_FRAME_PENALTY);
                     }
-                    Flag::RequireID => {
-                        println!("RequireID Flag received");
-                        let next_id = self.retrieve_next_id().to_string();
-                        match self.send_message(&sender_sockip, &next_id, Flag::GiveID) {
-                            Ok(_) => println!("ID correctement envoyé"),
-                            Err(e) => println!("Err: {}", e),
+                };
+                if self.remove_from_network(sender_id, sender_sockip.to_owned()) {
+                    let broadcast_message = format!("{};{}", sender_sockip, sender_id);
+                    self.broadcast_to_network(&broadcast_message, Flag::BroadcastDisconnect, sender_sockip);
+                }
+            }
+            Flag::BroadcastDisconnect => {
+                // Forward the disconnection only the first time we hear about it, so the broadcast stops by itself.
+                // The Miner leaving may announce it itself, anyone else's word is checked first, see `confirm_departures`
+                println!("BroadcastDisconnect Flag received");
+                let splitted: Vec<&str> = message.split(';').collect();
+                if splitted.len() == 2 {
+                    let gone_sockip = splitted[0].trim_matches(char::from(0)).to_string();
+                    match splitted[1].trim_matches(|c| c == char::from(0) || c == '\n').parse::<u32>() {
+                        Ok(gone_id) if self.identity.addresses_of(&peer_key).contains(&gone_sockip) => {
+                            if self.remove_from_network(gone_id, gone_sockip) {
+                                self.broadcast_to_network(&message, Flag::BroadcastDisconnect, sender_sockip);
+                            }
                         }
+                        Ok(gone_id) => {
+                            if self.network.contains(&(gone_id, gone_sockip.to_string())) {
+                                self.departures.push((gone_id, gone_sockip, sender_sockip));
+                            }
+                        }
+                        Err(e) => println!("Err: {}, invalid BroadcastDisconnect id", e),
                     }
-                    Flag::Check => {
-                        println!("Check Flag received");
-                        self.send_message(&sender_sockip, &String::from(""), Flag::Ack);
+                }
+            }
+            Flag::Shutdown => {
+                // Admin command, only accepted from the machine the Miner runs on
+                println!("Shutdown Flag received");
+                match stream.peer_addr() {
+                    Ok(addr) if addr.ip().is_loopback() => self.running.store(false, Ordering::SeqCst),
+                    _ => println!("Shutdown refused: not sent from localhost"),
+                }
+            }
+            Flag::Ok => {
+                println!("Ok Flag received");
+                let received_network: &String = &message;
+                println!("Reply is ok!\nNetwork: {} \n Count: {}", received_network, received_network.chars().count());
+                let network: HashSet<(u32,String)> = match hashset_from_string(received_network.to_string()) {
+                    Ok(network) => network,
+                    Err(e) => {
+                        println!("Err: {}, invalid network received", e);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::MALFORMED_FRAME_PENALTY);
                     }
-                    Flag::Ack => {
-                        println!("Ack Flag received: Do nothing");     
+                };
+
+                self.network = self.network.union(&network).cloned().collect::<HashSet<_>>();
+                println!("New network: ");
+                for (i,e) in &self.network {
+                    println!("{}, {}",i,e);
+                }
+                // self.broadcast(&message, flag);
+            }
+            Flag::RequireID => {
+                println!("RequireID Flag received");
+                let next_id = self.retrieve_next_id().to_string();
+                match self.send_message(&sender_sockip, &next_id, Flag::GiveID) {
+                    Ok(_) => println!("ID correctement envoyé"),
+                    Err(e) => println!("Err: {}", e),
+                }
+            }
+            Flag::Check => {
+                // The nonce of the ping is sent back so the sender can match it
+                println!("Check Flag received");
+                let nonce = message.trim_matches(|c| c == char::from(0) || c == '\n').to_string();
+                self.reply(stream, Flag::Ack, nonce, "the answer to a healthcheck");
+            }
+            Flag::Ack => {
+                // Acks are read by `ping` on the connection it opened, one arriving here was not asked for
+                println!("Unexpected Ack Flag received");
+                for sender_id in self.network_ids_of(&peer_key) {
+                    self.penalize_peer(sender_id, peer::MISBEHAVIOUR_PENALTY);
+                }
+            }
+            Flag::Block => {
+                println!("Block received");
+                let block = match block::Block::from_str(&message) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        println!("Invalid block received: {}", e);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::MALFORMED_FRAME_PENALTY);
                     }
-                    Flag::Block => {
-                        println!("Block received");
-                        self.refresh_nodes_status();
-                        // Check block
-                        // if &self.check_block(block::Block::from(message)){
-                            // forward block
-                            // &self.broadcast_to_network(message, Flag::Block,sender_sockip);
-                            // add to blockchain
-                            // Remove transaction already done
-                        // } else {
-                            // Invalid block
-                        //}   
+                };
+                match self.accept_block(block.clone()) {
+                    Ok(_) => self.broadcast_to_network(&block.to_string(), Flag::Block, sender_sockip),
+                    Err(ChainError::Known) => println!("Block {} already known", block.index),
+                    Err(ChainError::Orphan) => println!("Block {} from {} kept until its parent arrives", block.index, &sender_sockip),
+                    Err(ChainError::Invalid(reason)) => {
+                        println!("Invalid block received: {}", reason);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::INVALID_BLOCK_PENALTY);
                     }
-                    Flag::Transaction => {
-                        println!("Transaction Flag received");
-                        self.refresh_nodes_status();
-                        // Je regarde si je l'ai deja
-                        let transaction : String = message.trim_matches(|c| c == char::from(0) || c == '\n').to_string();
-                        
-                        if !&self.payload.contains(&transaction) {
-                            self.payload.push(transaction.to_string());
-                            &self.broadcast_to_network(&transaction, Flag::Transaction, sender_sockip.to_owned());
+                }
+            }
+            Flag::Transaction => {
+                // The sender waits for `accepted` once the transaction is in our mempool, or `rejected <reason>`
+                println!("Transaction Flag received");
+                let transaction : String = message.trim_matches(|c| c == char::from(0) || c == '\n').to_string();
+                let inserted = self.mempool.insert(transaction.to_string(), &self.chain);
+                let result = match &inserted {
+                    Ok(_) | Err(MempoolError::Duplicate) | Err(MempoolError::Confirmed) => "accepted".to_string(),
+                    Err(e) => format!("rejected {}", e),
+                };
+                self.reply(stream, Flag::TransactionResult, result, "the transaction result");
+                match inserted {
+                    Ok(id) => {
+                        println!("Transaction {} added to the mempool ({} waiting)", &id, self.mempool.len());
+                        self.broadcast_to_network(&transaction, Flag::Transaction, sender_sockip.to_owned());
+                        let mut notifications = self.subscriptions.mempool_changed(&self.mempool);
+                        if let Ok(parsed) = Transaction::from_str(&transaction) {
+                            notifications.extend(self.subscriptions.transaction_added(&parsed));
                         }
-                        // TODO: Handle \u{0} char
-                        println!("{:?}",&self.payload);
-
-                        // Check payload size
-                        if &self.payload.len() == &BLOCK_PAYLOAD_SIZE {
-                            // &self.broadcast_to_network(&self.payload.join(";").to_string(), Flag::MineTransaction, sender_sockip);
-                            let payload = &self.payload.join("$").to_string();
-                            println!("Payload to mine: {}", payload);
-                            let mined_block : block::Block = self.hash_block(payload.to_string());
-                            println!("Mined block: {:?}", &mined_block);
-                            // Ajoute a la blockchain
-                            if self.blocks.len() as u32 == mined_block.index {
-                                &self.blocks.push(block::Block::from_str(&mined_block.to_string()).unwrap());
-                                &self.broadcast_to_network(&mined_block.to_string(), Flag::Block, sender_sockip.to_owned());
-                                self.payload = Vec::new();
-                                println!("Chain: {:?}", &self.blocks);
-                            }
-                            // TODO: Thread
-                                    // Lock var
-                            // let m = Mutex::new(0);
-                            // // let m = Mutex::new(self.current_block_id);
-                            // if let Ok(mut x) = m.lock() {
-                            //     println!("Thread 1 acquired lock");
-                            //     self.current_block_id += 1;
-                            // };   
-                            // let new_id = self.current_block_id;
-                            // println!("{}",new_id);  
-                            // std::mem::drop(m); // Unlock
-                            // thread::scope(|s| {
-                            //     s.spawn(move |_| {
-                            //         let mined_block : block::Block = self.hash_block(payload.to_string());
-                            //         println!("Mined block: {:?}", &mined_block);
-                            //         // Ajoute a la blockchain
-                            //         if self.blocks.len() as u32 == mined_block.index {
-                            //             &self.blocks.push(&mined_block);
-                            //             &self.broadcast_to_network(&mined_block.to_string(), Flag::Block, sender_sockip.to_owned());
-                            //             println!("Chain: {:?}", &self.blocks);
-                            //         }
-                            //     });
-                            // });
-                            
-                        }    
+                        self.notify(notifications);
                     }
-                    Flag::MineTransaction => {
-                        // Verif if transaction are in payload
-
-                        // If true
-                            // 
+                    Err(MempoolError::Invalid(reason)) => {
+                        println!("Invalid transaction received: {}", reason);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::INVALID_TRANSACTION_PENALTY);
                     }
-                    Flag::OkMineTransaction => {
-                        // Verif if transaction are in payload
-                        // If true
-                            // 
+                    Err(e) => println!("Transaction ignored: {}", e),
+                }
+
+                if self.mempool.len() >= BLOCK_PAYLOAD_SIZE {
+                    self.mine_request = Some(sender_sockip);
+                }
+            }
+            Flag::MineTransaction => {
+                // Admin command, only accepted from the machine the Miner runs on
+                println!("MineTransaction Flag received");
+                match stream.peer_addr() {
+                    Ok(addr) if addr.ip().is_loopback() => self.mine_request = Some(sender_sockip),
+                    _ => println!("Mining refused: not sent from localhost"),
+                }
+            }
+            Flag::OkMineTransaction => {
+                // Verif if transaction are in payload
+                // If true
+                    // 
+            }
+            Flag::BroadcastConnect => {
+                println!("BroadcastConnect Flag received");
+                let (new_sockip, new_id_as_str) = match message.split_once(";") {
+                    Some((new_sockip, new_id_as_str)) => (new_sockip.trim_matches(char::from(0)).to_string(), new_id_as_str.trim_matches(char::from(0))),
+                    None => {
+                        println!("Err: invalid BroadcastConnect message");
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::MALFORMED_FRAME_PENALTY);
                     }
-                    Flag::BroadcastConnect => {
-                        println!("BroadcastConnect Flag received");
-                        let splitted: Vec<&str> = message.split(";").collect();
-                        let new_sockip = String::from(splitted[0].to_string().trim_matches(char::from(0)));
-                        let new_id_as_str = String::from(splitted[1].to_string().trim_matches(char::from(0)));
-                        println!("id:{}, sockip:{}", new_id_as_str.to_string(), new_sockip.to_string());
-                        println!("The message is: -{}-", &message);
-                        let new_id = new_id_as_str.parse::<u32>().unwrap();
-                        
-                        if self.add_to_network(new_id, new_sockip.to_string()) {
-                            self.broadcast_to_network(&message, Flag::BroadcastConnect, sender_sockip);
-                        }
+                };
+                println!("id:{}, sockip:{}", new_id_as_str.to_string(), new_sockip.to_string());
+                println!("The message is: -{}-", &message);
+                let new_id = match new_id_as_str.parse::<u32>() {
+                    Ok(new_id) => new_id,
+                    Err(e) => {
+                        println!("Err: {}, invalid BroadcastConnect id", e);
+                        return !self.misbehaving(peer_ip, Some(&peer_key), ban::MALFORMED_FRAME_PENALTY);
                     }
-                    Flag::RequireWalletID => {
-                        println!("Required Wallet ID Flag Received");
-                        let next_id = self.retrieve_next_wallet_id();
-                        match self.send_message(&sender_sockip, &next_id.to_string(), Flag::GiveID) {
-                            Ok(_) => println!("ID Wallet bien envoyé"),
-                            Err(e) => println!("Err: {}", e),
-                        }
-                        self.add_to_wallets(next_id, sender_sockip);
+                };
+                
+                if self.add_to_network(new_id, new_sockip.to_string()) {
+                    self.broadcast_to_network(&message, Flag::BroadcastConnect, sender_sockip);
+                }
+            }
+            Flag::RequireWalletID => {
+                // The address of the wallet is bound to its key, see `NodeIdentity::bind`, so a wallet registering again keeps its ID.
+                // A wallet we do not know, because we restarted since, keeps the ID it sends when no other wallet has it
+                println!("Required Wallet ID Flag Received");
+                let registered = self.wallets.iter().find(|(_, wallet)| *wallet == sender_sockip).map(|(id, _)| *id);
+                if registered.is_none() && self.wallets.len() >= MAX_WALLETS {
+                    println!("Wallet {} refused: we already serve {} wallets", &sender_sockip, MAX_WALLETS);
+                    return true;
+                }
+                let kept = sender_id_as_str.parse::<u32>().ok().filter(|id| *id != 0 && !self.wallets.iter().any(|(wallet_id, _)| wallet_id == id));
+                let next_id = registered.or(kept).unwrap_or_else(|| self.retrieve_next_wallet_id());
+                match self.send_message(&sender_sockip, &next_id.to_string(), Flag::GiveID) {
+                    Ok(_) => println!("ID Wallet bien envoyé"),
+                    Err(e) => println!("Err: {}", e),
+                }
+                self.add_to_wallets(next_id, sender_sockip);
+            }
+            Flag::Subscribe => {
+                // Only the wallets which asked us for their ID are notified
+                println!("Subscribe Flag received");
+                let address = message.trim_matches(|c| c == char::from(0) || c == '\n').to_string();
+                if !self.wallets.iter().any(|(_, wallet)| *wallet == sender_sockip) {
+                    println!("Subscription refused: {} is not one of our wallets", &sender_sockip);
+                } else if let Err(e) = address::check(&address) {
+                    println!("Subscription refused: {}", e);
+                } else {
+                    match self.subscriptions.subscribe(sender_sockip.to_string(), address.to_string()) {
+                        Ok(()) => println!("Wallet {} subscribed to {}", &sender_sockip, &address),
+                        Err(e) => println!("Subscription refused: {}", e),
                     }
-                    Flag::RequireBlockchain => {
-                        println!("Required Blockchain hashes");
-                        for block in self.blocks.iter() {
-                            self.send_message(&sender_sockip, &block.to_string(), Flag::SendBlockchain);
-                        }
+                }
+            }
+            Flag::RequireBlockchain => {
+                // The request is `<from> <to> <max>`, the answer the blocks of the main chain from
+                // height `from` to `to` included, `max` at most, one `SendBlockchain` each, then an `EndBlockchain` with
+                // the number of blocks sent and the height of our chain, so the receiver knows it got all of them
+                println!("RequireBlockchain Flag received");
+                let request: Vec<&str> = message.trim_matches(|c| c == char::from(0) || c == '\n').split(' ').collect();
+                let range = match request.as_slice() {
+                    [from, to, max] => match (from.parse::<u32>(), to.parse::<u32>(), max.parse::<u32>()) {
+                        (Ok(from), Ok(to), Ok(max)) => self.block_range(from, to, max),
+                        _ => 0..0,
+                    },
+                    _ => 0..0,
+                };
+                let sent = range.len();
+                for block in &self.chain.blocks[range] {
+                    if !self.reply(stream, Flag::SendBlockchain, block.to_string(), "blocks") {
+                        return false;
                     }
-                    _ => { println!("Error: flag not recognized"); }
-                } 
-                data = [0 as u8; TRAM_SIZE];
-                true
-            },
-            Ok(_) => { println!("No message received"); false },
+                }
+                let end = format!("{} {}", sent, self.chain.height());
+                self.reply(stream, Flag::EndBlockchain, end, "blocks");
+            }
+            Flag::RequireFeeEstimate => {
+                println!("RequireFeeEstimate Flag received");
+                let estimate = self.mempool.estimate_fees(&self.chain, MAX_BLOCK_SIZE - COINBASE_RESERVED_SIZE);
+                self.reply(stream, Flag::FeeEstimate, estimate.to_string(), "fee estimate");
+            }
+            Flag::RequireBalance => {
+                println!("RequireBalance Flag received");
+                let address = message.trim_matches(|c| c == char::from(0) || c == '\n');
+                let summary = self.mempool.summary(&self.chain, address);
+                self.reply(stream, Flag::Balance, summary.to_string(), "balance");
+            }
+            Flag::RequireChainConfig => {
+                println!("RequireChainConfig Flag received");
+                self.reply(stream, Flag::ChainConfig, self.chain.config.to_string(), "chain parameters");
+            }
+            Flag::RequireUnspent => {
+                println!("RequireUnspent Flag received");
+                let address = message.trim_matches(|c| c == char::from(0) || c == '\n');
+                let unspent = utxo::encode_unspent(&self.mempool.unspent(&self.chain, address));
+                self.reply(stream, Flag::Unspent, unspent, "unspent outputs");
+            }
+            Flag::RequireTransaction => {
+                println!("RequireTransaction Flag received");
+                let status = self.transaction_status(message.trim_matches(|c| c == char::from(0) || c == '\n'));
+                self.reply(stream, Flag::TransactionStatus, status.to_string(), "transaction status");
+            }
+            Flag::RequireHistory => {
+                // The request is `<address> [<skip>]`, the answer is sent in parts, see `protocol::answer_part`
+                println!("RequireHistory Flag received");
+                let (address, skip) = protocol::part_request(message.trim_matches(|c| c == char::from(0) || c == '\n'));
+                let history = protocol::answer_part(self.chain.index.history(address), skip, MAX_HISTORY_PART, ',');
+                self.reply(stream, Flag::History, history, "history");
+            }
+            Flag::RequireHistoryPage => {
+                // The request is `<skip> <count> <address>,<address>...`
+                println!("RequireHistoryPage Flag received");
+                let request: Vec<&str> = message.trim_matches(|c| c == char::from(0) || c == '\n').split(' ').collect();
+                let page = match request.as_slice() {
+                    [skip, count, addresses] => match (skip.parse::<usize>(), count.parse::<usize>()) {
+                        (Ok(skip), Ok(count)) => self.history_page(&addresses.split(',').collect::<Vec<&str>>(), skip, count.min(MAX_HISTORY_PAGE)),
+                        _ => HistoryPage { total: 0, entries: Vec::new() },
+                    },
+                    _ => HistoryPage { total: 0, entries: Vec::new() },
+                };
+                self.reply(stream, Flag::HistoryPage, page.to_string(), "history page");
+            }
+            Flag::RequirePending => {
+                // The request is addresses separated by `,` then `[<skip>]`, the answer is sent in parts
+                println!("RequirePending Flag received");
+                let (addresses, skip) = protocol::part_request(message.trim_matches(|c| c == char::from(0) || c == '\n'));
+                let transactions = self.mempool.transactions_of(&addresses.split(',').collect::<Vec<&str>>());
+                let pending = protocol::answer_part(&transactions, skip, MAX_PENDING_PART, block::TRANSACTION_SEPARATOR);
+                self.reply(stream, Flag::Pending, pending, "pending transactions");
+            }
+            Flag::RequireProof => {
+                // The proof is empty if the transaction is not in our main chain
+                println!("RequireProof Flag received");
+                let txid = message.trim_matches(|c| c == char::from(0) || c == '\n');
+                let proof = self.inclusion_proof(txid).map(|proof| proof.to_string()).unwrap_or_default();
+                self.reply(stream, Flag::Proof, proof, "proof");
+            }
+            Flag::RequirePeers => {
+                // The addresses of the other Miners separated by `,`, wallets use them to take over when we fail
+                println!("RequirePeers Flag received");
+                let peers: Vec<String> = self.network.iter()
+                    .filter(|(_, addr)| *addr != self.sockip)
+                    .map(|(_, addr)| addr.to_string())
+                    .collect();
+                self.reply(stream, Flag::Peers, peers.join(","), "peers");
+            }
+            _ => { println!("Error: flag not recognized"); }
+        } 
+        true
+    }
+
+    /// Answers a request on the connection it came on, where the sender waits for it
+    /// *`what` - what the answer holds, for the error message
+    /// Returns false if the answer could not be sent
+    fn reply(&self, stream: &mut SecureStream, flag: Flag, answer: String, what: &str) -> bool {
+        let m: &[u8] = &encode_message(flag, self.sockip.to_string(), self.id.to_string(), answer);
+        match stream.send(m) {
+            Ok(_) => true,
             Err(e) => {
-                println!("Error occurs, closing connection: {}", e);
-                stream.shutdown(Shutdown::Both).unwrap();
+                println!("Err: {}, while sending {}", e, what);
                 false
             }
         }
-        {}       
+    }
+
+    /// Records that a peer sent us something it should not have, bans it and drops it from the network once it did it too often
+    /// *`ip` - the address the faulty message came from
+    /// *`key` - the public key the peer proved it owns, the Miners whose address is bound to it are dropped from the network
+    /// *`penalty` - how bad the fault is
+    /// Returns true if the peer is now banned
+    pub fn misbehaving(&mut self, ip: IpAddr, key: Option<&[u8]>, penalty: u32) -> bool {
+        if !self.bans.lock().unwrap().misbehaved(ip, key, penalty) {
+            return false;
+        }
+        if let Some(key) = key {
+            let addresses = self.identity.addresses_of(key);
+            let banned: Vec<(u32, String)> = self.network.iter().filter(|(_, addr)| addresses.contains(addr) && *addr != self.sockip).cloned().collect();
+            for (peer_id, addr) in banned {
+                println!("Removing banned node: {},{}", &peer_id, &addr);
+                self.remove_from_network(peer_id, addr);
+            }
+        }
+        true
+    }
+
+    /// Returns the IDs of the Miners of our network whose address is bound to `key`, see `NodeIdentity::bind`
+    fn network_ids_of(&self, key: &[u8]) -> Vec<u32> {
+        let addresses = self.identity.addresses_of(key);
+        self.network.iter().filter(|(_, addr)| addresses.contains(addr)).map(|(id, _)| *id).collect()
     }
 
     /// Function to add a Miner to the network
@@ -627,106 +1012,168 @@ impl Miner {
     /// `peed_addr` - the socket of the Miner we want to remove from the network
     /// Update the current Miner's network, returns true if the Miner was deleted from the newtork, false if the Miner wasn't in the network
     pub fn remove_from_network(&mut self, peer_id: u32, peer_addr: String) -> bool {
+        self.peers.remove(&peer_id);
         self.network.remove(&(peer_id, peer_addr))
     }
     
     /// Function to listen for incoming Streams from the network
-    /// Read the stream and spawn a thread to handle the received data
-    pub fn listen(mut self) {
+    /// Read the stream and handle the received data, while a separate thread checks the health of the network every `REFRESH_TIME` seconds
+    /// Returns once `running` is set to false, after the Miner has saved its state and told its peers it is leaving
+    /// Returns the Miner once it shut down
+    pub fn listen(mut self) -> Arc<Mutex<Miner>> {
         println!("Server listening on port {}", &self.sockip);
-        let mut init_time = Instant::now();
-        let listener = TcpListener::bind(&self.sockip).unwrap();
-        // accept connections and process them, spawning a new thread for each one
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("New connection: {}", &stream.peer_addr().unwrap());  
-                    &self.handle_client(stream);
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => match self.transport.bind(&self.sockip) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    println!("Err: {}, could not listen on {}", e, &self.sockip);
+                    self.shutdown();
+                    return Arc::new(Mutex::new(self));
+                }
+            },
+        };
+        let running = Arc::clone(&self.running);
+        let identity = Arc::clone(&self.identity);
+        let bans = Arc::clone(&self.bans);
+        let slots = ConnectionSlots::new(self.limits.trusts_loopback());
+        println!("Node public key: {}", identity.public_key());
+        let miner = Arc::new(Mutex::new(self));
+
+        let monitored = Arc::clone(&miner);
+        let monitor_running = Arc::clone(&running);
+        let monitor = std_thread::spawn(move || {
+            let mut last_refresh = Instant::now();
+            while monitor_running.load(Ordering::SeqCst) {
+                std_thread::sleep(Duration::from_millis(ACCEPT_POLL_TIME));
+                if last_refresh.elapsed().as_secs() >= REFRESH_TIME {
+                    Miner::refresh_nodes_status(&monitored);
+                    monitored.lock().unwrap().expire_transactions();
+                    last_refresh = Instant::now();
+                }
+            }
+        });
+
+        while running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok(Some((stream, addr))) => {
+                    println!("New connection: {}", &addr);
+                    // Each connection gets its own thread, the handshake and the reads are done without the lock
+                    // so two Miners sending to each other at the same time do not wait for one another.
+                    // The accept loop itself never takes the lock, a Miner busy broadcasting must still accept connections.
+                    // The number of threads is capped, overall and per address, the slot being given back when the thread ends
+                    let slot = match slots.acquire(addr.ip()) {
+                        Some(slot) => slot,
+                        None => {
+                            println!("Connection refused: too many connections open, from {} or overall", &addr);
+                            stream.shutdown();
+                            continue;
+                        }
+                    };
+                    let miner = Arc::clone(&miner);
+                    let identity = Arc::clone(&identity);
+                    let bans = Arc::clone(&bans);
+                    std_thread::spawn(move || {
+                        let _slot = slot;
+                        if bans.lock().unwrap().is_address_banned(&addr.ip()) {
+                            println!("Connection refused: {} is banned", &addr);
+                            stream.shutdown();
+                            return;
+                        }
+                        match secure::accept(stream, &identity) {
+                            Ok(stream) => Miner::handle_client(&miner, stream),
+                            Err(e) => println!("Handshake with {} failed: {}", &addr, e),
+                        }
+                    });
+                }
+                Ok(None) => {
+                    std_thread::sleep(Duration::from_millis(ACCEPT_POLL_TIME));
                 }
                 Err(e) => {
                     println!("Error: {}", e);
                     /* connection failed */
                 }
             }
-            println!("Time spend: {}",&init_time.elapsed().as_secs());
-            if init_time.elapsed().as_secs() >= REFRESH_TIME {
-                println!("Check time spend");
-                init_time = Instant::now();
-                &self.refresh_nodes_status();
-            }
-            self.display_network();
         }
         // close the socket server
         println!("Closing listener");
         drop(listener);
+        if monitor.join().is_err() {
+            println!("Healthcheck thread panicked");
+        }
+        miner.lock().unwrap().shutdown();
+        miner
     }
 
-    /// Used by Miners to ask the Miner to which it is connecting to give us our ID
-    /// *`socket` - our IP address
-    /// *`destination` - the IP address of the Miner we are asking for an ID
-    pub fn ask_for_id(socket: &String, destination: &String) -> u32 {
-        println!("Asking {} for id...", &destination);
-        let listener = TcpListener::bind(&socket).unwrap();
-        let mut id: u32 = 0;
-        // Ask the ID
-        if let Ok(mut stream) = TcpStream::connect(&destination) {
-            let m: &[u8] = &encode_message(Flag::RequireID, socket.to_string(), "".to_string(), "".to_string());
-            match stream.write(m) {
-                Ok(_) => { println!("Asked for id"); }
-                Err(e) => { println!("Error: {}", e); }
+    /// Leaves the network cleanly: saves the chain and the pending transactions, then tells every peer we are leaving
+    pub fn shutdown(&mut self) {
+        println!("Shutting down Miner {}", &self.id);
+        self.save_state();
+        let peers: Vec<String> = self.network.iter()
+            .filter(|(id, _)| *id != self.id)
+            .map(|(_, addr)| addr.to_string())
+            .collect();
+        for peer_addr in peers {
+            match self.send_message(&peer_addr, &String::new(), Flag::Disconnect) {
+                Ok(_) => println!("Disconnect sent to {}", &peer_addr),
+                Err(e) => println!("Err: {}", e),
             }
-            println!("Message sended");
         }
-        // Handle the response
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("Getting Miner ID from Genesis");
-                    id = handle_id(stream);
-                    println!("My ID is {}.", &id);
-                    return id;
+        println!("Miner {} stopped", &self.id);
+    }
+
+    /// Saves the blockchain and the pending transactions in the data directory of this Miner
+    /// Each line is either `block <block>` or `tx <transaction>`
+    pub fn save_state(&self) {
+        let mut lines: Vec<String> = self.chain.blocks.iter().map(|b| format!("block {}", b)).collect();
+        lines.extend(self.mempool.transactions().iter().map(|t| format!("tx {}", t)));
+        let path = storage::node_file(&self.sockip, "state");
+        match storage::write_lines(&path, &lines) {
+            Ok(_) => println!("State saved to {}", path.display()),
+            Err(e) => println!("Err: {}, while saving state", e),
+        }
+    }
+
+    /// Restores the blockchain and the pending transactions saved by a previous run of this Miner, if any
+    pub fn load_state(&mut self) {
+        let path = storage::node_file(&self.sockip, "state");
+        let lines = match storage::read_lines(&path) {
+            Ok(lines) => lines,
+            Err(e) => {
+                println!("Err: {}, while loading state", e);
+                return;
+            }
+        };
+        for line in lines {
+            if let Some(encoded_block) = line.strip_prefix("block ") {
+                match block::Block::from_str(encoded_block).map_err(ChainError::Invalid).and_then(|b| self.chain.add_block(b)) {
+                    Ok(_) => {}
+                    Err(e) => println!("Err: {:?}, invalid block in state file", e),
                 }
-                Err(e) => {
-                    println!("Error: {}", e);
-                    return 0;
+            } else if let Some(transaction) = line.strip_prefix("tx ") {
+                if let Err(e) = self.mempool.insert(transaction.to_string(), &self.chain) {
+                    println!("Err: {}, transaction of the state file dropped", e);
                 }
             }
         }
-        return id;
+        println!("State loaded: {} blocks, {} pending transactions", self.chain.height(), self.mempool.len());
+    }
+
+    /// Used by Miners to ask the Miner to which it is connecting to give us our ID
+    /// *`socket` - our IP address
+    /// *`destination` - the IP address of the Miner we are asking for an ID
+    pub fn ask_for_id(transport: &dyn Transport, socket: &str, destination: &str) -> u32 {
+        ask_for_id(transport, socket, destination)
     } 
 
     /// Used by wallets to ask the Miner to which it is connecting to give us our ID
     /// *`socket` - our IP address
     /// *`destination` - the IP address of the Miner we are asking for an ID
-    pub fn ask_miner_for_wallet_id(socket: &String, miner: &String) -> u32 {
+    pub fn ask_miner_for_wallet_id(transport: &dyn Transport, socket: &str, miner: &str) -> u32 {
         println!("Asking {} for wallet ID", &miner);
-        let listener = TcpListener::bind(&socket).unwrap();
-        let mut id: u32 = 0;
-        // Ask the ID
-        if let Ok(mut stream) = TcpStream::connect(&miner) {
-            let m: &[u8] = &encode_message(Flag::RequireWalletID, socket.to_string(), "".to_string(), "".to_string());
-            match stream.write(m) {
-                Ok(_) => { println!("Asked for id"); }
-                Err(e) => { println!("Error: {}", e); }
-            }
-            println!("Message sended, wallet added to miner's wallet list");
-        }
-        // Handle the response
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("Getting Wallet ID from Miner");
-                    id = handle_id(stream);
-                    println!("My ID is {}", &id);
-                    return id;
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                    return 0;
-                }
-            }
-        }
-        return id;
+        let id = ask_and_wait_answer(transport, socket, miner, Flag::RequireWalletID).map(|answer| parse_id(&answer)).unwrap_or(0);
+        println!("My ID is {}", &id);
+        id
     }
 
     /// Simple function to add a new wallet to our Miner's wallet list
@@ -736,124 +1183,292 @@ impl Miner {
         self.wallets.insert((peer_id, peer_addr))
     }    
     /// Function to refresh all nodes status and remove those are not accessible
-    pub fn refresh_nodes_status(&mut self){
+    /// *`miner` - the shared Miner, only locked while reading the network and while recording the results so that
+    /// incoming messages keep being handled during the pings
+    pub fn refresh_nodes_status(miner: &Arc<Mutex<Miner>>) {
         println!("Refresh node status");
-        let nodes: &HashSet<(u32,String)> = &self.network.to_owned();
-        for (id,addr) in nodes {
-            if id != &self.id {
-                println!("\n node: {}",&id);
-                &self.health_check(&addr, &id);
+        let (sockip, id, nodes, identity, transport) = {
+            let miner = miner.lock().unwrap();
+            (miner.sockip.to_string(), miner.id, miner.network.to_owned(), Arc::clone(&miner.identity), Arc::clone(&miner.transport))
+        };
+        let mut results = Vec::new();
+        for (peer_id, addr) in nodes {
+            if peer_id != id {
+                println!("\n node: {}", &peer_id);
+                results.push((peer_id, addr.to_string(), Miner::health_check(transport.as_ref(), &sockip, id, &addr, &identity)));
+            }
+        }
+
+        let mut miner = miner.lock().unwrap();
+        for (peer_id, addr, result) in results {
+            let status = miner.peers.entry(peer_id).or_default();
+            match result {
+                Ok(rtt) => {
+                    println!("Ok healthcheck: {} answered in {:?}", &peer_id, rtt);
+                    status.record_success(rtt);
+                }
+                Err(HealthCheckError::Unreachable(e)) => {
+                    println!("HealthCheck failed: {}", e);
+                    status.record_failure();
+                }
+                Err(HealthCheckError::BadAck) => {
+                    println!("HealthCheck failed: wrong Ack from {}", &peer_id);
+                    status.record_failure();
+                    status.record_misbehaviour(peer::MISBEHAVIOUR_PENALTY);
+                }
+            }
+            if status.should_evict() {
+                println!("Removing node: {},{}", &peer_id, &addr);
+                miner.remove_from_network(peer_id, addr);
+                println!("node removed");
             }
         }
     }
 
     /// health_check
-    /// ping the destination. If it doesn't respond ok or at time, removing the destination node from network
-    /// 
-    pub fn health_check(&mut self, destination: &String, id: &u32) -> Result<u8,&'static str>{
-        let result = &self.send_message(destination, &String::from(""), Flag::Check);  
-         
-        match result{
-            Ok(code) => { println!("Ok healthcheck: {}", code); }
-            Err(error) => {
-                println!("HealthCheck failed: {}", &error);
-                println!("Removing node: {},{}", &id, &destination);
-                &self.remove_from_network(*id, String::from(destination));
-                println!("node removed");
+    /// Ping the destination with a random nonce and wait for the Ack carrying the same nonce on the same connection
+    /// *`sockip` - our IP address, sent along with the ping
+    /// *`id` - our ID
+    /// *`destination` - the IP address of the Miner to ping
+    /// *`identity` - our identity, used to open the authenticated connection
+    /// Returns the measured round-trip time, or why the ping failed
+    pub fn health_check(transport: &dyn Transport, sockip: &str, id: u32, destination: &str, identity: &NodeIdentity) -> Result<Duration, HealthCheckError> {
+        let nonce: u64 = rand::random();
+        let start = Instant::now();
+        let mut stream = secure::connect(transport, destination, identity).map_err(|e| HealthCheckError::Unreachable(e.to_string()))?;
+        stream.set_timeout(Some(peer::READ_TIMEOUT)).map_err(|e| HealthCheckError::Unreachable(e.to_string()))?;
+        let m: &[u8] = &encode_message(Flag::Check, sockip.to_string(), id.to_string(), nonce.to_string());
+        stream.send(m).map_err(|e| HealthCheckError::Unreachable(e.to_string()))?;
+
+        let data = stream.recv().map_err(|e| HealthCheckError::Unreachable(e.to_string()))?;
+        let rtt = start.elapsed();
+        let data = data.ok_or_else(|| HealthCheckError::Unreachable("connection closed before Ack".to_string()))?;
+        let (flag, _, _, message) = decode_message(&data).map_err(|_| HealthCheckError::BadAck)?;
+        let answered_nonce = message.trim_matches(|c| c == char::from(0) || c == '\n');
+        match flag {
+            Flag::Ack if answered_nonce == nonce.to_string() => Ok(rtt),
+            _ => Err(HealthCheckError::BadAck),
+        }
+    }
+
+    /// Lowers the score of a peer which sent us something it should not have, and evicts it once its score is exhausted
+    /// *`peer_id` - the ID of the misbehaving Miner
+    /// *`penalty` - how many points it loses
+    pub fn penalize_peer(&mut self, peer_id: u32, penalty: i32) {
+        if peer_id == self.id {
+            return;
+        }
+        let status = self.peers.entry(peer_id).or_default();
+        status.record_misbehaviour(penalty);
+        if status.should_evict() {
+            let evicted: Vec<(u32, String)> = self.network.iter().filter(|(id, _)| *id == peer_id).cloned().collect();
+            for (id, addr) in evicted {
+                println!("Removing misbehaving node: {},{}", &id, &addr);
+                self.remove_from_network(id, addr);
             }
+        }
+    }
 
+    /// Drops the transactions which waited too long in the mempool and tells their wallets
+    pub fn expire_transactions(&mut self) {
+        self.mempool.expire();
+        let notifications = self.subscriptions.mempool_changed(&self.mempool);
+        self.notify(notifications);
+    }
+
+    /// Queues the notifications for the subscribed wallets, sent by the `notifier` thread once we released our lock
+    /// The wallets it could not reach since the last call are unsubscribed first
+    /// *`notifications` - the events to send, with the IP address of the wallet to send each of them to
+    pub fn notify(&mut self, notifications: Vec<(String, TransactionEvent)>) {
+        for wallet in self.notifier.unreachable() {
+            println!("Wallet {} unsubscribed", &wallet);
+            self.subscriptions.unsubscribe(&wallet);
         }
-        Ok(0)
+        self.notifier.send(notifications);
     }
 
-    /// Function to check if the received block is valid
-    /// 
-    /// *`block` the received block
-    /// Return true if the block is valid, false else
-    pub fn check_block(&self, block: block::Block) -> bool {
-        // Verif hash 
-        let last_block: &block::Block = &self.blocks.last().unwrap();
-        println!("Last block: {:?}", last_block);
-        println!("Received block: {:?}", block);
-        if &last_block.index == &(block.index+1) && &last_block.payload == &block.payload && &last_block.hash == &last_block.prev_hash {
-            // hash
-            return true
-        } else {
-            return false
+    /// Looks a transaction up in the main chain, then in the mempool
+    pub fn transaction_status(&self, transaction_id: &str) -> TransactionStatus {
+        if let Some((location, confirmations)) = self.chain.locate(transaction_id) {
+            let block = &self.chain.blocks[location.height as usize];
+            if let Some(transaction) = block.transactions().into_iter().nth(location.position as usize) {
+                return TransactionStatus::Confirmed { location, confirmations, transaction };
+            }
+        }
+        match self.mempool.get(transaction_id) {
+            Some(entry) => TransactionStatus::Pending(entry.transaction.clone()),
+            None => TransactionStatus::Unknown,
         }
     }
 
-    /// Function to mine block
-    /// *`transaction` List of transactions to mine
-    /// *`MINING_DIFFICULTY` use 
-    /// *Return* the mined block
-    pub fn hash_block(&self, transactions: String) -> block::Block{
+    /// Returns a page of the transactions of our main chain sending to or from one of `addresses`, newest first
+    /// *`skip` - the number of newer transactions, on the previous pages
+    /// *`count` - the size of the page
+    pub fn history_page(&self, addresses: &[&str], skip: usize, count: usize) -> HistoryPage {
+        let mut located: Vec<(TransactionLocation, &String)> = addresses.iter()
+            .flat_map(|address| self.chain.index.history(address))
+            .filter_map(|txid| self.chain.index.transaction(txid).map(|location| (location, txid)))
+            .collect();
+        located.sort_by_key(|(location, _)| Reverse((location.height, location.position)));
+        located.dedup_by_key(|(location, _)| *location);
+        HistoryPage {
+            total: located.len() as u32,
+            entries: located.iter().skip(skip).take(count).map(|(_, txid)| self.transaction_status(txid)).collect(),
+        }
+    }
+
+    /// Proves that a transaction is in our main chain: its Merkle branch and the header of its block
+    /// Returns None if the transaction is not in our main chain
+    pub fn inclusion_proof(&self, transaction_id: &str) -> Option<InclusionProof> {
+        let (location, _) = self.chain.locate(transaction_id)?;
+        let block = self.chain.blocks.get(location.height as usize)?;
+        let branch = spv::merkle_branch(&block.transaction_ids(), location.position as usize)?;
+        Some(InclusionProof { branch, header: block.header() })
+    }
+
+    /// Returns the heights of the blocks of the main chain sent for a `RequireBlockchain`
+    /// *`from` - the first height
+    /// *`to` - the last height, included
+    /// *`max` - the most blocks sent, `MAX_BLOCKS_PER_REQUEST` at most
+    pub fn block_range(&self, from: u32, to: u32, max: u32) -> std::ops::Range<usize> {
+        let from = (from as usize).min(self.chain.height());
+        let count = max.min(MAX_BLOCKS_PER_REQUEST) as usize;
+        let end = (to as usize).saturating_add(1).min(from + count).min(self.chain.height());
+        from..end.max(from)
+    }
+
+    /// Sums what the coinbases of the main chain paid to our `payout` address
+    /// Returns the amounts which are mature (spendable) and those still waiting `coinbase_maturity` blocks
+    pub fn rewards(&self) -> (u64, u64) {
+        let height = self.chain.height() as u32;
+        let mut rewards = (0_u64, 0_u64);
+        for block in &self.chain.blocks {
+            if let Some(Ok(Transaction::Coinbase { height: coinbase_height, to, amount })) = block.transactions().first().map(|t| Transaction::from_str(t)) {
+                if to != self.payout {
+                    continue;
+                }
+                if self.chain.config.is_mature(coinbase_height, height) {
+                    rewards.0 = rewards.0.saturating_add(amount);
+                } else {
+                    rewards.1 = rewards.1.saturating_add(amount);
+                }
+            }
+        }
+        rewards
+    }
+
+    /// Adds a block to our chain, then updates the mempool with the blocks connected and disconnected
+    /// Returns the number of blocks which joined the main chain, 0 if the block went to a shorter branch
+    pub fn accept_block(&mut self, block: block::Block) -> Result<usize, ChainError> {
+        let events = self.chain.add_block(block)?;
+        self.mempool.apply(&events, &self.chain);
+        let notifications = self.subscriptions.chain_changed(&events, &self.chain, &self.mempool);
+        self.notify(notifications);
+        let connected = events.iter().filter(|e| matches!(e, ChainEvent::Connected(_))).count();
+        if connected < events.len() {
+            println!("Chain reorganised: {} blocks disconnected, {} connected", events.len() - connected, connected);
+        }
+        println!("Chain height: {}, {} transactions waiting", self.chain.height(), self.mempool.len());
+        Ok(connected)
+    }
+
+    /// Mines a block with the transactions paying the highest fee rates in another thread, then adds it to our chain and
+    /// sends it to the network. Our lock is only held to build the block and to add it, not during the proof of work, and
+    /// nothing is done if a block is already being mined
+    /// *`miner` - the shared Miner
+    /// *`sender` - the node which sent the last transaction, it already knows the transactions but not the block
+    pub fn mine_block(miner: &Arc<Mutex<Miner>>, sender: String) {
+        let mining = Arc::clone(&miner.lock().unwrap().mining);
+        if mining.swap(true, Ordering::SeqCst) {
+            println!("Already mining, the transactions wait for the next block");
+            return;
+        }
+        let miner = Arc::clone(miner);
+        std_thread::spawn(move || {
+            let (block, difficulty, running) = {
+                let miner = miner.lock().unwrap();
+                (miner.block_to_mine(), miner.chain.config.difficulty, Arc::clone(&miner.running))
+            };
+            match Miner::hash_block(block, difficulty, &running) {
+                Some(mined_block) => {
+                    println!("Mined block: {:?}", &mined_block);
+                    let mut miner = miner.lock().unwrap();
+                    match miner.accept_block(mined_block.clone()) {
+                        Ok(_) => {
+                            let (mature, immature) = miner.rewards();
+                            println!("Rewards of {}: {} spendable, {} maturing", &miner.payout, mature, immature);
+                            miner.broadcast_to_network(&mined_block.to_string(), Flag::Block, sender)
+                        }
+                        Err(e) => println!("Err: {:?}, mined block refused", e),
+                    }
+                }
+                None => println!("Mining aborted, transactions kept for the next start"),
+            }
+            mining.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Builds the block following the tip of our main chain, its proof of work not done yet
+    /// The block starts with a coinbase paying the block subsidy and the fees to our `payout` address, followed by the
+    /// transactions paying the highest fee rates. When no transaction can be mined, the block only holds the coinbase
+    pub fn block_to_mine(&self) -> block::Block {
+        if self.mempool.is_empty() {
+            println!("No transaction waiting, the block only holds the coinbase");
+        }
+        let (transactions, fees) = self.mempool.block_template(&self.chain, MAX_BLOCK_SIZE - COINBASE_RESERVED_SIZE);
+        let height = self.chain.height() as u32;
+        let amount = self.chain.config.block_subsidy(height).saturating_add(fees);
+        let coinbase = Transaction::Coinbase { height, to: self.payout.clone(), amount };
+        let payload = std::iter::once(coinbase.to_string()).chain(transactions).collect::<Vec<String>>()
+            .join(&block::TRANSACTION_SEPARATOR.to_string());
+        println!("Payload to mine: {}", payload);
 
         let start = SystemTime::now();
         let since_the_epoch = start
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
         let timestamp_ms = since_the_epoch.as_millis();
-        let nonce: u64 = 5;
-        
-        let index_: u32;
-        let timestamp_: u128 = timestamp_ms;
-        let payload_: String = transactions;
-        let nonce_: u64 = nonce;
-        let previous_hash_: String;
-        
-        let last_block: &block::Block;
-        match self.blocks.last() {
-            Some(block) => {
-                last_block = block;
-                index_ = last_block.index + 1;
-                previous_hash_ = hex::encode(&last_block.hash);
-            }
-            _ => {
-                // Genesis
-                index_ = 0;
-                let mut sha256 = Sha256::new();
-                sha256.update("first_block");
-                let first_hash: String = format!("{:x}", sha256.finalize());
-                println!("first_hash: {}",first_hash);
-                previous_hash_ = first_hash;
-            }
-        } 
-        
-        let mut to_hash;
-        let mut sha256;
-        let mut hash: String = String::from("");
+
+        let (index, prev_hash) = match self.chain.tip() {
+            Some(last_block) => (last_block.index + 1, last_block.hash.clone()),
+            // Genesis
+            None => (0, block::genesis_prev_hash()),
+        };
+        block::Block::new(index, payload, timestamp_ms, 0, prev_hash)
+    }
+
+    /// Function to mine block
+    /// *`block` - the block to mine, see `block_to_mine`
+    /// *`difficulty` - the hash must start with as many zeros
+    /// *`running` - set to false when the Miner is asked to shut down
+    /// *Return* the mined block, or None if the Miner was asked to shut down while mining
+    pub fn hash_block(mut block: block::Block, difficulty: usize, running: &AtomicBool) -> Option<block::Block> {
+        // Only the header is hashed, the Merkle root of the transactions is computed once
+        let mut header = block.header();
         for nonce_i in 0..(u64::max_value()) {
-            to_hash = index_.to_string() + &payload_ + &timestamp_.to_string() + &nonce_i.to_string() + &previous_hash_;
-            
-            sha256 = Sha256::new();
-            sha256.update(to_hash);
-            hash = format!("{:x}", sha256.finalize());
-            
-            
-            if hash.starts_with(&"0".repeat(MINING_DIFFICULTY)) {
+            if !running.load(Ordering::SeqCst) {
+                return None;
+            }
+            header.nonce = nonce_i;
+            let hash = header.compute_hash();
+            if hash.starts_with(&"0".repeat(difficulty)) {
                 println!("new_hash: {}",&hash);
-                return block::Block{
-                    index: index_, 
-                    payload: payload_, 
-                    timestamp: timestamp_, 
-                    nonce: nonce_i, 
-                    prev_hash: previous_hash_.as_bytes().to_vec(), 
-                    hash: hash.as_bytes().to_vec()
-                };
+                block.nonce = nonce_i;
+                block.hash = hash.into_bytes();
+                return Some(block);
             }
         }
-        /// Dead code, normaly upside code find hash before u64::max_value iteration.
-        return block::Block{
-            index: index_, 
-            payload: payload_, 
-            timestamp: timestamp_, 
-            nonce: nonce_, 
-            prev_hash: previous_hash_.as_bytes().to_vec(), 
-            hash: hash.as_bytes().to_vec()
-        };
+        None
     }
+}
 
+/// Why a healthcheck failed
+#[derive(Debug)]
+pub enum HealthCheckError {
+    /// The peer could not be reached or did not answer before the timeout
+    Unreachable(String),
+    /// The peer answered something other than an Ack carrying our nonce
+    BadAck,
 }
 
 impl Debug for Miner {
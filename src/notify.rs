@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use crate::block;
+use crate::chain::{Chain, ChainEvent};
+use crate::mempool::Mempool;
+use crate::protocol::{Flag, encode_message};
+use crate::secure::{self, NodeIdentity};
+use crate::transaction::Transaction;
+use crate::transport::Transport;
+
+/// Number of confirmations after which the wallets are told a transaction is settled, and it stops being followed
+pub const SETTLED_CONFIRMATIONS: u32 = 6;
+/// Most addresses one wallet can subscribe to
+pub const MAX_ADDRESSES_PER_WALLET: usize = 100;
+/// Most addresses subscribed to, all wallets together
+pub const MAX_SUBSCRIBED_ADDRESSES: usize = 10_000;
+/// Most notifications waiting to be sent, the next ones are dropped until the queue empties
+const MAX_QUEUED_NOTIFICATIONS: usize = 1024;
+
+/// What happened to a transaction, pushed by a Miner to the wallets of its addresses
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionEvent {
+    /// The transaction entered the mempool
+    Pending(String),
+    /// The transaction was included in the block of index `height` of the main chain
+    Included { txid: String, height: u32 },
+    /// The transaction has `confirmations` blocks on top of its own one, counting it
+    Confirmed { txid: String, confirmations: u32 },
+    /// The transaction left the mempool without being mined: expired, evicted or replaced
+    Dropped(String),
+    /// The block including the transaction left the main chain
+    Reorged(String),
+}
+
+impl TransactionEvent {
+
+    /// Returns the sentence shown to the user of the wallet
+    pub fn describe(&self) -> String {
+        match self {
+            TransactionEvent::Pending(txid) => format!("transaction {} is waiting in the mempool", txid),
+            TransactionEvent::Included { txid, height } => format!("transaction {} was included in block {}", txid, height),
+            TransactionEvent::Confirmed { txid, confirmations } => format!("transaction {} has {} confirmations", txid, confirmations),
+            TransactionEvent::Dropped(txid) => format!("transaction {} was dropped from the mempool", txid),
+            TransactionEvent::Reorged(txid) => format!("transaction {} left the main chain after a reorganisation", txid),
+        }
+    }
+}
+
+impl fmt::Display for TransactionEvent {
+    /// Writes `pending <txid>`, `included <txid> <height>`, `confirmed <txid> <confirmations>`, `dropped <txid>` or `reorged <txid>`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionEvent::Pending(txid) => write!(f, "pending {}", txid),
+            TransactionEvent::Included { txid, height } => write!(f, "included {} {}", txid, height),
+            TransactionEvent::Confirmed { txid, confirmations } => write!(f, "confirmed {} {}", txid, confirmations),
+            TransactionEvent::Dropped(txid) => write!(f, "dropped {}", txid),
+            TransactionEvent::Reorged(txid) => write!(f, "reorged {}", txid),
+        }
+    }
+}
+
+impl FromStr for TransactionEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let number = |i: usize| parts[i].parse::<u32>().map_err(|e| format!("invalid transaction event {}: {}", s, e));
+        match (parts.first().copied(), parts.len()) {
+            (Some("pending"), 2) => Ok(TransactionEvent::Pending(parts[1].to_string())),
+            (Some("included"), 3) => Ok(TransactionEvent::Included { txid: parts[1].to_string(), height: number(2)? }),
+            (Some("confirmed"), 3) => Ok(TransactionEvent::Confirmed { txid: parts[1].to_string(), confirmations: number(2)? }),
+            (Some("dropped"), 2) => Ok(TransactionEvent::Dropped(parts[1].to_string())),
+            (Some("reorged"), 2) => Ok(TransactionEvent::Reorged(parts[1].to_string())),
+            _ => Err(format!("invalid transaction event {}", s)),
+        }
+    }
+}
+
+/// The wallets to notify, and the transactions followed for them
+/// *`wallets` - the IP addresses of the wallets subscribed to each address
+/// *`followed` - the transactions of subscribed addresses which are not settled yet, with the wallets to notify
+/// and the height of the block including them, None while they wait in the mempool
+pub struct Subscriptions {
+    wallets: HashMap<String, HashSet<String>>,
+    followed: HashMap<String, (HashSet<String>, Option<u32>)>,
+}
+
+impl Subscriptions {
+
+    /// CONSTRUCTOR
+    /// Returns subscriptions without any wallet
+    pub fn new() -> Self {
+        Subscriptions {
+            wallets: HashMap::new(),
+            followed: HashMap::new(),
+        }
+    }
+
+    /// Notifies `wallet` of the transactions sending to or from `address` from now on
+    /// Fails if the wallet, or all the wallets together, already subscribed to as many addresses as allowed
+    pub fn subscribe(&mut self, wallet: String, address: String) -> Result<(), String> {
+        if self.wallets.get(&address).is_some_and(|wallets| wallets.contains(&wallet)) {
+            return Ok(());
+        }
+        if self.wallets.values().filter(|wallets| wallets.contains(&wallet)).count() >= MAX_ADDRESSES_PER_WALLET {
+            return Err(format!("{} already follows {} addresses", wallet, MAX_ADDRESSES_PER_WALLET));
+        }
+        if !self.wallets.contains_key(&address) && self.wallets.len() >= MAX_SUBSCRIBED_ADDRESSES {
+            return Err(format!("{} addresses are already followed", MAX_SUBSCRIBED_ADDRESSES));
+        }
+        self.wallets.entry(address).or_default().insert(wallet);
+        Ok(())
+    }
+
+    /// Stops notifying `wallet`, usually because it can not be reached anymore
+    pub fn unsubscribe(&mut self, wallet: &str) {
+        for wallets in self.wallets.values_mut() {
+            wallets.remove(wallet);
+        }
+        self.wallets.retain(|_, wallets| !wallets.is_empty());
+        for (wallets, _) in self.followed.values_mut() {
+            wallets.remove(wallet);
+        }
+        self.followed.retain(|_, (wallets, _)| !wallets.is_empty());
+    }
+
+    /// Returns the wallets subscribed to one of the addresses of `transaction`
+    fn subscribers(&self, transaction: &Transaction) -> HashSet<String> {
+        transaction.addresses().iter()
+            .filter_map(|address| self.wallets.get(address))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Follows a transaction which entered the mempool
+    /// Returns the notifications to send, with the wallet to send each of them to
+    pub fn transaction_added(&mut self, transaction: &Transaction) -> Vec<(String, TransactionEvent)> {
+        let wallets = self.subscribers(transaction);
+        if wallets.is_empty() {
+            return Vec::new();
+        }
+        let txid = transaction.id();
+        let notifications = wallets.iter().map(|wallet| (wallet.clone(), TransactionEvent::Pending(txid.clone()))).collect();
+        self.followed.insert(txid, (wallets, None));
+        notifications
+    }
+
+    /// Follows the changes of the main chain, once the mempool followed them too
+    /// The transactions of disconnected blocks are reorged, those of connected blocks included, and those
+    /// reaching `SETTLED_CONFIRMATIONS` confirmed then forgotten
+    /// Returns the notifications to send, with the wallet to send each of them to
+    pub fn chain_changed(&mut self, events: &[ChainEvent], chain: &Chain, mempool: &Mempool) -> Vec<(String, TransactionEvent)> {
+        let mut notifications = Vec::new();
+        for event in events {
+            match event {
+                ChainEvent::Disconnected(block) => {
+                    for transaction in block.transactions() {
+                        let txid = block::transaction_id(&transaction);
+                        if let Some((wallets, height)) = self.followed.get_mut(&txid) {
+                            *height = None;
+                            notifications.extend(wallets.iter().map(|wallet| (wallet.clone(), TransactionEvent::Reorged(txid.clone()))));
+                        }
+                    }
+                }
+                ChainEvent::Connected(block) => {
+                    for transaction in block.transactions() {
+                        let parsed = match Transaction::from_str(&transaction) {
+                            Ok(parsed) => parsed,
+                            Err(_) => continue,
+                        };
+                        let wallets = self.subscribers(&parsed);
+                        if wallets.is_empty() {
+                            continue;
+                        }
+                        let txid = parsed.id();
+                        notifications.extend(wallets.iter().map(|wallet| (wallet.clone(), TransactionEvent::Included { txid: txid.clone(), height: block.index })));
+                        self.followed.insert(txid, (wallets, Some(block.index)));
+                    }
+                }
+            }
+        }
+
+        let mut settled = Vec::new();
+        for (txid, (wallets, height)) in &self.followed {
+            if height.is_none() {
+                continue;
+            }
+            if let Some((_, confirmations)) = chain.locate(txid) {
+                if confirmations >= SETTLED_CONFIRMATIONS {
+                    notifications.extend(wallets.iter().map(|wallet| (wallet.clone(), TransactionEvent::Confirmed { txid: txid.clone(), confirmations })));
+                    settled.push(txid.clone());
+                }
+            }
+        }
+        for txid in settled {
+            self.followed.remove(&txid);
+        }
+        notifications.extend(self.mempool_changed(mempool));
+        notifications
+    }
+
+    /// Forgets the followed transactions which left the mempool without reaching the main chain
+    /// Returns the notifications to send, with the wallet to send each of them to
+    pub fn mempool_changed(&mut self, mempool: &Mempool) -> Vec<(String, TransactionEvent)> {
+        let dropped: Vec<String> = self.followed.iter()
+            .filter(|(txid, (_, height))| height.is_none() && mempool.get(txid).is_none())
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        let mut notifications = Vec::new();
+        for txid in dropped {
+            if let Some((wallets, _)) = self.followed.remove(&txid) {
+                notifications.extend(wallets.into_iter().map(|wallet| (wallet, TransactionEvent::Dropped(txid.clone()))));
+            }
+        }
+        notifications
+    }
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::new()
+    }
+}
+
+/// Sends the notifications to the wallets on its own thread, so the Miner never waits for a slow or unreachable
+/// wallet while it holds its lock
+/// *`queue` - the notifications waiting to be sent, with the IP address of the wallet to send each of them to
+/// *`unreachable` - the wallets the thread could not reach, to be unsubscribed by the Miner
+pub struct Notifier {
+    queue: SyncSender<(String, TransactionEvent)>,
+    unreachable: Mutex<Receiver<String>>,
+}
+
+impl Notifier {
+
+    /// Starts the thread sending the notifications, it stops once the Notifier is dropped
+    /// *`transport` - how the wallets are reached
+    /// *`identity` - the identity of the Miner, proven to the wallets
+    /// *`sockip` - the IP address of the Miner, written in the notifications
+    /// *`id` - the ID of the Miner, written in the notifications
+    pub fn start(transport: Arc<dyn Transport>, identity: Arc<NodeIdentity>, sockip: String, id: u32) -> Self {
+        let (queue, queued) = mpsc::sync_channel::<(String, TransactionEvent)>(MAX_QUEUED_NOTIFICATIONS);
+        let (failed, unreachable) = mpsc::channel();
+        thread::spawn(move || {
+            for (wallet, event) in queued {
+                let m: &[u8] = &encode_message(Flag::Notification, sockip.clone(), id.to_string(), event.to_string());
+                let sent = secure::connect(transport.as_ref(), &wallet, &identity).and_then(|mut stream| stream.send(m));
+                if let Err(e) = sent {
+                    println!("Err: {}, while notifying wallet {}", e, &wallet);
+                    if failed.send(wallet).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Notifier { queue, unreachable: Mutex::new(unreachable) }
+    }
+
+    /// Queues notifications to be sent, those which do not fit in the queue are dropped
+    pub fn send(&self, notifications: Vec<(String, TransactionEvent)>) {
+        for notification in notifications {
+            if let Err(TrySendError::Full((wallet, _))) = self.queue.try_send(notification) {
+                println!("Notification to {} dropped: too many notifications waiting", &wallet);
+            }
+        }
+    }
+
+    /// Returns the wallets which could not be reached since the last call
+    pub fn unreachable(&self) -> HashSet<String> {
+        self.unreachable.lock().unwrap().try_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::WalletKey;
+
+    #[test]
+    fn events_round_trip() {
+        let txid = "ab".repeat(32);
+        let events = [
+            TransactionEvent::Pending(txid.clone()),
+            TransactionEvent::Included { txid: txid.clone(), height: 3 },
+            TransactionEvent::Confirmed { txid: txid.clone(), confirmations: 6 },
+            TransactionEvent::Dropped(txid.clone()),
+            TransactionEvent::Reorged(txid.clone()),
+        ];
+        for event in events.iter() {
+            assert_eq!(event.to_string().parse::<TransactionEvent>(), Ok(event.clone()));
+        }
+        assert!("included abc".parse::<TransactionEvent>().is_err());
+        assert!("".parse::<TransactionEvent>().is_err());
+    }
+
+    #[test]
+    fn subscriptions_are_capped() {
+        let mut subscriptions = Subscriptions::new();
+        for i in 0..MAX_ADDRESSES_PER_WALLET {
+            subscriptions.subscribe("10.0.0.1:8000".to_string(), format!("address {}", i)).unwrap();
+        }
+        subscriptions.subscribe("10.0.0.1:8000".to_string(), "address 0".to_string()).unwrap();
+        assert!(subscriptions.subscribe("10.0.0.1:8000".to_string(), "another address".to_string()).is_err());
+        subscriptions.subscribe("10.0.0.2:8000".to_string(), "another address".to_string()).unwrap();
+
+        for i in subscriptions.wallets.len()..MAX_SUBSCRIBED_ADDRESSES {
+            subscriptions.wallets.entry(format!("filler {}", i)).or_default().insert(format!("10.0.1.{}:8000", i % 256));
+        }
+        assert!(subscriptions.subscribe("10.0.0.2:8000".to_string(), "one too many".to_string()).is_err());
+        subscriptions.subscribe("10.0.0.2:8000".to_string(), "address 0".to_string()).unwrap();
+    }
+
+    #[test]
+    fn unreachable_wallets_are_forgotten() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe("10.0.0.1:8000".to_string(), alice.address()).unwrap();
+        subscriptions.subscribe("10.0.0.2:8000".to_string(), bob.address()).unwrap();
+        let transfer = Transaction::signed_transfer(&alice, &bob.address(), 1, 1, 1, "");
+
+        let mut notified: Vec<String> = subscriptions.transaction_added(&transfer).into_iter().map(|(wallet, _)| wallet).collect();
+        notified.sort();
+        assert_eq!(notified, vec!["10.0.0.1:8000".to_string(), "10.0.0.2:8000".to_string()]);
+
+        subscriptions.unsubscribe("10.0.0.1:8000");
+        assert!(!subscriptions.wallets.contains_key(&alice.address()));
+        let notifications = subscriptions.transaction_added(&transfer);
+        assert_eq!(notifications, vec![("10.0.0.2:8000".to_string(), TransactionEvent::Pending(transfer.id()))]);
+        subscriptions.unsubscribe("10.0.0.2:8000");
+        assert!(subscriptions.followed.is_empty());
+        assert!(subscriptions.transaction_added(&transfer).is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::address;
+use crate::config::LedgerMode;
+use crate::keys;
+use crate::transaction::{OutPoint, Transaction};
+
+/// First line of every offline transaction file
+const OFFLINE_FORMAT: &str = "blockchain-offline-transaction";
+/// Version written in every offline transaction file, a file of a later version is refused
+pub const OFFLINE_VERSION: u32 = 1;
+
+/// A transaction carried between a wallet connected to the network and a wallet kept offline with the keys
+/// The connected wallet builds it, the offline one shows it and signs it, then the connected one sends it to its Miner.
+/// The file is written in clear, one `name value` per line, see `write`
+/// *`ledger` - the ledger mode of the chain, written so the signer knows how to read the transaction
+/// *`inputs` - for a spend, the outputs it spends with their amounts as told by the Miner of the connected wallet
+/// *`transaction` - the transaction, its signature being empty until it is signed
+pub struct OfflineTransaction {
+    pub ledger: LedgerMode,
+    pub inputs: Vec<(OutPoint, u64)>,
+    pub transaction: Transaction,
+}
+
+impl OfflineTransaction {
+
+    /// Returns true once the transaction carries a valid signature of its sender
+    pub fn is_signed(&self) -> bool {
+        self.transaction.has_valid_signature()
+    }
+
+    /// Checks that the file describes its transaction faithfully: a transaction of the ledger mode written,
+    /// and for a spend, inputs which are those of the transaction and whose amounts hold exactly its outputs and fee
+    pub fn check(&self) -> Result<(), String> {
+        if !self.ledger.accepts(&self.transaction) || self.transaction.is_coinbase() {
+            return Err(format!("not a transaction of a {} chain", self.ledger));
+        }
+        if let Transaction::Spend { inputs, outputs, fee, .. } = &self.transaction {
+            if self.inputs.iter().map(|(outpoint, _)| outpoint).ne(inputs.iter()) {
+                return Err("the inputs do not match the transaction".to_string());
+            }
+            let held = self.inputs.iter().try_fold(0_u64, |total, (_, amount)| total.checked_add(*amount));
+            let spent = outputs.iter().try_fold(*fee, |total, output| total.checked_add(output.amount));
+            if held.is_none() || held != spent {
+                return Err("the inputs do not hold exactly the outputs and the fee".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes what the transaction does, shown before signing or sending it
+    /// The ID of a transaction covers its signature, it is only shown once signed
+    pub fn describe(&self) -> String {
+        let state = match self.is_signed() {
+            true => format!("signed {}", self.transaction.id()),
+            false => "unsigned".to_string(),
+        };
+        let from = self.transaction.sender().map(address::from_public_key).unwrap_or_default();
+        match &self.transaction {
+            Transaction::Transfer { to, amount, fee, nonce, message, .. } => format!(
+                "{} transfer\n  from {}\n  to {}: {}\n  fee {}, nonce {}\n  message {:?}\n  total taken from {}: {}",
+                state, from, to, amount, fee, nonce, message, from, amount.saturating_add(*fee),
+            ),
+            Transaction::Spend { outputs, fee, message, .. } => {
+                let mut lines = format!("{} spend\n  from {}", state, from);
+                for (outpoint, amount) in &self.inputs {
+                    lines.push_str(&format!("\n  input {}: {}", outpoint, amount));
+                }
+                for output in outputs {
+                    let change = if output.address == from { " (change back to the sender)" } else { "" };
+                    lines.push_str(&format!("\n  to {}: {}{}", output.address, output.amount, change));
+                }
+                let sent: u64 = outputs.iter().filter(|output| output.address != from).fold(*fee, |total, output| total.saturating_add(output.amount));
+                lines.push_str(&format!("\n  fee {}\n  message {:?}\n  total taken from {}: {}", fee, message, from, sent));
+                lines
+            }
+            Transaction::Coinbase { .. } => format!("{} coinbase", state),
+        }
+    }
+
+    /// Reads a file written by `write`, and checks it, see `check`
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}, could not read {}", e, path.display()))?;
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.first() != Some(&OFFLINE_FORMAT) {
+            return Err(format!("{} is not an offline transaction file", path.display()));
+        }
+        let field = |name: &str| lines.iter()
+            .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')))
+            .ok_or_else(|| format!("missing field {}", name));
+        let version = field("version")?.parse::<u32>().map_err(|e| format!("invalid version: {}", e))?;
+        if version == 0 || version > OFFLINE_VERSION {
+            return Err(format!("unsupported offline transaction version {}", version));
+        }
+        let ledger = field("ledger")?.parse::<LedgerMode>()?;
+        let inputs = lines.iter()
+            .filter_map(|line| line.strip_prefix("input "))
+            .map(|input| match input.split_once(' ') {
+                Some((outpoint, amount)) => Ok((outpoint.parse::<OutPoint>()?, amount.parse::<u64>().map_err(|e| format!("invalid input amount: {}", e))?)),
+                None => Err(format!("invalid input {}", input)),
+            })
+            .collect::<Result<Vec<(OutPoint, u64)>, String>>()?;
+        let transaction = match (field("unsigned"), field("signed")) {
+            (Ok(unsigned), Err(_)) => read_unsigned(unsigned)?,
+            (Err(_), Ok(signed)) => {
+                let transaction = signed.parse::<Transaction>()?;
+                if !transaction.has_valid_signature() {
+                    return Err("the signature does not match the transaction, it was changed after being signed".to_string());
+                }
+                transaction
+            }
+            _ => return Err("the file must hold one transaction, signed or unsigned".to_string()),
+        };
+        let offline = OfflineTransaction { ledger, inputs, transaction };
+        offline.check()?;
+        Ok(offline)
+    }
+
+    /// Writes the file: the format, the version, the ledger mode, the inputs of a spend with their amounts,
+    /// then the transaction, `unsigned` followed by what its sender signs, or `signed` followed by the whole transaction
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut lines = vec![
+            OFFLINE_FORMAT.to_string(),
+            format!("version {}", OFFLINE_VERSION),
+            format!("ledger {}", self.ledger),
+        ];
+        lines.extend(self.inputs.iter().map(|(outpoint, amount)| format!("input {} {}", outpoint, amount)));
+        lines.push(match self.is_signed() {
+            true => format!("signed {}", self.transaction),
+            false => format!("unsigned {}", self.transaction.signed_part()),
+        });
+        fs::write(path, lines.join("\n") + "\n")
+    }
+}
+
+/// Reads what the sender of a transaction signs, the transaction without its signature
+/// It must be written exactly as the transaction would write it, so the signer signs what it was shown
+fn read_unsigned(unsigned: &str) -> Result<Transaction, String> {
+    // Transactions are only read with a signature, an empty one is put in its place
+    let placeholder = "0".repeat(2 * keys::SIGNATURE_SIZE);
+    let mut transaction = format!("{}|{}", unsigned, placeholder).parse::<Transaction>()?;
+    if let Transaction::Transfer { signature, .. } | Transaction::Spend { signature, .. } = &mut transaction {
+        signature.clear();
+    }
+    if transaction.signed_part() != unsigned {
+        return Err("the unsigned transaction is not written canonically".to_string());
+    }
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::WalletKey;
+    use crate::testutil::temp_path;
+    use crate::transaction::Output;
+
+    /// Returns an unsigned spend of two inputs of 60 and 40 paying 90 to `to`, with a fee of 10
+    fn unsigned_spend(key: &WalletKey, to: &WalletKey) -> OfflineTransaction {
+        let inputs = vec![(OutPoint { txid: "ab".repeat(32), index: 0 }, 60), (OutPoint { txid: "cd".repeat(32), index: 1 }, 40)];
+        let outpoints = inputs.iter().map(|(outpoint, _)| outpoint.clone()).collect();
+        let transaction = Transaction::spend(&key.public_key(), outpoints, vec![Output { address: to.address(), amount: 90 }], 10, "rent");
+        OfflineTransaction { ledger: LedgerMode::Utxo, inputs, transaction }
+    }
+
+    /// Rewrites the line of the file starting with `prefix`
+    fn edit(path: &Path, prefix: &str, change: impl Fn(&str) -> String) {
+        let content = fs::read_to_string(path).unwrap();
+        let lines: Vec<String> = content.lines().map(|line| if line.starts_with(prefix) { change(line) } else { line.to_string() }).collect();
+        fs::write(path, lines.join("\n")).unwrap();
+    }
+
+    #[test]
+    fn files_round_trip_before_and_after_signing() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let path = temp_path("offline-round-trip");
+        let mut offline = unsigned_spend(&key, &to);
+        offline.write(&path).unwrap();
+
+        let mut read = OfflineTransaction::read(&path).unwrap();
+        assert!(!read.is_signed());
+        assert_eq!(read.inputs, offline.inputs);
+        assert_eq!(read.transaction, offline.transaction);
+        assert!(read.describe().starts_with("unsigned spend"));
+
+        read.transaction.sign(&key);
+        read.write(&path).unwrap();
+        let signed = OfflineTransaction::read(&path).unwrap();
+        assert!(signed.is_signed());
+        offline.transaction.sign(&key);
+        assert_eq!(signed.transaction, offline.transaction);
+        assert!(signed.describe().starts_with(&format!("signed {}", offline.transaction.id())));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn transfers_round_trip() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let path = temp_path("offline-transfer");
+        let transaction = Transaction::transfer(&key.public_key(), &to.address(), 5, 1, 3, "multi word | message");
+        let offline = OfflineTransaction { ledger: LedgerMode::Account, inputs: Vec::new(), transaction };
+        offline.write(&path).unwrap();
+        assert_eq!(OfflineTransaction::read(&path).unwrap().transaction, offline.transaction);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn inputs_must_match_the_transaction() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let offline = unsigned_spend(&key, &to);
+        assert_eq!(offline.check(), Ok(()));
+
+        let mut inflated = unsigned_spend(&key, &to);
+        inflated.inputs[0].1 = 70;
+        assert_eq!(inflated.check(), Err("the inputs do not hold exactly the outputs and the fee".to_string()));
+        let mut overflowing = unsigned_spend(&key, &to);
+        overflowing.inputs[0].1 = u64::MAX;
+        assert!(overflowing.check().is_err());
+        let mut reordered = unsigned_spend(&key, &to);
+        reordered.inputs.reverse();
+        assert_eq!(reordered.check(), Err("the inputs do not match the transaction".to_string()));
+        let mut missing = unsigned_spend(&key, &to);
+        missing.inputs.pop();
+        assert_eq!(missing.check(), Err("the inputs do not match the transaction".to_string()));
+        let wrong_ledger = OfflineTransaction { ledger: LedgerMode::Account, ..unsigned_spend(&key, &to) };
+        assert!(wrong_ledger.check().is_err());
+    }
+
+    #[test]
+    fn tampered_files_are_refused() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let path = temp_path("offline-tampered");
+        unsigned_spend(&key, &to).write(&path).unwrap();
+
+        // The amount paid raised without touching the inputs
+        edit(&path, "unsigned ", |line| line.replace(&format!("{}-90", to.address()), &format!("{}-95", to.address())));
+        assert_eq!(OfflineTransaction::read(&path).err(), Some("the inputs do not hold exactly the outputs and the fee".to_string()));
+        edit(&path, "unsigned ", |line| line.replace(&format!("{}-95", to.address()), &format!("{}-090", to.address())));
+        assert_eq!(OfflineTransaction::read(&path).err(), Some("the unsigned transaction is not written canonically".to_string()));
+        edit(&path, "unsigned ", |line| line.replace(&format!("{}-090", to.address()), &format!("{}-90", to.address())));
+        edit(&path, "input ", |line| line.replace(" 60", " 65"));
+        assert!(OfflineTransaction::read(&path).is_err());
+        edit(&path, "input ", |line| line.replace(" 65", " 60"));
+        assert!(OfflineTransaction::read(&path).is_ok());
+
+        edit(&path, "version ", |_| format!("version {}", OFFLINE_VERSION + 1));
+        assert!(OfflineTransaction::read(&path).is_err());
+        edit(&path, "blockchain-offline-transaction", |_| "something else".to_string());
+        assert!(OfflineTransaction::read(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn signed_files_changed_after_signing_are_refused() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let path = temp_path("offline-tampered-signed");
+        let mut offline = unsigned_spend(&key, &to);
+        offline.transaction.sign(&key);
+        offline.write(&path).unwrap();
+
+        edit(&path, "signed ", |line| line.replace(&hex::encode("rent"), &hex::encode("tent")));
+        assert_eq!(OfflineTransaction::read(&path).err(), Some("the signature does not match the transaction, it was changed after being signed".to_string()));
+        edit(&path, "signed ", |line| format!("un{}", line));
+        edit(&path, "version ", |line| format!("{}\nsigned {}", line, offline.transaction));
+        assert_eq!(OfflineTransaction::read(&path).err(), Some("the file must hold one transaction, signed or unsigned".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+/// Score given to a peer the first time we hear about it
+pub const INITIAL_SCORE: i32 = 100;
+/// Score lost by a peer each time it does not answer a ping in time
+pub const TIMEOUT_PENALTY: i32 = 10;
+/// Score lost by a peer each time it sends us something wrong (bad nonce, unknown flag...)
+pub const MISBEHAVIOUR_PENALTY: i32 = 25;
+/// Score won back by a peer each time it answers a ping correctly
+pub const SUCCESS_REWARD: i32 = 5;
+/// Number of consecutive failed pings after which a peer is evicted from the network
+pub const MAX_FAILURES: u32 = 3;
+/// Time allowed to a peer to answer once the connection is open
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// This struct keeps what a Miner knows about the health of one of its peers
+/// *`score` - the reputation of the peer, lowered on timeouts or misbehaviour, capped at `INITIAL_SCORE`
+/// *`failures` - the number of consecutive pings that failed
+/// *`latency` - the round-trip time measured during the last successful ping
+/// *`last_seen` - when the peer answered a ping for the last time
+#[derive(Clone, Debug)]
+pub struct PeerStatus {
+    pub score: i32,
+    pub failures: u32,
+    pub latency: Option<Duration>,
+    pub last_seen: Option<Instant>,
+}
+
+impl PeerStatus {
+
+    /// CONSTRUCTOR
+    /// Returns the status of a peer we never pinged
+    pub fn new() -> Self {
+        PeerStatus {
+            score: INITIAL_SCORE,
+            failures: 0,
+            latency: None,
+            last_seen: None,
+        }
+    }
+
+    /// Records a ping answered with the right nonce
+    /// *`rtt` - the measured round-trip time
+    pub fn record_success(&mut self, rtt: Duration) {
+        self.failures = 0;
+        self.latency = Some(rtt);
+        self.last_seen = Some(Instant::now());
+        self.score = (self.score + SUCCESS_REWARD).min(INITIAL_SCORE);
+    }
+
+    /// Records a ping that could not connect or timed out
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+        self.score -= TIMEOUT_PENALTY;
+    }
+
+    /// Lowers the score of a peer that sent us something it should not have
+    /// *`penalty` - how many points the peer loses
+    pub fn record_misbehaviour(&mut self, penalty: i32) {
+        self.score -= penalty;
+    }
+
+    /// Returns true when the peer failed too many times in a row or when its score is exhausted
+    pub fn should_evict(&self) -> bool {
+        self.failures >= MAX_FAILURES || self.score <= 0
+    }
+}
+
+impl Default for PeerStatus {
+    fn default() -> Self {
+        PeerStatus::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_evicted_after_consecutive_failures() {
+        let mut status = PeerStatus::new();
+        for _ in 1..MAX_FAILURES {
+            status.record_failure();
+        }
+        assert!(!status.should_evict());
+        // An answered ping resets the failures, not the score
+        status.record_success(Duration::from_millis(20));
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.latency, Some(Duration::from_millis(20)));
+        assert!(status.last_seen.is_some());
+        assert_eq!(status.score, INITIAL_SCORE - (MAX_FAILURES as i32 - 1) * TIMEOUT_PENALTY + SUCCESS_REWARD);
+        for _ in 0..MAX_FAILURES {
+            status.record_failure();
+        }
+        assert!(status.should_evict());
+    }
+
+    #[test]
+    fn scores_are_capped_and_misbehaviour_exhausts_them() {
+        let mut status = PeerStatus::new();
+        status.record_success(Duration::from_millis(5));
+        assert_eq!(status.score, INITIAL_SCORE);
+        status.record_misbehaviour(MISBEHAVIOUR_PENALTY);
+        assert_eq!(status.score, INITIAL_SCORE - MISBEHAVIOUR_PENALTY);
+        status.record_misbehaviour(INITIAL_SCORE - MISBEHAVIOUR_PENALTY - 1);
+        assert!(!status.should_evict());
+        status.record_misbehaviour(1);
+        assert_eq!(status.failures, 0);
+        assert!(status.should_evict());
+    }
+}
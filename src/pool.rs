@@ -0,0 +1,125 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::peer::PeerStatus;
+use crate::protocol;
+use crate::storage;
+
+/// Most Miners a wallet remembers, the others it learns about are ignored
+pub const MAX_MINERS: usize = 16;
+
+/// The Miners a wallet knows: given on the command line or learned from the peer list of its Miner
+/// Requests go to the first one, the others take over when it fails
+/// *`path` - the file where they are kept between runs, `data/<socket>.miners`
+/// *`miners` - their addresses, the one in use first
+/// *`statuses` - the health of the Miners we checked, see `PeerStatus`
+pub struct MinerPool {
+    path: PathBuf,
+    miners: Vec<String>,
+    statuses: HashMap<String, PeerStatus>,
+}
+
+impl MinerPool {
+
+    /// Reads the Miners known by the wallet listening on `sockip`
+    /// *`configured` - the Miners given by the user, they come first in their order, then the saved ones
+    pub fn load(sockip: &str, configured: &[String]) -> Self {
+        let path = storage::node_file(sockip, "miners");
+        let saved = storage::read_lines(&path).unwrap_or_else(|e| {
+            println!("Err: {}, the known Miners are lost", e);
+            Vec::new()
+        });
+        let mut pool = MinerPool { path, miners: Vec::new(), statuses: HashMap::new() };
+        for miner in configured.iter().chain(saved.iter()) {
+            if !pool.miners.contains(miner) && pool.miners.len() < MAX_MINERS {
+                pool.miners.push(miner.to_string());
+            }
+        }
+        if pool.miners != saved {
+            pool.save();
+        }
+        pool
+    }
+
+    /// Returns the Miner the requests go to, empty if the wallet knows none
+    pub fn current(&self) -> String {
+        self.miners.first().cloned().unwrap_or_default()
+    }
+
+    /// Returns every Miner known, the one in use first
+    pub fn miners(&self) -> &[String] {
+        &self.miners
+    }
+
+    /// Adds Miners at the end of the list, the invalid addresses and those already known are skipped
+    /// Returns the number of Miners added
+    pub fn learn(&mut self, miners: impl IntoIterator<Item = String>) -> usize {
+        let known = self.miners.len();
+        for miner in miners {
+            if self.miners.len() < MAX_MINERS && !self.miners.contains(&miner) && protocol::validate_address(miner.clone()).is_ok() {
+                self.miners.push(miner);
+            }
+        }
+        if self.miners.len() > known {
+            self.save();
+        }
+        self.miners.len() - known
+    }
+
+    /// Makes `miner` the one the requests go to
+    pub fn promote(&mut self, miner: &str) {
+        if let Some(position) = self.miners.iter().position(|known| known == miner) {
+            let miner = self.miners.remove(position);
+            self.miners.insert(0, miner);
+            self.save();
+        }
+    }
+
+    /// Returns the Miners which may take over from the one in use, the healthiest first: best score, then lowest latency
+    pub fn candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = self.miners.iter().skip(1).cloned().collect();
+        candidates.sort_by_key(|miner| {
+            let status = self.status(miner);
+            (Reverse(status.score), status.latency.unwrap_or(Duration::MAX))
+        });
+        candidates
+    }
+
+    /// Returns what our health checks measured of `miner`
+    pub fn status(&self, miner: &str) -> PeerStatus {
+        self.statuses.get(miner).cloned().unwrap_or_default()
+    }
+
+    /// Records a health check or a request `miner` answered
+    /// *`rtt` - the measured round-trip time
+    pub fn record_success(&mut self, miner: &str, rtt: Duration) {
+        self.statuses.entry(miner.to_string()).or_default().record_success(rtt);
+    }
+
+    /// Records a health check or a request `miner` did not answer
+    pub fn record_failure(&mut self, miner: &str) {
+        self.statuses.entry(miner.to_string()).or_default().record_failure();
+    }
+
+    /// Lists the Miners with their health, the one in use first
+    pub fn describe(&self) -> String {
+        self.miners.iter().enumerate().map(|(i, miner)| {
+            let status = self.status(miner);
+            let health = match (status.latency, status.failures) {
+                (_, failures) if failures > 0 => format!("{} failed checks", failures),
+                (Some(latency), _) => format!("answers in {} ms", latency.as_millis()),
+                (None, _) => "not checked".to_string(),
+            };
+            let role = if i == 0 { ", in use" } else { "" };
+            format!("\n  {}{}: {}, score {}", miner, role, health, status.score)
+        }).collect()
+    }
+
+    /// Saves the Miners, so they are known at the next run
+    fn save(&self) {
+        if let Err(e) = storage::write_lines(&self.path, &self.miners) {
+            println!("Err: {}, while saving the known Miners", e);
+        }
+    }
+}
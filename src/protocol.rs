@@ -0,0 +1,402 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Time allowed to open a connection to another node
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Longest hostname accepted in an address (RFC 1035)
+const MAX_HOSTNAME_SIZE: usize = 253;
+
+/// Last item of a part of a long answer when more items follow, see `answer_part`
+pub const MORE: &str = "more";
+
+/// Kind of address carried in a message, written just after the flag
+const ADDR_NONE: u8 = 0;
+const ADDR_IPV4: u8 = 4;
+const ADDR_IPV6: u8 = 6;
+const ADDR_HOST: u8 = 1;
+
+/// Used for signaling what kind of requests we are sending when networking
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Flag {
+    /// Ok -> Network
+    Ok,
+    Connect, // flag to signal that a Miner joined the newtwork
+    Disconnect, // flag to signal that a Miner disconnected from the network
+    RequireID,
+    GiveID,
+    BroadcastConnect,
+    BroadcastDisconnect,
+    Check,
+    Ack,
+    Block,
+    Transaction,
+    MineTransaction,
+    OkMineTransaction,
+    RequireWalletID,
+    RequireBlockchain,
+    SendBlockchain,
+    Shutdown,
+    RequireFeeEstimate,
+    FeeEstimate,
+    RequireBalance,
+    Balance,
+    RequireChainConfig,
+    ChainConfig,
+    RequireUnspent,
+    Unspent,
+    RequireTransaction,
+    TransactionStatus,
+    RequireHistory,
+    History,
+    Subscribe,
+    Notification,
+    RequireProof,
+    Proof,
+    RequirePending,
+    Pending,
+    RequireHistoryPage,
+    HistoryPage,
+    TransactionResult,
+    RequirePeers,
+    Peers,
+    EndBlockchain,
+}
+
+impl Flag {
+    /// Simple function to convert a integer to a Flag
+    /// Used when receiving/sending a message as primary types are easier to pass than objects
+    /// Returns None if the integer is not a known flag
+    pub fn from_u8(value: u8) -> Option<Flag> {
+        match value {
+            0 => Some(Flag::Ok),
+            1 => Some(Flag::Connect),
+            2 => Some(Flag::Disconnect),
+            3 => Some(Flag::RequireID),
+            4 => Some(Flag::GiveID),
+            5 => Some(Flag::BroadcastConnect),
+            6 => Some(Flag::BroadcastDisconnect),
+            7 => Some(Flag::Check),
+            8 => Some(Flag::Ack),
+            9 => Some(Flag::Block),
+            10 => Some(Flag::Transaction),
+            11 => Some(Flag::MineTransaction),
+            12 => Some(Flag::OkMineTransaction),
+            13 => Some(Flag::RequireWalletID),
+            14 => Some(Flag::RequireBlockchain),
+            15 => Some(Flag::SendBlockchain),
+            16 => Some(Flag::Shutdown),
+            17 => Some(Flag::RequireFeeEstimate),
+            18 => Some(Flag::FeeEstimate),
+            19 => Some(Flag::RequireBalance),
+            20 => Some(Flag::Balance),
+            21 => Some(Flag::RequireChainConfig),
+            22 => Some(Flag::ChainConfig),
+            23 => Some(Flag::RequireUnspent),
+            24 => Some(Flag::Unspent),
+            25 => Some(Flag::RequireTransaction),
+            26 => Some(Flag::TransactionStatus),
+            27 => Some(Flag::RequireHistory),
+            28 => Some(Flag::History),
+            29 => Some(Flag::Subscribe),
+            30 => Some(Flag::Notification),
+            31 => Some(Flag::RequireProof),
+            32 => Some(Flag::Proof),
+            33 => Some(Flag::RequirePending),
+            34 => Some(Flag::Pending),
+            35 => Some(Flag::RequireHistoryPage),
+            36 => Some(Flag::HistoryPage),
+            37 => Some(Flag::TransactionResult),
+            38 => Some(Flag::RequirePeers),
+            39 => Some(Flag::Peers),
+            40 => Some(Flag::EndBlockchain),
+            _ => None,
+        }
+    }
+}
+
+/// The address of a node on the network
+/// Either an IP address with its port (v4 or v6), or a hostname with its port which is resolved at connection time
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NodeAddr {
+    Ip(SocketAddr),
+    Host(String, u16),
+}
+
+impl NodeAddr {
+    /// Resolves the address, hostnames are looked up each time so a node can change its IP
+    pub fn resolve(&self) -> io::Result<SocketAddr> {
+        match self {
+            NodeAddr::Ip(addr) => Ok(*addr),
+            NodeAddr::Host(host, port) => (host.as_str(), *port).to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no address found for {}", host))),
+        }
+    }
+
+    /// Appends the address to a message: kind, length, bytes then port
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            NodeAddr::Ip(SocketAddr::V4(addr)) => {
+                out.extend_from_slice(&[ADDR_IPV4, 4]);
+                out.extend_from_slice(&addr.ip().octets());
+                out.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            NodeAddr::Ip(SocketAddr::V6(addr)) => {
+                out.extend_from_slice(&[ADDR_IPV6, 16]);
+                out.extend_from_slice(&addr.ip().octets());
+                out.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            NodeAddr::Host(host, port) => {
+                out.extend_from_slice(&[ADDR_HOST, host.len() as u8]);
+                out.extend_from_slice(host.as_bytes());
+                out.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeAddr::Ip(addr) => write!(f, "{}", addr),
+            NodeAddr::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl FromStr for NodeAddr {
+    type Err = String;
+
+    /// Parses `ip:port`, `[ipv6]:port` or `hostname:port`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(NodeAddr::Ip(addr));
+        }
+        let (host, port) = match s.rfind(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(format!("{}: missing port, expected HOST:PORT", s)),
+        };
+        let port = port.parse::<u16>().map_err(|_| format!("{}: invalid port", s))?;
+        if host.is_empty() || host.len() > MAX_HOSTNAME_SIZE {
+            return Err(format!("{}: invalid hostname length", s));
+        }
+        let valid = host.split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+        if !valid {
+            return Err(format!("{}: invalid hostname", s));
+        }
+        Ok(NodeAddr::Host(host.to_string(), port))
+    }
+}
+
+/// Checks an address given on the command line
+pub fn validate_address(address: String) -> Result<(), String> {
+    address.parse::<NodeAddr>().map(|_| ())
+}
+
+/// Opens a connection to a node, resolving its address first
+/// *`destination` - the address of the node, as `HOST:PORT`
+pub fn connect(destination: &str) -> io::Result<TcpStream> {
+    let address = destination.parse::<NodeAddr>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .resolve()?;
+    TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+}
+
+/// Encode the sended message
+/// Layout: flag (1) | address kind (1) | address length (1) | address | port (2) | id length (1) | id | message
+/// *`flag` - a Flag which represents what kind of request/message we are sending
+/// *`sockip` - the address of the sender, may be empty when the sender does not listen (admin commands)
+/// *`id` - the ID of the sender, may be empty when it does not have one yet
+/// *`msg` - the message sended to the recipient
+pub fn encode_message(flag : Flag, sockip : String, id : String, msg : String) -> Vec<u8> {
+    let mut encoded = vec![flag as u8];
+    match sockip.parse::<NodeAddr>() {
+        Ok(address) => address.encode(&mut encoded),
+        Err(_) => encoded.extend_from_slice(&[ADDR_NONE, 0]),
+    }
+    let id = id.as_bytes();
+    encoded.push(id.len().min(u8::MAX as usize) as u8);
+    encoded.extend_from_slice(&id[..id.len().min(u8::MAX as usize)]);
+    encoded.extend_from_slice(msg.as_bytes());
+    encoded
+}
+
+/// Decode the message received
+/// Returns the flag, the address and the ID of the sender and the message, or why the message is malformed
+pub fn decode_message(msg : &[u8]) -> Result<(Flag, String, String, String), String> {
+    let mut reader = Reader { data: msg, position: 0 };
+    let flag_value = reader.take(1)?[0];
+    let flag = Flag::from_u8(flag_value).ok_or_else(|| format!("unknown flag {}", flag_value))?;
+
+    let kind = reader.take(1)?[0];
+    let length = reader.take(1)?[0] as usize;
+    let address = match kind {
+        ADDR_NONE => None,
+        ADDR_IPV4 if length == 4 => {
+            let b = reader.take(4)?;
+            Some(NodeAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])), reader.port()?)))
+        }
+        ADDR_IPV6 if length == 16 => {
+            let mut octets = [0_u8; 16];
+            octets.copy_from_slice(reader.take(16)?);
+            Some(NodeAddr::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), reader.port()?)))
+        }
+        ADDR_HOST => {
+            let host = std::str::from_utf8(reader.take(length)?).map_err(|e| e.to_string())?.to_string();
+            let port = reader.port()?;
+            Some(format!("{}:{}", host, port).parse::<NodeAddr>()?)
+        }
+        _ => return Err(format!("invalid address kind {} of length {}", kind, length)),
+    };
+
+    let id_length = reader.take(1)?[0] as usize;
+    let id = std::str::from_utf8(reader.take(id_length)?).map_err(|e| e.to_string())?.to_string();
+    let message = std::str::from_utf8(reader.rest()).map_err(|e| e.to_string())?.to_string();
+    let sockip = address.map(|a| a.to_string()).unwrap_or_default();
+    Ok((flag, sockip, id, message))
+}
+
+/// Reads a request for a part of a long answer, `<request> <skip>`, the first part being asked with the request alone
+/// Returns the request and the number of items already received, to skip
+pub fn part_request(message: &str) -> (&str, usize) {
+    match message.rsplit_once(' ').map(|(request, skip)| (request, skip.parse::<usize>())) {
+        Some((request, Ok(skip))) => (request, skip),
+        _ => (message, 0),
+    }
+}
+
+/// Writes a part of a long answer: at most `max` items after the first `skip`, separated by `separator`,
+/// then `MORE` if other items follow, which are asked for again with the number of items received
+pub fn answer_part(items: &[String], skip: usize, max: usize, separator: char) -> String {
+    let mut part: Vec<&str> = items.iter().skip(skip).take(max).map(|item| item.as_str()).collect();
+    if items.len() > skip.saturating_add(max) {
+        part.push(MORE);
+    }
+    part.join(&separator.to_string())
+}
+
+/// Reads a part of a long answer written by `answer_part`
+/// Returns its items and whether other items follow
+pub fn read_part(answer: &str, separator: char) -> (Vec<String>, bool) {
+    let mut items: Vec<String> = answer.split(separator).filter(|item| !item.is_empty()).map(|item| item.to_string()).collect();
+    let more = items.last().map(|item| item.as_str()) == Some(MORE);
+    if more {
+        items.pop();
+    }
+    (items, more)
+}
+
+/// Small cursor over a received message, every read checks the bounds
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        if self.position + size > self.data.len() {
+            return Err("message too short".to_string());
+        }
+        let slice = &self.data[self.position..self.position + size];
+        self.position += size;
+        Ok(slice)
+    }
+
+    fn port(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.position..];
+        self.position = self.data.len();
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip_with_every_kind_of_address() {
+        for sockip in ["127.0.0.1:8000", "[::1]:8000", "[2001:db8::7]:65535", "node-1.example.org:80", ""] {
+            let encoded = encode_message(Flag::Block, sockip.to_string(), "12".to_string(), "a message: with | separators".to_string());
+            assert_eq!(decode_message(&encoded), Ok((Flag::Block, sockip.to_string(), "12".to_string(), "a message: with | separators".to_string())));
+        }
+        let encoded = encode_message(Flag::Ok, "not an address".to_string(), String::new(), String::new());
+        assert_eq!(decode_message(&encoded), Ok((Flag::Ok, String::new(), String::new(), String::new())));
+    }
+
+    #[test]
+    fn long_answers_are_sent_in_parts() {
+        let items: Vec<String> = (0..5).map(|i| format!("tx{}", i)).collect();
+        assert_eq!(answer_part(&items, 0, 2, ','), "tx0,tx1,more");
+        assert_eq!(read_part("tx0,tx1,more", ','), (vec!["tx0".to_string(), "tx1".to_string()], true));
+        assert_eq!(answer_part(&items, 4, 2, '$'), "tx4");
+        assert_eq!(read_part("tx4", '$'), (vec!["tx4".to_string()], false));
+        assert_eq!(answer_part(&items, 3, 2, ','), "tx3,tx4");
+        assert_eq!(answer_part(&items, 9, 2, ','), "");
+        assert_eq!(read_part("", ','), (Vec::new(), false));
+
+        assert_eq!(part_request("RXaddress"), ("RXaddress", 0));
+        assert_eq!(part_request("RXa,RXb 400"), ("RXa,RXb", 400));
+        assert_eq!(part_request("RXa,RXb x"), ("RXa,RXb x", 0));
+    }
+
+    #[test]
+    fn truncated_messages_are_refused() {
+        for sockip in ["127.0.0.1:8000", "[::1]:8000", "node-1.example.org:80"] {
+            let encoded = encode_message(Flag::Check, sockip.to_string(), "12".to_string(), "message".to_string());
+            // Everything before the message is needed, the message itself may be cut anywhere
+            let header = encoded.len() - "message".len();
+            for size in 0..header {
+                assert_eq!(decode_message(&encoded[..size]), Err("message too short".to_string()), "{} bytes of {}", size, sockip);
+            }
+            assert!(decode_message(&encoded[..header]).is_ok());
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        let encoded = encode_message(Flag::Check, "127.0.0.1:8000".to_string(), "12".to_string(), "message".to_string());
+        let mut unknown_flag = encoded.clone();
+        unknown_flag[0] = 200;
+        assert_eq!(decode_message(&unknown_flag), Err("unknown flag 200".to_string()));
+        let mut wrong_length = encoded.clone();
+        wrong_length[2] = 6;
+        assert_eq!(decode_message(&wrong_length), Err("invalid address kind 4 of length 6".to_string()));
+        let mut not_utf8 = encoded.clone();
+        not_utf8.push(0xff);
+        assert!(decode_message(&not_utf8).is_err());
+
+        let mut bad_host = vec![Flag::Check as u8, ADDR_HOST, 4];
+        bad_host.extend_from_slice(b"a..b");
+        bad_host.extend_from_slice(&[0, 80, 0]);
+        assert!(decode_message(&bad_host).is_err());
+        assert_eq!(decode_message(&[]), Err("message too short".to_string()));
+    }
+
+    #[test]
+    fn long_ids_are_cut() {
+        let encoded = encode_message(Flag::Ok, String::new(), "7".repeat(300), String::new());
+        assert_eq!(decode_message(&encoded).unwrap().2, "7".repeat(255));
+    }
+
+    #[test]
+    fn addresses_are_checked() {
+        assert_eq!("[::1]:80".parse::<NodeAddr>(), Ok(NodeAddr::Ip("[::1]:80".parse().unwrap())));
+        assert_eq!("localhost:80".parse::<NodeAddr>(), Ok(NodeAddr::Host("localhost".to_string(), 80)));
+        for invalid in ["localhost", "localhost:port", ":80", "-node:80", "a..b:80", "node_1:80", "localhost:65536"] {
+            assert!(invalid.parse::<NodeAddr>().is_err(), "{}", invalid);
+        }
+        assert!(format!("{}:80", "a".repeat(MAX_HOSTNAME_SIZE + 1)).parse::<NodeAddr>().is_err());
+    }
+}
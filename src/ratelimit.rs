@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::protocol::Flag;
+
+/// Time between two passes dropping the buckets of the peers which stopped sending
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// Most connections handled at the same time, each one holding a thread
+pub const MAX_CONNECTIONS: usize = 128;
+/// Most connections handled at the same time for one remote address
+pub const MAX_CONNECTIONS_PER_ADDRESS: usize = 8;
+
+/// Kinds of messages limited separately, so that a flood of one kind does not starve the others
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageClass {
+    /// `Transaction`
+    Transaction,
+    /// `RequireID` and `RequireWalletID`
+    Identity,
+    /// `RequireBlockchain`, each one makes us send up to `MAX_BLOCKS_PER_REQUEST` blocks
+    Blockchain,
+    /// Everything else (network changes, healthchecks, blocks...)
+    Other,
+}
+
+impl MessageClass {
+    /// Returns the class a flag belongs to
+    pub fn of(flag: Flag) -> MessageClass {
+        match flag {
+            Flag::Transaction => MessageClass::Transaction,
+            Flag::RequireID | Flag::RequireWalletID => MessageClass::Identity,
+            Flag::RequireBlockchain => MessageClass::Blockchain,
+            _ => MessageClass::Other,
+        }
+    }
+
+    /// Returns how many messages of this class a peer may send in a burst, and how many per second after that
+    fn limits(self) -> (f64, f64) {
+        match self {
+            MessageClass::Transaction => (20.0, 5.0),
+            MessageClass::Identity => (3.0, 0.1),
+            MessageClass::Blockchain => (5.0, 1.0),
+            MessageClass::Other => (50.0, 10.0),
+        }
+    }
+}
+
+/// A token bucket: each message takes one token, tokens come back at a fixed rate up to the capacity
+/// *`tokens` - the tokens currently available
+/// *`capacity` - the most tokens the bucket can hold, the size of the allowed burst
+/// *`rate` - the tokens given back each second
+/// *`last_refill` - when the tokens were last given back
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+
+    /// CONSTRUCTOR
+    /// Returns a full bucket
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        TokenBucket {
+            tokens: capacity,
+            capacity,
+            rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes one token, returns false if the bucket is empty
+    pub fn take(&mut self) -> bool {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Gives back the tokens earned since the last refill
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Returns true if the bucket is full again, it is then the same as a new one and can be dropped
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate >= self.capacity
+    }
+}
+
+/// Keeps one token bucket per peer address and per message class
+/// *`buckets` - the buckets of the peers which sent something lately, a bucket is dropped once full again
+/// *`last_prune` - when the full buckets were last dropped
+/// *`trust_loopback` - whether local connections are not limited, see `RateLimiter::new`
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<(IpAddr, MessageClass), TokenBucket>,
+    last_prune: Option<Instant>,
+    trust_loopback: bool,
+}
+
+impl RateLimiter {
+
+    /// CONSTRUCTOR
+    /// *`trust_loopback` - true to let local connections through without limit, for a network of nodes sharing the machine.
+    /// Off by default: a proxy or a tunnel on the machine would otherwise make every peer behind it unlimited
+    pub fn new(trust_loopback: bool) -> Self {
+        RateLimiter { trust_loopback, ..RateLimiter::default() }
+    }
+
+    /// Returns true if local connections are not limited
+    pub fn trusts_loopback(&self) -> bool {
+        self.trust_loopback
+    }
+
+    /// Returns true if the peer may send one more message of this kind right now
+    /// *`ip` - the address the message came from
+    /// *`flag` - the flag of the message
+    pub fn allow(&mut self, ip: IpAddr, flag: Flag) -> bool {
+        if self.trust_loopback && ip.is_loopback() {
+            return true;
+        }
+        let now = Instant::now();
+        if self.last_prune.is_none_or(|last| now.duration_since(last) >= PRUNE_INTERVAL) {
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+            self.last_prune = Some(now);
+        }
+        let class = MessageClass::of(flag);
+        let (capacity, rate) = class.limits();
+        self.buckets.entry((ip, class)).or_insert_with(|| TokenBucket::new(capacity, rate)).take()
+    }
+}
+
+/// Counts the connections being handled, per remote address, so that no peer can hold all our handler threads
+/// *`open` - the number of connections being handled, by remote address
+/// *`trust_loopback` - whether local connections only count towards `MAX_CONNECTIONS`, see `RateLimiter::new`
+#[derive(Clone, Default)]
+pub struct ConnectionSlots {
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    trust_loopback: bool,
+}
+
+impl ConnectionSlots {
+
+    /// CONSTRUCTOR
+    pub fn new(trust_loopback: bool) -> Self {
+        ConnectionSlots { trust_loopback, ..ConnectionSlots::default() }
+    }
+
+    /// Takes a slot for a connection from `ip`, returns None if we handle too many connections, or too many from `ip`
+    /// The slot is given back when the returned value is dropped
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut open = self.open.lock().unwrap();
+        let total: usize = open.values().sum();
+        let from_ip = open.get(&ip).copied().unwrap_or(0);
+        if total >= MAX_CONNECTIONS || (!(self.trust_loopback && ip.is_loopback()) && from_ip >= MAX_CONNECTIONS_PER_ADDRESS) {
+            return None;
+        }
+        open.insert(ip, from_ip + 1);
+        Some(ConnectionSlot { slots: self.clone(), ip })
+    }
+}
+
+/// A connection being handled, see `ConnectionSlots::acquire`
+pub struct ConnectionSlot {
+    slots: ConnectionSlots,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.slots.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let mut bucket = TokenBucket::new(3.0, 1.0);
+        assert!(bucket.take() && bucket.take() && bucket.take());
+        assert!(!bucket.take());
+        let now = Instant::now();
+        assert!(!bucket.is_full(now));
+        assert!(bucket.is_full(now + Duration::from_secs(4)));
+        bucket.refill(now + Duration::from_secs(1));
+        assert!(bucket.tokens >= 1.0 && bucket.tokens < 2.0);
+    }
+
+    #[test]
+    fn peers_and_classes_are_limited_apart() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..3 {
+            assert!(limiter.allow(peer(1), Flag::RequireWalletID));
+        }
+        assert!(!limiter.allow(peer(1), Flag::RequireID));
+        assert!(limiter.allow(peer(1), Flag::Transaction));
+        assert!(limiter.allow(peer(2), Flag::RequireID));
+    }
+
+    #[test]
+    fn local_connections_are_limited_unless_trusted() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut limiter = RateLimiter::default();
+        assert_eq!((0..100).filter(|_| limiter.allow(local, Flag::RequireID)).count(), 3);
+        let mut trusting = RateLimiter::new(true);
+        assert!((0..100).all(|_| trusting.allow(local, Flag::RequireID)));
+        assert!(trusting.trusts_loopback());
+        assert!((0..3).all(|_| trusting.allow(peer(1), Flag::RequireID)));
+        assert!(!trusting.allow(peer(1), Flag::RequireID));
+
+        let slots = ConnectionSlots::default();
+        let held: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS_PER_ADDRESS).map(|_| slots.acquire(local).unwrap()).collect();
+        assert!(slots.acquire(local).is_none());
+        let trusting = ConnectionSlots::new(true);
+        let trusted: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS).map(|_| trusting.acquire(local).unwrap()).collect();
+        assert!(trusting.acquire(local).is_none());
+        drop((held, trusted));
+    }
+
+    #[test]
+    fn full_buckets_are_dropped() {
+        let mut limiter = RateLimiter::default();
+        for last in 1..=10 {
+            limiter.allow(peer(last), Flag::Check);
+        }
+        assert_eq!(limiter.buckets.len(), 10);
+        // A peer which stopped sending for long enough has a full bucket again
+        for ((ip, _), bucket) in limiter.buckets.iter_mut() {
+            if *ip != peer(1) {
+                bucket.tokens = bucket.capacity;
+            }
+        }
+        limiter.last_prune = None;
+        limiter.allow(peer(1), Flag::Check);
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn connections_are_capped_per_address_and_in_total() {
+        let slots = ConnectionSlots::default();
+        let held: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS_PER_ADDRESS).map(|_| slots.acquire(peer(1)).unwrap()).collect();
+        assert!(slots.acquire(peer(1)).is_none());
+        drop(held);
+        assert!(slots.open.lock().unwrap().is_empty());
+
+        let mut held = Vec::new();
+        for i in 0..MAX_CONNECTIONS {
+            held.push(slots.acquire(peer((i / MAX_CONNECTIONS_PER_ADDRESS) as u8)).unwrap());
+        }
+        assert!(slots.acquire(peer(255)).is_none());
+        assert!(ConnectionSlots { open: Arc::clone(&slots.open), trust_loopback: true }.acquire("127.0.0.1".parse().unwrap()).is_none());
+        held.pop();
+        assert!(slots.acquire(peer(255)).is_some());
+    }
+}
@@ -0,0 +1,331 @@
+use std::path::PathBuf;
+use crate::storage;
+
+/// Number of commands kept in the command history of a wallet
+const MAX_HISTORY: usize = 200;
+
+/// Used to represent the commands that the user can send to the wallet via the standard input
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UserCommand {
+    Send,
+    Export,
+    Import,
+    Check,
+    Verify,
+    Fees,
+    Balance,
+    Lookup,
+    History,
+    Pending,
+    Mnemonic,
+    NewAddress,
+    Watch,
+    Miners,
+    Sync,
+    Commands,
+    Help,
+    Exit,
+}
+
+impl UserCommand {
+
+    /// Every command, in the order `help` lists them
+    pub const ALL: [UserCommand; 18] = [
+        UserCommand::Send, UserCommand::Export, UserCommand::Import, UserCommand::Balance, UserCommand::History,
+        UserCommand::Pending, UserCommand::Lookup, UserCommand::Verify, UserCommand::Fees, UserCommand::NewAddress,
+        UserCommand::Watch, UserCommand::Mnemonic, UserCommand::Miners, UserCommand::Sync, UserCommand::Check,
+        UserCommand::Commands, UserCommand::Help, UserCommand::Exit,
+    ];
+
+    /// Returns the name typed to run the command, whatever its case
+    pub fn name(&self) -> &'static str {
+        match self {
+            UserCommand::Send => "Send",
+            UserCommand::Export => "Export",
+            UserCommand::Import => "Import",
+            UserCommand::Check => "Check",
+            UserCommand::Verify => "Verify",
+            UserCommand::Fees => "Fees",
+            UserCommand::Balance => "Balance",
+            UserCommand::Lookup => "Lookup",
+            UserCommand::History => "History",
+            UserCommand::Pending => "Pending",
+            UserCommand::Mnemonic => "Mnemonic",
+            UserCommand::NewAddress => "NewAddress",
+            UserCommand::Watch => "Watch",
+            UserCommand::Miners => "Miners",
+            UserCommand::Sync => "Sync",
+            UserCommand::Commands => "Commands",
+            UserCommand::Help => "Help",
+            UserCommand::Exit => "Exit",
+        }
+    }
+
+    /// Returns the arguments of the command, optional ones between brackets
+    pub fn usage(&self) -> &'static str {
+        match self {
+            UserCommand::Send => "<address> <amount> [fee=<n>] [message...]",
+            UserCommand::Export => "<file> <address> <amount> [fee=<n>] [message...]",
+            UserCommand::Import => "<file>",
+            UserCommand::Watch => "<public key|address>",
+            UserCommand::Verify | UserCommand::Lookup => "<txid>",
+            UserCommand::History => "[page]",
+            UserCommand::Help => "[command]",
+            _ => "",
+        }
+    }
+
+    /// Returns what the command does, shown by `help`
+    pub fn description(&self) -> &'static str {
+        match self {
+            UserCommand::Send => "sends amount to address, paying fee (the normal estimate if missing), with an optional message",
+            UserCommand::Export => "writes to file the transaction Send would send, unsigned, to be signed offline by `wallet sign`",
+            UserCommand::Import => "sends to the Miner a transaction signed offline, read from file",
+            UserCommand::Check => "checks that the wallet is running",
+            UserCommand::Verify => "checks with a proof from the Miner that a transaction is in the main chain",
+            UserCommand::Fees => "shows the fee rates advised by the Miner",
+            UserCommand::Balance => "shows the confirmed, unconfirmed and spendable amounts of our addresses",
+            UserCommand::Lookup => "shows where a transaction is: its block, or the mempool",
+            UserCommand::History => "shows a page of the transactions of our addresses, newest first",
+            UserCommand::Pending => "shows our transactions waiting in the mempool",
+            UserCommand::Mnemonic => "shows the words restoring the wallet",
+            UserCommand::NewAddress => "creates a new address to receive coins",
+            UserCommand::Watch => "tracks the balance and history of a public key or an address, in a watch-only wallet",
+            UserCommand::Sync => "downloads the blocks of the main chain the wallet does not have yet, resuming the last sync",
+            UserCommand::Miners => "checks the health of the Miners the wallet knows, another one takes over if ours fails",
+            UserCommand::Commands => "lists the commands typed before, !N runs the command N again and !! the last one",
+            UserCommand::Help => "lists the commands, or explains one of them",
+            UserCommand::Exit => "stops the wallet",
+        }
+    }
+
+    /// Returns the least and the most arguments the command takes, None if there is no maximum
+    pub fn arguments(&self) -> (usize, Option<usize>) {
+        match self {
+            UserCommand::Send => (2, None),
+            UserCommand::Export => (3, None),
+            UserCommand::Verify | UserCommand::Lookup | UserCommand::Import | UserCommand::Watch => (1, Some(1)),
+            UserCommand::History | UserCommand::Help => (0, Some(1)),
+            _ => (0, Some(0)),
+        }
+    }
+
+    /// Finds the command named `name`, whatever its case
+    pub fn parse(name: &str) -> Option<UserCommand> {
+        UserCommand::ALL.iter().copied().find(|command| command.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A line typed by the user: a command and its arguments, whose count was checked
+#[derive(Clone, Debug, PartialEq)]
+pub struct Input {
+    pub command: UserCommand,
+    pub arguments: Vec<String>,
+}
+
+/// Reads a line typed by the user
+/// Returns None for an empty line, or an error telling what is wrong with the command or the number of its arguments
+pub fn parse_line(line: &str) -> Result<Option<Input>, String> {
+    let mut words = split_words(line)?;
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let name = words.remove(0);
+    let command = UserCommand::parse(&name).ok_or_else(|| format!("unknown command {}, type help for the list of commands", name))?;
+    let (least, most) = command.arguments();
+    if words.len() < least || most.is_some_and(|most| words.len() > most) {
+        return Err(format!("usage: {} {}", command.name(), command.usage()));
+    }
+    Ok(Some(Input { command, arguments: words }))
+}
+
+/// Splits a line into words separated by spaces
+/// A word between double or single quotes keeps its spaces, and `\` keeps the next character as it is, outside single quotes
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // A word is started by quotes even if they are empty
+    let mut started = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => word.push(chars.next().ok_or("nothing to escape after \\")?),
+            (Some(_), c) => word.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                started = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if started || !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                started = false;
+            }
+            (None, c) => word.push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unclosed quote".to_string());
+    }
+    if started || !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Reads the arguments of a payment, `<address> <amount> [fee=<n>] [message...]`, as `Send` and `Export` take them
+/// The fee is given right after the amount as `fee=<n>` or `--fee <n>`, so a message may start with a number
+/// Returns the address, the amount, the fee if given and the message, its words joined by spaces
+pub fn parse_payment(arguments: &[String]) -> Result<(String, u64, Option<u64>, String), String> {
+    let amount = arguments[1].parse::<u64>().map_err(|_| format!("invalid amount {}", arguments[1]))?;
+    let (fee, message) = match arguments.get(2).map(|word| word.as_str()) {
+        Some("--fee") => (Some(arguments.get(3).ok_or("missing fee after --fee")?.as_str()), 4),
+        Some(word) => match word.strip_prefix("fee=") {
+            Some(fee) => (Some(fee), 3),
+            None => (None, 2),
+        },
+        None => (None, 2),
+    };
+    let fee = match fee {
+        Some(fee) => Some(fee.parse::<u64>().map_err(|_| format!("invalid fee {}", fee))?),
+        None => None,
+    };
+    let message = arguments.get(message..).unwrap_or_default().join(" ");
+    Ok((arguments[0].clone(), amount, fee, message))
+}
+
+/// Returns the list of the commands, or the usage and description of `command`
+pub fn help(command: Option<&str>) -> String {
+    match command.map(|name| UserCommand::parse(name).ok_or(name)) {
+        Some(Ok(command)) => format!("{} {}\n  {}", command.name(), command.usage(), command.description()),
+        Some(Err(name)) => format!("unknown command {}", name),
+        None => UserCommand::ALL.iter()
+            .map(|command| format!("\n  {:<10} {:<40} {}", command.name(), command.usage(), command.description()))
+            .collect::<String>(),
+    }
+}
+
+/// The commands typed in a wallet, saved so they are kept between runs
+/// *`path` - the file of the history, `data/<socket>.history`
+/// *`commands` - the commands, oldest first
+pub struct CommandHistory {
+    path: PathBuf,
+    commands: Vec<String>,
+}
+
+impl CommandHistory {
+
+    /// Reads the history of the wallet listening on `sockip`, empty if it can not be read
+    pub fn load(sockip: &str) -> Self {
+        let path = storage::node_file(sockip, "history");
+        let commands = storage::read_lines(&path).unwrap_or_else(|e| {
+            println!("Err: {}, the command history is lost", e);
+            Vec::new()
+        });
+        CommandHistory { path, commands }
+    }
+
+    /// Replaces `!!` by the last command and `!N` by the command N of the list, other lines are returned as they are
+    pub fn expand(&self, line: &str) -> Result<String, String> {
+        let recalled = match line.strip_prefix('!') {
+            Some("!") => self.commands.last(),
+            Some(number) => match number.parse::<usize>() {
+                Ok(number) if number > 0 => self.commands.get(number - 1),
+                _ => return Err(format!("invalid command number {}", number)),
+            },
+            None => return Ok(line.to_string()),
+        };
+        match recalled {
+            Some(command) => {
+                println!("{}", command);
+                Ok(command.clone())
+            }
+            None => Err("no such command in the history".to_string()),
+        }
+    }
+
+    /// Adds a command at the end of the history and saves it, the oldest ones are forgotten past `MAX_HISTORY`
+    pub fn push(&mut self, command: &str) {
+        if self.commands.last().map(|last| last.as_str()) == Some(command) {
+            return;
+        }
+        self.commands.push(command.to_string());
+        if self.commands.len() > MAX_HISTORY {
+            self.commands.remove(0);
+        }
+        if let Err(e) = storage::write_lines(&self.path, &self.commands) {
+            println!("Err: {}, while saving the command history", e);
+        }
+    }
+
+    /// Lists the commands with their numbers, oldest first
+    pub fn list(&self) -> String {
+        match self.commands.is_empty() {
+            true => "No command typed yet".to_string(),
+            false => self.commands.iter().enumerate().map(|(i, command)| format!("\n{:>4}  {}", i + 1, command)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the words as owned arguments
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn words_keep_their_quotes_and_escapes() {
+        assert_eq!(split_words("  send  a 10 ").unwrap(), words(&["send", "a", "10"]));
+        assert_eq!(split_words("send a 10 \"thanks for lunch\"").unwrap(), words(&["send", "a", "10", "thanks for lunch"]));
+        assert_eq!(split_words("say 'a \\ b' \"c \\\" d\" e\\ f").unwrap(), words(&["say", "a \\ b", "c \" d", "e f"]));
+        assert_eq!(split_words("empty '' \"\"").unwrap(), words(&["empty", "", ""]));
+        assert_eq!(split_words("").unwrap(), Vec::<String>::new());
+        assert!(split_words("send 'unclosed").is_err());
+        assert!(split_words("trailing \\").is_err());
+    }
+
+    #[test]
+    fn lines_are_checked_against_the_command() {
+        assert_eq!(parse_line("   ").unwrap(), None);
+        let input = parse_line("SEND a 10").unwrap().unwrap();
+        assert_eq!(input, Input { command: UserCommand::Send, arguments: words(&["a", "10"]) });
+        assert_eq!(parse_line("history").unwrap().unwrap().command, UserCommand::History);
+        assert!(parse_line("fly away").unwrap_err().starts_with("unknown command fly"));
+        assert_eq!(parse_line("send a").unwrap_err(), "usage: Send <address> <amount> [fee=<n>] [message...]");
+        assert!(parse_line("verify a b").is_err());
+        assert!(parse_line("balance now").is_err());
+    }
+
+    #[test]
+    fn payments_take_the_fee_only_when_named() {
+        let payment = |line: &[&str]| parse_payment(&words(line));
+        assert_eq!(payment(&["a", "10"]).unwrap(), ("a".to_string(), 10, None, String::new()));
+        assert_eq!(payment(&["a", "10", "thanks", "for", "lunch"]).unwrap(), ("a".to_string(), 10, None, "thanks for lunch".to_string()));
+        assert_eq!(payment(&["a", "10", "2", "coffees"]).unwrap(), ("a".to_string(), 10, None, "2 coffees".to_string()));
+        assert_eq!(payment(&["a", "10", "fee=3", "2", "coffees"]).unwrap(), ("a".to_string(), 10, Some(3), "2 coffees".to_string()));
+        assert_eq!(payment(&["a", "10", "--fee", "3"]).unwrap(), ("a".to_string(), 10, Some(3), String::new()));
+        assert_eq!(payment(&["a", "10", "thanks", "fee=3"]).unwrap().3, "thanks fee=3");
+        assert!(payment(&["a", "ten"]).is_err());
+        assert!(payment(&["a", "10", "fee=three"]).is_err());
+        assert!(payment(&["a", "10", "--fee"]).is_err());
+    }
+
+    #[test]
+    fn commands_are_recalled_by_number() {
+        let history = CommandHistory { path: PathBuf::new(), commands: words(&["balance", "send a 10"]) };
+        assert_eq!(history.expand("pending").unwrap(), "pending");
+        assert_eq!(history.expand("!!").unwrap(), "send a 10");
+        assert_eq!(history.expand("!1").unwrap(), "balance");
+        assert!(history.expand("!3").is_err());
+        assert!(history.expand("!0").is_err());
+        assert!(history.expand("!x").is_err());
+        let empty = CommandHistory { path: PathBuf::new(), commands: Vec::new() };
+        assert!(empty.expand("!!").is_err());
+    }
+}
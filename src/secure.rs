@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, ED25519};
+use crate::transport::{Connection, Transport};
+use crate::storage;
+
+/// Time allowed to the other side to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Size of a public key, for both Ed25519 and X25519
+const KEY_SIZE: usize = 32;
+/// Size of an Ed25519 signature
+const SIGNATURE_SIZE: usize = 64;
+/// Prefixes of what each side signs during the handshake, so a signature can not be reused for the other role
+const INITIATOR_CONTEXT: &[u8] = b"blockchain-handshake-initiator";
+const RESPONDER_CONTEXT: &[u8] = b"blockchain-handshake-responder";
+/// Most addresses bound to a key on first use
+const MAX_KNOWN: usize = 4096;
+
+/// The long-term identity of a node
+/// *`key_pair` - the Ed25519 key pair used to sign handshakes, saved next to the node state so it survives restarts
+/// *`pinned` - the public keys (hex) expected from some addresses, connections to or from them with another key are refused
+/// *`known` - the public key (hex) proven for each address which is not pinned, see `bind`, trusted from then on like a pin
+/// *`known_path` - where `known` is saved, the `known` file of the node, None for a throwaway identity
+pub struct NodeIdentity {
+    key_pair: Ed25519KeyPair,
+    pinned: HashMap<String, String>,
+    known: Mutex<HashMap<String, String>>,
+    known_path: Option<PathBuf>,
+}
+
+impl NodeIdentity {
+
+    /// Loads the identity of the node listening on `sockip`, or creates and saves a new one the first time
+    /// Pinned keys are read from the `pins` file of the node, one `HOST:PORT hexkey` per line, known keys from its `known` file
+    pub fn load_or_create(sockip: &str) -> io::Result<Self> {
+        let key_path = storage::node_file(sockip, "key");
+        let pkcs8 = match storage::read_lines(&key_path)?.first() {
+            Some(line) => hex::decode(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+            None => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| crypto_error("key generation failed"))?;
+                storage::write_lines(&key_path, &[hex::encode(pkcs8.as_ref())])?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| crypto_error("invalid key file"))?;
+
+        let pinned = read_keys(&storage::node_file(sockip, "pins"))?;
+        let known_path = storage::node_file(sockip, "known");
+        let known = Mutex::new(read_keys(&known_path)?);
+        Ok(NodeIdentity { key_pair, pinned, known, known_path: Some(known_path) })
+    }
+
+    /// Creates a throwaway identity, for one-shot commands which are not nodes of the network
+    pub fn ephemeral() -> io::Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| crypto_error("key generation failed"))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|_| crypto_error("invalid key"))?;
+        Ok(NodeIdentity { key_pair, pinned: HashMap::new(), known: Mutex::new(HashMap::new()), known_path: None })
+    }
+
+    /// Returns our public key as hex, to be given to the operators who want to pin it
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Returns false if a key is pinned for `address` and `key` is not that one
+    /// *`address` - the address the peer claims to listen on
+    /// *`key` - the public key the peer proved it owns during the handshake
+    pub fn accepts(&self, address: &str, key: &[u8]) -> bool {
+        match self.pinned.get(address) {
+            Some(expected) => *expected == hex::encode(key),
+            None => true,
+        }
+    }
+
+    /// Returns false if `address` is pinned or already bound to another key than `key`
+    /// *`address` - the address the peer claims to listen on
+    /// *`key` - the public key the peer proved it owns during the handshake
+    pub fn may_claim(&self, address: &str, key: &[u8]) -> bool {
+        if !self.accepts(address, key) {
+            return false;
+        }
+        match self.known.lock().unwrap().get(address) {
+            Some(expected) => *expected == hex::encode(key),
+            None => true,
+        }
+    }
+
+    /// Returns true if `address` is pinned or bound to `key`, binding it first if it is bound to no key yet.
+    /// A claim is not enough to bind an address: we connect back to it and bind it only if the node listening there
+    /// proves it owns `key`. Then the binding is appended to the `known` file, until `MAX_KNOWN` addresses are bound
+    /// *`transport` - how to connect back to `address`
+    /// *`address` - the address the peer claims to listen on, nothing can be proven for an empty one
+    /// *`key` - the public key the peer proved it owns during the handshake
+    pub fn bind(&self, transport: &dyn Transport, address: &str, key: &[u8]) -> bool {
+        if address.is_empty() || !self.may_claim(address, key) {
+            return false;
+        }
+        if self.pinned.contains_key(address) || self.known.lock().unwrap().contains_key(address) {
+            return true;
+        }
+        // The lock is not held while connecting back, the node may be slow to answer
+        match connect(transport, address, self) {
+            Ok(stream) if stream.peer_key == key => stream.shutdown(),
+            Ok(stream) => {
+                println!("{} is not listening on {}: another key answers there", hex::encode(key), address);
+                stream.shutdown();
+                return false;
+            }
+            Err(e) => {
+                println!("Err: {}, could not connect back to {}", e, address);
+                return false;
+            }
+        }
+        let key = hex::encode(key);
+        let mut known = self.known.lock().unwrap();
+        if let Some(expected) = known.get(address) {
+            return *expected == key;
+        }
+        if known.len() >= MAX_KNOWN {
+            println!("{} not bound: {} addresses are already bound", address, MAX_KNOWN);
+            return false;
+        }
+        if let Some(path) = &self.known_path {
+            if let Err(e) = storage::append_line(path, &format!("{} {}", address, key)) {
+                println!("Err: {}, could not save the known keys", e);
+            }
+        }
+        known.insert(address.to_string(), key);
+        true
+    }
+
+    /// Returns the addresses bound to `key`, pinned or first seen with it
+    pub fn addresses_of(&self, key: &[u8]) -> Vec<String> {
+        let key = hex::encode(key);
+        let known = self.known.lock().unwrap();
+        self.pinned.iter().chain(known.iter())
+            .filter(|(_, bound)| **bound == key)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+}
+
+/// Reads a file of `HOST:PORT hexkey` lines, the lines which are not one are skipped
+fn read_keys(path: &PathBuf) -> io::Result<HashMap<String, String>> {
+    let mut keys = HashMap::new();
+    for line in storage::read_lines(path)? {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() == 2 {
+            keys.insert(parts[0].to_string(), parts[1].to_lowercase());
+        }
+    }
+    Ok(keys)
+}
+
+/// An authenticated and encrypted connection to another node
+/// Every frame is a ChaCha20-Poly1305 ciphertext, the nonce being a per-direction counter
+/// *`peer_key` - the Ed25519 public key of the other side, proven during the handshake
+pub struct SecureStream {
+    connection: Box<dyn Connection>,
+    sealing: LessSafeKey,
+    opening: LessSafeKey,
+    sent: u64,
+    received: u64,
+    pub peer_key: Vec<u8>,
+}
+
+/// Opens an authenticated and encrypted connection to a node
+/// *`transport` - how to reach the node
+/// *`destination` - the address of the node, as `HOST:PORT`
+/// *`identity` - our identity, proven to the node during the handshake
+pub fn connect(transport: &dyn Transport, destination: &str, identity: &NodeIdentity) -> io::Result<SecureStream> {
+    let connection = transport.connect(destination)?;
+    let secure = SecureStream::handshake(connection, identity, true)?;
+    if !identity.accepts(destination, &secure.peer_key) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} did not present its pinned key", destination)));
+    }
+    Ok(secure)
+}
+
+/// Runs the responder side of the handshake on a connection we accepted
+pub fn accept(connection: Box<dyn Connection>, identity: &NodeIdentity) -> io::Result<SecureStream> {
+    SecureStream::handshake(connection, identity, false)
+}
+
+impl SecureStream {
+
+    /// Authenticated key exchange
+    /// Each side sends `identity key | ephemeral X25519 key | signature`, the initiator signing its ephemeral key and
+    /// the responder signing both ephemeral keys. The X25519 shared secret is then expanded with HKDF into one key per direction
+    fn handshake(mut connection: Box<dyn Connection>, identity: &NodeIdentity, initiator: bool) -> io::Result<Self> {
+        connection.set_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let rng = SystemRandom::new();
+        let ephemeral = EphemeralPrivateKey::generate(&X25519, &rng).map_err(|_| crypto_error("key generation failed"))?;
+        let our_ephemeral = ephemeral.compute_public_key().map_err(|_| crypto_error("key generation failed"))?.as_ref().to_vec();
+
+        let (peer_key, peer_ephemeral) = if initiator {
+            connection.send_frame(&hello(identity, INITIATOR_CONTEXT, &our_ephemeral, &[]))?;
+            read_hello(connection.as_mut(), RESPONDER_CONTEXT, &our_ephemeral)?
+        } else {
+            let (peer_key, peer_ephemeral) = read_hello(connection.as_mut(), INITIATOR_CONTEXT, &[])?;
+            connection.send_frame(&hello(identity, RESPONDER_CONTEXT, &our_ephemeral, &peer_ephemeral))?;
+            (peer_key, peer_ephemeral)
+        };
+
+        let (initiator_ephemeral, responder_ephemeral) = if initiator {
+            (&our_ephemeral, &peer_ephemeral)
+        } else {
+            (&peer_ephemeral, &our_ephemeral)
+        };
+        let salt = Salt::new(HKDF_SHA256, &[initiator_ephemeral.as_slice(), responder_ephemeral.as_slice()].concat());
+        let (to_responder, to_initiator) = agreement::agree_ephemeral(
+            ephemeral,
+            &agreement::UnparsedPublicKey::new(&X25519, &peer_ephemeral),
+            crypto_error("key agreement failed"),
+            |secret| {
+                let prk = salt.extract(secret);
+                let derive = |info: &[u8]| -> Result<LessSafeKey, io::Error> {
+                    let info = [info];
+                    let okm = prk.expand(&info, &CHACHA20_POLY1305).map_err(|_| crypto_error("key derivation failed"))?;
+                    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+                };
+                Ok((derive(b"initiator to responder")?, derive(b"responder to initiator")?))
+            },
+        )?;
+        let (sealing, opening) = if initiator { (to_responder, to_initiator) } else { (to_initiator, to_responder) };
+
+        connection.set_timeout(None)?;
+        Ok(SecureStream { connection, sealing, opening, sent: 0, received: 0, peer_key })
+    }
+
+    /// Encrypts and sends one message
+    pub fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let mut in_out = message.to_vec();
+        self.sealing.seal_in_place_append_tag(counter_nonce(self.sent), Aad::empty(), &mut in_out)
+            .map_err(|_| crypto_error("encryption failed"))?;
+        self.sent += 1;
+        self.connection.send_frame(&in_out)
+    }
+
+    /// Receives and decrypts one message
+    /// Returns None when the other side closed the connection, an error if the frame was altered or replayed
+    pub fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut in_out = match self.connection.recv_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let size = self.opening.open_in_place(counter_nonce(self.received), Aad::empty(), &mut in_out)
+            .map_err(|_| crypto_error("invalid frame: authentication failed"))?
+            .len();
+        self.received += 1;
+        in_out.truncate(size);
+        Ok(Some(in_out))
+    }
+
+    /// Sets how long `recv` waits for a frame, None to wait forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.connection.set_timeout(timeout)
+    }
+
+    /// Returns the address of the other side of the connection
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.connection.peer_addr()
+    }
+
+    /// Closes the connection
+    pub fn shutdown(&self) {
+        self.connection.shutdown();
+    }
+}
+
+/// Builds our half of the handshake
+fn hello(identity: &NodeIdentity, context: &[u8], our_ephemeral: &[u8], peer_ephemeral: &[u8]) -> Vec<u8> {
+    let signature = identity.key_pair.sign(&[context, our_ephemeral, peer_ephemeral].concat());
+    [identity.key_pair.public_key().as_ref(), our_ephemeral, signature.as_ref()].concat()
+}
+
+/// Reads the other half of the handshake and checks its signature
+/// Returns the identity key and the ephemeral key of the other side
+fn read_hello(connection: &mut dyn Connection, context: &[u8], our_ephemeral: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let frame = connection.recv_frame()?.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during handshake"))?;
+    if frame.len() != 2 * KEY_SIZE + SIGNATURE_SIZE {
+        return Err(crypto_error("invalid handshake"));
+    }
+    let peer_key = &frame[..KEY_SIZE];
+    let peer_ephemeral = &frame[KEY_SIZE..2 * KEY_SIZE];
+    let signed = [context, peer_ephemeral, our_ephemeral].concat();
+    signature::UnparsedPublicKey::new(&ED25519, peer_key)
+        .verify(&signed, &frame[2 * KEY_SIZE..])
+        .map_err(|_| crypto_error("invalid handshake signature"))?;
+    Ok((peer_key.to_vec(), peer_ephemeral.to_vec()))
+}
+
+/// Nonce made of a frame counter, never reused since every connection derives fresh keys
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0_u8; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn crypto_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::sim::{SimConfig, SimNetwork};
+    use crate::testutil::temp_path;
+
+    /// Opens a secure connection from `client` to `server` over a simulated network losing nothing
+    /// Returns the client side, then the server side
+    fn secure_pair(client: NodeIdentity, server: &NodeIdentity) -> (SecureStream, SecureStream) {
+        let config = SimConfig { seed: 1, min_latency: Duration::from_millis(1), max_latency: Duration::from_millis(1), loss: 0.0, reorder: 0.0 };
+        let network = SimNetwork::new(config);
+        let listener = network.transport("10.0.0.2:8000").unwrap().bind("10.0.0.2:8000").unwrap();
+        let transport = network.transport("10.0.0.1:8000").unwrap();
+        let opening = thread::spawn(move || connect(&transport, "10.0.0.2:8000", &client).unwrap());
+        let connection = loop {
+            match listener.accept().unwrap() {
+                Some((connection, _)) => break connection,
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        let accepted = accept(connection, server).unwrap();
+        (opening.join().unwrap(), accepted)
+    }
+
+    #[test]
+    fn handshakes_prove_both_keys_and_messages_go_through() {
+        let (client, server) = (NodeIdentity::ephemeral().unwrap(), NodeIdentity::ephemeral().unwrap());
+        let client_key = client.public_key();
+        let (mut client_side, mut server_side) = secure_pair(client, &server);
+        assert_eq!(hex::encode(&client_side.peer_key), server.public_key());
+        assert_eq!(hex::encode(&server_side.peer_key), client_key);
+
+        client_side.send(b"first").unwrap();
+        client_side.send(b"").unwrap();
+        server_side.send(b"answer").unwrap();
+        assert_eq!(server_side.recv().unwrap(), Some(b"first".to_vec()));
+        assert_eq!(server_side.recv().unwrap(), Some(Vec::new()));
+        assert_eq!(client_side.recv().unwrap(), Some(b"answer".to_vec()));
+    }
+
+    #[test]
+    fn frames_out_of_order_are_refused() {
+        let (client, server) = (NodeIdentity::ephemeral().unwrap(), NodeIdentity::ephemeral().unwrap());
+        let (mut client_side, mut server_side) = secure_pair(client, &server);
+        // As if a frame had been dropped: the counters of both sides differ, so the next frame does not authenticate
+        server_side.received = 1;
+        client_side.send(b"first").unwrap();
+        assert_eq!(server_side.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Runs `identity` on `address` of `network`, answering the handshakes of the next `count` connections
+    /// Returns its public key, then the thread to join once the connections were made
+    fn serve(network: &SimNetwork, address: &str, identity: NodeIdentity, count: usize) -> (Vec<u8>, thread::JoinHandle<()>) {
+        let key = identity.key_pair.public_key().as_ref().to_vec();
+        let listener = network.transport(address).unwrap().bind(address).unwrap();
+        let serving = thread::spawn(move || {
+            for _ in 0..count {
+                let connection = loop {
+                    match listener.accept().unwrap() {
+                        Some((connection, _)) => break connection,
+                        None => thread::sleep(Duration::from_millis(1)),
+                    }
+                };
+                accept(connection, &identity).unwrap();
+            }
+        });
+        (key, serving)
+    }
+
+    fn quiet_network() -> SimNetwork {
+        SimNetwork::new(SimConfig { seed: 1, min_latency: Duration::from_millis(1), max_latency: Duration::from_millis(1), loss: 0.0, reorder: 0.0 })
+    }
+
+    #[test]
+    fn addresses_are_bound_once_the_key_is_proven_there() {
+        let network = quiet_network();
+        let transport = network.transport("10.0.0.9:8000").unwrap();
+        let path = temp_path("secure-bound.known");
+        let identity = NodeIdentity { known_path: Some(path.clone()), ..NodeIdentity::ephemeral().unwrap() };
+        let (key, serving) = serve(&network, "10.0.0.1:8000", NodeIdentity::ephemeral().unwrap(), 2);
+        let other = [2_u8; KEY_SIZE];
+
+        // Another key answers on the address, and nobody listens on the second one
+        assert!(!identity.bind(&transport, "10.0.0.1:8000", &other));
+        assert!(!identity.bind(&transport, "10.0.0.2:8000", &key));
+        assert!(!identity.bind(&transport, "", &key));
+        assert!(identity.known.lock().unwrap().is_empty());
+
+        assert!(identity.bind(&transport, "10.0.0.1:8000", &key));
+        serving.join().unwrap();
+        // Bound addresses are not proven again, the node does not need to be listening any more
+        assert!(identity.bind(&transport, "10.0.0.1:8000", &key));
+        assert!(!identity.bind(&transport, "10.0.0.1:8000", &other));
+        assert!(!identity.may_claim("10.0.0.1:8000", &other));
+        assert!(identity.may_claim("10.0.0.2:8000", &other));
+        assert_eq!(identity.addresses_of(&key), vec!["10.0.0.1:8000".to_string()]);
+        assert!(identity.addresses_of(&other).is_empty());
+        assert_eq!(read_keys(&path).unwrap(), identity.known.lock().unwrap().clone());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bindings_are_appended_until_the_cap() {
+        let network = quiet_network();
+        let transport = network.transport("10.0.0.9:8000").unwrap();
+        let path = temp_path("secure-capped.known");
+        storage::write_lines(&path, &["10.0.0.3:8000 0303".to_string()]).unwrap();
+        storage::append_line(&path, "").unwrap();
+        let identity = NodeIdentity { known_path: Some(path.clone()), known: Mutex::new(read_keys(&path).unwrap()), ..NodeIdentity::ephemeral().unwrap() };
+        let (first, serving) = serve(&network, "10.0.0.1:8000", NodeIdentity::ephemeral().unwrap(), 1);
+        assert!(identity.bind(&transport, "10.0.0.1:8000", &first));
+        serving.join().unwrap();
+        assert_eq!(storage::read_lines(&path).unwrap(), vec!["10.0.0.3:8000 0303".to_string(), format!("10.0.0.1:8000 {}", hex::encode(&first))]);
+
+        let mut known = identity.known.lock().unwrap();
+        for i in known.len()..MAX_KNOWN {
+            known.insert(format!("10.1.{}.{}:8000", i / 256, i % 256), "00".to_string());
+        }
+        drop(known);
+        let (second, serving) = serve(&network, "10.0.0.2:8000", NodeIdentity::ephemeral().unwrap(), 1);
+        assert!(!identity.bind(&transport, "10.0.0.2:8000", &second));
+        serving.join().unwrap();
+        assert_eq!(storage::read_lines(&path).unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pinned_keys_win_over_first_use() {
+        let network = quiet_network();
+        let transport = network.transport("10.0.0.9:8000").unwrap();
+        let mut identity = NodeIdentity::ephemeral().unwrap();
+        let (key, other) = ([1_u8; KEY_SIZE], [2_u8; KEY_SIZE]);
+        identity.pinned.insert("10.0.0.1:8000".to_string(), hex::encode(key));
+        assert!(!identity.accepts("10.0.0.1:8000", &other));
+        assert!(!identity.bind(&transport, "10.0.0.1:8000", &other));
+        // Pinned addresses need no proof, nobody listens on this one
+        assert!(identity.bind(&transport, "10.0.0.1:8000", &key));
+        assert!(identity.known.lock().unwrap().is_empty());
+        assert_eq!(identity.addresses_of(&key), vec!["10.0.0.1:8000".to_string()]);
+    }
+}
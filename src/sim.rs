@@ -0,0 +1,501 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+use crate::config::ChainConfig;
+use crate::miner;
+use crate::protocol::{encode_message, Flag, NodeAddr};
+use crate::keys::WalletKey;
+use crate::secure::{self, NodeIdentity};
+use crate::storage;
+use crate::transaction::Transaction;
+use crate::transport::{Connection, Listener, Transport};
+
+/// Longest a blocked `recv_frame` sleeps before checking again whether the connection was closed
+const POLL_TIME: Duration = Duration::from_millis(20);
+/// First port given to the outgoing side of simulated connections
+const FIRST_EPHEMERAL_PORT: u16 = 40000;
+
+/// Settings of the simulated network
+/// *`seed` - the seed of every random decision, each link drawing from its own generator seeded from it and from its two ends,
+/// so the same seed and the same frames sent on a link give the same losses and delays, whatever the other links do
+/// *`min_latency`, `max_latency` - the bounds of the delay of each frame
+/// *`loss` - the probability that a connection attempt is lost (the sender sees a timeout)
+/// *`reorder` - the probability that a connection is delayed enough to be accepted after connections opened later
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    pub loss: f64,
+    pub reorder: f64,
+}
+
+/// A connection waiting to be accepted by a simulated listener
+struct Pending {
+    arrival: Instant,
+    order: u64,
+    connection: SimConnection,
+    from: SocketAddr,
+}
+
+type PendingQueue = Arc<Mutex<Vec<Pending>>>;
+
+/// The two nodes at the ends of a link, by the address each one listens on, the sender first
+type LinkEnds = (SocketAddr, SocketAddr);
+
+/// One direction between two nodes
+/// *`rng` - the generator of every decision taken for what goes through the link
+/// *`log` - the decisions taken, in order
+struct Link {
+    rng: StdRng,
+    log: Vec<String>,
+}
+
+impl Link {
+    /// Returns a link whose generator is seeded from the seed of the network and from its two ends
+    fn new(seed: u64, (from, to): LinkEnds) -> Self {
+        let digest = Sha256::digest(format!("{} {} {}", seed, from, to).as_bytes());
+        let mut link_seed = [0u8; 32];
+        link_seed.copy_from_slice(&digest);
+        Link { rng: StdRng::from_seed(link_seed), log: Vec::new() }
+    }
+
+    /// Draws the delay of one frame
+    fn latency(&mut self, config: &SimConfig) -> Duration {
+        let min = config.min_latency.as_millis() as u64;
+        let max = (config.max_latency.as_millis() as u64).max(min);
+        Duration::from_millis(self.rng.gen_range(min..=max))
+    }
+}
+
+/// Shared state of the simulated network
+/// *`listeners` - the connections waiting on each bound address
+/// *`links` - the links used so far, created on their first connection
+/// *`partitions` - the groups of IPs which can reach each other, empty when the network is whole
+struct SimState {
+    config: SimConfig,
+    listeners: HashMap<SocketAddr, PendingQueue>,
+    links: HashMap<LinkEnds, Link>,
+    partitions: Vec<Vec<IpAddr>>,
+    next_port: u16,
+    next_order: u64,
+}
+
+impl SimState {
+    /// Returns the link from one node to another, with the settings of the network
+    fn link(&mut self, ends: LinkEnds) -> (&SimConfig, &mut Link) {
+        let seed = self.config.seed;
+        (&self.config, self.links.entry(ends).or_insert_with(|| Link::new(seed, ends)))
+    }
+
+    /// Returns true if the two IPs are on the same side of every partition
+    fn reachable(&self, a: IpAddr, b: IpAddr) -> bool {
+        a == b || self.partitions.is_empty() || self.partitions.iter().any(|group| group.contains(&a) && group.contains(&b))
+    }
+}
+
+/// An in-process network between nodes, with configurable latency, loss, reordering and partitions
+/// Nodes get their `Transport` from `transport`, every address must be an `IP:PORT`
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimNetwork {
+
+    /// CONSTRUCTOR
+    pub fn new(config: SimConfig) -> Self {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimState {
+                config,
+                listeners: HashMap::new(),
+                links: HashMap::new(),
+                partitions: Vec::new(),
+                next_port: FIRST_EPHEMERAL_PORT,
+                next_order: 0,
+            })),
+        }
+    }
+
+    /// Returns the transport of the node with the given address
+    pub fn transport(&self, local: &str) -> io::Result<SimTransport> {
+        Ok(SimTransport { network: self.clone(), local: parse_sim_addr(local)? })
+    }
+
+    /// Splits the network: nodes only reach the nodes of their own group, frames sent across are silently dropped
+    /// *`groups` - the IPs of each side
+    pub fn partition(&self, groups: Vec<Vec<IpAddr>>) {
+        self.state.lock().unwrap().partitions = groups;
+    }
+
+    /// Removes every partition
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitions.clear();
+    }
+
+    /// Returns every decision taken so far, `<from> -> <to> <decision>`, link by link and in order on each link
+    /// Two runs of the same scenario with the same seed give the same log
+    pub fn delivery_log(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let links: BTreeMap<&LinkEnds, &Link> = state.links.iter().collect();
+        links.into_iter()
+            .flat_map(|((from, to), link)| link.log.iter().map(move |decision| format!("{} -> {} {}", from, to, decision)))
+            .collect()
+    }
+}
+
+/// The transport of one node of a `SimNetwork`
+pub struct SimTransport {
+    network: SimNetwork,
+    local: SocketAddr,
+}
+
+impl Transport for SimTransport {
+    fn connect(&self, destination: &str) -> io::Result<Box<dyn Connection>> {
+        let destination = parse_sim_addr(destination)?;
+        let mut state = self.network.state.lock().unwrap();
+        if !state.reachable(self.local.ip(), destination.ip()) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "simulated partition"));
+        }
+        let queue = state.listeners.get(&destination).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused, "nobody listens on this simulated address"))?;
+        let (config, link) = state.link((self.local, destination));
+        if link.rng.gen_bool(config.loss) {
+            link.log.push("connection lost".to_string());
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "simulated loss"));
+        }
+        let mut delay = link.latency(config);
+        if link.rng.gen_bool(config.reorder) {
+            delay += link.latency(config) * 2;
+        }
+        link.log.push(format!("connection delayed {:?}", delay));
+        let from = SocketAddr::new(self.local.ip(), state.next_port);
+        state.next_port = state.next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        let order = state.next_order;
+        state.next_order += 1;
+        drop(state);
+
+        let (ours, theirs) = SimConnection::pair((from, self.local), (destination, destination), self.network.clone());
+        queue.lock().unwrap().push(Pending { arrival: Instant::now() + delay, order, connection: theirs, from });
+        Ok(Box::new(ours))
+    }
+
+    fn bind(&self, address: &str) -> io::Result<Box<dyn Listener>> {
+        let address = parse_sim_addr(address)?;
+        let mut state = self.network.state.lock().unwrap();
+        if state.listeners.contains_key(&address) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "simulated address already bound"));
+        }
+        let queue: PendingQueue = Arc::new(Mutex::new(Vec::new()));
+        state.listeners.insert(address, Arc::clone(&queue));
+        Ok(Box::new(SimListener { address, queue, network: self.network.clone() }))
+    }
+}
+
+/// A bound address of the simulated network, connections are accepted by order of arrival
+struct SimListener {
+    address: SocketAddr,
+    queue: PendingQueue,
+    network: SimNetwork,
+}
+
+impl Listener for SimListener {
+    fn accept(&self) -> io::Result<Option<(Box<dyn Connection>, SocketAddr)>> {
+        let now = Instant::now();
+        let mut queue = self.queue.lock().unwrap();
+        let next = queue.iter().enumerate()
+            .filter(|(_, pending)| pending.arrival <= now)
+            .min_by_key(|(_, pending)| (pending.arrival, pending.order))
+            .map(|(i, _)| i);
+        Ok(next.map(|i| {
+            let pending = queue.remove(i);
+            (Box::new(pending.connection) as Box<dyn Connection>, pending.from)
+        }))
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        let mut state = self.network.state.lock().unwrap();
+        if matches!(state.listeners.get(&self.address), Some(queue) if Arc::ptr_eq(queue, &self.queue)) {
+            state.listeners.remove(&self.address);
+        }
+    }
+}
+
+/// One side of a simulated connection, frames keep their order but each one is delayed
+/// *`local`, `peer` - the addresses of the two sides of the connection
+/// *`link` - the link the frames we send go through, from the node of this side to the node of the other side
+struct SimConnection {
+    local: SocketAddr,
+    peer: SocketAddr,
+    link: LinkEnds,
+    outgoing: Sender<(Instant, Vec<u8>)>,
+    incoming: Receiver<(Instant, Vec<u8>)>,
+    early: Option<(Instant, Vec<u8>)>,
+    last_arrival: Instant,
+    timeout: Option<Duration>,
+    closed: Arc<AtomicBool>,
+    network: SimNetwork,
+}
+
+impl SimConnection {
+    /// Returns both sides of a connection
+    /// *`a`, `b` - the address of each side, with the address the node of that side listens on
+    fn pair((a, node_a): (SocketAddr, SocketAddr), (b, node_b): (SocketAddr, SocketAddr), network: SimNetwork) -> (SimConnection, SimConnection) {
+        let (to_b, from_a) = mpsc::channel();
+        let (to_a, from_b) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let now = Instant::now();
+        let side = |local, peer, link, outgoing, incoming| SimConnection {
+            local,
+            peer,
+            link,
+            outgoing,
+            incoming,
+            early: None,
+            last_arrival: now,
+            timeout: None,
+            closed: Arc::clone(&closed),
+            network: network.clone(),
+        };
+        (side(a, b, (node_a, node_b), to_b, from_b), side(b, a, (node_b, node_a), to_a, from_a))
+    }
+}
+
+impl Connection for SimConnection {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "simulated connection closed"));
+        }
+        let mut state = self.network.state.lock().unwrap();
+        let reachable = state.reachable(self.local.ip(), self.peer.ip());
+        let (config, link) = state.link(self.link);
+        if !reachable {
+            link.log.push(format!("frame of {} bytes dropped by a partition", frame.len()));
+            return Ok(());
+        }
+        let delay = link.latency(config);
+        link.log.push(format!("frame of {} bytes delayed {:?}", frame.len(), delay));
+        drop(state);
+        let arrival = (Instant::now() + delay).max(self.last_arrival);
+        self.last_arrival = arrival;
+        self.outgoing.send((arrival, frame.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "simulated connection closed"))
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let now = Instant::now();
+            if let Some((arrival, _)) = &self.early {
+                if *arrival <= now {
+                    return Ok(self.early.take().map(|(_, frame)| frame));
+                }
+            }
+            if matches!(deadline, Some(deadline) if deadline <= now) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "simulated timeout"));
+            }
+            let mut wait = POLL_TIME;
+            if let Some(deadline) = deadline {
+                wait = wait.min(deadline - now);
+            }
+            if let Some((arrival, _)) = &self.early {
+                thread::sleep(wait.min(*arrival - now));
+                continue;
+            }
+            match self.incoming.recv_timeout(wait) {
+                Ok(frame) => self.early = Some(frame),
+                Err(RecvTimeoutError::Timeout) if self.closed.load(Ordering::SeqCst) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Simulated addresses must be IPs, there is no name resolution in the simulation
+fn parse_sim_addr(address: &str) -> io::Result<SocketAddr> {
+    match address.parse::<NodeAddr>() {
+        Ok(NodeAddr::Ip(address)) => Ok(address),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: simulated addresses must be IP:PORT", address))),
+    }
+}
+
+/// Runs a whole network of Miners inside this process
+/// Miner `i` listens on `10.0.0.<i+1>:8000`, the first one creates the network and the others join it one by one.
+/// A client then sends `transactions` transactions to the Miners in turn, and everything is stopped after `duration`.
+/// Nobody owns coins when the network starts, so each transaction comes from a new account and moves nothing
+/// *`config` - the behaviour of the simulated network
+/// *`nodes` - how many Miners to run
+/// *`partition` - when to split the Miners in two halves and when to heal the network, counted from the first transaction
+pub fn run(config: SimConfig, nodes: usize, transactions: usize, duration: Duration, partition: Option<(Duration, Duration)>) {
+    let data_dir = std::env::temp_dir().join(format!("blockchain-sim-{}", config.seed));
+    if let Err(e) = std::fs::remove_dir_all(&data_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            println!("Err: {}, while cleaning {}", e, data_dir.display());
+        }
+    }
+    storage::set_data_dir(data_dir);
+    println!("Simulating {} miners with {:?}", nodes, &config);
+
+    let network = SimNetwork::new(config);
+    let addresses: Vec<String> = (0..nodes).map(|i| format!("10.0.{}.{}:8000", i / 250, i % 250 + 1)).collect();
+    let mut running = Vec::new();
+    let mut handles = Vec::new();
+    for (i, address) in addresses.iter().enumerate() {
+        let transport = match network.transport(address) {
+            Ok(transport) => Arc::new(transport),
+            Err(e) => {
+                println!("Err: {}", e);
+                return;
+            }
+        };
+        let (miner_type, destination) = if i == 0 { ('c', String::new()) } else { ('j', addresses[0].to_string()) };
+        match miner::start_miner(transport, miner_type, address.to_string(), destination, None, ChainConfig::default()) {
+            Some(miner) => {
+                running.push(Arc::clone(&miner.running));
+                handles.push(thread::spawn(move || miner.listen()));
+            }
+            None => println!("Miner {} could not start", address),
+        }
+    }
+
+    if let (Ok(client), Ok(identity)) = (network.transport("10.1.0.1:9000"), NodeIdentity::ephemeral()) {
+        for k in 0..transactions {
+            let destination = &addresses[k % addresses.len()];
+            let key = match WalletKey::generate() {
+                Ok(key) => key,
+                Err(e) => {
+                    println!("Err: {}", e);
+                    break;
+                }
+            };
+            let transaction = Transaction::signed_transfer(&key, &key.address(), 0, 0, 1, &format!("sim-transaction-{}", k));
+            let message = encode_message(Flag::Transaction, String::new(), String::new(), transaction.to_string());
+            match secure::connect(&client, destination, &identity) {
+                Ok(mut stream) => if let Err(e) = stream.send(&message) { println!("Err: {}", e); },
+                Err(e) => println!("Transaction {} to {} lost: {}", k, destination, e),
+            }
+        }
+    }
+
+    let start = Instant::now();
+    if let Some((split, heal)) = partition {
+        let ips: Vec<IpAddr> = addresses.iter().filter_map(|address| parse_sim_addr(address).ok()).map(|address| address.ip()).collect();
+        let (left, right) = ips.split_at(ips.len() / 2);
+        thread::sleep(split.min(duration));
+        println!("Partitioning the network: {:?} | {:?}", left, right);
+        network.partition(vec![left.to_vec(), right.to_vec()]);
+        thread::sleep(heal.min(duration).saturating_sub(start.elapsed()));
+        println!("Healing the network");
+        network.heal();
+    }
+    thread::sleep(duration.saturating_sub(start.elapsed()));
+    for flag in running {
+        flag.store(false, Ordering::SeqCst);
+    }
+    let log = network.delivery_log();
+    println!("\nSimulation summary:");
+    println!("\t{} network decisions, {} connections lost", log.len(), log.iter().filter(|decision| decision.ends_with("connection lost")).count());
+    for handle in handles {
+        match handle.join() {
+            Ok(miner) => {
+                let miner = miner.lock().unwrap();
+                println!("\tminer {} ({}): {} blocks, {} pending transactions, {} known miners",
+                    miner.id, miner.sockip, miner.chain.height(), miner.mempool.len(), miner.network.len());
+            }
+            Err(_) => println!("\ta miner panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ten nodes each send three frames to every other node, all at the same time, each node from its own thread,
+    /// then every node reads what reached it
+    /// Returns the log of the network, and the frames received by each node
+    fn simulate(seed: u64) -> (Vec<String>, Vec<Vec<String>>) {
+        let config = SimConfig {
+            seed,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(5),
+            loss: 0.2,
+            reorder: 0.2,
+        };
+        let network = SimNetwork::new(config);
+        let addresses: Vec<String> = (1..=10).map(|i| format!("10.0.0.{}:8000", i)).collect();
+        let listeners: Vec<Box<dyn Listener>> = addresses.iter()
+            .map(|address| network.transport(address).unwrap().bind(address).unwrap())
+            .collect();
+
+        let senders: Vec<_> = addresses.iter().map(|from| {
+            let transport = network.transport(from).unwrap();
+            let (from, addresses) = (from.clone(), addresses.clone());
+            thread::spawn(move || {
+                let mut opened = Vec::new();
+                for to in addresses.iter().filter(|to| **to != from) {
+                    for k in 0..3 {
+                        if let Ok(mut connection) = transport.connect(to) {
+                            connection.send_frame(format!("{} {}", from, k).as_bytes()).unwrap();
+                            opened.push(connection);
+                        }
+                    }
+                }
+                opened
+            })
+        }).collect();
+        let opened: Vec<_> = senders.into_iter().map(|sender| sender.join().unwrap()).collect();
+
+        // The slowest connection waits three times the largest latency before it can be accepted
+        thread::sleep(Duration::from_millis(50));
+        let received = listeners.iter().map(|listener| {
+            let mut frames = Vec::new();
+            while let Some((mut connection, _)) = listener.accept().unwrap() {
+                connection.set_timeout(Some(Duration::from_millis(100))).unwrap();
+                frames.push(String::from_utf8(connection.recv_frame().unwrap().unwrap()).unwrap());
+            }
+            frames.sort();
+            frames
+        }).collect();
+        drop(opened);
+        (network.delivery_log(), received)
+    }
+
+    #[test]
+    fn same_seed_gives_same_deliveries() {
+        let (log, received) = simulate(7);
+        assert!(log.iter().any(|decision| decision.ends_with("connection lost")));
+        assert!(log.iter().any(|decision| decision.contains("frame of")));
+        assert_eq!(received.iter().map(Vec::len).sum::<usize>(), log.iter().filter(|decision| decision.contains("frame of")).count());
+        assert_eq!((log, received), simulate(7));
+    }
+
+    #[test]
+    fn other_seed_gives_other_deliveries() {
+        assert_ne!(simulate(7).0, simulate(8).0);
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::str::FromStr;
+use sha2::{Digest, Sha256};
+use crate::block::{self, BlockHeader};
+
+/// Separator between the fields of an encoded proof
+const FIELD_SEPARATOR: char = '|';
+/// Separator between the hashes of a Merkle branch
+const HASH_SEPARATOR: char = ',';
+
+/// Prefix of the hash of a leaf of a Merkle tree
+const LEAF_TAG: u8 = 0x00;
+/// Prefix of the hash of an inner node of a Merkle tree, so a pair of nodes can never pass for a leaf
+const NODE_TAG: u8 = 0x01;
+
+/// Returns the hash of a leaf of a Merkle tree, the SHA-256 of `LEAF_TAG` followed by the transaction ID
+fn hash_leaf(txid: &[u8]) -> Vec<u8> {
+    let mut sha256 = Sha256::new();
+    sha256.update([LEAF_TAG]);
+    sha256.update(txid);
+    sha256.finalize().to_vec()
+}
+
+/// Returns the hash of two nodes of a Merkle tree, the SHA-256 of `NODE_TAG` followed by their concatenation
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut sha256 = Sha256::new();
+    sha256.update([NODE_TAG]);
+    sha256.update(left);
+    sha256.update(right);
+    sha256.finalize().to_vec()
+}
+
+/// Returns the level above `level` in a Merkle tree, the last node being paired with itself when alone
+/// Blocks including a transaction twice are invalid, so two lists of IDs can not share a root this way
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level.chunks(2).map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0]))).collect()
+}
+
+/// Returns the leaves of the Merkle tree of the transaction IDs
+fn leaves(txids: &[String]) -> Vec<Vec<u8>> {
+    txids.iter().map(|txid| hash_leaf(&hex::decode(txid).unwrap_or_default())).collect()
+}
+
+/// Returns the root of the Merkle tree whose leaves are the transaction IDs, as lowercase hex
+/// The root of an empty tree is the ID of an empty transaction
+pub fn merkle_root(txids: &[String]) -> String {
+    if txids.is_empty() {
+        return block::transaction_id("");
+    }
+    let mut level = leaves(txids);
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    hex::encode(&level[0])
+}
+
+/// Returns the branch proving that the transaction at `position` is a leaf of the Merkle tree of `txids`
+pub fn merkle_branch(txids: &[String], position: usize) -> Option<MerkleBranch> {
+    if position >= txids.len() {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut level = leaves(txids);
+    let mut index = position;
+    while level.len() > 1 {
+        let sibling = level.get(index ^ 1).unwrap_or(&level[index]);
+        siblings.push(hex::encode(sibling));
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(MerkleBranch { position: position as u32, siblings })
+}
+
+/// The hashes needed to go from a leaf of a Merkle tree to its root
+/// *`position` - the index of the leaf, telling on which side each sibling is
+/// *`siblings` - the other node of each pair, from the leaf up, as lowercase hex
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleBranch {
+    pub position: u32,
+    pub siblings: Vec<String>,
+}
+
+impl MerkleBranch {
+
+    /// Returns the root reached from the leaf `txid`, as lowercase hex
+    pub fn root(&self, txid: &str) -> Result<String, String> {
+        let mut node = hash_leaf(&hex::decode(txid).map_err(|e| format!("invalid transaction ID: {}", e))?);
+        let mut index = self.position;
+        for sibling in &self.siblings {
+            let sibling = hex::decode(sibling).map_err(|e| format!("invalid Merkle branch: {}", e))?;
+            node = match index % 2 {
+                0 => hash_pair(&node, &sibling),
+                _ => hash_pair(&sibling, &node),
+            };
+            index /= 2;
+        }
+        Ok(hex::encode(node))
+    }
+}
+
+/// What a Miner sends to prove that a transaction is in its main chain
+/// *`branch` - the Merkle branch of the transaction in its block
+/// *`header` - the header of the block including the transaction, the wallet finds the blocks on top of it in those it synced
+#[derive(Clone, PartialEq)]
+pub struct InclusionProof {
+    pub branch: MerkleBranch,
+    pub header: BlockHeader,
+}
+
+impl InclusionProof {
+
+    /// Checks that the transaction `txid` is in the block of the header and that the block has a proof of work of `difficulty`
+    pub fn verify(&self, txid: &str, difficulty: usize) -> Result<(), String> {
+        if self.branch.root(txid)? != self.header.merkle_root {
+            return Err(format!("transaction {} is not in block {}", txid, self.header.index));
+        }
+        if !self.header.has_valid_proof(difficulty) {
+            return Err(format!("invalid proof of work for block {}", self.header.index));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for InclusionProof {
+    /// Writes `<position>|<siblings separated by ,>|<header>`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}{}{}", self.branch.position, FIELD_SEPARATOR, self.branch.siblings.join(&HASH_SEPARATOR.to_string()),
+            FIELD_SEPARATOR, self.header)
+    }
+}
+
+impl FromStr for InclusionProof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(FIELD_SEPARATOR).collect();
+        if parts.len() != 3 {
+            return Err(format!("invalid proof {}", s));
+        }
+        Ok(InclusionProof {
+            branch: MerkleBranch {
+                position: parts[0].parse::<u32>().map_err(|e| format!("invalid position: {}", e))?,
+                siblings: parts[1].split(HASH_SEPARATOR).filter(|h| !h.is_empty()).map(|h| h.to_string()).collect(),
+            },
+            header: parts[2].parse::<BlockHeader>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::testutil;
+
+    fn txids(count: usize) -> Vec<String> {
+        (0..count).map(|i| block::transaction_id(&i.to_string())).collect()
+    }
+
+    /// Returns the header of a block of proof of work `difficulty` including `txids`
+    fn mine(txids: &[String], difficulty: usize) -> BlockHeader {
+        let mut header = Block::new(0, String::new(), 0, 0, block::genesis_prev_hash()).header();
+        header.merkle_root = merkle_root(txids);
+        testutil::prove(&mut header, difficulty);
+        header
+    }
+
+    #[test]
+    fn every_branch_leads_to_the_root() {
+        for count in 1..=9 {
+            let txids = txids(count);
+            let root = merkle_root(&txids);
+            for (position, txid) in txids.iter().enumerate() {
+                let branch = merkle_branch(&txids, position).unwrap();
+                assert_eq!(branch.root(txid), Ok(root.clone()), "{} of {}", position, count);
+                assert_ne!(branch.root(&block::transaction_id("other")), Ok(root.clone()), "{} of {}", position, count);
+            }
+            assert_eq!(merkle_branch(&txids, count), None);
+        }
+        assert!(merkle_branch(&txids(3), 0).unwrap().root("not hex").is_err());
+    }
+
+    #[test]
+    fn inner_nodes_do_not_pass_for_leaves() {
+        let txids = txids(4);
+        let inner: Vec<String> = next_level(&leaves(&txids)).iter().map(hex::encode).collect();
+        assert_ne!(merkle_root(&inner), merkle_root(&txids));
+        // Without the tags, a branch of the level above would prove the inner node is a transaction
+        let branch = merkle_branch(&inner, 0).unwrap();
+        assert_ne!(branch.root(&inner[0]), Ok(merkle_root(&txids)));
+    }
+
+    #[test]
+    fn proofs_check_the_transaction_and_the_header() {
+        let txids = txids(5);
+        let proof = InclusionProof { branch: merkle_branch(&txids, 3).unwrap(), header: mine(&txids, 1) };
+        assert_eq!(proof.verify(&txids[3], 1), Ok(()));
+        assert!(proof.verify(&txids[2], 1).is_err());
+        assert!(proof.verify(&txids[3], 4).is_err());
+
+        let decoded = proof.to_string().parse::<InclusionProof>().unwrap();
+        assert!(decoded == proof);
+        assert!(format!("{}|", proof).parse::<InclusionProof>().is_err());
+
+        let mut forged = proof.clone();
+        forged.header.merkle_root = merkle_root(&txids[..1]);
+        assert!(forged.verify(&txids[0], 1).is_err());
+    }
+}
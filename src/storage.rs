@@ -0,0 +1,57 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Directory in which every node keeps the files that must survive a restart
+const DATA_DIR: &str = "data";
+
+/// Directory used instead of `DATA_DIR` when set, so simulations do not mix their files with real nodes
+static DATA_DIR_OVERRIDE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Makes every node of this process keep its files in `dir` instead of `DATA_DIR`
+pub fn set_data_dir(dir: PathBuf) {
+    *DATA_DIR_OVERRIDE.lock().unwrap() = Some(dir);
+}
+
+/// Builds the path of a file owned by the node listening on `sockip`
+/// *`sockip` - the IP address of the node, used to keep the files of several nodes apart on the same machine
+/// *`name` - the kind of file (`state`, `bans`...)
+/// # Example
+/// ```rust
+/// assert_eq!(node_file("127.0.0.1:8080", "state"), PathBuf::from("data/127.0.0.1_8080.state"));
+/// ```
+pub fn node_file(sockip: &str, name: &str) -> PathBuf {
+    let node: String = sockip.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' }).collect();
+    let dir = DATA_DIR_OVERRIDE.lock().unwrap().clone().unwrap_or_else(|| PathBuf::from(DATA_DIR));
+    dir.join(format!("{}.{}", node, name))
+}
+
+/// Writes the given lines to the file, replacing its previous content
+/// The content is written to a temporary file first, then renamed, so a crash never leaves a half-written file
+pub fn write_lines(path: &PathBuf, lines: &[String]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, lines.join("\n"))?;
+    fs::rename(&tmp, path)
+}
+
+/// Adds a line at the end of the file, creating it if it does not exist yet
+pub fn append_line(path: &PathBuf, line: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+/// Reads the lines of the file, returns an empty list if it does not exist yet
+pub fn read_lines(path: &PathBuf) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+use crate::block::BlockHeader;
+
+/// Returns a path in the temporary directory, no file existing there yet
+/// *`name` - unique among the tests, as they run at the same time
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("blockchain-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Looks for the nonce giving `header` a proof of work of `difficulty`, and sets its hash
+pub fn prove(header: &mut BlockHeader, difficulty: usize) {
+    while !header.compute_hash().starts_with(&"0".repeat(difficulty)) {
+        header.nonce += 1;
+    }
+    header.hash = header.compute_hash().into_bytes();
+}
@@ -0,0 +1,369 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use crate::address;
+use crate::block;
+use crate::keys::{self, WalletKey};
+
+/// Separator between the fields of an encoded transaction
+/// The characters used by the encoding of blocks (`$`, `;`, `:`) never appear in a transaction
+const FIELD_SEPARATOR: char = '|';
+/// Separator between the items of a list field (inputs, outputs)
+const LIST_SEPARATOR: char = ',';
+/// Separator between the parts of an item (`txid-index`, `address-amount`)
+const ITEM_SEPARATOR: char = '-';
+
+/// A reference to an output of a transaction, written `txid-index`
+/// The coinbase of a block creates the output 0 of its own transaction
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: String,
+    pub index: u32,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.txid, ITEM_SEPARATOR, self.index)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (txid, index) = s.split_once(ITEM_SEPARATOR).ok_or_else(|| format!("invalid output reference {}", s))?;
+        check_hex(txid, 32, "transaction ID")?;
+        Ok(OutPoint { txid: txid.to_string(), index: index.parse::<u32>().map_err(|e| format!("invalid output index: {}", e))? })
+    }
+}
+
+/// Coins created by a transaction, written `address-amount`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Output {
+    pub address: String,
+    pub amount: u64,
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.address, ITEM_SEPARATOR, self.amount)
+    }
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, amount) = s.split_once(ITEM_SEPARATOR).ok_or_else(|| format!("invalid output {}", s))?;
+        address::check(address)?;
+        Ok(Output { address: address.to_string(), amount: amount.parse::<u64>().map_err(|e| format!("invalid amount: {}", e))? })
+    }
+}
+
+/// Writes a list field, items separated by `,`
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items.iter().map(|item| item.to_string()).collect::<Vec<String>>().join(&LIST_SEPARATOR.to_string())
+}
+
+/// Reads a list field, which must not be empty
+fn split<T: FromStr<Err = String>>(field: &str) -> Result<Vec<T>, String> {
+    field.split(LIST_SEPARATOR).map(|item| item.parse::<T>()).collect()
+}
+
+/// A transaction, as found in the payload of a block
+/// Transactions are written as text, fields separated by `|`, free text being hex encoded:
+/// *`transfer|<from>|<to>|<amount>|<fee>|<nonce>|<hex message>|<signature>` - sent by a wallet, moves `amount` from `from` to `to`,
+/// `fee` going to the miner of the block including it. `from` is the public key of the wallet, the coins leaving its address,
+/// `nonce` follows the nonce of its previous transaction and `signature` is its Ed25519 signature of everything before the last `|`
+/// *`spend|<from>|<inputs>|<outputs>|<fee>|<hex message>|<signature>` - sent by a wallet of a UTXO chain, consumes the
+/// `inputs` (`txid-index` separated by `,`), all owned by the address of `from`, and creates the `outputs` (`address-amount` separated by `,`).
+/// The inputs must hold exactly the outputs plus `fee`, `signature` works like for transfers
+/// *`coinbase|<height>|<address>|<amount>` - first transaction of every block, pays the miner
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transaction {
+    Transfer { from: String, to: String, amount: u64, fee: u64, nonce: u64, message: String, signature: String },
+    Spend { from: String, inputs: Vec<OutPoint>, outputs: Vec<Output>, fee: u64, message: String, signature: String },
+    Coinbase { height: u32, to: String, amount: u64 },
+}
+
+impl Transaction {
+
+    /// Creates a transfer signed by `key`
+    pub fn signed_transfer(key: &WalletKey, to: &str, amount: u64, fee: u64, nonce: u64, message: &str) -> Self {
+        let mut transaction = Transaction::transfer(&key.public_key(), to, amount, fee, nonce, message);
+        transaction.sign(key);
+        transaction
+    }
+
+    /// Creates a transfer from the owner of the public key `from`, not signed yet, see `sign`
+    pub fn transfer(from: &str, to: &str, amount: u64, fee: u64, nonce: u64, message: &str) -> Self {
+        Transaction::Transfer {
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            fee,
+            nonce,
+            message: message.to_string(),
+            signature: String::new(),
+        }
+    }
+
+    /// Creates a spend of outputs owned by the public key `from`, not signed yet, see `sign`
+    pub fn spend(from: &str, inputs: Vec<OutPoint>, outputs: Vec<Output>, fee: u64, message: &str) -> Self {
+        Transaction::Spend {
+            from: from.to_string(),
+            inputs,
+            outputs,
+            fee,
+            message: message.to_string(),
+            signature: String::new(),
+        }
+    }
+
+    /// Signs the transaction with `key`, replacing its signature, coinbases are not signed
+    pub fn sign(&mut self, key: &WalletKey) {
+        let signed = key.sign(self.signed_part().as_bytes());
+        match self {
+            Transaction::Transfer { signature, .. } | Transaction::Spend { signature, .. } => *signature = signed,
+            Transaction::Coinbase { .. } => {}
+        }
+    }
+
+    /// Returns the size of the encoded transaction once signed, signed or not yet
+    pub fn signed_size(&self) -> usize {
+        match self {
+            Transaction::Transfer { .. } | Transaction::Spend { .. } => self.signed_part().len() + 1 + 2 * keys::SIGNATURE_SIZE,
+            Transaction::Coinbase { .. } => self.to_string().len(),
+        }
+    }
+
+    /// Returns the public key of the sender, None for coinbases
+    pub fn sender(&self) -> Option<&str> {
+        match self {
+            Transaction::Transfer { from, .. } | Transaction::Spend { from, .. } => Some(from),
+            Transaction::Coinbase { .. } => None,
+        }
+    }
+
+    /// Returns the fee paid to the miner, coinbases pay none
+    pub fn fee(&self) -> u64 {
+        match self {
+            Transaction::Transfer { fee, .. } | Transaction::Spend { fee, .. } => *fee,
+            Transaction::Coinbase { .. } => 0,
+        }
+    }
+
+    /// Returns the addresses the transaction sends from or to, each one once
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addresses = match self {
+            Transaction::Transfer { from, to, .. } => vec![address::from_public_key(from), to.clone()],
+            Transaction::Spend { from, outputs, .. } =>
+                std::iter::once(address::from_public_key(from)).chain(outputs.iter().map(|o| o.address.clone())).collect(),
+            Transaction::Coinbase { to, .. } => vec![to.clone()],
+        };
+        let mut seen = HashSet::new();
+        addresses.retain(|address| seen.insert(address.clone()));
+        addresses
+    }
+
+    /// Returns what the transaction gives to `addresses` and what it takes from them, fee included
+    /// A transaction between two of the addresses gives and takes the amount moved
+    pub fn value_for(&self, addresses: &[String]) -> (u64, u64) {
+        let ours = |address: &String| addresses.contains(address);
+        match self {
+            Transaction::Transfer { from, to, amount, fee, .. } => {
+                let received = if ours(to) { *amount } else { 0 };
+                let sent = if ours(&address::from_public_key(from)) { amount.saturating_add(*fee) } else { 0 };
+                (received, sent)
+            }
+            Transaction::Spend { from, outputs, fee, .. } => {
+                let received = outputs.iter().filter(|output| ours(&output.address)).fold(0_u64, |total, output| total.saturating_add(output.amount));
+                let sent = match ours(&address::from_public_key(from)) {
+                    true => outputs.iter().fold(*fee, |total, output| total.saturating_add(output.amount)),
+                    false => 0,
+                };
+                (received, sent)
+            }
+            Transaction::Coinbase { to, amount, .. } => (if ours(to) { *amount } else { 0 }, 0),
+        }
+    }
+
+    /// Returns the outputs the transaction creates, by index
+    /// Transfers create none, they change balances instead
+    pub fn outputs(&self) -> Vec<Output> {
+        match self {
+            Transaction::Spend { outputs, .. } => outputs.clone(),
+            Transaction::Coinbase { to, amount, .. } => vec![Output { address: to.clone(), amount: *amount }],
+            Transaction::Transfer { .. } => Vec::new(),
+        }
+    }
+
+    /// Returns true for the transaction paying the miner of a block
+    pub fn is_coinbase(&self) -> bool {
+        matches!(self, Transaction::Coinbase { .. })
+    }
+
+    /// Returns the ID of the transaction, see `block::transaction_id`
+    pub fn id(&self) -> String {
+        block::transaction_id(&self.to_string())
+    }
+
+    /// Returns the canonical encoding of a transfer without its signature, what the wallet signs
+    pub fn signed_part(&self) -> String {
+        match self {
+            Transaction::Transfer { from, to, amount, fee, nonce, message, .. } =>
+                format!("transfer|{}|{}|{}|{}|{}|{}", from, to, amount, fee, nonce, hex::encode(message)),
+            Transaction::Spend { from, inputs, outputs, fee, message, .. } =>
+                format!("spend|{}|{}|{}|{}|{}", from, join(inputs), join(outputs), fee, hex::encode(message)),
+            Transaction::Coinbase { .. } => self.to_string(),
+        }
+    }
+
+    /// Returns true if the transfer was signed by the owner of `from`
+    /// Coinbases are not signed, the block they start is checked instead
+    pub fn has_valid_signature(&self) -> bool {
+        match self {
+            Transaction::Transfer { from, signature, .. } | Transaction::Spend { from, signature, .. } =>
+                keys::verify(from, self.signed_part().as_bytes(), signature),
+            Transaction::Coinbase { .. } => true,
+        }
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transaction::Transfer { signature, .. } | Transaction::Spend { signature, .. } => write!(f, "{}|{}", self.signed_part(), signature),
+            Transaction::Coinbase { height, to, amount } => write!(f, "coinbase|{}|{}|{}", height, to, amount),
+        }
+    }
+}
+
+/// Checks that `value` is the hex encoding of exactly `size` bytes
+fn check_hex(value: &str, size: usize, name: &str) -> Result<(), String> {
+    match hex::decode(value) {
+        Ok(bytes) if bytes.len() == size => Ok(()),
+        _ => Err(format!("invalid {} {}", name, value)),
+    }
+}
+
+impl FromStr for Transaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(FIELD_SEPARATOR).collect();
+        let number = |i: usize, name: &str| parts[i].parse::<u64>().map_err(|e| format!("invalid {}: {}", name, e));
+        match (parts[0], parts.len()) {
+            ("transfer", 8) => {
+                check_hex(parts[1], keys::PUBLIC_KEY_SIZE, "public key")?;
+                address::check(parts[2])?;
+                check_hex(parts[7], keys::SIGNATURE_SIZE, "signature")?;
+                let message = hex::decode(parts[6]).map_err(|e| format!("invalid message: {}", e))?;
+                Ok(Transaction::Transfer {
+                    from: parts[1].to_string(),
+                    to: parts[2].to_string(),
+                    amount: number(3, "amount")?,
+                    fee: number(4, "fee")?,
+                    nonce: number(5, "nonce")?,
+                    message: String::from_utf8(message).map_err(|e| format!("invalid message: {}", e))?,
+                    signature: parts[7].to_string(),
+                })
+            }
+            ("spend", 7) => {
+                check_hex(parts[1], keys::PUBLIC_KEY_SIZE, "public key")?;
+                check_hex(parts[6], keys::SIGNATURE_SIZE, "signature")?;
+                let inputs: Vec<OutPoint> = split(parts[2])?;
+                let outputs: Vec<Output> = split(parts[3])?;
+                let fee = number(4, "fee")?;
+                if inputs.iter().collect::<HashSet<&OutPoint>>().len() != inputs.len() {
+                    return Err("output spent twice".to_string());
+                }
+                if outputs.iter().try_fold(fee, |total, output| total.checked_add(output.amount)).is_none() {
+                    return Err("outputs and fee overflow".to_string());
+                }
+                let message = hex::decode(parts[5]).map_err(|e| format!("invalid message: {}", e))?;
+                Ok(Transaction::Spend {
+                    from: parts[1].to_string(),
+                    inputs,
+                    outputs,
+                    fee,
+                    message: String::from_utf8(message).map_err(|e| format!("invalid message: {}", e))?,
+                    signature: parts[6].to_string(),
+                })
+            }
+            ("coinbase", 4) => {
+                address::check(parts[2])?;
+                Ok(Transaction::Coinbase {
+                    height: parts[1].parse::<u32>().map_err(|e| format!("invalid height: {}", e))?,
+                    to: parts[2].to_string(),
+                    amount: number(3, "amount")?,
+                })
+            }
+            _ => Err(format!("unknown transaction {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_transfers_verify_until_changed() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let transfer = Transaction::signed_transfer(&key, &to.address(), 10, 1, 1, "thanks | for lunch");
+        assert!(transfer.has_valid_signature());
+        assert_eq!(transfer.to_string().len(), transfer.signed_size());
+        assert_eq!(transfer.to_string().parse::<Transaction>(), Ok(transfer.clone()));
+
+        let mut changed = transfer.clone();
+        if let Transaction::Transfer { amount, .. } = &mut changed {
+            *amount = 1000;
+        }
+        assert!(!changed.has_valid_signature());
+        let mut stolen = transfer.clone();
+        if let Transaction::Transfer { from, .. } = &mut stolen {
+            *from = to.public_key();
+        }
+        assert!(!stolen.has_valid_signature());
+        let unsigned = Transaction::transfer(&key.public_key(), &to.address(), 10, 1, 1, "");
+        assert!(!unsigned.has_valid_signature());
+        assert_eq!(unsigned.signed_size(), Transaction::signed_transfer(&key, &to.address(), 10, 1, 1, "").to_string().len());
+    }
+
+    #[test]
+    fn signed_spends_verify_until_changed() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let inputs = vec![OutPoint { txid: "ab".repeat(32), index: 2 }];
+        let mut spend = Transaction::spend(&key.public_key(), inputs, vec![Output { address: to.address(), amount: 5 }], 1, "");
+        spend.sign(&key);
+        assert!(spend.has_valid_signature());
+        assert_eq!(spend.to_string().parse::<Transaction>(), Ok(spend.clone()));
+
+        if let Transaction::Spend { outputs, .. } = &mut spend {
+            outputs[0].address = key.address();
+        }
+        assert!(!spend.has_valid_signature());
+    }
+
+    #[test]
+    fn malformed_transactions_are_refused() {
+        let (key, to) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let encoded = Transaction::signed_transfer(&key, &to.address(), 10, 1, 1, "").to_string();
+        let mut fields: Vec<&str> = encoded.split(FIELD_SEPARATOR).collect();
+        let signature = fields[7];
+        fields[7] = &signature[2..];
+        assert!(fields.join("|").parse::<Transaction>().is_err());
+        fields[7] = signature;
+        fields[3] = "-10";
+        assert!(fields.join("|").parse::<Transaction>().is_err());
+        assert!(fields[..7].join("|").parse::<Transaction>().is_err());
+        assert!("".parse::<Transaction>().is_err());
+
+        let input = "ab".repeat(32) + "-0";
+        let spent_twice = format!("spend|{}|{},{}|{}-1|0||{}", key.public_key(), input, input, to.address(), "00".repeat(keys::SIGNATURE_SIZE));
+        assert_eq!(spent_twice.parse::<Transaction>(), Err("output spent twice".to_string()));
+        let overflow = format!("spend|{}|{}|{}-{}|1||{}", key.public_key(), input, to.address(), u64::MAX, "00".repeat(keys::SIGNATURE_SIZE));
+        assert_eq!(overflow.parse::<Transaction>(), Err("outputs and fee overflow".to_string()));
+    }
+}
@@ -0,0 +1,166 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+use crate::protocol::{self, NodeAddr};
+
+/// Biggest frame accepted from the network, anything bigger is considered malformed
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// A connection between two nodes, carrying whole frames
+pub trait Connection: Send {
+    /// Sends one frame
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+    /// Receives one frame, None if the other side closed the connection
+    fn recv_frame(&mut self) -> io::Result<Option<Vec<u8>>>;
+    /// Sets how long `recv_frame` and `send_frame` may block, None to wait forever
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Returns the address of the other side
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// Closes the connection
+    fn shutdown(&self);
+}
+
+/// A socket waiting for connections from other nodes
+pub trait Listener: Send + Sync {
+    /// Returns the next pending connection and the address it comes from, or None if there is none right now
+    fn accept(&self) -> io::Result<Option<(Box<dyn Connection>, SocketAddr)>>;
+}
+
+/// The way a node reaches the others: real TCP sockets, or the simulated network of `sim`
+pub trait Transport: Send + Sync {
+    /// Opens a connection to a node
+    /// *`destination` - the address of the node, as `HOST:PORT`
+    fn connect(&self, destination: &str) -> io::Result<Box<dyn Connection>>;
+    /// Starts listening on an address
+    fn bind(&self, address: &str) -> io::Result<Box<dyn Listener>>;
+}
+
+/// The default transport, over TCP
+/// Frames are written as `length (4, big-endian) | frame`
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect(&self, destination: &str) -> io::Result<Box<dyn Connection>> {
+        let stream = protocol::connect(destination)?;
+        Ok(Box::new(TcpConnection { stream }))
+    }
+
+    fn bind(&self, address: &str) -> io::Result<Box<dyn Listener>> {
+        let address = address.parse::<NodeAddr>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .resolve()?;
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Box::new(TcpListenerWrapper { listener }))
+    }
+}
+
+/// A TCP connection carrying length-prefixed frames
+pub struct TcpConnection {
+    stream: TcpStream,
+}
+
+impl Connection for TcpConnection {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+        self.stream.write_all(frame)
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut length = [0_u8; 4];
+        match self.stream.read_exact(&mut length) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too big", length)));
+        }
+        let mut frame = vec![0_u8; length];
+        self.stream.read_exact(&mut frame)?;
+        Ok(Some(frame))
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// A non-blocking TCP listener
+struct TcpListenerWrapper {
+    listener: TcpListener,
+}
+
+impl Listener for TcpListenerWrapper {
+    fn accept(&self) -> io::Result<Option<(Box<dyn Connection>, SocketAddr)>> {
+        match self.listener.accept() {
+            Ok((stream, addr)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some((Box::new(TcpConnection { stream }), addr)))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a frame connection and the raw socket at its other end
+    fn pair() -> (TcpConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let raw = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut connection = TcpConnection { stream };
+        connection.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        (connection, raw)
+    }
+
+    #[test]
+    fn frames_keep_their_bounds() {
+        let (mut connection, raw) = pair();
+        let mut other = TcpConnection { stream: raw };
+        other.send_frame(b"first").unwrap();
+        other.send_frame(b"").unwrap();
+        other.send_frame(b"second").unwrap();
+        other.shutdown();
+        assert_eq!(connection.recv_frame().unwrap(), Some(b"first".to_vec()));
+        assert_eq!(connection.recv_frame().unwrap(), Some(Vec::new()));
+        assert_eq!(connection.recv_frame().unwrap(), Some(b"second".to_vec()));
+        assert_eq!(connection.recv_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let (mut connection, mut raw) = pair();
+        raw.write_all(&10_u32.to_be_bytes()).unwrap();
+        raw.write_all(b"abc").unwrap();
+        raw.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(connection.recv_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // A length cut in the middle is a closed connection, nothing was received
+        let (mut connection, mut raw) = pair();
+        raw.write_all(&[0, 0]).unwrap();
+        raw.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(connection.recv_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_reading_them() {
+        let (mut connection, mut raw) = pair();
+        raw.write_all(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes()).unwrap();
+        assert_eq!(connection.recv_frame().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
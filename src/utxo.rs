@@ -0,0 +1,274 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use crate::address;
+use crate::block::Block;
+use crate::config::ChainConfig;
+use crate::ledger::LedgerError;
+use crate::transaction::{OutPoint, Output, Transaction};
+
+/// An output which was not spent yet
+/// *`output` - who owns the coins and how many
+/// *`height` - the index of the block which created it
+/// *`coinbase` - true if a coinbase created it, it can then only be spent once mature
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utxo {
+    pub output: Output,
+    pub height: u32,
+    pub coinbase: bool,
+}
+
+/// What a block changed in the unspent outputs, to roll it back
+/// *`spent` - the outputs it spent, with their content
+/// *`created` - the outputs it created and which it did not spend itself
+struct BlockUndo {
+    spent: Vec<(OutPoint, Utxo)>,
+    created: Vec<OutPoint>,
+}
+
+/// The unspent outputs of the main chain
+/// *`outputs` - the unspent outputs, by reference
+/// *`undo` - for each block of the main chain, what it changed
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, Utxo>,
+    undo: Vec<BlockUndo>,
+}
+
+impl UtxoSet {
+
+    /// CONSTRUCTOR
+    /// Returns the outputs of an empty chain
+    pub fn new() -> Self {
+        UtxoSet {
+            outputs: HashMap::new(),
+            undo: Vec::new(),
+        }
+    }
+
+    /// Returns an unspent output, None if it does not exist or was spent
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.outputs.get(outpoint)
+    }
+
+    /// Returns the unspent outputs owned by `address`
+    pub fn owned_by(&self, address: &str) -> Vec<(OutPoint, Utxo)> {
+        self.outputs.iter()
+            .filter(|(_, utxo)| utxo.output.address == address)
+            .map(|(outpoint, utxo)| (outpoint.clone(), utxo.clone()))
+            .collect()
+    }
+
+    /// Returns the total of the unspent outputs owned by `address`, mature or not
+    pub fn balance(&self, address: &str) -> u64 {
+        self.outputs.values()
+            .filter(|utxo| utxo.output.address == address)
+            .fold(0, |total, utxo| total.saturating_add(utxo.output.amount))
+    }
+
+    /// Checks that a spend can go in the block of index `height`: its inputs exist, belong to its sender, are mature,
+    /// and hold exactly its outputs plus its fee
+    /// *`lookup` - finds the unspent outputs, including those created earlier in the same block
+    /// Returns the total of the inputs
+    pub fn check_spend<'a>(transaction: &Transaction, height: u32, config: &ChainConfig, lookup: impl Fn(&OutPoint) -> Option<&'a Utxo>) -> Result<u64, LedgerError> {
+        let (from, inputs, outputs, fee) = match transaction {
+            Transaction::Spend { from, inputs, outputs, fee, .. } => (from, inputs, outputs, *fee),
+            _ => return Ok(0),
+        };
+        let mut total: u64 = 0;
+        for input in inputs {
+            let utxo = lookup(input).ok_or_else(|| LedgerError::Missing(input.to_string()))?;
+            if utxo.output.address != address::from_public_key(from) {
+                return Err(LedgerError::NotOwner(input.to_string()));
+            }
+            if utxo.coinbase && !config.is_mature(utxo.height, height) {
+                return Err(LedgerError::Immature(input.to_string()));
+            }
+            total = total.checked_add(utxo.output.amount).ok_or(LedgerError::Overflow)?;
+        }
+        let needed = outputs.iter().try_fold(fee, |sum, output| sum.checked_add(output.amount)).ok_or(LedgerError::Overflow)?;
+        if total != needed {
+            return Err(LedgerError::Unbalanced { inputs: total, needed });
+        }
+        Ok(total)
+    }
+
+    /// Applies the transactions of the next block of the main chain, all of them or none
+    /// An output spent twice, in the chain or in the block, is missing the second time
+    pub fn apply_block(&mut self, block: &Block, config: &ChainConfig) -> Result<(), LedgerError> {
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        let mut created: HashMap<OutPoint, Utxo> = HashMap::new();
+        for transaction in block.transactions() {
+            // Blocks reaching the ledger were checked by the chain, only the state is verified here
+            let parsed = match Transaction::from_str(&transaction) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if let Transaction::Spend { inputs, .. } = &parsed {
+                UtxoSet::check_spend(&parsed, block.index, config, |outpoint| match spent.contains(outpoint) {
+                    true => None,
+                    false => created.get(outpoint).or_else(|| self.outputs.get(outpoint)),
+                })?;
+                for input in inputs {
+                    if created.remove(input).is_none() {
+                        spent.insert(input.clone());
+                    }
+                }
+            }
+            let txid = parsed.id();
+            for (index, output) in parsed.outputs().into_iter().enumerate() {
+                let utxo = Utxo { output, height: block.index, coinbase: parsed.is_coinbase() };
+                created.insert(OutPoint { txid: txid.clone(), index: index as u32 }, utxo);
+            }
+        }
+
+        let mut undo = BlockUndo { spent: Vec::new(), created: created.keys().cloned().collect() };
+        for outpoint in spent {
+            if let Some(utxo) = self.outputs.remove(&outpoint) {
+                undo.spent.push((outpoint, utxo));
+            }
+        }
+        self.outputs.extend(created);
+        self.undo.push(undo);
+        Ok(())
+    }
+
+    /// Rolls back the last block applied
+    pub fn undo_block(&mut self) {
+        if let Some(undo) = self.undo.pop() {
+            for outpoint in undo.created {
+                self.outputs.remove(&outpoint);
+            }
+            self.outputs.extend(undo.spent);
+        }
+    }
+}
+
+/// Writes unspent outputs sent to a wallet: `txid-index-amount`, separated by `,`
+pub fn encode_unspent(unspent: &[(OutPoint, u64)]) -> String {
+    unspent.iter().map(|(outpoint, amount)| format!("{}-{}", outpoint, amount)).collect::<Vec<String>>().join(",")
+}
+
+/// Reads the unspent outputs sent by a Miner, see `encode_unspent`
+pub fn decode_unspent(encoded: &str) -> Result<Vec<(OutPoint, u64)>, String> {
+    encoded.split(',').filter(|item| !item.is_empty()).map(|item| {
+        let (outpoint, amount) = item.rsplit_once('-').ok_or_else(|| format!("invalid unspent output {}", item))?;
+        Ok((outpoint.parse::<OutPoint>()?, amount.parse::<u64>().map_err(|e| format!("invalid amount: {}", e))?))
+    }).collect()
+}
+
+impl Default for UtxoSet {
+    fn default() -> Self {
+        UtxoSet::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::TRANSACTION_SEPARATOR;
+    use crate::keys::WalletKey;
+
+    /// Returns a block of `transactions`, the UTXO set does not look at the hashes
+    fn block(index: u32, transactions: &[Transaction]) -> Block {
+        let payload = transactions.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(&TRANSACTION_SEPARATOR.to_string());
+        Block::new(index, payload, 0, 0, Vec::new())
+    }
+
+    fn coinbase(height: u32, to: &WalletKey, amount: u64) -> Transaction {
+        Transaction::Coinbase { height, to: to.address(), amount }
+    }
+
+    fn signed_spend(key: &WalletKey, inputs: Vec<OutPoint>, outputs: Vec<(&WalletKey, u64)>, fee: u64) -> Transaction {
+        let outputs = outputs.into_iter().map(|(to, amount)| Output { address: to.address(), amount }).collect();
+        let mut spend = Transaction::spend(&key.public_key(), inputs, outputs, fee, "");
+        spend.sign(key);
+        spend
+    }
+
+    fn outpoint(transaction: &Transaction, index: u32) -> OutPoint {
+        OutPoint { txid: transaction.id(), index }
+    }
+
+    fn config() -> ChainConfig {
+        ChainConfig { coinbase_maturity: 0, ..ChainConfig::default() }
+    }
+
+    #[test]
+    fn spends_replace_their_inputs_by_their_outputs() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut outputs = UtxoSet::new();
+        let reward = coinbase(0, &alice, 100);
+        outputs.apply_block(&block(0, std::slice::from_ref(&reward)), &config()).unwrap();
+        let pay = signed_spend(&alice, vec![outpoint(&reward, 0)], vec![(&bob, 60), (&alice, 39)], 1);
+        // An output created earlier in the same block can be spent
+        let forward = signed_spend(&bob, vec![outpoint(&pay, 0)], vec![(&bob, 60)], 0);
+        outputs.apply_block(&block(1, &[coinbase(1, &bob, 1), pay.clone(), forward.clone()]), &config()).unwrap();
+
+        assert!(outputs.get(&outpoint(&reward, 0)).is_none());
+        assert!(outputs.get(&outpoint(&pay, 0)).is_none());
+        assert_eq!(outputs.get(&outpoint(&pay, 1)), Some(&Utxo { output: Output { address: alice.address(), amount: 39 }, height: 1, coinbase: false }));
+        assert_eq!(outputs.balance(&alice.address()), 39);
+        assert_eq!(outputs.balance(&bob.address()), 61);
+        assert_eq!(outputs.owned_by(&bob.address()).len(), 2);
+    }
+
+    #[test]
+    fn invalid_spends_change_nothing() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut outputs = UtxoSet::new();
+        let reward = coinbase(0, &alice, 100);
+        outputs.apply_block(&block(0, std::slice::from_ref(&reward)), &config()).unwrap();
+        let input = outpoint(&reward, 0);
+
+        let stolen = signed_spend(&bob, vec![input.clone()], vec![(&bob, 100)], 0);
+        assert_eq!(outputs.apply_block(&block(1, &[stolen]), &config()), Err(LedgerError::NotOwner(input.to_string())));
+        let unbalanced = signed_spend(&alice, vec![input.clone()], vec![(&bob, 100)], 1);
+        assert_eq!(outputs.apply_block(&block(1, &[unbalanced]), &config()), Err(LedgerError::Unbalanced { inputs: 100, needed: 101 }));
+        let twice = [
+            signed_spend(&alice, vec![input.clone()], vec![(&bob, 100)], 0),
+            signed_spend(&alice, vec![input.clone()], vec![(&alice, 100)], 0),
+        ];
+        assert_eq!(outputs.apply_block(&block(1, &twice), &config()), Err(LedgerError::Missing(input.to_string())));
+        let early = signed_spend(&alice, vec![input.clone()], vec![(&bob, 100)], 0);
+        let mature_later = ChainConfig { coinbase_maturity: 2, ..config() };
+        assert_eq!(outputs.apply_block(&block(1, &[early]), &mature_later), Err(LedgerError::Immature(input.to_string())));
+
+        assert_eq!(outputs.owned_by(&alice.address()).len(), 1);
+        assert_eq!(outputs.balance(&bob.address()), 0);
+        assert_eq!(outputs.undo.len(), 1);
+    }
+
+    #[test]
+    fn undo_then_apply_again_gives_the_same_outputs() {
+        let (alice, bob) = (WalletKey::generate().unwrap(), WalletKey::generate().unwrap());
+        let mut outputs = UtxoSet::new();
+        let reward = coinbase(0, &alice, 100);
+        outputs.apply_block(&block(0, std::slice::from_ref(&reward)), &config()).unwrap();
+        let before = outputs.outputs.clone();
+        let pay = signed_spend(&alice, vec![outpoint(&reward, 0)], vec![(&bob, 100)], 0);
+        let first = block(1, &[coinbase(1, &bob, 50), pay]);
+        outputs.apply_block(&first, &config()).unwrap();
+        let applied = outputs.outputs.clone();
+
+        // A reorganisation replaces the block by another one spending the same output, then the first branch wins again
+        outputs.undo_block();
+        assert_eq!(outputs.outputs, before);
+        let other = block(1, &[coinbase(1, &alice, 50), signed_spend(&alice, vec![outpoint(&reward, 0)], vec![(&alice, 100)], 0)]);
+        outputs.apply_block(&other, &config()).unwrap();
+        assert_eq!(outputs.balance(&alice.address()), 150);
+        outputs.undo_block();
+        outputs.apply_block(&first, &config()).unwrap();
+        assert_eq!(outputs.outputs, applied);
+
+        outputs.undo_block();
+        outputs.undo_block();
+        assert!(outputs.outputs.is_empty());
+    }
+
+    #[test]
+    fn unspent_outputs_encoding_round_trips() {
+        let unspent = vec![(OutPoint { txid: "ab".repeat(32), index: 3 }, 42), (OutPoint { txid: "cd".repeat(32), index: 0 }, 7)];
+        assert_eq!(decode_unspent(&encode_unspent(&unspent)), Ok(unspent));
+        assert_eq!(decode_unspent(""), Ok(Vec::new()));
+        assert!(decode_unspent("ab-1-2").is_err());
+    }
+}
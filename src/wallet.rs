@@ -1,228 +1,521 @@
-use std::net::{TcpStream, TcpListener, Shutdown};
+use std::cmp::Reverse;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use std::fmt::{self, Debug, Formatter};
-use std::io::{self, Write, Read};
-use crate::miner::Miner;
-use ring::digest::{Algorithm};
+use std::io;
+use std::path::Path;
+use crate::miner::{HealthCheckError, Miner, MAX_BLOCKS_PER_REQUEST};
 use std::collections::HashSet;
 use std::process::Command;
-use crate::block::Block;
-use std::str::FromStr;
-use merkle;
-
-#[path="./block.rs"] mod block;
-
-/// Used for signaling what kind of requests we are sending when networking
-/// 
-#[derive(Copy, Clone)]
-pub enum Flag {
-    /// Ok -> Network
-    Ok,
-    Connect, // flag to signal that a Miner joined the newtwork
-    Disconnect, // flag to signal that a Miner disconnected from the network
-    RequireID,
-    GiveID,
-    BroadcastConnect,
-    BroadcastDisconnect,
-    Check,
-    Ack,
-    Block,
-    Transaction,
-    MineTransaction,
-    OkMineTransaction,
-    RequireWalletID,
-    RequireBlockchain,
-    SendBlockchain,
-}
-
+use crate::protocol::{self, Flag, encode_message, decode_message};
+use crate::secure::{self, NodeIdentity, SecureStream};
+use crate::address;
+use crate::keys::WalletKey;
+use crate::repl::{self, CommandHistory, UserCommand};
+use crate::hd;
+use crate::keystore::{self, Keystore};
+use crate::transport::{Listener, TcpTransport, Transport};
+use crate::mempool::{self, FeeEstimate};
+use crate::config::{ChainConfig, LedgerMode};
+use crate::ledger::AccountSummary;
+use crate::index::{HistoryPage, TransactionStatus};
+use crate::notify::{self, TransactionEvent};
+use crate::pool::MinerPool;
+use crate::peer;
+use crate::storage;
+use crate::spv::InclusionProof;
+use crate::transaction::{OutPoint, Output, Transaction};
+use crate::utxo;
+use crate::block::{self, Block};
+use crate::blockstore::BlockStore;
+use crate::watch::WatchList;
+use crate::offline::OfflineTransaction;
 
-impl Flag {
-    /// Simple function to convert a integer to a Flag
-    /// Used when receiving/sending a message as primary types are easier to pass than objects
-    /// 
-    fn from_u8(value: u8) -> Flag {
-        match value {
-            0 => Flag::Ok,
-            1 => Flag::Connect,
-            2 => Flag::Disconnect,
-            3 => Flag::RequireID,
-            4 => Flag::GiveID,
-            5 => Flag::BroadcastConnect,
-            6 => Flag::BroadcastDisconnect,
-            7 => Flag::Check,
-            8 => Flag::Ack,
-            9 => Flag::Block,
-            10 => Flag::Transaction,
-            11 => Flag::MineTransaction,
-            12 => Flag::OkMineTransaction,
-            13 => Flag::RequireWalletID,
-            14 => Flag::RequireBlockchain,
-            15 => Flag::SendBlockchain,
-            _ => panic!("Unknown value: {}", value),
-        }
-    }
-}
+/// Time in ms between two checks for incoming connections
+const ACCEPT_POLL_TIME: u64 = 100;
+/// Time the wallet waits for the answer of its Miner on the same connection
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of consecutive unused addresses after which a restored wallet stops looking for its addresses in the chain
+const GAP_LIMIT: u32 = 20;
+/// Number of transactions shown by each page of `History`
+pub const HISTORY_PAGE_SIZE: usize = 10;
 
-/// Used to represent the commands that the user can send to the wallet via the standard input
-/// 
-#[derive(Copy, Clone)]
-pub enum UserCommand {
-    Send,
-    Check,
-    Verify,
-    Exit,
+/// Why a command of the wallet could not be done
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    /// Our Miner could not be reached, or did not answer as expected
+    Miner(String),
+    /// The command itself failed: invalid argument, not enough coins, transaction not verified...
+    Failed(String),
 }
 
-/// This function converts the String text passed in the console to UserCommands
-/// 
-impl UserCommand {
-    fn from_string(value: String) -> UserCommand {
-        let v = value.trim();
-        let s_value: &str = &v[..];  // take a full slice of the string
-        match s_value {
-            "Send" => UserCommand::Send,
-            "Check" => UserCommand::Check,
-            "Verify" => UserCommand::Verify,
-            "Exit" => UserCommand::Exit,
-            _ => panic!("Unknown value: {}", value),
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CommandError::Miner(reason) | CommandError::Failed(reason) => write!(f, "{}", reason),
         }
     }
 }
 
-/// Util
-/// Concat u8 array
-/// * `first`
-/// * `second`
-/// Example 
-/// ```rust 
-/// let first: &[u8] = String::from("Hello, ").as_bytes();
-/// let second: &[u8] = String::from("World!").as_bytes();
-/// asserteq!("Hello, World!",concat_u8(&first,&second));
-/// ```
-pub fn concat_u8(first: &[u8], second: &[u8]) -> Vec<u8> {
-    [first, second].concat()
+impl From<String> for CommandError {
+    fn from(reason: String) -> Self {
+        CommandError::Failed(reason)
+    }
 }
 
+/// What the addresses of a wallet own
+/// *`confirmed` - the total of their balances in the main chain
+/// *`incoming` - what the transactions waiting in the mempool give them
+/// *`outgoing` - what the transactions waiting in the mempool take from them, fees included
+/// *`spendable` - what they can still spend: the mature balances, minus what the waiting transactions already spend
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WalletBalance {
+    pub confirmed: u64,
+    pub incoming: u64,
+    pub outgoing: u64,
+    pub spendable: u64,
+}
 
-/// Add padding to the socket ip to allow fixed size data structure when sending data
-pub fn encode_sockip(sockip: String) -> String {
-    return format!("{:X<21}", sockip);
+impl fmt::Display for WalletBalance {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "confirmed {}, unconfirmed +{} -{}, spendable {}", self.confirmed, self.incoming, self.outgoing, self.spendable)
+    }
 }
 
-/// Remove the padding from the socket ip
-pub fn decode_sockip(sockip: String) -> String {
-    return str::replace(&sockip, "X", "");
+/// What a sync of the blocks of the main chain did
+/// *`downloaded` - the number of blocks downloaded
+/// *`rewound` - the number of blocks forgotten because they left the main chain of the Miner
+/// *`height` - the number of blocks stored once the sync is done
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub downloaded: usize,
+    pub rewound: usize,
+    pub height: usize,
 }
 
-/// Add padding to the ID to allow fixed sized data structure when sending an ID
-pub fn encode_id(id: String) -> String {
-    return format!("{:Y<10}", id);
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} blocks downloaded, {} rewound, {} blocks stored", self.downloaded, self.rewound, self.height)
+    }
 }
 
-/// Remove the padding from the ID field
-pub fn decode_id(message: String) -> String {
-    let id = str::replace(&message, "Y", "");
-    return id;
+/// What gives a wallet its addresses: its keys, or only the addresses it watches
+pub enum Holder {
+    /// Our opened keystore, new addresses are derived from its seed
+    /// *`key` - the key pair signing our transactions, its public key is our main address
+    Keys { key: WalletKey, keystore: Keystore },
+    /// A watch-only wallet: it tracks the balance and history of public keys and addresses, but can not sign
+    Watch(WatchList),
 }
 
-/// Remove the padding from the ID field
-pub fn decode_id_response(message: String) -> String {
-    return str::replace(&message, "Y", "");
+impl Holder {
+
+    /// Returns our addresses, the main one first
+    pub fn addresses(&self) -> io::Result<Vec<String>> {
+        match self {
+            Holder::Keys { keystore, .. } => keystore.addresses(),
+            Holder::Watch(watch_list) => Ok(watch_list.addresses()),
+        }
+    }
+
+    /// Returns the key pair signing our transactions, or why a watch-only wallet can not sign
+    pub fn key(&self) -> Result<&WalletKey, CommandError> {
+        match self {
+            Holder::Keys { key, .. } => Ok(key),
+            Holder::Watch(_) => Err(CommandError::Failed("the wallet is watch-only, it can not sign, use Export and sign offline".to_string())),
+        }
+    }
+
+    /// Returns the public key our transactions are sent from, None for a watch-only wallet knowing only addresses
+    pub fn public_key(&self) -> Option<String> {
+        match self {
+            Holder::Keys { key, .. } => Some(key.public_key()),
+            Holder::Watch(watch_list) => watch_list.public_key(),
+        }
+    }
+
+    /// Returns the Miner saved with our addresses, and the ID it gave us
+    pub fn registration(&self) -> (String, u32) {
+        match self {
+            Holder::Keys { keystore, .. } => (keystore.miner.to_string(), keystore.wallet_id),
+            Holder::Watch(watch_list) => (watch_list.miner.to_string(), watch_list.wallet_id),
+        }
+    }
+
+    /// Saves the Miner we talk to and the ID it gave us
+    pub fn register(&mut self, miner: &str, id: u32) -> io::Result<()> {
+        match self {
+            Holder::Keys { keystore, .. } => {
+                keystore.miner = miner.to_string();
+                keystore.wallet_id = id;
+                keystore.save()
+            }
+            Holder::Watch(watch_list) => {
+                watch_list.miner = miner.to_string();
+                watch_list.wallet_id = id;
+                watch_list.save()
+            }
+        }
+    }
+
+    /// Returns our keystore, or why a watch-only wallet can not do what needs it
+    pub fn keystore(&mut self) -> Result<&mut Keystore, CommandError> {
+        match self {
+            Holder::Keys { keystore, .. } => Ok(keystore),
+            Holder::Watch(_) => Err(CommandError::Failed("the wallet is watch-only, it holds no key".to_string())),
+        }
+    }
 }
 
 /// This struct represent the wallets
 /// *`id` - an integer than should be unique to each wallet within a miner's wallet list
-/// *`miner` - the IP address of the miner to which the wallet is binded
+/// *`miners` - the Miners the wallet knows, requests go to the first one and the others take over when it fails
 /// *`socket` - the IP address on which the wallet listens for incoming messages
+/// *`identity` - the key pair proving who we are when connecting to the miner
+/// *`holder` - our keys, or the addresses we watch
+/// *`transport` - how the wallet reaches its miner
+/// *`listening` - true once the wallet receives notifications on its socket, a Miner taking over must then subscribe it
 pub struct Wallet {
     pub id: u32, // Our ID
-    pub miner: String,
+    pub miners: Arc<Mutex<MinerPool>>,
     pub socket: String,
-}
-
-/// This function is used to send a message to another entity on the network
-/// *`flag` - a Flag which represents what kind of request/message we are sending
-/// *`sockip` - the IP address of the recipient of the message
-/// *`id` - only used when sending an ID value, otherwise it should be left blank
-/// *`msg` - the message sended to the recipient
-pub fn encode_message(flag : Flag, sockip : String, id : String, msg : String) -> Vec<u8>{
-    println!("\nEncoding message");
-    let flag_convert: &[u8] = &[flag as u8];
-    let sockip_convert : String = encode_sockip(sockip);
-    let id_convert : String = encode_id(id);
-    println!("\tmessage to encode: {}", &msg);
-    let msg_convert : &[u8] = msg.as_bytes();
-    println!("\tmessage encoded: {:?}", &msg_convert);
-    concat_u8(flag_convert, &concat_u8(sockip_convert.as_bytes(), &concat_u8(id_convert.as_bytes(), msg_convert)))
-}
-
-/// Decode the message received
-pub fn decode_message(msg : &[u8]) -> (Flag, String, String, String){
-    println!("SSSDL111 .{:?}.", msg);
-    let flag = Flag::from_u8(msg[0]); // get the flag
-    let sockip_encoded = std::str::from_utf8(&msg[1..21]).unwrap();
-    let id_encoded = std::str::from_utf8(&msg[22..31]).unwrap();
-    let msg = std::str::from_utf8(&msg[32..]).unwrap();
-    let sockip = decode_sockip(sockip_encoded.to_string());
-    (flag, decode_sockip(sockip.to_string()), decode_id(id_encoded.to_string()), msg.to_string())
+    pub identity: Arc<NodeIdentity>,
+    pub holder: Mutex<Holder>,
+    pub transport: Arc<dyn Transport>,
+    pub listening: AtomicBool,
 }
 
 /// This function creates a wallet and make it listen for the user input
+/// The keys of the wallet come from its keystore, opened or created with the passphrase of the user.
+/// A watch-only wallet has no keystore and asks for no passphrase, it only tracks the addresses of its watch list
 /// *`socket` - the IP address on which the wallet is listening
-/// *`miner` - the IP address of the miner it is binded to
-pub fn create_wallet(socket: String, miner: String) {
+/// *`miners` - the IP addresses of the Miners it may use, the first one is used unless it does not answer
+/// *`restore` - true to create the keystore from the mnemonic of the user, then find the addresses it used in the chain
+/// *`watch` - public keys or addresses added to the watch list, making the wallet watch-only if it is new
+pub fn create_wallet(socket: String, miners: Vec<String>, restore: bool, watch: Vec<String>) {
     println!("Wallet creation...");
-    //Ask our miner what our ID is and create the wallet with given id
-    let new_id: u32 = Miner::ask_miner_for_wallet_id(&socket, &miner);
-    let wallet = Wallet::new(socket, miner, new_id);
+    let miner = miners[0].to_string();
+    let opened = match watch.is_empty() && !WatchList::exists(&socket) {
+        true => open_keys(&socket, &miner, restore),
+        false => open_watch_list(&socket, &miner, &watch),
+    };
+    let holder = match opened {
+        Ok(holder) => holder,
+        Err(e) => {
+            println!("Err: {}", e);
+            return;
+        }
+    };
+    let transport: Arc<dyn Transport> = Arc::new(TcpTransport);
+    let (_, id) = holder.registration();
+    let mut wallet = Wallet::new(socket.to_string(), MinerPool::load(&socket, &miners), id, Arc::clone(&transport), holder);
+    if let Err(e) = wallet.health_check(&wallet.miner()) {
+        if let Err(e) = wallet.fail_over(&e) {
+            println!("Err: {}", e);
+        }
+    }
+    //Ask our miner what our ID is and create the wallet with given id, unless it already gave us one
+    let miner = wallet.miner();
+    let (registered_miner, registered_id) = wallet.holder.lock().unwrap().registration();
+    if registered_miner != miner || registered_id == 0 {
+        wallet.id = Miner::ask_miner_for_wallet_id(transport.as_ref(), &socket, &miner);
+        if let Err(e) = wallet.holder.lock().unwrap().register(&miner, wallet.id) {
+            println!("Err: {}, while saving the Miner of the wallet", e);
+        }
+    } else {
+        println!("My ID is {}", &wallet.id);
+    }
+    wallet.learn_miners(&miner);
+    let resent = wallet.rebroadcast(&miner);
+    if resent > 0 {
+        println!("{} transactions not settled sent again to Miner {}", resent, miner);
+    }
+    if restore {
+        match wallet.scan_addresses() {
+            Ok(used) => println!("Wallet restored, {} addresses in use", used),
+            Err(e) => println!("Err: {}, the addresses in use could not be found, see NewAddress", e),
+        }
+    }
 
+    match &*wallet.holder.lock().unwrap() {
+        Holder::Keys { key, .. } => println!("Wallet address: {}", key.address()),
+        Holder::Watch(watch_list) => println!("Watch-only wallet, watching: {}", watch_list.addresses().join(", ")),
+    }
+    wallet.listen_to_miner();
     //Listen for user input
     wallet.listen_for_user_input();
 }
 
+/// Opens the keystore of the wallet listening on `socket` with the passphrase of the user, or creates it
+/// *`miner` - the Miner saved in a new keystore
+/// *`restore` - see `create_wallet`
+fn open_keys(socket: &str, miner: &str, restore: bool) -> Result<Holder, String> {
+    let keystore = keystore::read_passphrase().and_then(|passphrase| match restore {
+        true => {
+            let seed = keystore::read_mnemonic()?;
+            let seed = hd::from_mnemonic(&seed).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Keystore::restore(socket, miner, &passphrase, seed)
+        }
+        false => Keystore::open_or_create(socket, miner, &passphrase),
+    }).map_err(|e| format!("{}, could not open the keystore", e))?;
+    let key = keystore.keys().map_err(|e| format!("{}, could not read the keystore", e))?.into_iter().next().ok_or("the keystore holds no key")?;
+    Ok(Holder::Keys { key, keystore })
+}
+
+/// Opens the watch list of the wallet listening on `socket`, or creates it, then adds `watch` to it
+/// *`miner` - the Miner saved in a new watch list
+fn open_watch_list(socket: &str, miner: &str, watch: &[String]) -> Result<Holder, String> {
+    let mut watch_list = WatchList::open_or_create(socket, miner).map_err(|e| format!("{}, could not open the watch list", e))?;
+    for entry in watch {
+        watch_list.add(entry)?;
+    }
+    if watch_list.addresses().is_empty() {
+        return Err("the watch list is empty, give the public keys or addresses to watch with --watch".to_string());
+    }
+    Ok(Holder::Watch(watch_list))
+}
+
+/// Opens the wallet listening on `socket` to run one command, without listening for notifications nor asking for a new ID
+/// A watch-only wallet is opened without asking for the passphrase
+/// *`miners` - the Miners to talk to first, then those known by the wallet and the one saved in the keystore
+/// Returns why the wallet could not be opened: no keystore, wrong passphrase...
+pub fn open_wallet(socket: &str, miners: &[String]) -> Result<Wallet, String> {
+    let holder = match WatchList::exists(socket) {
+        true => Holder::Watch(WatchList::open_existing(socket).map_err(|e| format!("{}, could not open the watch list", e))?),
+        false => {
+            let keystore = keystore::read_passphrase()
+                .and_then(|passphrase| Keystore::open_existing(socket, &passphrase))
+                .map_err(|e| format!("{}, could not open the keystore", e))?;
+            let key = keystore.keys().map_err(|e| e.to_string())?.into_iter().next().ok_or("the keystore holds no key")?;
+            Holder::Keys { key, keystore }
+        }
+    };
+    let (miner, id) = holder.registration();
+    let mut pool = MinerPool::load(socket, miners);
+    pool.learn(vec![miner]);
+    Ok(Wallet::new(socket.to_string(), pool, id, Arc::new(TcpTransport), holder))
+}
+
+/// Receives the notifications our Miner sends to our socket, until the wallet exits
+/// They are shown right away, even while the user is typing a command
+/// *`listener` - bound on the socket of the wallet
+/// *`identity` - our identity, used to authenticate the connections
+/// *`miners` - the Miners we know, the messages of the nodes other than the one in use are refused
+pub fn receive_from_miner(listener: Box<dyn Listener>, identity: &NodeIdentity, miners: &Mutex<MinerPool>) {
+    loop {
+        let connection = match listener.accept() {
+            Ok(Some((connection, _))) => connection,
+            Ok(None) => {
+                thread::sleep(Duration::from_millis(ACCEPT_POLL_TIME));
+                continue;
+            }
+            Err(e) => {
+                println!("Error: {}", e);
+                continue;
+            }
+        };
+        let mut stream = match secure::accept(connection, identity) {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Error: {}", e);
+                continue;
+            }
+        };
+        // Connections are served one at a time, a peer sending nothing must not block the next ones
+        if let Err(e) = stream.set_timeout(Some(peer::READ_TIMEOUT)) {
+            println!("Error: {}", e);
+            continue;
+        }
+        let (flag, sender, _, message) = match stream.recv() {
+            Ok(Some(data)) => match decode_message(&data) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    println!("Invalid message received: {}", e);
+                    continue;
+                }
+            },
+            Ok(None) => continue,
+            Err(e) => {
+                println!("Error occured, closing connection: {}", e);
+                stream.shutdown();
+                continue;
+            }
+        };
+        let miner = miners.lock().unwrap().current();
+        if sender != miner || !identity.accepts(&miner, &stream.peer_key) {
+            println!("Message refused: {} is not our Miner", sender);
+            continue;
+        }
+        if flag == Flag::GiveID {
+            // The answer of a Miner which took over to our registration, see `Wallet::register`
+            println!("\nRegistered with Miner {}", miner);
+            continue;
+        }
+        if flag != Flag::Notification {
+            println!("Unexpected message from our Miner: {:?}", flag);
+            continue;
+        }
+        match message.trim_matches(|c| c == char::from(0) || c == '\n').parse::<TransactionEvent>() {
+            Ok(event) => println!("\nNotification: {}", event.describe()),
+            Err(e) => println!("Invalid notification received: {}", e),
+        }
+    }
+}
+
 impl Wallet {
 
     /// CTOR
     /// *`socket` - the IP address where the wallet listens
-    /// *`miner` - the IP address miner to which that wallet is tied
+    /// *`miners` - the Miners the wallet knows, the first one is used
     /// *`id` - the unique ID that must be assigned to this wallet
+    /// *`transport` - how the wallet reaches its miner
+    /// *`holder` - our keys read from our keystore, or the addresses we watch
     /// Returns a new wallet with parameters initialized as given
-    pub fn new(socket: String, miner: String, id: u32) -> Self {
+    pub fn new(socket: String, miners: MinerPool, id: u32, transport: Arc<dyn Transport>, holder: Holder) -> Self {
         return Wallet {
+            identity: Arc::new(NodeIdentity::load_or_create(&socket).expect("could not load the node identity")),
+            holder: Mutex::new(holder),
             socket: socket,
-            miner: miner,
+            miners: Arc::new(Mutex::new(miners)),
             id: id,
+            transport,
+            listening: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the Miner our requests go to
+    pub fn miner(&self) -> String {
+        self.miners.lock().unwrap().current()
+    }
+
+    /// Listens on our socket for the notifications of our Miner, in the background, then subscribes to those of our addresses
+    pub fn listen_to_miner(&self) {
+        let listener = match self.transport.bind(&self.socket) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Err: {}, could not listen on {}, notifications disabled", e, &self.socket);
+                return;
+            }
+        };
+        let identity = Arc::clone(&self.identity);
+        let miners = Arc::clone(&self.miners);
+        thread::spawn(move || receive_from_miner(listener, &identity, &miners));
+        self.listening.store(true, Ordering::SeqCst);
+        // Our Miner may have restarted since it gave us our ID, registering again makes it accept our subscriptions
+        if let Err(e) = self.register(&self.miner()) {
+            println!("Err: {}, notifications disabled", e);
         }
     }
 
+    /// Finds the keys of our seed used in the chain of our Miner: they are tried in order until `GAP_LIMIT`
+    /// consecutive addresses have no transaction, then the keystore keeps those up to the last one used
+    /// Returns the number of keys of the seed the wallet uses
+    pub fn scan_addresses(&self) -> Result<u32, String> {
+        let mut holder = self.holder.lock().unwrap();
+        let keystore = holder.keystore().map_err(|e| e.to_string())?;
+        let mut used = 0;
+        let mut index = 0;
+        while index < used + GAP_LIMIT {
+            let address = keystore.derived_address(index).map_err(|e| e.to_string())?;
+            let history = self.ask_history(&address).map_err(|e| e.to_string())?;
+            if !history.is_empty() {
+                used = index + 1;
+            }
+            index += 1;
+        }
+        keystore.set_used(used).map_err(|e| e.to_string())?;
+        Ok(used.max(1))
+    }
+
     /// This function allows our wallet to listen to the commands given by the user on std input
-    /// It checks for validity of the input, and act accordingly
+    /// It checks for validity of the input, and act accordingly. A wrong command is explained and the wallet waits for the
+    /// next one, the wallet stops on `Exit` or at the end of the input
     pub fn listen_for_user_input(&self) {
         let stdin = io::stdin();
+        let mut history = CommandHistory::load(&self.socket);
+        println!("Type help for the list of commands");
         loop {
             let mut buffer = String::new();
             println!("Ready for input...");
-            stdin.read_line(&mut buffer);
-            let splitted: Vec<&str> = buffer.split(" ").collect();
-            let command = UserCommand::from_string(splitted[0].to_string());
+            match stdin.read_line(&mut buffer) {
+                Ok(0) => {
+                    println!("End of input");
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    println!("Err: {}, could not read the input", e);
+                    break;
+                }
+            }
+            let line = match history.expand(buffer.trim()) {
+                Ok(line) => line,
+                Err(e) => {
+                    println!("Error: {}\n", e);
+                    continue;
+                }
+            };
+            let input = match repl::parse_line(&line) {
+                Ok(Some(input)) => input,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("Error: {}\n", e);
+                    continue;
+                }
+            };
+            history.push(&line);
 
             //On gère l'input de l'utilisateur
-            match command {
+            let arguments = &input.arguments;
+            match input.command {
                 UserCommand::Send => {
-                    let message = splitted[1].to_string();
-                    println!("Response: {}\n", self.handle_user_input(command, self.miner.to_string(), message.to_string()));
+                    match repl::parse_payment(arguments) {
+                        Ok((to, amount, fee, message)) => println!("Response: {}\n", self.send_transaction(&to, amount, fee, &message)),
+                        Err(e) => println!("Error: {}\n", e),
+                    }
                 }
-                UserCommand::Check => {
-                    println!("Response: {}\n", self.handle_user_input(command, "".to_string(), "".to_string()));
+                UserCommand::Export => {
+                    let response = match repl::parse_payment(&arguments[1..]) {
+                        Ok((to, amount, fee, message)) => match self.export_transaction(Path::new(&arguments[0]), &to, amount, fee, &message) {
+                            Ok(offline) => format!("{}\nwritten to {}, sign it offline with `wallet sign`", offline.describe(), arguments[0]),
+                            Err(e) => format!("Error: {}", e),
+                        },
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    println!("Response: {}\n", response);
+                }
+                UserCommand::Import => {
+                    let response = match self.import_transaction(Path::new(&arguments[0])) {
+                        Ok(transaction) => format!("Transaction {} sent with a fee of {}", transaction.id(), transaction.fee()),
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    println!("Response: {}\n", response);
                 }
                 UserCommand::Verify => {
-                    let message = splitted[1].to_string();
-                    println!("Response: {}\n", self.handle_user_input(command, "".to_string(), message.to_string()));
+                    println!("Response: {}\n", self.handle_user_input(input.command, "".to_string(), arguments[0].clone()));
+                }
+                UserCommand::Lookup | UserCommand::Watch => {
+                    println!("Response: {}\n", self.handle_user_input(input.command, arguments[0].clone(), "".to_string()));
+                }
+                UserCommand::History => {
+                    let page = arguments.first().cloned().unwrap_or_else(|| "1".to_string());
+                    println!("Response: {}\n", self.handle_user_input(input.command, page, "".to_string()));
+                }
+                UserCommand::Help => {
+                    println!("Response: {}\n", repl::help(arguments.first().map(|name| name.as_str())));
+                }
+                UserCommand::Commands => {
+                    println!("Response: {}\n", history.list());
                 }
                 UserCommand::Exit => {
                     println!("Response: {}\n", "Ok".to_string());
                     break;
                 }
-                _ => { () }
+                command => {
+                    println!("Response: {}\n", self.handle_user_input(command, "".to_string(), "".to_string()));
+                }
             }
         }
 
@@ -234,52 +527,713 @@ impl Wallet {
     pub fn handle_user_input(&self, command: UserCommand, target: String, message: String) -> String {
         return match command {
             UserCommand::Send => {
-                println!("Sending message to Miner...");
-                let listener = TcpListener::bind(&self.socket).unwrap();
-                if let Ok(mut stream) = TcpStream::connect(&self.miner) {
-                    let m: &[u8] = &encode_message(Flag::Transaction, self.socket.to_string(), self.id.to_string(), message.to_string());
-                    match stream.write(m) {
-                        Ok(_) => { println!("Message {} sended to {}", message.to_string(), target.to_string()); }
-                        Err(e) => { println!("Error: {}", e); }
+                return self.send_transaction(&target, 0, None, &message);
+            }
+            UserCommand::Fees => {
+                return match self.ask_fee_estimate() {
+                    Ok(estimate) => format!("fee rates per 1000 bytes: fast {}, normal {}, slow {}", estimate.fast, estimate.normal, estimate.slow),
+                    Err(e) => format!("Fee estimate unavailable: {}", e),
+                };
+            }
+            UserCommand::Balance => {
+                return match self.balance() {
+                    Ok(balance) => balance.to_string(),
+                    Err(e) => format!("Balance unavailable: {}", e),
+                };
+            }
+            UserCommand::History => {
+                let page = match target.parse::<usize>() {
+                    Ok(page) if page > 0 => page,
+                    _ => return format!("Error: invalid page {}, pages start at 1", target),
+                };
+                return match self.ask_history_page(&self.addresses(), (page - 1) * HISTORY_PAGE_SIZE, HISTORY_PAGE_SIZE) {
+                    Ok(history) => self.describe_history(&history, page),
+                    Err(e) => format!("History unavailable: {}", e),
+                };
+            }
+            UserCommand::Pending => {
+                let addresses = self.addresses();
+                return match self.ask_pending(&addresses) {
+                    Ok(pending) if pending.is_empty() => "No pending transaction".to_string(),
+                    Ok(pending) => pending.iter()
+                        .map(|transaction| {
+                            let (received, sent) = transaction.value_for(&addresses);
+                            format!("\n{} {} fee {}", transaction.id(), signed_value(received, sent), transaction.fee())
+                        })
+                        .collect::<String>(),
+                    Err(e) => format!("Pending transactions unavailable: {}", e),
+                };
+            }
+            UserCommand::Lookup => {
+                return match self.ask_transaction(&target) {
+                    Ok(TransactionStatus::Confirmed { location, confirmations, transaction }) =>
+                        format!("{} in block {} at position {}, {} confirmations", transaction, location.height, location.position, confirmations),
+                    Ok(TransactionStatus::Pending(transaction)) => format!("{} waiting in the mempool", transaction),
+                    Ok(TransactionStatus::Unknown) => "Unknown transaction".to_string(),
+                    Err(e) => format!("Transaction status unavailable: {}", e),
+                };
+            }
+            UserCommand::Mnemonic => {
+                let mut holder = self.holder.lock().unwrap();
+                let keystore = match holder.keystore() {
+                    Ok(keystore) => keystore,
+                    Err(e) => return format!("Error: {}", e),
+                };
+                return match keystore.imported_count() {
+                    0 => format!("write these words down, they restore every address of the wallet: {}", keystore.mnemonic()),
+                    count => format!("write these words down, they restore every address of the wallet but the {} imported ones, \
+                        keep the keystore too: {}", count, keystore.mnemonic()),
+                };
+            }
+            UserCommand::NewAddress => {
+                let created = self.holder.lock().unwrap().keystore().and_then(|keystore| keystore.new_address().map_err(|e| e.to_string().into()));
+                return match created {
+                    Ok(address) => {
+                        if let Err(e) = self.send_message(&self.miner(), &address, Flag::Subscribe) {
+                            println!("Err: {}, no notifications for {}", e, address);
+                        }
+                        format!("new address {}", address)
                     }
-                    println!("Message sended");
-                }
-
-                
-                return "".to_string();
+                    Err(e) => format!("Error: {}, no address created", e),
+                };
+            }
+            UserCommand::Watch => {
+                let added = match &mut *self.holder.lock().unwrap() {
+                    Holder::Watch(watch_list) => watch_list.add(&target),
+                    Holder::Keys { .. } => Err("the wallet holds keys, only a watch-only wallet watches addresses".to_string()),
+                };
+                return match added {
+                    Ok(Some(address)) => {
+                        if let Err(e) = self.send_message(&self.miner(), &address, Flag::Subscribe) {
+                            println!("Err: {}, no notifications for {}", e, address);
+                        }
+                        format!("watching {}", address)
+                    }
+                    Ok(None) => format!("{} is already watched", target),
+                    Err(e) => format!("Error: {}", e),
+                };
+            }
+            UserCommand::Sync => {
+                return match self.sync() {
+                    Ok(report) => report.to_string(),
+                    Err(e) => format!("Sync failed: {}", e),
+                };
+            }
+            UserCommand::Miners => {
+                return self.check_miners();
             }
             UserCommand::Check => {
                 //let response = self.send_message(miner.to_string(), "".to_string(), Flag::Check);
                 return "Check ok".to_string();
             }
             UserCommand::Verify => {
-                let array: &[u8] = &message.as_bytes();
-                let vector: Vec<u8> = array.iter().cloned().collect();
-                let b = self.verify_transaction(vector);
-                let resp: String;
-                match b {
-                    True => resp = "Transaction is verified !".to_string(),
-                    False => resp = "Transaction is not correct !".to_string(),
-                }
-                return resp;
+                return match self.verify_transaction(&message) {
+                    Ok(confirmations) => format!("Transaction is verified ! {} confirmations", confirmations),
+                    Err(e) => format!("Transaction is not verified: {}", e),
+                };
             }
             _ => "Unknown command".to_string()
         }
     }
 
+    /// Returns our addresses, the main one first
+    pub fn addresses(&self) -> Vec<String> {
+        let holder = self.holder.lock().unwrap();
+        match holder.addresses() {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                println!("Err: {}, only the main address is used", e);
+                holder.public_key().map(|public_key| address::from_public_key(&public_key)).into_iter().collect()
+            }
+        }
+    }
+
+    /// Sums the balances of our addresses, and what our waiting transactions will give and take
+    pub fn balance(&self) -> Result<WalletBalance, CommandError> {
+        let addresses = self.addresses();
+        let mut balance = WalletBalance::default();
+        for address in &addresses {
+            let summary = self.ask_balance(address)?;
+            balance.confirmed = balance.confirmed.saturating_add(summary.balance);
+            balance.spendable = balance.spendable.saturating_add(summary.spendable);
+        }
+        for transaction in self.ask_pending(&addresses)? {
+            let (received, sent) = transaction.value_for(&addresses);
+            balance.incoming = balance.incoming.saturating_add(received);
+            balance.outgoing = balance.outgoing.saturating_add(sent);
+        }
+        Ok(balance)
+    }
+
+    /// Writes a page of our history, a line for each transaction with its block, its confirmations and what it gave or took
+    /// *`page` - the number of the page, from 1
+    pub fn describe_history(&self, history: &HistoryPage, page: usize) -> String {
+        let addresses = self.addresses();
+        let pages = (history.total as usize).div_ceil(HISTORY_PAGE_SIZE).max(1);
+        let mut lines = format!("page {} of {}, {} transactions", page, pages, history.total);
+        for entry in &history.entries {
+            if let TransactionStatus::Confirmed { location, confirmations, transaction } = entry {
+                let value = match transaction.parse::<Transaction>() {
+                    Ok(parsed) => {
+                        let (received, sent) = parsed.value_for(&addresses);
+                        signed_value(received, sent)
+                    }
+                    Err(_) => "?".to_string(),
+                };
+                lines.push_str(&format!("\nblock {}, {} confirmations: {} {}", location.height, confirmations, block::transaction_id(transaction), value));
+            }
+        }
+        lines
+    }
+
+    /// Sends `amount` to `to` with a transaction carrying `message`, signed with our key
+    /// Returns the answer shown to the user, see `submit_transaction`
+    pub fn send_transaction(&self, to: &str, amount: u64, fee: Option<u64>, message: &str) -> String {
+        println!("Sending transaction to Miner...");
+        match self.submit_transaction(to, amount, fee, message) {
+            Ok(transaction) => format!("Transaction {} sent with a fee of {}", transaction.id(), transaction.fee()),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    /// Builds the transaction sending `amount` to `to` and carrying `message`, signed with our key, then sends it
+    /// The transaction is a transfer or a spend, depending on the ledger mode of the chain of our Miner
+    /// It is kept until it is settled, to be sent again to the Miners taking over, see `rebroadcast`
+    /// *`fee` - the fee paid to the miner of the block, when None the `normal` estimate of our Miner is paid
+    /// Returns the transaction sent
+    pub fn submit_transaction(&self, to: &str, amount: u64, fee: Option<u64>, message: &str) -> Result<Transaction, CommandError> {
+        let from = self.holder.lock().unwrap().key()?.public_key();
+        let (mut transaction, _) = self.build_transaction(&from, to, amount, fee, message)?;
+        transaction.sign(self.holder.lock().unwrap().key()?);
+        self.submit_signed(&transaction)?;
+        Ok(transaction)
+    }
+
+    /// Sends a signed transaction to our Miner, another one taking over if it fails, and keeps it until it is settled, unless the Miner rejects it
+    pub fn submit_signed(&self, transaction: &Transaction) -> Result<(), CommandError> {
+        match self.push_transaction(&self.miner(), &transaction.to_string()) {
+            Err(CommandError::Miner(e)) => {
+                let miner = self.fail_over(&e)?;
+                self.push_transaction(&miner, &transaction.to_string())?;
+            }
+            result => result?,
+        }
+        self.remember_sent(transaction);
+        Ok(())
+    }
+
+    /// Builds the transaction sending `amount` to `to` from the owner of the public key `from`, not signed yet
+    /// The transaction is a transfer or a spend, depending on the ledger mode of the chain of our Miner
+    /// *`fee` - see `submit_transaction`
+    /// Returns the transaction, and the outputs it spends with their amounts for a spend
+    pub fn build_transaction(&self, from: &str, to: &str, amount: u64, fee: Option<u64>, message: &str) -> Result<(Transaction, Vec<(OutPoint, u64)>), CommandError> {
+        address::check(to)?;
+        match self.ask_chain_config()?.ledger {
+            LedgerMode::Account => Ok((self.build_transfer(from, to, amount, fee, message)?, Vec::new())),
+            LedgerMode::Utxo => self.build_spend(from, to, amount, fee, message),
+        }
+    }
+
+    /// Builds the transaction sending `amount` to `to` from our main address, then writes it to `path` unsigned,
+    /// to be signed by a wallet kept offline with our keys, see `sign_offline`
+    /// A watch-only wallet sends from the first public key it watches
+    /// *`fee` - see `submit_transaction`
+    /// Returns the transaction written
+    pub fn export_transaction(&self, path: &Path, to: &str, amount: u64, fee: Option<u64>, message: &str) -> Result<OfflineTransaction, CommandError> {
+        let from = self.holder.lock().unwrap().public_key()
+            .ok_or_else(|| "the wallet watches no public key, the transaction can not be built".to_string())?;
+        let (transaction, inputs) = self.build_transaction(&from, to, amount, fee, message)?;
+        let ledger = match transaction {
+            Transaction::Spend { .. } => LedgerMode::Utxo,
+            _ => LedgerMode::Account,
+        };
+        let offline = OfflineTransaction { ledger, inputs, transaction };
+        offline.write(path).map_err(|e| format!("{}, could not write {}", e, path.display()))?;
+        Ok(offline)
+    }
+
+    /// Signs a transaction read from a file with the key of its sender, which must be in our keystore
+    /// Nothing is asked to the Miner, the wallet may be offline
+    pub fn sign_offline(&self, offline: &mut OfflineTransaction) -> Result<(), CommandError> {
+        let sender = offline.transaction.sender().ok_or_else(|| "a coinbase is not signed".to_string())?.to_string();
+        let keys = self.holder.lock().unwrap().keystore()?.keys().map_err(|e| e.to_string())?;
+        let key = keys.iter().find(|key| key.public_key() == sender)
+            .ok_or_else(|| format!("the keystore holds no key of {}", address::from_public_key(&sender)))?;
+        offline.transaction.sign(key);
+        Ok(())
+    }
+
+    /// Reads a transaction signed offline from `path`, then sends it to our Miner, see `submit_signed`
+    /// Returns the transaction sent
+    pub fn import_transaction(&self, path: &Path) -> Result<Transaction, CommandError> {
+        let offline = OfflineTransaction::read(path)?;
+        if !offline.is_signed() {
+            return Err(CommandError::Failed(format!("{} is not signed", path.display())));
+        }
+        self.submit_signed(&offline.transaction)?;
+        Ok(offline.transaction)
+    }
+
+    /// Sends a transaction to `miner`, which adds it to its mempool and broadcasts it, then waits for its answer
+    /// Returns why `miner` rejected the transaction
+    pub fn push_transaction(&self, miner: &str, transaction: &str) -> Result<(), CommandError> {
+        let result = self.ask(miner, Flag::Transaction, transaction.to_string(), Flag::TransactionResult)?;
+        match result.split_once(' ') {
+            None if result == "accepted" => Ok(()),
+            Some(("rejected", reason)) => Err(CommandError::Failed(format!("Miner {} rejected the transaction: {}", miner, reason))),
+            _ => Err(CommandError::Miner(format!("invalid answer from the Miner: {}", result))),
+        }
+    }
+
+    /// Builds a transfer for an account chain, not signed yet, its nonce following the transactions of `from` known by our Miner,
+    /// mined or waiting
+    /// *`from` - the public key of the sender
+    /// *`fee` - see `submit_transaction`
+    pub fn build_transfer(&self, from: &str, to: &str, amount: u64, fee: Option<u64>, message: &str) -> Result<Transaction, CommandError> {
+        let summary = self.ask_balance(&address::from_public_key(from))?;
+        let nonce = summary.next_nonce;
+        let fee = match fee {
+            Some(fee) => fee,
+            None => {
+                let rate = self.ask_fee_estimate()?.normal;
+                // The size of the fee itself is not known yet, count the longest one
+                let size = Transaction::transfer(from, to, amount, u64::MAX, nonce, message).signed_size();
+                mempool::fee_for_rate(rate, size)
+            }
+        };
+        let needed = amount.checked_add(fee).ok_or_else(|| "amount and fee overflow".to_string())?;
+        if needed > summary.spendable {
+            return Err(CommandError::Failed(format!("only {} spendable, {} needed", summary.spendable, needed)));
+        }
+        Ok(Transaction::transfer(from, to, amount, fee, nonce, message))
+    }
+
+    /// Builds a spend for a UTXO chain, not signed yet: the biggest unspent outputs of `from` are spent until they cover
+    /// the amount and the fee, what is left coming back to it in a second output
+    /// *`from` - the public key of the sender
+    /// *`fee` - see `submit_transaction`
+    /// Returns the spend and the outputs it spends, with their amounts
+    pub fn build_spend(&self, from: &str, to: &str, amount: u64, fee: Option<u64>, message: &str) -> Result<(Transaction, Vec<(OutPoint, u64)>), CommandError> {
+        let address = address::from_public_key(from);
+        let mut unspent = self.ask_unspent(&address)?;
+        unspent.sort_by_key(|(_, value)| Reverse(*value));
+        let rate = match fee {
+            Some(_) => 0,
+            None => self.ask_fee_estimate()?.normal,
+        };
+        let mut fee = fee.unwrap_or(0);
+        // More inputs make the transaction bigger, and its fee higher, until the inputs cover it
+        loop {
+            let needed = amount.checked_add(fee).ok_or_else(|| "amount and fee overflow".to_string())?;
+            let mut spent: Vec<(OutPoint, u64)> = Vec::new();
+            let mut total: u64 = 0;
+            for (outpoint, value) in &unspent {
+                if total >= needed {
+                    break;
+                }
+                spent.push((outpoint.clone(), *value));
+                total = total.saturating_add(*value);
+            }
+            let inputs: Vec<OutPoint> = spent.iter().map(|(outpoint, _)| outpoint.clone()).collect();
+            if total < needed {
+                return Err(CommandError::Failed(format!("only {} spendable, {} needed", total, needed)));
+            }
+            if rate > 0 {
+                // The size of the fee and the change are not known yet, count the longest ones
+                let longest = vec![Output { address: to.to_string(), amount }, Output { address: address.clone(), amount: u64::MAX }];
+                let size = Transaction::spend(from, inputs.clone(), longest, u64::MAX, message).signed_size();
+                let required = mempool::fee_for_rate(rate, size);
+                if required > fee {
+                    fee = required;
+                    continue;
+                }
+            }
+            let mut outputs = vec![Output { address: to.to_string(), amount }];
+            if total > needed {
+                outputs.push(Output { address: address.clone(), amount: total - needed });
+            }
+            return Ok((Transaction::spend(from, inputs, outputs, fee, message), spent));
+        }
+    }
+
+    /// Asks our Miner for the consensus parameters of its chain
+    pub fn ask_chain_config(&self) -> Result<ChainConfig, CommandError> {
+        let config = self.ask_miner(Flag::RequireChainConfig, String::new(), Flag::ChainConfig)?;
+        config.parse::<ChainConfig>().map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner for the outputs `address` can spend, with their amounts
+    pub fn ask_unspent(&self, address: &str) -> Result<Vec<(OutPoint, u64)>, CommandError> {
+        let unspent = self.ask_miner(Flag::RequireUnspent, address.to_string(), Flag::Unspent)?;
+        utxo::decode_unspent(&unspent).map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner for the fee rates to pay, the answer comes back on the same connection
+    pub fn ask_fee_estimate(&self) -> Result<FeeEstimate, CommandError> {
+        let estimate = self.ask_miner(Flag::RequireFeeEstimate, String::new(), Flag::FeeEstimate)?;
+        estimate.parse::<FeeEstimate>().map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner for the balance, spendable amount and next nonce of `address`
+    pub fn ask_balance(&self, address: &str) -> Result<AccountSummary, CommandError> {
+        let summary = self.ask_miner(Flag::RequireBalance, address.to_string(), Flag::Balance)?;
+        summary.parse::<AccountSummary>().map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner where the transaction `txid` is: in its main chain, in its mempool or nowhere
+    pub fn ask_transaction(&self, txid: &str) -> Result<TransactionStatus, CommandError> {
+        let status = self.ask_miner(Flag::RequireTransaction, txid.to_string(), Flag::TransactionStatus)?;
+        status.parse::<TransactionStatus>().map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner for the IDs of the transactions of its main chain sending to or from `address`, oldest first
+    pub fn ask_history(&self, address: &str) -> Result<Vec<String>, CommandError> {
+        self.ask_parts(Flag::RequireHistory, address, Flag::History, ',')
+    }
+
+    /// Asks our Miner for a page of the transactions of its main chain sending to or from `addresses`, newest first
+    /// *`skip` - the number of newer transactions, on the previous pages
+    /// *`count` - the size of the page
+    pub fn ask_history_page(&self, addresses: &[String], skip: usize, count: usize) -> Result<HistoryPage, CommandError> {
+        let request = format!("{} {} {}", skip, count, addresses.join(","));
+        let page = self.ask_miner(Flag::RequireHistoryPage, request, Flag::HistoryPage)?;
+        page.parse::<HistoryPage>().map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner for the transactions of its mempool sending to or from `addresses`, in order of arrival
+    pub fn ask_pending(&self, addresses: &[String]) -> Result<Vec<Transaction>, CommandError> {
+        self.ask_parts(Flag::RequirePending, &addresses.join(","), Flag::Pending, block::TRANSACTION_SEPARATOR)?
+            .iter()
+            .map(|transaction| transaction.parse::<Transaction>())
+            .collect::<Result<Vec<Transaction>, String>>()
+            .map_err(CommandError::Miner)
+    }
+
+    /// Asks our Miner for every part of a long answer, see `protocol::answer_part`
+    /// Returns the items of the parts, separated by `separator`
+    fn ask_parts(&self, flag: Flag, request: &str, expected: Flag, separator: char) -> Result<Vec<String>, CommandError> {
+        let mut items: Vec<String> = Vec::new();
+        loop {
+            let message = match items.is_empty() {
+                true => request.to_string(),
+                false => format!("{} {}", request, items.len()),
+            };
+            let (part, more) = protocol::read_part(&self.ask_miner(flag, message, expected)?, separator);
+            if more && part.is_empty() {
+                return Err(CommandError::Miner("empty part of an answer announcing more".to_string()));
+            }
+            items.extend(part);
+            if !more {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Downloads the blocks of the main chain of our Miner we do not have yet, into `data/<socket>.blocks`
+    /// Each request starts with our last block: if the Miner no longer has it in its main chain, it is forgotten
+    /// and the sync goes back one block, until our blocks follow its chain again
+    /// The blocks are saved after each request, so an interrupted sync resumes where it stopped
+    /// Their proof of work is checked with the difficulty of the first sync, see `BlockStore::trust_difficulty`
+    pub fn sync(&self) -> Result<SyncReport, CommandError> {
+        let mut store = BlockStore::load(&self.socket);
+        let difficulty = store.trust_difficulty(self.ask_chain_config()?.difficulty).map_err(CommandError::Miner)?;
+        let mut report = SyncReport::default();
+        let saved = |store: &BlockStore| store.save().map_err(|e| CommandError::Failed(format!("{}, while saving the blocks", e)));
+        loop {
+            let from = store.height().saturating_sub(1) as u32;
+            let (mut blocks, height) = self.ask_blocks(from, u32::MAX, MAX_BLOCKS_PER_REQUEST)?;
+            if let Some(tip) = store.tip() {
+                if blocks.first().map(|first| first.hash != tip.hash).unwrap_or(true) {
+                    store.rewind(1);
+                    report.rewound += 1;
+                    continue;
+                }
+                blocks.remove(0);
+            }
+            if blocks.is_empty() {
+                break;
+            }
+            report.downloaded += blocks.len();
+            let appended = store.append(blocks, difficulty);
+            saved(&store)?;
+            appended.map_err(|e| CommandError::Miner(format!("invalid blocks from the Miner: {}", e)))?;
+            if store.height() >= height {
+                break;
+            }
+        }
+        saved(&store)?;
+        report.height = store.height();
+        Ok(report)
+    }
+
+    /// Asks our Miner for the blocks of its main chain from height `from` to `to` included, `max` at most
+    /// When our Miner fails, another one takes over and the request is sent to it, see `fail_over`
+    /// Returns the blocks and the height of the chain of the Miner
+    pub fn ask_blocks(&self, from: u32, to: u32, max: u32) -> Result<(Vec<Block>, usize), CommandError> {
+        match self.fetch_blocks(&self.miner(), from, to, max) {
+            Err(CommandError::Miner(e)) => {
+                let miner = self.fail_over(&e)?;
+                self.fetch_blocks(&miner, from, to, max)
+            }
+            result => result,
+        }
+    }
+
+    /// Asks `miner` for blocks of its main chain, which come back one by one on the same connection,
+    /// then an end marker with the number of blocks sent and the height of its chain
+    /// Returns the blocks and the height, or an error if the blocks are invalid or fewer than the Miner sent
+    pub fn fetch_blocks(&self, miner: &str, from: u32, to: u32, max: u32) -> Result<(Vec<Block>, usize), CommandError> {
+        let unreachable = |e: io::Error| CommandError::Miner(format!("{}, Miner {} unreachable", e, miner));
+        let mut stream = secure::connect(self.transport.as_ref(), miner, &self.identity).map_err(unreachable)?;
+        let m: &[u8] = &encode_message(Flag::RequireBlockchain, self.socket.to_string(), self.id.to_string(), format!("{} {} {}", from, to, max));
+        stream.set_timeout(Some(ANSWER_TIMEOUT)).and_then(|_| stream.send(m)).map_err(unreachable)?;
+        let mut blocks: Vec<Block> = Vec::new();
+        loop {
+            let data = stream.recv().map_err(unreachable)?
+                .ok_or_else(|| CommandError::Miner(format!("Miner {} closed the connection before the end of the blocks", miner)))?;
+            let (flag, _, _, message) = decode_message(&data).map_err(|e| CommandError::Miner(format!("invalid answer from the Miner: {}", e)))?;
+            let message = message.trim_matches(char::from(0));
+            match flag {
+                Flag::SendBlockchain if blocks.len() < max as usize => {
+                    blocks.push(message.parse::<Block>().map_err(|e| CommandError::Miner(format!("invalid block from the Miner: {}", e)))?);
+                }
+                Flag::EndBlockchain => {
+                    let end: Vec<usize> = message.split(' ').filter_map(|number| number.parse::<usize>().ok()).collect();
+                    return match end.as_slice() {
+                        [count, height] if *count == blocks.len() => Ok((blocks, *height)),
+                        [count, _] => Err(CommandError::Miner(format!("{} blocks received, the Miner sent {}", blocks.len(), count))),
+                        _ => Err(CommandError::Miner("invalid end of the blocks from the Miner".to_string())),
+                    };
+                }
+                flag => return Err(CommandError::Miner(format!("unexpected answer {:?} from the Miner", flag))),
+            }
+        }
+    }
+
+    /// Sends a request to our Miner and waits for the answer, which comes back on the same connection
+    /// When our Miner fails, another one takes over and the request is sent to it, see `fail_over`
+    /// *`answer` - the flag the answer must have
+    /// Returns the message of the answer
+    pub fn ask_miner(&self, flag: Flag, message: String, answer: Flag) -> Result<String, CommandError> {
+        match self.ask(&self.miner(), flag, message.clone(), answer) {
+            Err(CommandError::Miner(e)) => {
+                let miner = self.fail_over(&e)?;
+                self.ask(&miner, flag, message, answer)
+            }
+            result => result,
+        }
+    }
+
+    /// Sends a request to `miner` and waits for the answer, which comes back on the same connection
+    /// *`answer` - the flag the answer must have
+    /// Returns the message of the answer
+    pub fn ask(&self, miner: &str, flag: Flag, message: String, answer: Flag) -> Result<String, CommandError> {
+        let unreachable = |e: io::Error| CommandError::Miner(format!("{}, Miner {} unreachable", e, miner));
+        let mut stream = secure::connect(self.transport.as_ref(), miner, &self.identity).map_err(unreachable)?;
+        let m: &[u8] = &encode_message(flag, self.socket.to_string(), self.id.to_string(), message);
+        stream.set_timeout(Some(ANSWER_TIMEOUT)).and_then(|_| stream.send(m)).map_err(unreachable)?;
+        match stream.recv().map_err(unreachable)? {
+            Some(data) => match decode_message(&data) {
+                Ok((flag, _, _, message)) if flag == answer => Ok(message.trim_matches(char::from(0)).to_string()),
+                Ok((flag, _, _, _)) => Err(CommandError::Miner(format!("unexpected answer {:?} from the Miner", flag))),
+                Err(e) => Err(CommandError::Miner(format!("invalid answer from the Miner: {}", e))),
+            },
+            None => Err(CommandError::Miner("no answer from the Miner".to_string())),
+        }
+    }
+
+    /// Pings `miner` and records whether it answered, see `Miner::health_check`
+    /// Returns the round-trip time, or why the Miner is not healthy
+    pub fn health_check(&self, miner: &str) -> Result<Duration, String> {
+        let checked = Miner::health_check(self.transport.as_ref(), &self.socket, self.id, miner, &self.identity);
+        let mut miners = self.miners.lock().unwrap();
+        match checked {
+            Ok(rtt) => {
+                miners.record_success(miner, rtt);
+                Ok(rtt)
+            }
+            Err(e) => {
+                miners.record_failure(miner);
+                Err(match e {
+                    HealthCheckError::Unreachable(reason) => format!("{}, Miner {} unreachable", reason, miner),
+                    HealthCheckError::BadAck => format!("Miner {} answered the health check wrongly", miner),
+                })
+            }
+        }
+    }
+
+    /// Health checks the Miners we know, then lists them
+    /// If the Miner in use does not answer, another one takes over
+    pub fn check_miners(&self) -> String {
+        let miners = self.miners.lock().unwrap().miners().to_vec();
+        for miner in &miners {
+            let _ = self.health_check(miner);
+        }
+        let current = self.miner();
+        if self.miners.lock().unwrap().status(&current).failures > 0 {
+            if let Err(e) = self.fail_over(&format!("Miner {} does not answer health checks", current)) {
+                self.report(&format!("Err: {}", e));
+            }
+        }
+        self.miners.lock().unwrap().describe()
+    }
+
+    /// Replaces our Miner, which failed: the other Miners we know are health checked, the healthiest first,
+    /// and the first one answering takes over, see `take_over`
+    /// *`reason` - why our Miner failed
+    /// Returns the Miner which took over, or an error if none answers
+    pub fn fail_over(&self, reason: &str) -> Result<String, CommandError> {
+        let failed = self.miner();
+        self.miners.lock().unwrap().record_failure(&failed);
+        let candidates = self.miners.lock().unwrap().candidates();
+        for candidate in candidates {
+            if self.health_check(&candidate).is_ok() {
+                self.report(&format!("{}, Miner {} takes over", reason, candidate));
+                self.miners.lock().unwrap().promote(&candidate);
+                self.take_over(&candidate);
+                return Ok(candidate);
+            }
+        }
+        Err(CommandError::Miner(format!("{}, and no other Miner answers", reason)))
+    }
+
+    /// Makes a Miner which took over know us: it registers us if we listen for notifications, its peers are learned,
+    /// and our transactions not settled yet are sent again in case it never received them
+    fn take_over(&self, miner: &str) {
+        if self.listening.load(Ordering::SeqCst) {
+            if let Err(e) = self.register(miner) {
+                self.report(&format!("Err: {}, no notifications from Miner {}", e, miner));
+            }
+        }
+        self.learn_miners(miner);
+        let resent = self.rebroadcast(miner);
+        if resent > 0 {
+            self.report(&format!("{} transactions not settled sent again to Miner {}", resent, miner));
+        }
+    }
+
+    /// Asks `miner` for a wallet ID, which makes it accept our subscriptions, then subscribes to our addresses
+    /// Our current ID is sent along, the Miner keeps it when no other wallet of its own has it
+    /// Everything is sent on the same connection so the Miner handles the subscriptions after the registration
+    pub fn register(&self, miner: &str) -> Result<(), String> {
+        let addresses = self.holder.lock().unwrap().addresses().map_err(|e| e.to_string())?;
+        let mut stream = secure::connect(self.transport.as_ref(), miner, &self.identity).map_err(|e| e.to_string())?;
+        let registration = encode_message(Flag::RequireWalletID, self.socket.to_string(), self.id.to_string(), String::new());
+        stream.send(&registration).map_err(|e| e.to_string())?;
+        for address in addresses {
+            let m: &[u8] = &encode_message(Flag::Subscribe, self.socket.to_string(), self.id.to_string(), address);
+            stream.send(m).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Asks `miner` for the other Miners of its network, and remembers them to take over when our Miner fails
+    /// Returns the number of Miners we did not know
+    pub fn learn_miners(&self, miner: &str) -> usize {
+        match self.ask(miner, Flag::RequirePeers, String::new(), Flag::Peers) {
+            Ok(peers) => self.miners.lock().unwrap().learn(peers.split(',').map(|peer| peer.to_string())),
+            Err(e) => {
+                self.report(&format!("Err: {}, no Miner learned", e));
+                0
+            }
+        }
+    }
+
+    /// Keeps a transaction we sent in `data/<socket>.sent` until it is settled, see `rebroadcast`
+    fn remember_sent(&self, transaction: &Transaction) {
+        let path = storage::node_file(&self.socket, "sent");
+        let saved = storage::read_lines(&path).and_then(|mut sent| {
+            sent.push(transaction.to_string());
+            storage::write_lines(&path, &sent)
+        });
+        if let Err(e) = saved {
+            self.report(&format!("Err: {}, transaction {} will not be sent again if our Miner fails", e, transaction.id()));
+        }
+    }
+
+    /// Sends again to `miner` the transactions we sent which it does not know, lost when our previous Miner failed
+    /// The transactions with `SETTLED_CONFIRMATIONS` confirmations are forgotten, and so are those which can not be included anymore
+    /// Returns the number of transactions sent again
+    pub fn rebroadcast(&self, miner: &str) -> usize {
+        let path = storage::node_file(&self.socket, "sent");
+        let sent = match storage::read_lines(&path) {
+            Ok(sent) => sent,
+            Err(e) => {
+                self.report(&format!("Err: {}, our transactions could not be sent again", e));
+                return 0;
+            }
+        };
+        let mut kept: Vec<String> = Vec::new();
+        let mut resent = 0;
+        for transaction in sent {
+            let parsed = match transaction.parse::<Transaction>() {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            let txid = parsed.id();
+            let status = self.ask(miner, Flag::RequireTransaction, txid.clone(), Flag::TransactionStatus)
+                .and_then(|status| status.parse::<TransactionStatus>().map_err(CommandError::Miner));
+            match status {
+                Ok(TransactionStatus::Confirmed { confirmations, .. }) if confirmations >= notify::SETTLED_CONFIRMATIONS => continue,
+                Ok(TransactionStatus::Unknown) => match self.is_superseded(miner, &parsed) {
+                    Ok(true) => {
+                        self.report(&format!("Transaction {} can not be included anymore, it is forgotten", txid));
+                        continue;
+                    }
+                    Ok(false) if self.push_transaction(miner, &transaction).is_ok() => resent += 1,
+                    _ => {}
+                },
+                _ => {}
+            }
+            kept.push(transaction);
+        }
+        if let Err(e) = storage::write_lines(&path, &kept) {
+            self.report(&format!("Err: {}, while saving our transactions not settled", e));
+        }
+        resent
+    }
+
+    /// Asks `miner` whether a transaction it does not know can not be included anymore, see `superseded`
+    fn is_superseded(&self, miner: &str, transaction: &Transaction) -> Result<bool, CommandError> {
+        let from = match transaction.sender() {
+            Some(key) => address::from_public_key(key),
+            None => return Ok(false),
+        };
+        let (next_nonce, unspent) = match transaction {
+            Transaction::Spend { .. } => {
+                let unspent = self.ask(miner, Flag::RequireUnspent, from, Flag::Unspent)?;
+                (0, utxo::decode_unspent(&unspent).map_err(CommandError::Miner)?)
+            }
+            _ => {
+                let summary = self.ask(miner, Flag::RequireBalance, from, Flag::Balance)?;
+                (summary.parse::<AccountSummary>().map_err(CommandError::Miner)?.next_nonce, Vec::new())
+            }
+        };
+        Ok(superseded(transaction, next_nonce, &unspent))
+    }
+
+    /// Shows what the wallet did on its own, on the error output for one-shot commands so their output stays parsable
+    fn report(&self, message: &str) {
+        match self.listening.load(Ordering::SeqCst) {
+            true => println!("{}", message),
+            false => eprintln!("{}", message),
+        }
+    }
+
     /// This handles incoming message, by decoding them and transforming them into usable data
-    /// *`stream` - is a TcpStream instance containing the bytes that we received
-    pub fn handle_message(&self, mut stream: TcpStream) -> String {
-        let mut data = [0 as u8; 50];
-        match stream.read(&mut data) {
-            Ok(size) if size > 0 => {
-                let response_decoded = decode_id_response(std::str::from_utf8(&data[32..size]).unwrap().to_owned());
-                return response_decoded;
+    /// *`stream` - is an authenticated connection on which we receive the message
+    pub fn handle_message(&self, mut stream: SecureStream) -> String {
+        match stream.recv() {
+            Ok(Some(data)) => {
+                match decode_message(&data) {
+                    Ok((_, _, _, response)) => return response,
+                    Err(e) => println!("Invalid message received: {}", e),
+                }
             },
-            Ok(_) => { println!("No message received");},
+            Ok(None) => { println!("No message received");},
             Err(e) => {
                 println!("Error occured, closing connection: {}", e);
-                stream.shutdown(Shutdown::Both).unwrap();
+                stream.shutdown();
                 return "Error".to_string();
             }
         }
@@ -293,12 +1247,12 @@ impl Wallet {
     pub fn send_message(&self, destination: &String, message: &String, flag: Flag) -> Result<u8, &'static str> {
         let f = flag as u8;
         println!("Sending message: {} \nTo: {} .. {} \nWith Flag: {}", &message, &destination, &destination.chars().count(), &f);
-        match TcpStream::connect(&destination) {
+        match secure::connect(self.transport.as_ref(), destination, &self.identity) {
             Ok(mut stream) => {
                 println!("Connection established.");
                 let m: &[u8] = &encode_message(flag, self.socket.to_string(), self.id.to_string(), message.to_string());
                 println!("Byte message: {:?}",&m);
-                match stream.write(m) {
+                match stream.send(m) {
                     Ok(_) => println!("Message writen in buffer"),
                     Err(e) => println!("Error during writing: {}",e.to_string()),
                 }
@@ -312,104 +1266,49 @@ impl Wallet {
         }
     }
 
-    /// Function to decode a single Block that was sended via a TCP connection
-    /// * `encoded_block` - a string containing the info of a block
-    /// Return the input data as a Block struct as defined
-    pub fn decode_block(&self, encoded_block: String) -> block::Block {
-        let new_block = block::Block::from_str(&encoded_block).unwrap();
-        return new_block;
-    }
-
-    /// Function to decode the Blockchain sended via a TCP connection by the Miner
-    /// * `stream` - a TCPStream containing the data that needs to be handled
-    /// * `blocks` - a vector representing the Blockchain
-    /// Update the `blocks` variable by adding every received block to it
-    pub fn handle_blockchain(&self, mut stream: TcpStream, blocks: &mut Vec<block::Block>) -> bool {
-        let mut data = [0 as u8; 500];
-        match stream.read(&mut data) {
-            Ok(size) if size > 0 => {
-                let tuple : (Flag, String, String, String) = decode_message(&data);
-                let new_block = self.decode_block(tuple.3);
-                blocks.push(new_block);
-                return true;
-            },
-            Ok(_) => { return false; },
-            Err(e) => {
-                println!("Error occured, closing connection: {}", e);
-                stream.shutdown(Shutdown::Both).unwrap();
-                return false;
-            }
-        }
-    }
-
-    /// Function to get the Blockchain from our Miner
-    /// Returns the whole Blockchain
-    pub fn get_blockchain_from_miner(&self) -> Vec<block::Block> {
-        let miner = &self.miner;
-        let socket = &self.socket;
-        println!("Asking {} for wallet ID", miner);
-        let listener = TcpListener::bind(socket).unwrap();
-        // Ask the ID
-        if let Ok(mut stream) = TcpStream::connect(&miner) {
-            let m: &[u8] = &encode_message(Flag::RequireBlockchain, socket.to_string(), "".to_string(), "".to_string());
-            match stream.write(m) {
-                Ok(_) => { println!("Asked for Blockchain"); }
-                Err(e) => { println!("Error: {}", e); }
-            }
-        }
-        
-        println!("Getting Blockchain from Miner");
-        let mut blockchain: Vec<block::Block>;
-        blockchain = Vec::new();
-        // Handle the response
-        let mut i = 0;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let not_empty = self.handle_blockchain(stream, &mut blockchain);
-                    if(!not_empty) {
-                        i += 1;
-                    } else {
-                        i = 0;
-                    }
-                    if(i == 5) {
-                        return blockchain;
-                    }
-                }
-                Err(e) => {
-                    panic!("Error: {}", e);
-                }
-            }
-        }
-        return blockchain;
-    }
-
-    /// Function to verify if a transaction is valid
-    /// 
-    pub fn verify_transaction(&self, transaction: Vec<u8>) -> bool {
-        // First get the Blockchain from Miner
-        let blockchain = self.get_blockchain_from_miner();
-        let mut hashchain: Vec::<Vec<u8>> = Vec::new();
-        for block in blockchain.iter() {
-            hashchain.push(block.hash.clone());
+    /// Asks our Miner for a proof that the transaction `txid` is in its main chain, then checks the proof against
+    /// the blocks we downloaded, synced first, see `sync`: the Merkle branch must lead to the root of the block header,
+    /// the header must carry a proof of work of the difficulty we keep and be the one of our block at its height
+    /// Returns the number of confirmations of the transaction in our blocks, or why it could not be verified
+    pub fn verify_transaction(&self, txid: &str) -> Result<u32, CommandError> {
+        self.sync()?;
+        let store = BlockStore::load(&self.socket);
+        let difficulty = store.difficulty().ok_or_else(|| CommandError::Failed("no difficulty kept, the blocks were never synced".to_string()))?;
+        let proof = self.ask_miner(Flag::RequireProof, txid.to_string(), Flag::Proof)?;
+        if proof.is_empty() {
+            return Err(CommandError::Failed(format!("transaction {} is not in the main chain of the Miner", txid)));
         }
-        // Then transform it into a Merkle Tree
-        static digest: &'static Algorithm = &ring::digest::SHA256;
-        let merkle_tree = merkle::MerkleTree::from_vec(digest, hashchain);
-        // Check if the transaction is known by the Merkel tree
-        let proof = merkle_tree.gen_proof(transaction);
-        match proof {
-            Some(x) => {
-                return x.validate(merkle_tree.root_hash());
-            }
-            None => {
-                return false;
-            }
+        let proof = proof.parse::<InclusionProof>()?;
+        proof.verify(txid, difficulty)?;
+        let header = &proof.header;
+        match store.get(header.index) {
+            Some(block) if block.header() == *header => Ok(store.height() as u32 - header.index),
+            Some(_) => Err(CommandError::Miner(format!("block {} of the proof is not the one of our chain", header.index))),
+            None => Err(CommandError::Failed(format!("block {} is not synced yet", header.index))),
         }
     }
 }
 
 
+/// Writes what a transaction changed for us, `+received`, `-sent` or `0`
+fn signed_value(received: u64, sent: u64) -> String {
+    match received.cmp(&sent) {
+        std::cmp::Ordering::Greater => format!("+{}", received - sent),
+        std::cmp::Ordering::Less => format!("-{}", sent - received),
+        std::cmp::Ordering::Equal => "0".to_string(),
+    }
+}
+
+/// Tells whether a transaction we sent can not be included anymore, another one took its place
+/// *`next_nonce` - the nonce the next transfer of the sender must use, a transfer with a lower nonce is superseded
+/// *`unspent` - the outputs the sender can spend, a spend of any other output is superseded
+fn superseded(transaction: &Transaction, next_nonce: u64, unspent: &[(OutPoint, u64)]) -> bool {
+    match transaction {
+        Transaction::Transfer { nonce, .. } => *nonce < next_nonce,
+        Transaction::Spend { inputs, .. } => inputs.iter().any(|input| unspent.iter().all(|(outpoint, _)| outpoint != input)),
+        Transaction::Coinbase { .. } => false,
+    }
+}
 
 impl Debug for Wallet {
     fn fmt (&self, f: &mut Formatter) -> fmt::Result {
@@ -417,4 +1316,25 @@ impl Debug for Wallet {
             &self.id,
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transactions_are_superseded_once_their_nonce_or_inputs_are_used() {
+        let key = WalletKey::generate().unwrap();
+        let to = address::from_public_key(&key.public_key());
+        let transfer = Transaction::signed_transfer(&key, &to, 10, 1, 3, "");
+        assert!(!superseded(&transfer, 3, &[]));
+        assert!(superseded(&transfer, 4, &[]));
+
+        let (kept, spent) = (OutPoint { txid: "ab".repeat(32), index: 0 }, OutPoint { txid: "cd".repeat(32), index: 1 });
+        let output = Output { address: to.clone(), amount: 10 };
+        let spend = Transaction::spend(&key.public_key(), vec![kept.clone(), spent.clone()], vec![output], 5, "");
+        assert!(!superseded(&spend, 0, &[(kept.clone(), 5), (spent, 10)]));
+        assert!(superseded(&spend, 0, &[(kept, 5)]));
+        assert!(!superseded(&Transaction::Coinbase { height: 1, to, amount: 50 }, 9, &[]));
+    }
+}
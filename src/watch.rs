@@ -0,0 +1,172 @@
+use std::io;
+use std::path::PathBuf;
+use crate::address;
+use crate::keys;
+use crate::storage;
+
+/// Version written at the top of every watch list, a list of a later version is refused
+const WATCH_VERSION: u32 = 1;
+
+/// What a watch-only wallet remembers between runs: the addresses it tracks, without any private key
+/// Nothing is secret, so nothing is encrypted and no passphrase is asked. It is written one `name value` per line
+/// *`path` - where the list is saved, `data/<socket>.watch`
+/// *`miner` - the IP address of the Miner the wallet talks to
+/// *`wallet_id` - the ID this Miner gave the wallet
+/// *`watched` - the public keys (hex) and addresses tracked, in the order they were added.
+/// A public key also lets the wallet build transactions spending from its address, to be signed offline
+pub struct WatchList {
+    path: PathBuf,
+    pub miner: String,
+    pub wallet_id: u32,
+    watched: Vec<String>,
+}
+
+impl WatchList {
+
+    /// Returns true if the wallet listening on `sockip` is watch-only
+    pub fn exists(sockip: &str) -> bool {
+        storage::read_lines(&storage::node_file(sockip, "watch")).is_ok_and(|lines| !lines.is_empty())
+    }
+
+    /// Opens the watch list of the wallet listening on `sockip`, or creates an empty one the first time
+    /// A wallet which already has a keystore can not become watch-only
+    /// *`miner` - the Miner the wallet talks to, saved in a new list
+    pub fn open_or_create(sockip: &str, miner: &str) -> io::Result<Self> {
+        if WatchList::exists(sockip) {
+            return WatchList::open_existing(sockip);
+        }
+        let keystore = storage::node_file(sockip, "keystore");
+        if !storage::read_lines(&keystore)?.is_empty() {
+            return Err(invalid_watch_list(&format!("{} holds keys, the wallet can not be watch-only", keystore.display())));
+        }
+        let watch_list = WatchList { path: storage::node_file(sockip, "watch"), miner: miner.to_string(), wallet_id: 0, watched: Vec::new() };
+        watch_list.save()?;
+        Ok(watch_list)
+    }
+
+    /// Opens the watch list of the wallet listening on `sockip`, fails if it has none
+    pub fn open_existing(sockip: &str) -> io::Result<Self> {
+        let path = storage::node_file(sockip, "watch");
+        let lines = storage::read_lines(&path)?;
+        if lines.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no watch list {}, start the wallet with --watch first", path.display())));
+        }
+        let field = |name: &str| lines.iter()
+            .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')))
+            .ok_or_else(|| invalid_watch_list(&format!("missing field {}", name)));
+        let number = |name: &str| field(name)?.parse::<u32>().map_err(|e| invalid_watch_list(&format!("invalid {}: {}", name, e)));
+        let version = number("version")?;
+        if version == 0 || version > WATCH_VERSION {
+            return Err(invalid_watch_list("unsupported watch list version"));
+        }
+        let watched: Vec<String> = lines.iter().filter_map(|line| line.strip_prefix("watch ")).map(|entry| entry.to_string()).collect();
+        if let Some(e) = watched.iter().find_map(|entry| check_entry(entry).err()) {
+            return Err(invalid_watch_list(&e));
+        }
+        Ok(WatchList { miner: field("miner")?.to_string(), wallet_id: number("id")?, path, watched })
+    }
+
+    /// Starts tracking a public key or an address, then saves the list
+    /// A public key replaces its address when the address is already tracked, it can then be exported from
+    /// Returns the address tracked, None if it already was
+    pub fn add(&mut self, entry: &str) -> Result<Option<String>, String> {
+        check_entry(entry)?;
+        let address = entry_address(entry);
+        match self.watched.iter().position(|watched| entry_address(watched) == address) {
+            Some(position) if is_public_key(entry) && !is_public_key(&self.watched[position]) => self.watched[position] = entry.to_string(),
+            Some(_) => return Ok(None),
+            None => self.watched.push(entry.to_string()),
+        }
+        self.save().map_err(|e| format!("{}, while saving the watch list", e))?;
+        Ok(Some(address))
+    }
+
+    /// Returns the addresses tracked, in the order they were added
+    pub fn addresses(&self) -> Vec<String> {
+        self.watched.iter().map(|entry| entry_address(entry)).collect()
+    }
+
+    /// Returns the first public key tracked, the one whose address the unsigned transactions spend from
+    pub fn public_key(&self) -> Option<String> {
+        self.watched.iter().find(|entry| is_public_key(entry)).cloned()
+    }
+
+    /// Writes the list
+    pub fn save(&self) -> io::Result<()> {
+        let mut lines = vec![
+            format!("version {}", WATCH_VERSION),
+            format!("miner {}", self.miner),
+            format!("id {}", self.wallet_id),
+        ];
+        lines.extend(self.watched.iter().map(|entry| format!("watch {}", entry)));
+        storage::write_lines(&self.path, &lines)
+    }
+}
+
+/// Checks that `entry` is an address, see `address::check`, or a public key written as hex
+pub fn check_entry(entry: &str) -> Result<(), String> {
+    match is_public_key(entry) {
+        true => Ok(()),
+        false => address::check(entry).map_err(|e| format!("{}, and not a public key either", e)),
+    }
+}
+
+/// Returns true if `entry` is the hex of a public key rather than an address
+fn is_public_key(entry: &str) -> bool {
+    entry.len() == 2 * keys::PUBLIC_KEY_SIZE && hex::decode(entry).is_ok()
+}
+
+/// Returns the address of an entry of the list, the entry itself if it is already one
+fn entry_address(entry: &str) -> String {
+    match is_public_key(entry) {
+        true => address::from_public_key(entry),
+        false => entry.to_string(),
+    }
+}
+
+fn invalid_watch_list(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::WalletKey;
+    use crate::testutil::temp_path;
+    use crate::wallet::Holder;
+
+    fn watch_list(name: &str) -> WatchList {
+        WatchList { path: temp_path(name), miner: "127.0.0.1:8000".to_string(), wallet_id: 0, watched: Vec::new() }
+    }
+
+    #[test]
+    fn public_keys_replace_their_address() {
+        let mut watch_list = watch_list("watch-replace");
+        let key = WalletKey::generate().unwrap();
+        assert!(watch_list.add("not an address").is_err());
+        assert_eq!(watch_list.add(&key.address()), Ok(Some(key.address())));
+        assert_eq!(watch_list.public_key(), None);
+        assert_eq!(watch_list.add(&key.public_key()), Ok(Some(key.address())));
+        assert_eq!(watch_list.add(&key.address()), Ok(None));
+        assert_eq!(watch_list.addresses(), vec![key.address()]);
+        assert_eq!(watch_list.public_key(), Some(key.public_key()));
+        assert_eq!(storage::read_lines(&watch_list.path).unwrap().last(), Some(&format!("watch {}", key.public_key())));
+        std::fs::remove_file(&watch_list.path).unwrap();
+    }
+
+    #[test]
+    fn watch_only_wallets_refuse_to_sign() {
+        let mut watch_list = watch_list("watch-sign");
+        let key = WalletKey::generate().unwrap();
+        watch_list.add(&key.public_key()).unwrap();
+        let path = watch_list.path.clone();
+        let mut holder = Holder::Watch(watch_list);
+
+        // Transactions can still be built from the public key, to be signed offline
+        assert_eq!(holder.public_key(), Some(key.public_key()));
+        assert_eq!(holder.addresses().unwrap(), vec![key.address()]);
+        assert!(holder.key().err().is_some_and(|e| e.to_string().contains("can not sign")));
+        assert!(holder.keystore().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}